@@ -1,4 +1,5 @@
 #include <inttypes.h>
+#include <stdbool.h>
 
 /*
  * Sets the log level for the library.
@@ -77,7 +78,7 @@ int32_t krun_set_root_disk(uint32_t ctx_id, const char *disk_path);
 
 /*
  * Sets the path to the disk image that contains the file-system to be used as a data partition for the microVM.
- * The only supported image format is "raw". Only available in libkrun-SEV.
+ * The only supported image format is "raw". Not available on macOS.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
@@ -89,6 +90,265 @@ int32_t krun_set_root_disk(uint32_t ctx_id, const char *disk_path);
  */
 int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
 
+/* Supported disk image formats */
+#define KRUN_DISK_FORMAT_RAW 0
+#define KRUN_DISK_FORMAT_QCOW2 1
+#define KRUN_DISK_FORMAT_NBD 2
+
+/*
+ * Adds a disk image to be used as a general partition for the microVM. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "block_id"    - a null-terminated string representing the partition. It must be unique
+ *                  and can't be "root" or "data", which are reserved for krun_set_root_disk
+ *                  and krun_set_data_disk.
+ *  "disk_path"   - a null-terminated string representing the path leading to the disk image.
+ *                  For KRUN_DISK_FORMAT_NBD, it's the URI of the export instead, either
+ *                  "nbd://host[:port]/export" or "nbd+unix:///export?socket=/path/to/socket".
+ *  "disk_format" - the format of the disk image. Supported values are KRUN_DISK_FORMAT_RAW,
+ *                  KRUN_DISK_FORMAT_QCOW2 and KRUN_DISK_FORMAT_NBD. Backing files of qcow2
+ *                  images are opened read-only, and relative paths are resolved from the
+ *                  image's directory. NBD servers must support the fixed newstyle handshake,
+ *                  and the connection is made when the microVM starts.
+ *  "read_only"   - whether the disk should be exposed to the guest as read-only.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when a disk with the same "block_id" was already added
+ */
+int32_t krun_add_disk(uint32_t ctx_id, const char *block_id, const char *disk_path,
+                      uint32_t disk_format, bool read_only);
+
+/* Flags for krun_add_disk_overlay */
+#define KRUN_DISK_OVERLAY_DISCARD_ON_EXIT (1 << 0)
+
+/*
+ * Adds a disk backed by a read-only base image and a copy-on-write overlay file. Writes from
+ * the guest only go to the overlay, so the same base image can be shared by many microVMs.
+ * Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "block_id"     - a null-terminated string representing the partition, with the same
+ *                   constraints as in krun_add_disk.
+ *  "base_path"    - a null-terminated string representing the path leading to the base image,
+ *                   which is never written to.
+ *  "base_format"  - the format of the base image, KRUN_DISK_FORMAT_RAW, KRUN_DISK_FORMAT_QCOW2
+ *                   or KRUN_DISK_FORMAT_NBD.
+ *  "overlay_path" - a null-terminated string representing the path leading to the overlay. If it
+ *                   doesn't exist or is empty it's created, otherwise it must have been created
+ *                   over a base image of the same size.
+ *  "flags"        - KRUN_DISK_OVERLAY_DISCARD_ON_EXIT removes the overlay as soon as it's opened,
+ *                   throwing away every write to the disk when the microVM exits.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when a disk with the same "block_id" was already added
+ */
+int32_t krun_add_disk_overlay(uint32_t ctx_id, const char *block_id, const char *base_path,
+                              uint32_t base_format, const char *overlay_path, uint32_t flags);
+
+/*
+ * Sets the number of request queues of a disk previously configured with krun_set_root_disk,
+ * krun_set_data_disk or krun_add_disk. Whatever the number of queues, requests are executed
+ * asynchronously, using io_uring for raw images and a pool of worker threads otherwise, so a
+ * slow disk doesn't hold up other devices, and may complete out of order. More queues let the
+ * guest submit requests from several CPUs at once. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "block_id"   - a null-terminated string identifying the disk ("root", "data", or the
+ *                 "block_id" given to krun_add_disk).
+ *  "num_queues" - the number of request queues, between 1 (the default) and 16.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ *       -EINVAL when "num_queues" is out of range
+ */
+int32_t krun_set_disk_queues(uint32_t ctx_id, const char *block_id, uint32_t num_queues);
+
+/*
+ * Sets whether a disk previously configured with krun_set_root_disk, krun_set_data_disk,
+ * krun_add_disk or krun_add_disk_overlay is exposed to the guest as read-only. Not available
+ * on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "block_id"  - a null-terminated string identifying the disk.
+ *  "read_only" - whether the guest is only allowed to read the disk.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_read_only(uint32_t ctx_id, const char *block_id, bool read_only);
+
+/*
+ * Sets the serial the guest reads from a disk, which Linux guests expose in
+ * /dev/disk/by-id/virtio-<serial>. By default, the serial is derived from the device and inode
+ * numbers of the disk image. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "block_id" - a null-terminated string identifying the disk.
+ *  "serial"   - a null-terminated string of up to 20 bytes.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "serial" is longer than 20 bytes
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_serial(uint32_t ctx_id, const char *block_id, const char *serial);
+
+/*
+ * Sets the block sizes the guest is told to use for a disk. Images formatted with 4096 byte
+ * sectors need a logical block size of 4096. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"              - the configuration context ID.
+ *  "block_id"            - a null-terminated string identifying the disk.
+ *  "logical_block_size"  - the smallest unit the guest addresses, in bytes. A power of two
+ *                          between 512 and 65536, or zero for 512.
+ *  "physical_block_size" - the smallest unit the guest can write without a read-modify-write
+ *                          cycle on the host, in bytes. A power of two no smaller than the
+ *                          logical block size and no larger than 65536, or zero for the logical
+ *                          block size.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when a block size is out of range
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_block_size(uint32_t ctx_id, const char *block_id,
+                                 uint32_t logical_block_size, uint32_t physical_block_size);
+
+/*
+ * Sets the maximum number of data buffers the guest may use in a single request to a disk.
+ * By default the limit isn't advertised, and Linux guests use a single buffer per request.
+ * Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "block_id" - a null-terminated string identifying the disk.
+ *  "seg_max"  - the number of buffers, up to 254, or zero to not advertise a limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "seg_max" is larger than 254
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_seg_max(uint32_t ctx_id, const char *block_id, uint32_t seg_max);
+
+/*
+ * Verifies every block the guest reads from a disk against a hash tree, like dm-verity does.
+ * Reads of blocks that don't match fail with an I/O error in the guest, so a tampered disk
+ * image can't feed the guest unexpected data. The disk is exposed as read-only, unless it
+ * was configured with krun_add_disk_overlay, in which case the base image is verified.
+ * Not available on macOS.
+ *
+ * The hash tree must be in the format created by "veritysetup format", using sha256 and
+ * including the superblock, which is the default.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "block_id"    - a null-terminated string identifying the disk.
+ *  "hash_path"   - path of the file holding the hash tree.
+ *  "hash_offset" - offset of the verity superblock in "hash_path", in bytes.
+ *  "root_hash"   - a null-terminated string with the trusted root hash, as the 64 hexadecimal
+ *                  digits printed by "veritysetup format".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "root_hash" isn't a valid sha256 digest
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_verity(uint32_t ctx_id, const char *block_id, const char *hash_path,
+                             uint64_t hash_offset, const char *root_hash);
+
+/* Supported disk cache modes */
+#define KRUN_DISK_CACHE_UNSAFE 0
+#define KRUN_DISK_CACHE_WRITEBACK 1
+#define KRUN_DISK_CACHE_DIRECT 2
+
+/*
+ * Sets how a disk previously configured with krun_set_root_disk, krun_set_data_disk,
+ * krun_add_disk or krun_add_disk_overlay uses the host page cache. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "block_id"   - a null-terminated string identifying the disk.
+ *  "cache_mode" - one of:
+ *                   KRUN_DISK_CACHE_UNSAFE: writes go through the host page cache and flush
+ *                   requests aren't supported, so data may be lost if the host crashes.
+ *                   KRUN_DISK_CACHE_WRITEBACK (the default): writes go through the host page
+ *                   cache, and flush requests from the guest sync them to the host's storage.
+ *                   KRUN_DISK_CACHE_DIRECT: the disk image is opened with O_DIRECT, bypassing the
+ *                   host page cache, and flush requests are honored. Only supported for raw
+ *                   images whose size is a multiple of the logical block size (4096 bytes for
+ *                   regular files). This is the equivalent of QEMU's "none" cache mode.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_cache_mode(uint32_t ctx_id, const char *block_id, uint32_t cache_mode);
+
+/*
+ * Tells the guest a disk image was resized on the host, so it picks up the new capacity
+ * without rebooting. Can be called from another thread while the microVM is running. Only
+ * supported for raw disk images without an overlay. Not available on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "block_id" - a null-terminated string identifying the disk.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when, with direct I/O, the new size isn't a multiple of the logical block size
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ *       -ENOTSUP when the disk can't be resized
+ */
+int32_t krun_resize_disk(uint32_t ctx_id, const char *block_id);
+
+/*
+ * Limits the bandwidth and the rate of operations of a disk previously configured with
+ * krun_set_root_disk, krun_set_data_disk, krun_add_disk or krun_add_disk_overlay. Each limit is
+ * a token bucket refilled at the given rate, which can hold up to "burst" tokens. Requests over
+ * budget are deferred until enough tokens are available. This function may also be called from
+ * another thread while the microVM is running, to change the limits at runtime. Not available
+ * on macOS.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "block_id"      - a null-terminated string identifying the disk.
+ *  "bytes_per_sec" - the number of bytes the disk may transfer every second, or zero for no limit.
+ *  "bytes_burst"   - the number of bytes that may be transferred at once after an idle period, or
+ *                    zero to allow one second worth of transfers.
+ *  "ops_per_sec"   - the number of requests the disk may execute every second, or zero for no
+ *                    limit.
+ *  "ops_burst"     - the number of requests that may be executed at once after an idle period, or
+ *                    zero to allow one second worth of requests.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when no disk with the given "block_id" has been configured
+ */
+int32_t krun_set_disk_rate_limit(uint32_t ctx_id, const char *block_id, uint64_t bytes_per_sec,
+                                 uint64_t bytes_burst, uint64_t ops_per_sec, uint64_t ops_burst);
+
 /*
  * Configures the mapped volumes for the microVM. Only supported on macOS, on Linux use
  * user_namespaces and bind-mounts instead. Not available in libkrun-SEV.
@@ -104,7 +364,9 @@ int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
 int32_t krun_set_mapped_volumes(uint32_t ctx_id, char *const mapped_volumes[]);
 
 /*
- * Configures the networking to use passt.
+ * Configures the networking to use passt, adding a virtio-net interface with the "iface_id"
+ * "eth0". It's a shorthand for krun_add_net with the "eth0" "iface_id" and a "passt:FD"
+ * backend, so it can be combined with the other functions adding interfaces, in any order.
  * Call to this function disables TSI backend to use passt instead.
  *
  * Arguments:
@@ -117,26 +379,310 @@ int32_t krun_set_mapped_volumes(uint32_t ctx_id, char *const mapped_volumes[]);
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when an interface with the "eth0" "iface_id" was already added, for instance
+ *               by an earlier call to krun_add_net without "iface_id"
+ *       -ENOTSUP when libkrun was built without network devices
  */
 int32_t krun_set_passt_fd(uint32_t ctx_id, int fd);
 
 /*
- * Configures a map of host to guest TCP ports for the microVM.
+ * Adds a virtio-net interface backed by a passt instance that libkrun starts itself, instead
+ * of one started by the caller like with krun_set_passt_fd. libkrun creates the socket pair,
+ * runs passt in the foreground with its end of it, and restarts passt whenever it exits,
+ * keeping the guest's interface in place. The guest is then asked to announce itself, so the
+ * new instance learns its address right away. If passt keeps exiting right after starting,
+ * libkrun waits longer and longer before starting it again, up to 30 seconds, but never gives
+ * up. libkrun stops passt when the microVM exits. Linux only.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "iface_id"   - a null-terminated string identifying the interface in other calls, or NULL
+ *                 to use the first free one of "eth0", "eth1"...
+ *  "passt_path" - a null-terminated string with the path of the passt executable.
+ *  "argv"       - an array of string pointers with extra arguments for passt, which must end
+ *                 with a NULL pointer, or NULL for none. libkrun adds "--foreground" and "--fd".
+ *  "mac"        - a pointer to the 6 bytes of the MAC address of the guest interface, or NULL
+ *                 to let the guest pick a random one.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when an interface with the same "iface_id" was already added
+ *       -ENOTSUP when libkrun was built without network devices, or not for Linux
+ */
+int32_t krun_add_net_passt(uint32_t ctx_id, const char *iface_id, const char *passt_path,
+                           const char *const argv[], const uint8_t *mac);
+
+/*
+ * Creates a learning switch, which connects the virtio-net interfaces of microVMs running in
+ * this process on a private ethernet segment, like a bridge on the host would, without any
+ * privileges. The switch learns which interface each MAC address is behind from the frames
+ * it forwards, and floods the frames for unknown addresses to every other interface. It runs
+ * in its own thread until it's destroyed with krun_net_switch_destroy, and an interface leaves
+ * it when its microVM exits, once the switch next tries to forward a frame to it.
+ *
+ * Returns:
+ *  The switch ID on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_net_switch_create(void);
+
+/*
+ * Destroys a switch created by krun_net_switch_create, stopping its thread and closing its
+ * ports. The interfaces still connected to it lose their link to the other microVMs.
+ *
+ * Arguments:
+ *  "switch_id" - the switch ID.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there's no such switch
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_net_switch_destroy(uint32_t switch_id);
+
+/*
+ * Adds a virtio-net interface connected to a port of a switch created by
+ * krun_net_switch_create. It uses the first free "iface_id" of "eth0", "eth1"...
+ * Call to this function disables TSI backend. The interface is added next to the one set by
+ * krun_set_passt_fd, if any.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "switch_id" - the switch ID.
+ *  "mac"       - a pointer to the 6 bytes of the MAC address of the guest interface, or NULL
+ *                to let the guest pick a random one. Interfaces on the same switch need
+ *                different addresses.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there's no such context or switch
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_add_net_switch_port(uint32_t ctx_id, uint32_t switch_id, const uint8_t *mac);
+
+/*
+ * Adds a virtio-net interface to the microVM. It can be called several times to give the
+ * microVM several interfaces, which the guest sees in the order they were added.
+ * Call to this function disables TSI backend. The interface is added next to the one set by
+ * krun_set_passt_fd, if any.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "iface_id" - a null-terminated string identifying the interface in other calls, or NULL to
+ *               use the first free one of "eth0", "eth1"... It's not visible to the guest.
+ *  "backend"  - a null-terminated string describing where the frames of the interface go on
+ *               the host, in "type:argument" or "type" format:
+ *                 "passt:FD"       - a socket connected to a running passt instance. A
+ *                                    comma-separated list of sockets gives the interface one
+ *                                    queue pair for each.
+ *                 "stream:PATH"    - a unix stream socket speaking the format of QEMU's
+ *                                    "-netdev stream", where each frame is prefixed by its
+ *                                    length as a 32-bit big-endian integer.
+ *                 "unixgram:PATH"  - a unix datagram socket carrying one frame per datagram,
+ *                                    like gvproxy's vfkit mode. libkrun binds its end of the
+ *                                    socket next to PATH and sends the "VFKT" greeting first.
+ *                 "unixgram-fd:FD" - a unix datagram socket already connected to the server.
+ *                 "tap:NAME"       - a TAP interface, created if it doesn't exist (Linux only).
+ *                 "tap-fd:FD"      - a file descriptor already attached to a TAP interface with
+ *                                    IFF_VNET_HDR, for instance by a CNI plugin (Linux only).
+ *                                    A comma-separated list of file descriptors attached to
+ *                                    the queues of a IFF_MULTI_QUEUE interface gives the
+ *                                    interface one queue pair for each.
+ *                 "nat"            - a userspace NAT built into libkrun, which needs no
+ *                                    privileges. It serves the guest 10.0.2.15/24 over DHCP,
+ *                                    reaches the host's loopback interface through the gateway
+ *                                    10.0.2.2 and forwards DNS from 10.0.2.3 to the host's first
+ *                                    nameserver. Ping and protocols other than TCP and UDP need
+ *                                    ping sockets or raw sockets to be allowed on the host.
+ *               libkrun takes ownership of the file descriptors.
+ *  "mac"      - a pointer to the 6 bytes of the MAC address of the guest interface, or NULL to
+ *               let the guest pick a random one.
+ *  "mtu"      - the MTU advertised to the guest, or zero to let the guest use the ethernet
+ *               default. It should match the MTU of the host side of the network.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when an interface with the same "iface_id" was already added
+ *       -EINVAL when "backend" isn't valid or "mtu" is lower than 68
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_add_net(uint32_t ctx_id, const char *iface_id, const char *backend,
+                     const uint8_t *mac, uint16_t mtu);
+
+/*
+ * Writes every frame exchanged by a virtio-net interface with its backend to a pcapng file,
+ * as if it was captured on the guest's side of the interface. Frames sent by the guest are
+ * flagged as outbound and frames delivered to it as inbound.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "iface_id" - a null-terminated string with the ID of an interface added by krun_add_net, or
+ *               "eth0" for the interface set by krun_set_passt_fd.
+ *  "path"     - a null-terminated string with the path of the capture file, which is truncated
+ *               when the microVM starts, or NULL to stop capturing.
+ *  "max_size" - once the capture file grows past this many bytes, it's renamed with a ".1"
+ *               suffix, replacing the previous one, and a new file is started. This keeps the
+ *               most recent frames while using at most about twice this size. Zero means no
+ *               limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there's no interface with the given "iface_id"
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_set_net_pcap(uint32_t ctx_id, const char *iface_id, const char *path,
+                          uint64_t max_size);
+
+/*
+ * Sets the number of receive and transmit queue pairs of a virtio-net interface. The guest
+ * spreads its traffic across them, each of them being served independently, and may choose
+ * to use fewer. Each queue pair has its own queue of a "tap" backend, which requires the
+ * TAP interface to support IFF_MULTI_QUEUE, while "passt" and "tap-fd" backends need one file
+ * descriptor per queue pair. Other backends only support a single queue pair.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "iface_id"        - a null-terminated string with the ID of an interface added by
+ *                      krun_add_net.
+ *  "num_queue_pairs" - the number of queue pairs, between 1 (the default, or the number of
+ *                      file descriptors given to the backend) and 16.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there's no interface with the given "iface_id"
+ *       -EINVAL when "num_queue_pairs" is out of range
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_set_net_queues(uint32_t ctx_id, const char *iface_id, uint32_t num_queue_pairs);
+
+/*
+ * Plugs the virtual cable of a virtio-net interface in or out, for instance to simulate a
+ * network partition. While the link is down, the guest sees no carrier and every frame sent
+ * or received by the interface is dropped. When it comes back up, the guest is asked to
+ * announce itself to the network. Can be called before starting the microVM, to start with
+ * the link down, or from another thread while the microVM is running.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "iface_id" - a null-terminated string with the ID of an interface added by krun_add_net, or
+ *               "eth0" for the interface set by krun_set_passt_fd.
+ *  "up"       - whether the link is up.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there's no interface with the given "iface_id"
+ *       -ENOTSUP when libkrun was built without network devices
+ */
+int32_t krun_set_link_state(uint32_t ctx_id, const char *iface_id, bool up);
+
+#define KRUN_RATE_LIMIT_RX 0
+#define KRUN_RATE_LIMIT_TX 1
+
+/*
+ * Limits the bandwidth and the packet rate of a virtio-net interface in one direction. Each
+ * limit is a token bucket refilled at the given rate, which can hold up to "burst" tokens.
+ * Frames over budget stay queued, in the guest or in the backend, until enough tokens are
+ * available. This function may also be called from another thread while the microVM is
+ * running, to change the limits at runtime. Only available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "iface_id"        - a null-terminated string with the ID of an interface added by
+ *                      krun_add_net, or "eth0" for the interface set by krun_set_passt_fd.
+ *  "direction"       - KRUN_RATE_LIMIT_RX for the frames received by the guest, or
+ *                      KRUN_RATE_LIMIT_TX for the ones it sends.
+ *  "bytes_per_sec"   - the number of bytes that may go through every second, or zero for no
+ *                      limit.
+ *  "bytes_burst"     - the number of bytes that may go through at once after an idle period, or
+ *                      zero to allow one second worth of traffic.
+ *  "packets_per_sec" - the number of frames that may go through every second, or zero for no
+ *                      limit.
+ *  "packets_burst"   - the number of frames that may go through at once after an idle period,
+ *                      or zero to allow one second worth of frames.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "direction" is invalid
+ *       -ENOENT when there's no interface with the given "iface_id"
+ *       -ENOTSUP when libkrun was built without network devices or not for Linux
+ */
+int32_t krun_set_net_rate_limit(uint32_t ctx_id, const char *iface_id, uint32_t direction,
+                                uint64_t bytes_per_sec, uint64_t bytes_burst,
+                                uint64_t packets_per_sec, uint64_t packets_burst);
+
+/*
+ * Adds a virtio-net interface connected to a TAP interface of the host, so the microVM can join
+ * a host bridge. It's a shorthand for krun_add_net with a "tap:" backend, no "iface_id" and no
+ * "mtu". Only available on Linux.
+ * Call to this function disables TSI backend. The interface is added next to the one set by
+ * krun_set_passt_fd, if any.
  *
  * Arguments:
  *  "ctx_id"   - the configuration context ID.
- *  "port_map" - an array of string pointers with format "host_port:guest_port"
+ *  "tap_name" - a null-terminated string with the name of the TAP interface. It's created if it
+ *               doesn't exist yet, which requires CAP_NET_ADMIN.
+ *  "mac"      - a pointer to the 6 bytes of the MAC address of the guest interface, or NULL to
+ *               let the guest pick a random one.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  *  Documented errors:
- *       -ENOTSUP when passt networking is used
+ *       -ENOTSUP when libkrun was built without network devices or not for Linux
+ */
+int32_t krun_add_net_tap(uint32_t ctx_id, const char *tap_name, const uint8_t *mac);
+
+/*
+ * Like krun_add_net_tap, but uses a file descriptor already attached to a TAP interface, for
+ * instance by a CNI plugin. The interface must have been set up with IFF_VNET_HDR. libkrun
+ * takes ownership of the file descriptor.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor of /dev/net/tun attached to a TAP interface.
+ *  "mac"    - a pointer to the 6 bytes of the MAC address of the guest interface, or NULL to
+ *             let the guest pick a random one.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOTSUP when libkrun was built without network devices or not for Linux
+ */
+int32_t krun_add_net_tap_fd(uint32_t ctx_id, int fd, const uint8_t *mac);
+
+/*
+ * Configures a map of host to guest TCP and UDP ports for the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "port_map" - an array of string pointers with format "host_port:guest_port", for TCP, or
+ *               "host_port:guest_port/tcp" or "host_port:guest_port/udp".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOTSUP when passt or TAP networking is used
  *
  * Notes:
  *  Passing NULL (or not calling this function) as "port_map" has a different meaning than
  *  passing an empty array. The first one will instruct libkrun to attempt to expose all
  *  listening ports in the guest to the host, while the second means that no port from
- *  the guest will be exposed to host.
+ *  the guest will be exposed to host. Ports are exposed when a TCP socket of the guest listens,
+ *  and when a UDP socket of the guest is bound. A map without "/udp" entries leaves UDP ports
+ *  exposed as if no map was given, and UDP sockets bound to port 0 are always allowed. A bound
+ *  UDP socket only learns the contents of the datagrams it receives, not where they come from:
+ *  replies sent without a destination go to their sender, as long as they all came from the
+ *  same one, and fail with EDESTADDRREQ once a second sender shows up. Replies are subject to
+ *  the egress policy.
  *
  *  Exposed ports will only become accessible by their "host_port" in the guest too. This
  *  means that for a map such as "8080:80", applications running inside the guest will also
@@ -147,6 +693,83 @@ int32_t krun_set_passt_fd(uint32_t ctx_id, int fd);
  */
 int32_t krun_set_port_map(uint32_t ctx_id, char *const port_map[]);
 
+/*
+ * Configures a map of host to guest paths of unix sockets for the microVM. When an application
+ * in the guest connects a stream unix socket to one of the guest paths, TSI connects it to the
+ * unix socket of the host at the mapped path instead, for instance to reach the Docker daemon
+ * of the host through "/run/docker.sock" in the guest. Unix sockets of the guest can't connect
+ * to the host through any other path, and can't listen for connections from the host.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "unix_socket_map" - an array of string pointers with format "host_path:guest_path", both
+ *                      being absolute paths. The host path ends at the first ':', so only
+ *                      the guest path may contain ':'.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when an entry is malformed, or maps the same guest path twice
+ *       -ENOTSUP when passt or TAP networking is used
+ *
+ * Notes:
+ *  This requires a guest kernel with support for unix sockets in TSI.
+ */
+int32_t krun_set_unix_socket_map(uint32_t ctx_id, char *const unix_socket_map[]);
+
+/*
+ * Configures which destinations the microVM may reach through TSI. Each rule has the format
+ * "<action> <protocol> <network> <ports>", where the action is "allow" or "deny", the protocol
+ * "tcp", "udp" or "any", the network an IPv4 or IPv6 address with an optional prefix length, or
+ * "any", and the ports a port, a range like "8000-8080", or "any". For instance:
+ *
+ *     "allow tcp 151.101.0.0/16 443"
+ *     "allow udp 10.0.2.3 53"
+ *     "deny any any any"
+ *
+ * The first rule matching a connection or a datagram decides whether it goes through; those no
+ * rule matches are allowed. Denied connections fail in the guest with "denied_errno", while
+ * denied datagrams are dropped. Every denied attempt is logged as a warning.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "rules"        - an array of string pointers with the rules, terminated by a NULL pointer.
+ *  "denied_errno" - the error number, as Linux defines it, denied connections fail with, for
+ *                   instance 1 (EPERM) or 111 (ECONNREFUSED).
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when a rule is malformed or "denied_errno" isn't positive
+ *       -ENOTSUP when passt or TAP networking is used
+ */
+int32_t krun_set_egress_policy(uint32_t ctx_id, char *const rules[], int32_t denied_errno);
+
+/*
+ * Caps the throughput of TSI in one direction, for all the connections of the microVM together.
+ * The limit is a token bucket refilled at the given rate, which can hold up to "bytes_burst"
+ * tokens. Once it's exhausted, TSI stops reading from the guest, or from the host sockets,
+ * until enough tokens are available. This function may also be called from another thread
+ * while the microVM is running, to change the limit at runtime. Only available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "direction"     - KRUN_RATE_LIMIT_RX for the data received by the guest, or
+ *                    KRUN_RATE_LIMIT_TX for the data it sends.
+ *  "bytes_per_sec" - the number of bytes that may go through every second, or zero for no limit.
+ *  "bytes_burst"   - the number of bytes that may go through at once after an idle period, or
+ *                    zero to allow one second worth of traffic.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "direction" is invalid
+ *       -ENOENT when the microVM is running without TSI
+ *       -ENOTSUP when passt or TAP networking is used, or libkrun wasn't built for Linux
+ */
+int32_t krun_set_tsi_rate_limit(uint32_t ctx_id, uint32_t direction, uint64_t bytes_per_sec,
+                                uint64_t bytes_burst);
+
 /*
  * Configures a map of rlimits to be set in the guest before starting the isolated binary.
  *
@@ -14,6 +14,8 @@ use std::io;
 
 mod bus;
 pub mod legacy;
+#[cfg(target_os = "linux")]
+pub mod rate_limiter;
 pub mod virtio;
 
 pub use self::bus::{Bus, BusDevice, Error as BusError};
@@ -0,0 +1,332 @@
+//! Token bucket rate limiting for device I/O.
+//!
+//! A `RateLimiter` holds up to two token buckets, one counting bytes and one
+//! counting operations. When a request doesn't fit in the remaining budget,
+//! the limiter arms a timerfd for the moment it will, and reports itself as
+//! blocked until the device handles the timer event.
+
+use std::cmp;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use utils::timerfd::TimerFd;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Configuration of a single token bucket.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TokenBucketConfig {
+    /// Tokens added to the bucket every second. Zero disables the bucket.
+    pub rate: u64,
+    /// Maximum number of tokens the bucket holds, which is the largest burst
+    /// allowed after an idle period. Zero means one second worth of tokens.
+    pub burst: u64,
+}
+
+/// Configuration of a `RateLimiter`. The default doesn't limit anything.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RateLimiterConfig {
+    /// Limits the number of bytes transferred.
+    pub bandwidth: TokenBucketConfig,
+    /// Limits the number of operations.
+    pub ops: TokenBucketConfig,
+}
+
+/// A bucket holding up to `capacity` tokens, refilled at `rate` tokens per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    budget: u64,
+    // Tokens taken beyond the budget by a request larger than the bucket,
+    // which must be refilled before the budget grows again.
+    debt: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket, or `None` if the configuration disables it.
+    pub fn new(config: TokenBucketConfig) -> Option<Self> {
+        if config.rate == 0 {
+            return None;
+        }
+        let capacity = if config.burst == 0 {
+            config.rate
+        } else {
+            config.burst
+        };
+        Some(TokenBucket {
+            rate: config.rate,
+            capacity,
+            budget: capacity,
+            debt: 0,
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Adds the tokens accumulated since the last update.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_nanos();
+        let tokens = elapsed * self.rate as u128 / NANOS_PER_SEC;
+        if tokens == 0 {
+            return;
+        }
+        // Only account for the time it took to produce whole tokens, so the
+        // remainder isn't lost.
+        let used = tokens * NANOS_PER_SEC / self.rate as u128;
+        self.last_update += Duration::from_nanos(used as u64);
+
+        let tokens = cmp::min(tokens, u64::MAX as u128) as u64;
+        let repaid = cmp::min(tokens, self.debt);
+        self.debt -= repaid;
+        self.budget = cmp::min(self.budget.saturating_add(tokens - repaid), self.capacity);
+        if self.budget == self.capacity {
+            self.last_update = now;
+        }
+    }
+
+    /// Number of tokens missing for a request of `tokens` to go through.
+    /// Requests larger than the bucket only need it to be full.
+    fn missing(&self, tokens: u64) -> u64 {
+        let needed = cmp::min(tokens, self.capacity);
+        (self.debt + needed).saturating_sub(self.budget)
+    }
+
+    fn take(&mut self, tokens: u64) {
+        if tokens > self.budget {
+            self.debt += tokens - self.budget;
+            self.budget = 0;
+        } else {
+            self.budget -= tokens;
+        }
+    }
+
+    /// Time it takes to refill `tokens`.
+    fn refill_time(&self, tokens: u64) -> Duration {
+        let nanos = (tokens as u128 * NANOS_PER_SEC).div_ceil(self.rate as u128);
+        Duration::from_nanos(cmp::min(nanos, u64::MAX as u128) as u64)
+    }
+}
+
+/// Rate limiter for the bytes and operations of a device.
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    timer_fd: TimerFd,
+    // Set while waiting for the timer to expire.
+    blocked: bool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimiterConfig) -> io::Result<Self> {
+        Ok(RateLimiter {
+            bandwidth: TokenBucket::new(config.bandwidth),
+            ops: TokenBucket::new(config.ops),
+            timer_fd: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Tries to consume `ops` operations and `bytes` bytes, taking nothing
+    /// from either bucket unless both have enough budget. Returns false if
+    /// the caller has to wait for the timer event before trying again.
+    pub fn consume(&mut self, ops: u64, bytes: u64) -> bool {
+        if self.blocked {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, tokens) in [(&mut self.ops, ops), (&mut self.bandwidth, bytes)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                let missing = bucket.missing(tokens);
+                if missing > 0 {
+                    wait = cmp::max(wait, bucket.refill_time(missing));
+                }
+            }
+        }
+
+        if wait.is_zero() {
+            if let Some(bucket) = self.ops.as_mut() {
+                bucket.take(ops);
+            }
+            if let Some(bucket) = self.bandwidth.as_mut() {
+                bucket.take(bytes);
+            }
+            return true;
+        }
+
+        if let Err(e) = self.timer_fd.reset(wait, None) {
+            // Retrying right away is better than never waking up.
+            error!("Failed to arm rate limiter timer: {:?}", e);
+            return true;
+        }
+        self.blocked = true;
+        false
+    }
+
+    /// Takes `ops` operations and `bytes` bytes for a transfer that already
+    /// happened, because its size couldn't be known beforehand. Buckets may
+    /// be overdrawn, in which case the limiter blocks until they can afford
+    /// another operation and byte.
+    pub fn charge(&mut self, ops: u64, bytes: u64) {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, tokens) in [(&mut self.ops, ops), (&mut self.bandwidth, bytes)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                bucket.take(tokens);
+                let missing = bucket.missing(1);
+                if missing > 0 {
+                    wait = cmp::max(wait, bucket.refill_time(missing));
+                }
+            }
+        }
+
+        if wait.is_zero() || self.blocked {
+            return;
+        }
+        if let Err(e) = self.timer_fd.reset(wait, None) {
+            error!("Failed to arm rate limiter timer: {:?}", e);
+            return;
+        }
+        self.blocked = true;
+    }
+
+    /// Whether the limiter is waiting for its timer to expire.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Handles the expiration of the timer, after which requests can be retried.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        self.timer_fd.wait()?;
+        self.blocked = false;
+        Ok(())
+    }
+
+    /// Replaces the limits. The new buckets start full.
+    pub fn update(&mut self, config: RateLimiterConfig) {
+        self.bandwidth = TokenBucket::new(config.bandwidth);
+        self.ops = TokenBucket::new(config.ops);
+        // Let a blocked device retry under the new limits right away.
+        if self.blocked {
+            if let Err(e) = self.timer_fd.reset(Duration::from_nanos(1), None) {
+                error!("Failed to arm rate limiter timer: {:?}", e);
+            }
+        }
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        assert!(TokenBucket::new(TokenBucketConfig::default()).is_none());
+
+        let mut bucket = TokenBucket::new(TokenBucketConfig {
+            rate: 1000,
+            burst: 100,
+        })
+        .unwrap();
+        let start = bucket.last_update;
+        assert_eq!(bucket.capacity, 100);
+
+        bucket.refill(start);
+        assert_eq!(bucket.missing(100), 0);
+        bucket.take(60);
+        assert_eq!(bucket.missing(60), 20);
+        assert_eq!(bucket.refill_time(20), Duration::from_millis(20));
+
+        // 10ms add 10 tokens.
+        bucket.refill(start + Duration::from_millis(10));
+        assert_eq!(bucket.budget, 50);
+        // The budget never exceeds the capacity.
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.budget, 100);
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        let mut bucket = TokenBucket::new(TokenBucketConfig {
+            rate: 1000,
+            burst: 0,
+        })
+        .unwrap();
+        let start = bucket.last_update;
+        assert_eq!(bucket.capacity, 1000);
+
+        // A request larger than the bucket goes through when it's full, and
+        // the excess must be refilled before the next one.
+        assert_eq!(bucket.missing(1500), 0);
+        bucket.take(1500);
+        assert_eq!(bucket.debt, 500);
+        assert_eq!(bucket.missing(1), 501);
+
+        bucket.refill(start + Duration::from_millis(600));
+        assert_eq!(bucket.debt, 0);
+        assert_eq!(bucket.budget, 100);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimiterConfig::default()).unwrap();
+        for _ in 0..1000 {
+            assert!(limiter.consume(1, 1 << 20));
+        }
+
+        limiter.update(RateLimiterConfig {
+            bandwidth: TokenBucketConfig {
+                rate: 1 << 20,
+                burst: 0,
+            },
+            ops: TokenBucketConfig { rate: 2, burst: 0 },
+        });
+        assert!(limiter.consume(1, 4096));
+        // Nothing is taken from the bandwidth bucket when ops run out.
+        assert!(limiter.consume(1, 4096));
+        assert!(!limiter.consume(1, 4096));
+        assert!(limiter.is_blocked());
+        assert!(!limiter.consume(0, 0));
+        let budget = limiter.bandwidth.as_ref().unwrap().budget;
+        assert!(((1 << 20) - 8192..(1 << 20) - 4096).contains(&budget));
+
+        // The timer expires once an operation is available again.
+        limiter.event_handler().unwrap();
+        assert!(!limiter.is_blocked());
+        assert!(limiter.consume(1, 4096));
+    }
+
+    #[test]
+    fn test_charge() {
+        let mut limiter = RateLimiter::new(RateLimiterConfig {
+            bandwidth: TokenBucketConfig {
+                rate: 1000,
+                burst: 0,
+            },
+            ops: TokenBucketConfig::default(),
+        })
+        .unwrap();
+        limiter.charge(1, 600);
+        assert!(!limiter.is_blocked());
+        // Overdrawing the bucket blocks until the debt is repaid.
+        limiter.charge(1, 600);
+        assert!(limiter.is_blocked());
+        assert_eq!(limiter.bandwidth.as_ref().unwrap().debt, 200);
+        assert!(!limiter.consume(0, 0));
+
+        limiter.event_handler().unwrap();
+        assert!(!limiter.is_blocked());
+        assert!(limiter.consume(1, 1));
+    }
+}
@@ -0,0 +1,190 @@
+//! Disk image formats that can be used as the backing store of a virtio block device.
+
+mod nbd;
+mod overlay;
+mod qcow2;
+mod raw;
+mod verity;
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+
+pub use self::nbd::NbdDisk;
+pub use self::overlay::{OverlayDisk, DEFAULT_CHUNK_SIZE};
+pub use self::qcow2::Qcow2Disk;
+pub use self::raw::RawDisk;
+pub use self::verity::{VerityDisk, DIGEST_SIZE as VERITY_DIGEST_SIZE};
+
+/// Size of the zeroed buffer used to emulate write zeroes requests when the
+/// backend can't do it in a cheaper way.
+const ZERO_BUF_SIZE: usize = 64 * 1024;
+
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ImageType {
+    /// The image is exposed to the guest as-is.
+    #[default]
+    Raw,
+    /// The image is a QEMU Copy-On-Write version 2 or 3 file.
+    Qcow2,
+    /// The image is exported by an NBD server. Its path is an
+    /// `nbd://host[:port]/export` or `nbd+unix:///export?socket=path` URI.
+    Nbd,
+}
+
+/// Copy-on-write overlay layered over a disk image, which is then only read.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OverlayConfig {
+    /// Path of the overlay file. It's created if it doesn't exist.
+    pub path: String,
+    /// Throw the overlay away, along with every write to the disk, on exit.
+    pub discard_on_exit: bool,
+}
+
+/// Hash tree used to verify every block read from a disk image, in the
+/// format created by `veritysetup format`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerityConfig {
+    /// Path of the file holding the superblock and the hash tree.
+    pub hash_path: String,
+    /// Offset of the superblock in the file.
+    pub hash_offset: u64,
+    /// Trusted sha256 digest of the top level of the tree.
+    pub root_hash: Vec<u8>,
+}
+
+/// Operations needed from a disk image to back a virtio block device.
+///
+/// Offsets and lengths are expressed in bytes and refer to the virtual disk
+/// seen by the guest, not to the host file.
+pub trait DiskBackend: Send + Sync {
+    /// Size of the virtual disk.
+    fn size(&self) -> u64;
+
+    /// Fills `buf` with the contents of the virtual disk starting at `offset`.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes the whole `buf` to the virtual disk starting at `offset`.
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Makes sure all previous writes have reached the host's physical media.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Tells the backend the given range is no longer used by the guest.
+    /// Discarding is only a hint, so backends are free to ignore it.
+    fn discard(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Zeroes the given range. If `unmap` is set, the backend may also
+    /// deallocate it.
+    fn write_zeroes(&self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        write_zeroes_slow(self, offset, len)
+    }
+
+    /// Host file descriptor whose contents match the virtual disk byte for
+    /// byte, if any. I/O on it can be submitted directly to the host kernel.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Alignment required for the buffers, offsets and lengths of I/O
+    /// submitted on `raw_fd`.
+    fn io_alignment(&self) -> usize {
+        1
+    }
+
+    /// Re-reads the size of the virtual disk after the image was resized on
+    /// the host, and returns it.
+    fn refresh_size(&self) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "resizing is not supported for this disk",
+        ))
+    }
+}
+
+/// Zeroes a range of `disk` by writing a zero-filled buffer over it.
+pub(crate) fn write_zeroes_slow<D: DiskBackend + ?Sized>(
+    disk: &D,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let zeroes = vec![0u8; cmp::min(len, ZERO_BUF_SIZE as u64) as usize];
+    let mut done = 0u64;
+    while done < len {
+        let chunk = cmp::min(len - done, zeroes.len() as u64) as usize;
+        disk.write_all_at(&zeroes[..chunk], offset + done)?;
+        done += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Manipulates the allocated space of `file` with fallocate(2).
+pub(crate) fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // Safe because the file descriptor is valid and we check the return value.
+    let ret = unsafe { libc::fallocate64(file.as_raw_fd(), mode, offset as i64, len as i64) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates the backend for the disk image in `file`, which was opened from `path`.
+pub fn open_disk_image(
+    file: File,
+    path: &Path,
+    image_type: ImageType,
+    read_only: bool,
+) -> io::Result<Arc<dyn DiskBackend>> {
+    match image_type {
+        ImageType::Raw => Ok(Arc::new(RawDisk::new(file)?)),
+        ImageType::Qcow2 => Ok(Arc::new(Qcow2Disk::new(file, path, read_only)?)),
+        ImageType::Nbd => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NBD exports are not files",
+        )),
+    }
+}
+
+/// Connects to the NBD export at `uri`.
+pub fn open_nbd(uri: &str, read_only: bool) -> io::Result<Arc<dyn DiskBackend>> {
+    Ok(Arc::new(NbdDisk::new(uri, read_only)?))
+}
+
+/// Layers the overlay described by `config` over `base`.
+pub fn open_overlay(
+    base: Arc<dyn DiskBackend>,
+    config: &OverlayConfig,
+) -> io::Result<Arc<dyn DiskBackend>> {
+    let path = Path::new(&config.path);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(config.discard_on_exit)
+        .open(path)?;
+    if config.discard_on_exit {
+        // Unlink it right away, so it's gone even if we don't exit cleanly.
+        fs::remove_file(path)?;
+    }
+    Ok(Arc::new(OverlayDisk::new(base, file, DEFAULT_CHUNK_SIZE)?))
+}
+
+/// Verifies every read from `base` with the hash tree described by `config`.
+pub fn open_verity(
+    base: Arc<dyn DiskBackend>,
+    config: &VerityConfig,
+) -> io::Result<Arc<dyn DiskBackend>> {
+    let hash_tree = File::open(&config.hash_path)?;
+    Ok(Arc::new(VerityDisk::new(
+        base,
+        hash_tree,
+        config.hash_offset,
+        &config.root_hash,
+    )?))
+}
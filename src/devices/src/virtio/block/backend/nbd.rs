@@ -0,0 +1,1093 @@
+//! Client for disks exported by a Network Block Device server.
+//!
+//! Only the fixed newstyle handshake is supported. Structured replies are
+//! used when the server offers them. Requests are sent one at a time over a
+//! single connection. If a request fails halfway through, or the server
+//! doesn't answer in time, the connection is dropped and the next request
+//! opens a new one.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{write_zeroes_slow, DiskBackend};
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+// Handshake flags.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+
+// Option replies.
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+
+// Export information.
+const NBD_INFO_EXPORT: u16 = 0;
+const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+// Transmission flags.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Commands.
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+// Structured reply chunks.
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+/// Port NBD servers listen on by default.
+pub const DEFAULT_PORT: u16 = 10809;
+
+// Largest payload we send or request in a single command, unless the server
+// advertises a different limit. Every server must accept this much.
+const DEFAULT_MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+
+// Largest range trimmed or zeroed in a single command.
+const MAX_RANGE_LEN: u64 = 1 << 30;
+
+// How long connecting to a TCP server may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the server may take to accept or answer a request.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The URI isn't a valid `nbd://` or `nbd+unix://` URI.
+    InvalidUri(String),
+    /// I/O error talking to the server.
+    Io(io::Error),
+    /// The server sent something that doesn't follow the protocol.
+    Protocol(&'static str),
+    /// The export is read-only, but the disk was configured as writable.
+    ReadOnlyExport,
+    /// The server rejected an option during the handshake.
+    Rejected { option: u32, reply: u32 },
+    /// The server doesn't support the fixed newstyle handshake.
+    UnsupportedHandshake,
+    /// The export changed size or became read-only while reconnecting.
+    ExportChanged,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidUri(uri) => write!(f, "invalid NBD URI: {uri}"),
+            Io(e) => write!(f, "NBD I/O error: {e}"),
+            Protocol(msg) => write!(f, "NBD protocol error: {msg}"),
+            ReadOnlyExport => write!(f, "NBD export is read-only"),
+            Rejected { option, reply } => {
+                write!(
+                    f,
+                    "NBD server rejected option {option} with error {reply:#x}"
+                )
+            }
+            UnsupportedHandshake => write!(f, "NBD server doesn't support fixed newstyle"),
+            ExportChanged => write!(f, "NBD export changed while reconnecting"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::InvalidUri(_) => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+            Error::ReadOnlyExport => io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()),
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Location of an NBD server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NbdAddress {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+/// Splits an NBD URI into the server address and the export name. Both
+/// `nbd://host[:port]/export` and `nbd+unix:///export?socket=path` are
+/// accepted.
+pub fn parse_uri(uri: &str) -> Result<(NbdAddress, String)> {
+    let invalid = || Error::InvalidUri(uri.to_string());
+
+    if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+        let (path, query) = rest.split_once('?').ok_or_else(invalid)?;
+        // Unix sockets have no host.
+        let export = path.strip_prefix('/').unwrap_or(path);
+        if !path.is_empty() && !path.starts_with('/') {
+            return Err(invalid());
+        }
+        let socket = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("socket="))
+            .filter(|socket| !socket.is_empty())
+            .ok_or_else(invalid)?;
+        return Ok((NbdAddress::Unix(PathBuf::from(socket)), export.to_string()));
+    }
+
+    let rest = uri.strip_prefix("nbd://").ok_or_else(invalid)?;
+    let (authority, export) = match rest.split_once('/') {
+        Some((authority, export)) => (authority, export),
+        None => (rest, ""),
+    };
+    // IPv6 addresses are enclosed in brackets.
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse::<u16>().map_err(|_| invalid())?)
+        }
+        _ => (authority, DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((NbdAddress::Tcp(host.to_string(), port), export.to_string()))
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Stream {
+    fn connect(address: &NbdAddress) -> io::Result<Self> {
+        let stream = match address {
+            NbdAddress::Tcp(host, port) => {
+                let mut result = Err(io::Error::from(io::ErrorKind::NotFound));
+                for addr in (host.as_str(), *port).to_socket_addrs()? {
+                    result = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                let stream = result?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            NbdAddress::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+        Ok(stream)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+            Stream::Unix(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    structured_replies: bool,
+    next_cookie: u64,
+    /// Whether the last reply was read in full, so the next request can
+    /// follow on the same stream.
+    in_sync: bool,
+}
+
+fn read_u16(stream: &mut Stream) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut Stream) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut Stream) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn skip(stream: &mut Stream, len: u64) -> io::Result<()> {
+    io::copy(&mut stream.take(len), &mut io::sink())?;
+    Ok(())
+}
+
+/// Maps the errno values defined by the protocol to an I/O error.
+fn command_error(error: u32) -> io::Error {
+    let errno = match error {
+        1 => libc::EPERM,
+        5 => libc::EIO,
+        12 => libc::ENOMEM,
+        22 => libc::EINVAL,
+        28 => libc::ENOSPC,
+        75 => libc::EOVERFLOW,
+        95 => libc::EOPNOTSUPP,
+        108 => libc::ESHUTDOWN,
+        _ => libc::EIO,
+    };
+    io::Error::from_raw_os_error(errno)
+}
+
+/// Size and capabilities of an export, learned during the handshake.
+struct Export {
+    size: u64,
+    flags: u16,
+    max_payload: u32,
+}
+
+impl Connection {
+    /// Connects to `address` and selects the export named `export_name`.
+    fn open(address: &NbdAddress, export_name: &str, timeout: Duration) -> Result<(Self, Export)> {
+        let stream = Stream::connect(address)?;
+        stream.set_timeout(timeout)?;
+        let mut connection = Connection {
+            stream,
+            structured_replies: false,
+            next_cookie: 0,
+            in_sync: true,
+        };
+        let export = connection.handshake(export_name)?;
+        Ok((connection, export))
+    }
+
+    fn send_option(&mut self, option: u32, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)
+    }
+
+    /// Reads an option reply, returning its type and data.
+    fn read_option_reply(&mut self, option: u32) -> Result<(u32, Vec<u8>)> {
+        if read_u64(&mut self.stream)? != OPTION_REPLY_MAGIC {
+            return Err(Error::Protocol("bad option reply magic"));
+        }
+        if read_u32(&mut self.stream)? != option {
+            return Err(Error::Protocol("reply to an unexpected option"));
+        }
+        let reply = read_u32(&mut self.stream)?;
+        let len = read_u32(&mut self.stream)?;
+        if len > DEFAULT_MAX_PAYLOAD {
+            return Err(Error::Protocol("option reply too long"));
+        }
+        let mut data = vec![0u8; len as usize];
+        self.stream.read_exact(&mut data)?;
+        Ok((reply, data))
+    }
+
+    fn handshake(&mut self, export_name: &str) -> Result<Export> {
+        if read_u64(&mut self.stream)? != NBD_MAGIC || read_u64(&mut self.stream)? != IHAVEOPT {
+            return Err(Error::UnsupportedHandshake);
+        }
+        let server_flags = read_u16(&mut self.stream)?;
+        if server_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(Error::UnsupportedHandshake);
+        }
+        let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES != 0;
+        let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+        if no_zeroes {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+        self.stream.write_all(&client_flags.to_be_bytes())?;
+
+        self.send_option(NBD_OPT_STRUCTURED_REPLY, &[])?;
+        match self.read_option_reply(NBD_OPT_STRUCTURED_REPLY)? {
+            (NBD_REP_ACK, _) => self.structured_replies = true,
+            (reply, _) if reply & NBD_REP_FLAG_ERROR != 0 => (),
+            _ => return Err(Error::Protocol("unexpected structured reply option reply")),
+        }
+
+        match self.go(export_name)? {
+            Some(export) => Ok(export),
+            // Old servers only know how to pick the export this way.
+            None => self.export_name(export_name, no_zeroes),
+        }
+    }
+
+    /// Selects the export with `NBD_OPT_GO`, or returns `None` if the server
+    /// doesn't support it.
+    fn go(&mut self, export_name: &str) -> Result<Option<Export>> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(export_name.len() as u32).to_be_bytes());
+        data.extend_from_slice(export_name.as_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&NBD_INFO_BLOCK_SIZE.to_be_bytes());
+        self.send_option(NBD_OPT_GO, &data)?;
+
+        let mut export = None;
+        let mut max_payload = DEFAULT_MAX_PAYLOAD;
+        loop {
+            match self.read_option_reply(NBD_OPT_GO)? {
+                (NBD_REP_ACK, _) => break,
+                (NBD_REP_INFO, info) if info.len() >= 2 => {
+                    match u16::from_be_bytes([info[0], info[1]]) {
+                        NBD_INFO_EXPORT if info.len() == 12 => {
+                            export = Some((
+                                u64::from_be_bytes(info[2..10].try_into().unwrap()),
+                                u16::from_be_bytes([info[10], info[11]]),
+                            ));
+                        }
+                        NBD_INFO_BLOCK_SIZE if info.len() == 14 => {
+                            let max = u32::from_be_bytes(info[10..14].try_into().unwrap());
+                            max_payload = cmp::min(max, DEFAULT_MAX_PAYLOAD);
+                        }
+                        // Ignore information we didn't ask for.
+                        _ => (),
+                    }
+                }
+                (NBD_REP_INFO, _) => return Err(Error::Protocol("short info reply")),
+                (NBD_REP_ERR_UNSUP, _) => return Ok(None),
+                (reply, _) if reply & NBD_REP_FLAG_ERROR != 0 => {
+                    return Err(Error::Rejected {
+                        option: NBD_OPT_GO,
+                        reply,
+                    })
+                }
+                _ => return Err(Error::Protocol("unexpected reply to NBD_OPT_GO")),
+            }
+        }
+
+        let (size, flags) = export.ok_or(Error::Protocol("no export information"))?;
+        Ok(Some(Export {
+            size,
+            flags,
+            max_payload,
+        }))
+    }
+
+    fn export_name(&mut self, export_name: &str, no_zeroes: bool) -> Result<Export> {
+        self.send_option(NBD_OPT_EXPORT_NAME, export_name.as_bytes())?;
+        let size = read_u64(&mut self.stream)?;
+        let flags = read_u16(&mut self.stream)?;
+        if !no_zeroes {
+            skip(&mut self.stream, 124)?;
+        }
+        Ok(Export {
+            size,
+            flags,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+        })
+    }
+
+    fn send_request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let cookie = self.next_cookie;
+        self.next_cookie = self.next_cookie.wrapping_add(1);
+
+        let mut buf = Vec::with_capacity(28 + data.len());
+        buf.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(&cookie.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+        Ok(cookie)
+    }
+
+    /// Sends a command and waits for its reply. The data of read replies is
+    /// stored in `buf`, which covers the range the command was sent for.
+    fn command(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        self.in_sync = false;
+        let cookie = self.send_request(command, flags, offset, len, data)?;
+        let magic = read_u32(&mut self.stream)?;
+        match magic {
+            SIMPLE_REPLY_MAGIC => {
+                let error = read_u32(&mut self.stream)?;
+                if read_u64(&mut self.stream)? != cookie {
+                    return Err(Error::Protocol("reply to an unexpected request").into());
+                }
+                if error != 0 {
+                    self.in_sync = true;
+                    return Err(command_error(error));
+                }
+                // The data follows the reply, as long as the read succeeded.
+                self.stream.read_exact(buf)?;
+                self.in_sync = true;
+                Ok(())
+            }
+            STRUCTURED_REPLY_MAGIC if self.structured_replies => {
+                self.structured_reply(cookie, offset, buf)
+            }
+            _ => Err(Error::Protocol("bad reply magic").into()),
+        }
+    }
+
+    /// Reads the chunks of a structured reply until the last one. The magic
+    /// of the first chunk was already consumed. Unless the server reports an
+    /// error, the data and hole chunks must cover `buf` exactly once.
+    fn structured_reply(&mut self, cookie: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut result = Ok(());
+        // The parts of `buf` the chunks covered so far.
+        let mut covered = Vec::new();
+        loop {
+            let flags = read_u16(&mut self.stream)?;
+            let chunk_type = read_u16(&mut self.stream)?;
+            if read_u64(&mut self.stream)? != cookie {
+                return Err(Error::Protocol("reply to an unexpected request").into());
+            }
+            let len = read_u32(&mut self.stream)?;
+
+            match chunk_type {
+                NBD_REPLY_TYPE_NONE => skip(&mut self.stream, u64::from(len))?,
+                NBD_REPLY_TYPE_OFFSET_DATA if len >= 8 => {
+                    let start = read_u64(&mut self.stream)?;
+                    let data_len = u64::from(len - 8);
+                    let range = start
+                        .checked_sub(offset)
+                        .filter(|&pos| pos + data_len <= buf.len() as u64)
+                        .ok_or(Error::Protocol("data chunk out of range"))?;
+                    let range = range as usize..(range + data_len) as usize;
+                    self.stream.read_exact(&mut buf[range.clone()])?;
+                    covered.push(range);
+                }
+                NBD_REPLY_TYPE_OFFSET_HOLE if len == 12 => {
+                    let start = read_u64(&mut self.stream)?;
+                    let hole_len = u64::from(read_u32(&mut self.stream)?);
+                    let range = start
+                        .checked_sub(offset)
+                        .filter(|&pos| pos + hole_len <= buf.len() as u64)
+                        .ok_or(Error::Protocol("hole chunk out of range"))?;
+                    let range = range as usize..(range + hole_len) as usize;
+                    buf[range.clone()].fill(0);
+                    covered.push(range);
+                }
+                t if t & NBD_REPLY_TYPE_ERROR_BIT != 0 && len >= 6 => {
+                    let error = read_u32(&mut self.stream)?;
+                    // Skip the message and, for offset errors, the offset.
+                    skip(&mut self.stream, u64::from(len - 4))?;
+                    result = Err(command_error(error));
+                }
+                _ => return Err(Error::Protocol("unexpected structured reply chunk").into()),
+            }
+
+            if flags & NBD_REPLY_FLAG_DONE != 0 {
+                self.in_sync = true;
+                result?;
+                return if covers(&mut covered, buf.len()) {
+                    Ok(())
+                } else {
+                    Err(Error::Protocol("reply doesn't cover the requested range").into())
+                };
+            }
+            if read_u32(&mut self.stream)? != STRUCTURED_REPLY_MAGIC {
+                return Err(Error::Protocol("bad reply magic").into());
+            }
+        }
+    }
+}
+
+/// Whether the `ranges` of a buffer cover its `len` bytes exactly once.
+fn covers(ranges: &mut [std::ops::Range<usize>], len: usize) -> bool {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut end = 0;
+    for range in ranges.iter() {
+        if range.start != end {
+            return false;
+        }
+        end = range.end;
+    }
+    end == len
+}
+
+/// A disk exported by an NBD server.
+pub struct NbdDisk {
+    address: NbdAddress,
+    export_name: String,
+    timeout: Duration,
+    /// The connection to the server, unless the last one broke.
+    connection: Mutex<Option<Connection>>,
+    size: u64,
+    flags: u16,
+    max_payload: u32,
+}
+
+impl NbdDisk {
+    /// Connects to the export described by `uri`. Fails if `read_only` isn't
+    /// set and the export can't be written to.
+    pub fn new(uri: &str, read_only: bool) -> Result<Self> {
+        Self::with_timeout(uri, read_only, IO_TIMEOUT)
+    }
+
+    fn with_timeout(uri: &str, read_only: bool, timeout: Duration) -> Result<Self> {
+        let (address, export_name) = parse_uri(uri)?;
+        let (connection, export) = Connection::open(&address, &export_name, timeout)?;
+        if !read_only && export.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(Error::ReadOnlyExport);
+        }
+
+        Ok(NbdDisk {
+            address,
+            export_name,
+            timeout,
+            connection: Mutex::new(Some(connection)),
+            size: export.size,
+            flags: export.flags,
+            // Keep requests sector aligned.
+            max_payload: cmp::max(export.max_payload & !511, 512),
+        })
+    }
+
+    /// Runs `f` on the connection to the server, opening a new one if the
+    /// last one broke. If `f` leaves the connection in the middle of a
+    /// reply, the connection is dropped.
+    fn with_connection<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut Connection) -> io::Result<()>,
+    {
+        let mut guard = self.connection.lock().unwrap();
+        if guard.is_none() {
+            let (connection, export) =
+                Connection::open(&self.address, &self.export_name, self.timeout)?;
+            // Writes fail on their own if the export became read-only.
+            if export.size != self.size
+                || export.flags & !NBD_FLAG_READ_ONLY != self.flags & !NBD_FLAG_READ_ONLY
+            {
+                return Err(Error::ExportChanged.into());
+            }
+            *guard = Some(connection);
+        }
+
+        let connection = guard.as_mut().unwrap();
+        let result = f(connection);
+        if result.is_err() && !connection.in_sync {
+            warn!("NBD connection broken, reconnecting on the next request");
+            *guard = None;
+        }
+        result
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    /// Sends a command without payload for each piece of the range.
+    fn range_command(&self, command: u16, flags: u16, offset: u64, len: u64) -> io::Result<()> {
+        self.check_range(offset, len)?;
+        self.with_connection(|connection| {
+            let mut done = 0;
+            while done < len {
+                let chunk = cmp::min(len - done, MAX_RANGE_LEN);
+                connection.command(command, flags, offset + done, chunk as u32, &[], &mut [])?;
+                done += chunk;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl DiskBackend for NbdDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        self.with_connection(|connection| {
+            for (i, chunk) in buf.chunks_mut(self.max_payload as usize).enumerate() {
+                let pos = offset + (i * self.max_payload as usize) as u64;
+                let len = chunk.len() as u32;
+                connection.command(NBD_CMD_READ, 0, pos, len, &[], chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        self.with_connection(|connection| {
+            for (i, chunk) in buf.chunks(self.max_payload as usize).enumerate() {
+                let pos = offset + (i * self.max_payload as usize) as u64;
+                let len = chunk.len() as u32;
+                connection.command(NBD_CMD_WRITE, 0, pos, len, chunk, &mut [])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            // The server doesn't cache writes.
+            return Ok(());
+        }
+        self.with_connection(|connection| connection.command(NBD_CMD_FLUSH, 0, 0, 0, &[], &mut []))
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_TRIM == 0 {
+            return Ok(());
+        }
+        self.range_command(NBD_CMD_TRIM, 0, offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            return write_zeroes_slow(self, offset, len);
+        }
+        let flags = if unmap { 0 } else { NBD_CMD_FLAG_NO_HOLE };
+        self.range_command(NBD_CMD_WRITE_ZEROES, flags, offset, len)
+    }
+}
+
+impl Drop for NbdDisk {
+    fn drop(&mut self) {
+        // The server doesn't reply to disconnect requests.
+        if let Ok(Some(connection)) = self.connection.get_mut() {
+            let _ = connection.send_request(NBD_CMD_DISC, 0, 0, 0, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use utils::tempdir::TempDir;
+
+    const DISK_SIZE: usize = 1024 * 1024;
+    const TRANSMISSION_FLAGS: u16 =
+        1 | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+
+    /// Negotiates the options of the export with the client.
+    fn handshake(stream: &mut UnixStream, structured: bool) {
+        let mut hello = Vec::new();
+        hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        hello.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&hello).unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+
+        let reply = |stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]| {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+            buf.extend_from_slice(&option.to_be_bytes());
+            buf.extend_from_slice(&reply.to_be_bytes());
+            buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buf.extend_from_slice(data);
+            stream.write_all(&buf).unwrap();
+        };
+
+        // Option haggling.
+        loop {
+            let mut header = [0u8; 16];
+            stream.read_exact(&mut header).unwrap();
+            let option = u32::from_be_bytes(header[8..12].try_into().unwrap());
+            let len = u32::from_be_bytes(header[12..16].try_into().unwrap());
+            let mut data = vec![0u8; len as usize];
+            stream.read_exact(&mut data).unwrap();
+            match option {
+                NBD_OPT_STRUCTURED_REPLY if structured => reply(stream, option, 1, &[]),
+                NBD_OPT_STRUCTURED_REPLY => reply(stream, option, NBD_REP_ERR_UNSUP, &[]),
+                NBD_OPT_GO => {
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&(DISK_SIZE as u64).to_be_bytes());
+                    info.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                    reply(stream, option, NBD_REP_INFO, &info);
+                    // Force reads and writes to be split.
+                    let mut info = NBD_INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    for size in [1u32, 4096, 64 * 1024] {
+                        info.extend_from_slice(&size.to_be_bytes());
+                    }
+                    reply(stream, option, NBD_REP_INFO, &info);
+                    reply(stream, option, NBD_REP_ACK, &[]);
+                    break;
+                }
+                _ => reply(stream, option, NBD_REP_ERR_UNSUP, &[]),
+            }
+        }
+    }
+
+    /// Reads a request, returning its command, cookie, offset and length.
+    fn read_request(stream: &mut UnixStream) -> io::Result<(u16, [u8; 8], usize, usize)> {
+        let mut request = [0u8; 28];
+        stream.read_exact(&mut request)?;
+        let command = u16::from_be_bytes([request[6], request[7]]);
+        let cookie = request[8..16].try_into().unwrap();
+        let offset = u64::from_be_bytes(request[16..24].try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(request[24..28].try_into().unwrap()) as usize;
+        Ok((command, cookie, offset, len))
+    }
+
+    /// Builds a structured reply chunk.
+    fn reply_chunk(cookie: &[u8], flags: u16, chunk_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = STRUCTURED_REPLY_MAGIC.to_be_bytes().to_vec();
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&chunk_type.to_be_bytes());
+        buf.extend_from_slice(cookie);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Serves a single in-memory export over `stream`, until the client
+    /// disconnects. Returns the number of flushes and the final contents.
+    fn serve(mut stream: UnixStream, structured: bool) -> (usize, Vec<u8>) {
+        let mut disk = vec![0u8; DISK_SIZE];
+        let mut flushes = 0;
+
+        handshake(&mut stream, structured);
+
+        // Transmission.
+        while let Ok((command, cookie, offset, len)) = read_request(&mut stream) {
+            let cookie = &cookie[..];
+            let range = offset..offset + len;
+
+            let mut simple_reply = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+            simple_reply.extend_from_slice(&0u32.to_be_bytes());
+            simple_reply.extend_from_slice(cookie);
+            match command {
+                NBD_CMD_READ if structured => {
+                    // Return the first half as data and the second one as a
+                    // hole if it's all zeroes.
+                    let half = len / 2;
+                    let chunk = |flags, chunk_type, payload: &[u8]| {
+                        reply_chunk(cookie, flags, chunk_type, payload)
+                    };
+                    let mut payload = ((offset + half) as u64).to_be_bytes().to_vec();
+                    if disk[offset + half..offset + len].iter().all(|&b| b == 0) {
+                        payload.extend_from_slice(&((len - half) as u32).to_be_bytes());
+                        stream
+                            .write_all(&chunk(0, NBD_REPLY_TYPE_OFFSET_HOLE, &payload))
+                            .unwrap();
+                    } else {
+                        payload.extend_from_slice(&disk[offset + half..offset + len]);
+                        stream
+                            .write_all(&chunk(0, NBD_REPLY_TYPE_OFFSET_DATA, &payload))
+                            .unwrap();
+                    }
+                    let mut payload = (offset as u64).to_be_bytes().to_vec();
+                    payload.extend_from_slice(&disk[offset..offset + half]);
+                    stream
+                        .write_all(&chunk(
+                            NBD_REPLY_FLAG_DONE,
+                            NBD_REPLY_TYPE_OFFSET_DATA,
+                            &payload,
+                        ))
+                        .unwrap();
+                }
+                NBD_CMD_READ => {
+                    simple_reply.extend_from_slice(&disk[range]);
+                    stream.write_all(&simple_reply).unwrap();
+                }
+                NBD_CMD_WRITE => {
+                    stream.read_exact(&mut disk[range]).unwrap();
+                    stream.write_all(&simple_reply).unwrap();
+                }
+                NBD_CMD_DISC => break,
+                NBD_CMD_FLUSH => {
+                    flushes += 1;
+                    stream.write_all(&simple_reply).unwrap();
+                }
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                    disk[range].fill(0);
+                    stream.write_all(&simple_reply).unwrap();
+                }
+                _ => panic!("unexpected command {command}"),
+            }
+        }
+        (flushes, disk)
+    }
+
+    fn start_server(dir: &TempDir, structured: bool) -> (String, JoinHandle<(usize, Vec<u8>)>) {
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, structured)
+        });
+        let uri = format!("nbd+unix:///disk?socket={}", path.display());
+        (uri, server)
+    }
+
+    fn check_io(structured: bool) {
+        let dir = TempDir::new().unwrap();
+        let (uri, server) = start_server(&dir, structured);
+        let disk = Arc::new(NbdDisk::new(&uri, false).unwrap());
+        assert_eq!(disk.size(), DISK_SIZE as u64);
+        assert_eq!(
+            disk.connection
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .structured_replies,
+            structured
+        );
+
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        disk.write_all_at(&data, 4096).unwrap();
+        let mut buf = vec![0u8; data.len() + 8192];
+        disk.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+        assert_eq!(&buf[4096..4096 + data.len()], &data[..]);
+        assert!(buf[4096 + data.len()..].iter().all(|&b| b == 0));
+
+        disk.discard(8192, 4096).unwrap();
+        disk.write_zeroes(16384, 4096, false).unwrap();
+        disk.flush().unwrap();
+        let mut buf = vec![0xffu8; 4096];
+        disk.read_exact_at(&mut buf, 8192).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert!(disk.read_exact_at(&mut buf, DISK_SIZE as u64).is_err());
+
+        drop(disk);
+        let (flushes, contents) = server.join().unwrap();
+        assert_eq!(flushes, 1);
+        assert_eq!(&contents[4096..8192], &data[..4096]);
+        assert!(contents[8192..12288].iter().all(|&b| b == 0));
+        assert!(contents[16384..20480].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_structured_replies() {
+        check_io(true);
+    }
+
+    #[test]
+    fn test_simple_replies() {
+        check_io(false);
+    }
+
+    #[test]
+    fn test_incomplete_structured_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, true);
+            // Only the first half of the range.
+            let (_, cookie, offset, len) = read_request(&mut stream).unwrap();
+            let mut payload = (offset as u64).to_be_bytes().to_vec();
+            payload.resize(8 + len / 2, 0);
+            stream
+                .write_all(&reply_chunk(
+                    &cookie,
+                    NBD_REPLY_FLAG_DONE,
+                    NBD_REPLY_TYPE_OFFSET_DATA,
+                    &payload,
+                ))
+                .unwrap();
+            // The first half twice.
+            let (_, cookie, offset, len) = read_request(&mut stream).unwrap();
+            let mut payload = (offset as u64).to_be_bytes().to_vec();
+            payload.resize(8 + len / 2, 0);
+            let mut reply = reply_chunk(&cookie, 0, NBD_REPLY_TYPE_OFFSET_DATA, &payload);
+            reply.extend(reply_chunk(
+                &cookie,
+                NBD_REPLY_FLAG_DONE,
+                NBD_REPLY_TYPE_OFFSET_DATA,
+                &payload,
+            ));
+            stream.write_all(&reply).unwrap();
+            let (command, ..) = read_request(&mut stream).unwrap();
+            assert_eq!(command, NBD_CMD_DISC);
+        });
+
+        let uri = format!("nbd+unix:///disk?socket={}", path.display());
+        let disk = NbdDisk::new(&uri, false).unwrap();
+        let mut buf = vec![0u8; 4096];
+        for _ in 0..2 {
+            let err = disk.read_exact_at(&mut buf, 0).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            // The whole reply was read, so the connection is still usable.
+            assert!(disk.connection.lock().unwrap().is_some());
+        }
+        drop(disk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_after_broken_reply() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, false);
+            // Hang up in the middle of the reply.
+            read_request(&mut stream).unwrap();
+            stream.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes()).unwrap();
+            drop(stream);
+
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, false)
+        });
+
+        let uri = format!("nbd+unix:///disk?socket={}", path.display());
+        let disk = NbdDisk::new(&uri, false).unwrap();
+        let mut buf = vec![0u8; 4096];
+        assert!(disk.read_exact_at(&mut buf, 0).is_err());
+        assert!(disk.connection.lock().unwrap().is_none());
+
+        // The next request goes through a new connection.
+        disk.write_all_at(&[0xaa; 4096], 0).unwrap();
+        disk.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+        assert!(disk.connection.lock().unwrap().is_some());
+
+        drop(disk);
+        let (_, contents) = server.join().unwrap();
+        assert!(contents[..4096].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_reconnect_after_timeout() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (timed_out, wait_timeout) = std::sync::mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, false);
+            // Don't answer until the client gave up.
+            read_request(&mut stream).unwrap();
+            wait_timeout.recv().unwrap();
+            drop(stream);
+
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, false)
+        });
+
+        let uri = format!("nbd+unix:///disk?socket={}", path.display());
+        let disk = NbdDisk::with_timeout(&uri, false, Duration::from_millis(100)).unwrap();
+        assert!(disk.flush().is_err());
+        assert!(disk.connection.lock().unwrap().is_none());
+        timed_out.send(()).unwrap();
+
+        disk.flush().unwrap();
+        drop(disk);
+        let (flushes, _) = server.join().unwrap();
+        assert_eq!(flushes, 1);
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers(&mut [], 0));
+        assert!(covers(&mut [2..4, 0..2], 4));
+        assert!(!covers(std::slice::from_mut(&mut (0..2)), 4));
+        assert!(!covers(&mut [0..2, 0..2], 4));
+        assert!(!covers(&mut [0..3, 2..4], 4));
+        assert!(!covers(std::slice::from_mut(&mut (1..4)), 4));
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(
+            parse_uri("nbd://example.com/disk").unwrap(),
+            (
+                NbdAddress::Tcp("example.com".to_string(), DEFAULT_PORT),
+                "disk".to_string()
+            )
+        );
+        assert_eq!(
+            parse_uri("nbd://[::1]:1234").unwrap(),
+            (NbdAddress::Tcp("::1".to_string(), 1234), String::new())
+        );
+        assert_eq!(
+            parse_uri("nbd+unix:///disk?socket=/run/nbd.sock").unwrap(),
+            (
+                NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+                "disk".to_string()
+            )
+        );
+        assert!(parse_uri("nbd+unix:///disk").is_err());
+        assert!(parse_uri("nbd://:1234/disk").is_err());
+        assert!(parse_uri("http://example.com/disk").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use super::{fallocate, write_zeroes_slow, DiskBackend};
+
+const OVERLAY_MAGIC: &[u8; 8] = b"KRUNOVL\0";
+const OVERLAY_VERSION: u32 = 1;
+
+// The header fills the first 4 KiB of the file and is followed by the
+// allocation bitmap. Guest data is stored at the same offsets as in the base
+// image, shifted by the data offset, so unallocated chunks are file holes.
+const HEADER_SIZE: u64 = 4096;
+const HEADER_LEN: usize = 40;
+
+// Allocation granularities we accept, from one sector to 2 MiB.
+const MIN_CHUNK_SIZE: u32 = 512;
+const MAX_CHUNK_SIZE: u32 = 2 * 1024 * 1024;
+
+/// Allocation granularity of newly created overlays.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid allocation granularity.
+    InvalidChunkSize(u32),
+    /// The offsets in the header don't match the file.
+    InvalidHeader,
+    /// Invalid magic number.
+    InvalidMagic,
+    /// I/O error accessing the overlay.
+    Io(io::Error),
+    /// The overlay was created over a base image of a different size.
+    SizeMismatch { base: u64, overlay: u64 },
+    /// Only version 1 is supported.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidChunkSize(size) => write!(f, "invalid overlay chunk size: {size}"),
+            InvalidHeader => write!(f, "invalid overlay header"),
+            InvalidMagic => write!(f, "not a disk overlay"),
+            Io(e) => write!(f, "overlay I/O error: {e}"),
+            SizeMismatch { base, overlay } => write!(
+                f,
+                "overlay was created for a {overlay} bytes disk, but the base image has {base} bytes"
+            ),
+            UnsupportedVersion(version) => write!(f, "unsupported overlay version: {version}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+struct Header {
+    chunk_size: u32,
+    disk_size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+}
+
+impl Header {
+    fn new(chunk_size: u32, disk_size: u64) -> Self {
+        let bitmap_len = bitmap_len(disk_size, chunk_size as u64);
+        // Keep chunks aligned in the file, so they can be deallocated individually.
+        let align = cmp::max(chunk_size as u64, HEADER_SIZE);
+        Header {
+            chunk_size,
+            disk_size,
+            bitmap_offset: HEADER_SIZE,
+            data_offset: (HEADER_SIZE + bitmap_len).div_ceil(align) * align,
+        }
+    }
+
+    fn read_from(file: &File) -> Result<Self> {
+        let mut buf = [0u8; HEADER_LEN];
+        file.read_exact_at(&mut buf, 0)?;
+        if &buf[0..8] != OVERLAY_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != OVERLAY_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Ok(Header {
+            chunk_size: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            disk_size: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            bitmap_offset: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+            data_offset: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
+        })
+    }
+
+    fn write_to(&self, file: &File) -> io::Result<()> {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0..8].copy_from_slice(OVERLAY_MAGIC);
+        buf[8..12].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.chunk_size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.disk_size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.bitmap_offset.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.data_offset.to_le_bytes());
+        file.write_all_at(&buf, 0)
+    }
+}
+
+fn is_valid_chunk_size(chunk_size: u32) -> bool {
+    chunk_size.is_power_of_two() && (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size)
+}
+
+fn bitmap_len(disk_size: u64, chunk_size: u64) -> u64 {
+    disk_size.div_ceil(chunk_size).div_ceil(8)
+}
+
+fn is_allocated(bitmap: &[u8], chunk: u64) -> bool {
+    bitmap[(chunk / 8) as usize] & (1 << (chunk % 8)) != 0
+}
+
+/// A copy-on-write layer over a read-only disk image.
+///
+/// Writes go to a sparse overlay file, and the chunks they touch are marked
+/// as allocated in a bitmap stored in the same file. Reads of unallocated
+/// chunks are served from the base image. Partial writes to an unallocated
+/// chunk copy the rest of it from the base first.
+pub struct OverlayDisk {
+    base: Arc<dyn DiskBackend>,
+    overlay: File,
+    size: u64,
+    chunk_size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+    // Held for the whole duration of writes, so concurrent copies of the
+    // same chunk can't overwrite each other.
+    bitmap: Mutex<Vec<u8>>,
+}
+
+impl OverlayDisk {
+    /// Layers `overlay` over `base`. An empty overlay file is initialized to
+    /// track allocation every `chunk_size` bytes, otherwise it must have been
+    /// created over an image of the same size.
+    pub fn new(base: Arc<dyn DiskBackend>, overlay: File, chunk_size: u32) -> Result<Self> {
+        let size = base.size();
+        let header = if overlay.metadata()?.len() == 0 {
+            if !is_valid_chunk_size(chunk_size) {
+                return Err(Error::InvalidChunkSize(chunk_size));
+            }
+            let header = Header::new(chunk_size, size);
+            header.write_to(&overlay)?;
+            overlay.set_len(header.data_offset + size)?;
+            header
+        } else {
+            Header::read_from(&overlay)?
+        };
+
+        if !is_valid_chunk_size(header.chunk_size) {
+            return Err(Error::InvalidChunkSize(header.chunk_size));
+        }
+        if header.disk_size != size {
+            return Err(Error::SizeMismatch {
+                base: size,
+                overlay: header.disk_size,
+            });
+        }
+        let bitmap_len = bitmap_len(size, header.chunk_size as u64);
+        if header.bitmap_offset < HEADER_LEN as u64
+            || header.data_offset < header.bitmap_offset + bitmap_len
+            || overlay.metadata()?.len() < header.data_offset + size
+        {
+            return Err(Error::InvalidHeader);
+        }
+
+        let mut bitmap = vec![0u8; bitmap_len as usize];
+        overlay.read_exact_at(&mut bitmap, header.bitmap_offset)?;
+
+        Ok(OverlayDisk {
+            base,
+            overlay,
+            size,
+            chunk_size: header.chunk_size as u64,
+            bitmap_offset: header.bitmap_offset,
+            data_offset: header.data_offset,
+            bitmap: Mutex::new(bitmap),
+        })
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    /// Splits a range into runs of chunks that are all either allocated or
+    /// not, returned as (offset, length, allocated).
+    fn runs(&self, bitmap: &[u8], offset: u64, len: u64) -> Vec<(u64, u64, bool)> {
+        let mut runs: Vec<(u64, u64, bool)> = Vec::new();
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / self.chunk_size;
+            let next = cmp::min((chunk + 1) * self.chunk_size, end);
+            let allocated = is_allocated(bitmap, chunk);
+            match runs.last_mut() {
+                Some(run) if run.2 == allocated => run.1 += next - pos,
+                _ => runs.push((pos, next - pos, allocated)),
+            }
+            pos = next;
+        }
+        runs
+    }
+
+    fn mark_allocated(&self, bitmap: &mut [u8], chunk: u64) -> io::Result<()> {
+        if is_allocated(bitmap, chunk) {
+            return Ok(());
+        }
+        let index = (chunk / 8) as usize;
+        bitmap[index] |= 1 << (chunk % 8);
+        // The chunk data was written first, so a stale bit can never expose
+        // uninitialized overlay contents.
+        self.overlay
+            .write_all_at(&bitmap[index..index + 1], self.bitmap_offset + index as u64)
+    }
+}
+
+impl DiskBackend for OverlayDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        // A chunk may get allocated once we release the lock, but the base
+        // still holds its contents from before the write that allocated it.
+        let runs = self.runs(&self.bitmap.lock().unwrap(), offset, buf.len() as u64);
+        for (start, len, allocated) in runs {
+            let pos = (start - offset) as usize;
+            let part = &mut buf[pos..pos + len as usize];
+            if allocated {
+                self.overlay.read_exact_at(part, self.data_offset + start)?;
+            } else {
+                self.base.read_exact_at(part, start)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let end = offset + buf.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / self.chunk_size;
+            let chunk_start = chunk * self.chunk_size;
+            let chunk_end = cmp::min(chunk_start + self.chunk_size, self.size);
+            let next = cmp::min(chunk_end, end);
+            let data = &buf[(pos - offset) as usize..(next - offset) as usize];
+
+            if is_allocated(&bitmap, chunk) || (pos == chunk_start && next == chunk_end) {
+                self.overlay.write_all_at(data, self.data_offset + pos)?;
+            } else {
+                // Copy the rest of the chunk from the base before allocating it.
+                let mut chunk_buf = vec![0u8; (chunk_end - chunk_start) as usize];
+                self.base.read_exact_at(&mut chunk_buf, chunk_start)?;
+                chunk_buf[(pos - chunk_start) as usize..(next - chunk_start) as usize]
+                    .copy_from_slice(data);
+                self.overlay
+                    .write_all_at(&chunk_buf, self.data_offset + chunk_start)?;
+            }
+            self.mark_allocated(&mut bitmap, chunk)?;
+            pos = next;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // The base is never written to.
+        self.overlay.sync_all()
+    }
+
+    /// Allocates the whole chunks in the range as holes in the overlay, and
+    /// writes zeroes over the partial ones.
+    fn write_zeroes(&self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        self.check_range(offset, len)?;
+        let end = offset + len;
+        let first = offset.div_ceil(self.chunk_size) * self.chunk_size;
+        let last = if end == self.size {
+            end
+        } else {
+            end / self.chunk_size * self.chunk_size
+        };
+        if first >= last {
+            return write_zeroes_slow(self, offset, len);
+        }
+
+        write_zeroes_slow(self, offset, first - offset)?;
+        {
+            let mut bitmap = self.bitmap.lock().unwrap();
+            if fallocate(
+                &self.overlay,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                self.data_offset + first,
+                last - first,
+            )
+            .is_err()
+            {
+                drop(bitmap);
+                return write_zeroes_slow(self, first, end - first);
+            }
+            for chunk in first / self.chunk_size..last.div_ceil(self.chunk_size) {
+                self.mark_allocated(&mut bitmap, chunk)?;
+            }
+        }
+        write_zeroes_slow(self, last, end - last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    use crate::virtio::block::backend::RawDisk;
+
+    const CHUNK_SIZE: u32 = 4096;
+
+    fn create_base(size: usize, fill: u8) -> (TempFile, Arc<dyn DiskBackend>) {
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all_at(&vec![fill; size], 0).unwrap();
+        let base = RawDisk::new(File::open(f.as_path()).unwrap()).unwrap();
+        (f, Arc::new(base))
+    }
+
+    fn open_overlay(f: &TempFile, base: &Arc<dyn DiskBackend>) -> Result<OverlayDisk> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(f.as_path())
+            .unwrap();
+        OverlayDisk::new(base.clone(), file, CHUNK_SIZE)
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let (base_file, base) = create_base(0x10000, 0xaa);
+        let overlay_file = TempFile::new().unwrap();
+        let disk = open_overlay(&overlay_file, &base).unwrap();
+
+        // Partial write in the middle of a chunk, crossing into the next one.
+        disk.write_all_at(&[0x55; 512], 0x1f00).unwrap();
+        let mut buf = vec![0u8; 0x3000];
+        disk.read_exact_at(&mut buf, 0x1000).unwrap();
+        assert!(buf[..0xf00].iter().all(|&b| b == 0xaa));
+        assert!(buf[0xf00..0x1100].iter().all(|&b| b == 0x55));
+        assert!(buf[0x1100..].iter().all(|&b| b == 0xaa));
+
+        // The base image is left untouched.
+        let data = std::fs::read(base_file.as_path()).unwrap();
+        assert!(data.iter().all(|&b| b == 0xaa));
+
+        // The written chunks survive reopening the overlay.
+        drop(disk);
+        let disk = open_overlay(&overlay_file, &base).unwrap();
+        let mut buf = vec![0u8; 512];
+        disk.read_exact_at(&mut buf, 0x1f00).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+
+        assert!(disk.write_all_at(&[0; 512], 0x10000).is_err());
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let (_base_file, base) = create_base(0x10000, 0xaa);
+        let overlay_file = TempFile::new().unwrap();
+        let disk = open_overlay(&overlay_file, &base).unwrap();
+
+        disk.write_zeroes(0x800, 0x3000, true).unwrap();
+        let mut buf = vec![0u8; 0x4000];
+        disk.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..0x800].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x800..0x3800].iter().all(|&b| b == 0));
+        assert!(buf[0x3800..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_invalid_overlays() {
+        let (_base_file, base) = create_base(0x10000, 0xaa);
+        let overlay_file = TempFile::new().unwrap();
+        open_overlay(&overlay_file, &base).unwrap();
+
+        // Created over a base of a different size.
+        let (_small_file, small_base) = create_base(0x8000, 0xaa);
+        assert!(matches!(
+            open_overlay(&overlay_file, &small_base),
+            Err(Error::SizeMismatch { .. })
+        ));
+
+        let bad_file = TempFile::new().unwrap();
+        bad_file.as_file().write_all_at(&[0xff; 4096], 0).unwrap();
+        assert!(matches!(
+            open_overlay(&bad_file, &base),
+            Err(Error::InvalidMagic)
+        ));
+
+        let empty_file = TempFile::new().unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(empty_file.as_path())
+            .unwrap();
+        assert!(matches!(
+            OverlayDisk::new(base, file, 1000),
+            Err(Error::InvalidChunkSize(1000))
+        ));
+    }
+}
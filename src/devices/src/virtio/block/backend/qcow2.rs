@@ -0,0 +1,858 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::debug;
+
+use super::{DiskBackend, RawDisk};
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+
+// Cluster sizes allowed by the specification go from 512 bytes to 2 MiB.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+// We only know how to update 16 bit refcounts, which is the only width
+// supported by version 2 and the default for version 3.
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+// Upper bounds for the in-memory tables, to avoid huge allocations on bogus headers.
+const MAX_L1_SIZE: u32 = 32 * 1024 * 1024 / 8;
+const MAX_REFCOUNT_TABLE_CLUSTERS: u32 = 32 * 1024 * 1024 / 512;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The cluster is not shared with a snapshot and can be written in place.
+const CLUSTER_COPIED: u64 = 1 << 63;
+const CLUSTER_COMPRESSED: u64 = 1 << 62;
+// The cluster reads as zeroes (version 3 only).
+const CLUSTER_ZERO: u64 = 1;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Offset of the autoclear features field in the version 3 header.
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+// Maximum length of a chain of backing files, to stop on loops.
+const MAX_BACKING_DEPTH: u32 = 16;
+
+// Longest backing file name QEMU accepts.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+// Number of L2 tables kept in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Backing file chain is too long.
+    BackingChainTooDeep,
+    /// The backing file format is not raw or qcow2.
+    BackingFormat(String),
+    /// The image uses compressed clusters.
+    CompressedCluster,
+    /// The image is encrypted.
+    Encrypted,
+    /// Invalid cluster size.
+    InvalidClusterBits(u32),
+    /// Invalid magic number.
+    InvalidMagic,
+    /// A metadata offset or size in the header is out of range.
+    InvalidMetadata,
+    /// I/O error accessing the image.
+    Io(io::Error),
+    /// Ran out of space in the refcount table.
+    RefcountTableFull,
+    /// A cluster shared with a snapshot would need to be copied.
+    SharedCluster,
+    /// The image has internal snapshots and was opened for writing.
+    Snapshots,
+    /// The image needs incompatible features we don't implement.
+    UnsupportedFeatures(u64),
+    /// Refcounts are not 16 bits wide and the image was opened for writing.
+    UnsupportedRefcountOrder(u32),
+    /// Only versions 2 and 3 are supported.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            BackingChainTooDeep => write!(f, "qcow2 backing file chain is too deep"),
+            BackingFormat(format) => write!(f, "unsupported qcow2 backing format: {format}"),
+            CompressedCluster => write!(f, "compressed qcow2 clusters are not supported"),
+            Encrypted => write!(f, "encrypted qcow2 images are not supported"),
+            InvalidClusterBits(bits) => write!(f, "invalid qcow2 cluster bits: {bits}"),
+            InvalidMagic => write!(f, "not a qcow2 image"),
+            InvalidMetadata => write!(f, "invalid qcow2 metadata"),
+            Io(e) => write!(f, "qcow2 I/O error: {e}"),
+            RefcountTableFull => write!(f, "qcow2 refcount table is full"),
+            SharedCluster => write!(f, "writing to qcow2 clusters shared by snapshots"),
+            Snapshots => write!(f, "writing to qcow2 images with snapshots is not supported"),
+            UnsupportedFeatures(features) => {
+                write!(f, "unsupported qcow2 incompatible features: {features:#x}")
+            }
+            UnsupportedRefcountOrder(order) => {
+                write!(f, "unsupported qcow2 refcount order: {order}")
+            }
+            UnsupportedVersion(version) => write!(f, "unsupported qcow2 version: {version}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn read_be_u32(file: &File, offset: u64) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_be_u64_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries * 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+/// The fields of the qcow2 header we care about.
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl Header {
+    fn read_from(file: &File) -> Result<Header> {
+        let mut buf = [0u8; V3_HEADER_SIZE];
+        file.read_exact_at(&mut buf[..V2_HEADER_SIZE], 0)?;
+        let be_u32 = |buf: &[u8], o: usize| u32::from_be_bytes(buf[o..o + 4].try_into().unwrap());
+        let be_u64 = |buf: &[u8], o: usize| u64::from_be_bytes(buf[o..o + 8].try_into().unwrap());
+
+        if be_u32(&buf, 0) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = be_u32(&buf, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut header = Header {
+            version,
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            cluster_bits: be_u32(&buf, 20),
+            size: be_u64(&buf, 24),
+            crypt_method: be_u32(&buf, 32),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            nb_snapshots: be_u32(&buf, 60),
+            incompatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: V2_HEADER_SIZE as u32,
+        };
+
+        if version == 3 {
+            file.read_exact_at(&mut buf[V2_HEADER_SIZE..], V2_HEADER_SIZE as u64)?;
+            header.incompatible_features = be_u64(&buf, 72);
+            header.autoclear_features = be_u64(&buf, 88);
+            header.refcount_order = be_u32(&buf, 96);
+            header.header_length = be_u32(&buf, 100);
+            if (header.header_length as usize) < V3_HEADER_SIZE {
+                return Err(Error::InvalidMetadata);
+            }
+        }
+
+        Ok(header)
+    }
+
+    /// Looks for the backing file format among the header extensions.
+    fn backing_format(&self, file: &File, cluster_size: u64) -> Result<Option<String>> {
+        let mut offset = u64::from(self.header_length);
+        while offset + 8 <= cluster_size {
+            let ext_type = read_be_u32(file, offset)?;
+            let ext_len = read_be_u32(file, offset + 4)?;
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+            // Header extensions must fit in the first cluster.
+            if offset + 8 + u64::from(ext_len) > cluster_size {
+                return Err(Error::InvalidMetadata);
+            }
+            if ext_type == HEADER_EXT_BACKING_FORMAT {
+                let mut name = vec![0u8; ext_len as usize];
+                file.read_exact_at(&mut name, offset + 8)?;
+                return Ok(Some(String::from_utf8_lossy(&name).into_owned()));
+            }
+            // Extension data is padded to a multiple of 8 bytes.
+            offset += 8 + ((u64::from(ext_len) + 7) & !7);
+        }
+        Ok(None)
+    }
+}
+
+/// Where the data for a guest cluster lives.
+enum ClusterMapping {
+    /// Not allocated in this image; data comes from the backing file, if any.
+    Unallocated,
+    /// Reads as zeroes.
+    Zero,
+    /// Allocated at the given host offset.
+    Data(u64),
+}
+
+/// An open qcow2 image file, along with its cached metadata.
+struct QcowFile {
+    file: File,
+    read_only: bool,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    cluster_size: u64,
+    l2_entries: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_block_entries: u64,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    next_free_cluster: u64,
+    backing: Option<Box<dyn DiskBackend>>,
+}
+
+impl QcowFile {
+    fn open(file: File, path: &Path, read_only: bool, depth: u32) -> Result<Self> {
+        let header = Header::read_from(&file)?;
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(Error::InvalidClusterBits(header.cluster_bits));
+        }
+        if header.crypt_method != 0 {
+            return Err(Error::Encrypted);
+        }
+
+        // Images left dirty or marked corrupt can only be trusted for reading.
+        let mut supported_features = INCOMPAT_COMPRESSION_TYPE;
+        if read_only {
+            supported_features |= INCOMPAT_DIRTY | INCOMPAT_CORRUPT;
+        }
+        let unsupported_features = header.incompatible_features & !supported_features;
+        if unsupported_features != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported_features));
+        }
+        if !read_only {
+            if header.nb_snapshots != 0 {
+                return Err(Error::Snapshots);
+            }
+            if header.refcount_order != DEFAULT_REFCOUNT_ORDER {
+                return Err(Error::UnsupportedRefcountOrder(header.refcount_order));
+            }
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_entries_needed = header.size.div_ceil(cluster_size * l2_entries);
+        if header.l1_size > MAX_L1_SIZE
+            || u64::from(header.l1_size) < l1_entries_needed
+            || header.refcount_table_clusters > MAX_REFCOUNT_TABLE_CLUSTERS
+        {
+            return Err(Error::InvalidMetadata);
+        }
+
+        let l1_table = read_be_u64_table(&file, header.l1_table_offset, header.l1_size as usize)?;
+        let refcount_table = read_be_u64_table(
+            &file,
+            header.refcount_table_offset,
+            (u64::from(header.refcount_table_clusters) * cluster_size / 8) as usize,
+        )?;
+
+        let backing = if header.backing_file_offset != 0 {
+            if depth >= MAX_BACKING_DEPTH {
+                return Err(Error::BackingChainTooDeep);
+            }
+            let format = header.backing_format(&file, cluster_size)?;
+            Some(Self::open_backing_file(
+                &file, &header, path, format, depth,
+            )?)
+        } else {
+            None
+        };
+
+        if !read_only && header.version == 3 && header.autoclear_features != 0 {
+            // We don't know how to keep any of the autoclear features consistent.
+            file.write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET)?;
+        }
+
+        let file_len = file.metadata()?.len();
+        let next_free_cluster = (file_len + cluster_size - 1) & !(cluster_size - 1);
+
+        Ok(QcowFile {
+            file,
+            read_only,
+            version: header.version,
+            size: header.size,
+            cluster_bits: header.cluster_bits,
+            cluster_size,
+            l2_entries,
+            l1_table_offset: header.l1_table_offset,
+            l1_table,
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table,
+            refcount_block_entries: cluster_size * 8 / (1 << DEFAULT_REFCOUNT_ORDER),
+            l2_cache: HashMap::new(),
+            next_free_cluster,
+            backing,
+        })
+    }
+
+    fn open_backing_file(
+        file: &File,
+        header: &Header,
+        path: &Path,
+        format: Option<String>,
+        depth: u32,
+    ) -> Result<Box<dyn DiskBackend>> {
+        if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+            return Err(Error::InvalidMetadata);
+        }
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.read_exact_at(&mut name, header.backing_file_offset)?;
+        let name = PathBuf::from(String::from_utf8_lossy(&name).into_owned());
+        // Relative backing file names are relative to the image that references them.
+        let backing_path = match path.parent() {
+            Some(dir) if name.is_relative() => dir.join(name),
+            _ => name,
+        };
+        debug!("qcow2: opening backing file {:?}", backing_path);
+
+        let backing_file = OpenOptions::new().read(true).open(&backing_path)?;
+        let is_qcow2 = match format.as_deref() {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => return Err(Error::BackingFormat(format.to_string())),
+            // Without an explicit format, probe the image like QEMU does.
+            None => read_be_u32(&backing_file, 0).ok() == Some(QCOW_MAGIC),
+        };
+
+        if is_qcow2 {
+            let qcow = Self::open(backing_file, &backing_path, true, depth + 1)?;
+            Ok(Box::new(Qcow2Disk::from(qcow)))
+        } else {
+            Ok(Box::new(RawDisk::new(backing_file)?))
+        }
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset >> (self.cluster_bits * 2 - 3)) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        ((offset >> self.cluster_bits) & (self.l2_entries - 1)) as usize
+    }
+
+    /// Returns the L2 table at `l2_offset`, loading it into the cache if needed.
+    fn l2_table(&mut self, l2_offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            let table = read_be_u64_table(&self.file, l2_offset, self.l2_entries as usize)?;
+            if self.l2_cache.len() >= L2_CACHE_SIZE {
+                self.l2_cache.clear();
+            }
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    fn set_l2_entry(&mut self, l2_offset: u64, index: usize, entry: u64) -> io::Result<()> {
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + index as u64 * 8)?;
+        self.l2_table(l2_offset)?[index] = entry;
+        Ok(())
+    }
+
+    fn cluster_mapping(&mut self, offset: u64) -> Result<ClusterMapping> {
+        let l1_index = self.l1_index(offset);
+        let l2_offset = match self.l1_table.get(l1_index) {
+            Some(entry) => entry & L1_OFFSET_MASK,
+            None => return Err(Error::InvalidMetadata),
+        };
+        if l2_offset == 0 {
+            return Ok(ClusterMapping::Unallocated);
+        }
+
+        let l2_index = self.l2_index(offset);
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & CLUSTER_COMPRESSED != 0 {
+            return Err(Error::CompressedCluster);
+        }
+        if self.version >= 3 && entry & CLUSTER_ZERO != 0 {
+            return Ok(ClusterMapping::Zero);
+        }
+        match entry & L2_OFFSET_MASK {
+            0 => Ok(ClusterMapping::Unallocated),
+            host_offset => Ok(ClusterMapping::Data(host_offset)),
+        }
+    }
+
+    /// Reads from the backing file, treating anything past its end as zeroes.
+    fn read_backing(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self.backing.as_mut() {
+            Some(backing) if offset < backing.size() => {
+                let len = cmp::min(buf.len() as u64, backing.size() - offset) as usize;
+                backing.read_exact_at(&mut buf[..len], offset)?;
+                buf[len..].fill(0);
+            }
+            _ => buf.fill(0),
+        }
+        Ok(())
+    }
+
+    /// Allocates a new cluster at the end of the image and returns its offset.
+    fn allocate_cluster(&mut self) -> Result<u64> {
+        let offset = self.next_free_cluster;
+        self.next_free_cluster += self.cluster_size;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Allocates a new cluster and fills it with zeroes.
+    fn allocate_zeroed_cluster(&mut self) -> Result<u64> {
+        let offset = self.allocate_cluster()?;
+        self.file
+            .write_all_at(&vec![0u8; self.cluster_size as usize], offset)?;
+        Ok(offset)
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u16) -> Result<()> {
+        let cluster_index = cluster_offset >> self.cluster_bits;
+        let table_index = (cluster_index / self.refcount_block_entries) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(Error::RefcountTableFull);
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            // Take the new refcount block from the end of the file. Its own
+            // refcount is set afterwards, possibly in the block itself.
+            block_offset = self.next_free_cluster;
+            self.next_free_cluster += self.cluster_size;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size as usize], block_offset)?;
+            self.file.write_all_at(
+                &block_offset.to_be_bytes(),
+                self.refcount_table_offset + table_index as u64 * 8,
+            )?;
+            self.refcount_table[table_index] = block_offset;
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let entry_offset = block_offset + (cluster_index % self.refcount_block_entries) * 2;
+        self.file
+            .write_all_at(&refcount.to_be_bytes(), entry_offset)?;
+        Ok(())
+    }
+
+    /// Returns the offset of the L2 table covering `offset`, allocating it if needed.
+    fn l2_table_for_write(&mut self, offset: u64) -> Result<u64> {
+        let l1_index = self.l1_index(offset);
+        let l1_entry = *self.l1_table.get(l1_index).ok_or(Error::InvalidMetadata)?;
+        let l2_offset = l1_entry & L1_OFFSET_MASK;
+        if l2_offset != 0 {
+            if l1_entry & CLUSTER_COPIED == 0 {
+                return Err(Error::SharedCluster);
+            }
+            return Ok(l2_offset);
+        }
+
+        let l2_offset = self.allocate_zeroed_cluster()?;
+        let l1_entry = l2_offset | CLUSTER_COPIED;
+        self.file.write_all_at(
+            &l1_entry.to_be_bytes(),
+            self.l1_table_offset + l1_index as u64 * 8,
+        )?;
+        self.l1_table[l1_index] = l1_entry;
+        Ok(l2_offset)
+    }
+
+    /// Writes `buf`, which must fit in a single cluster, at guest `offset`.
+    fn write_cluster(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        let l2_offset = self.l2_table_for_write(offset)?;
+        let l2_index = self.l2_index(offset);
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & CLUSTER_COMPRESSED != 0 {
+            return Err(Error::CompressedCluster);
+        }
+
+        let in_cluster = offset & (self.cluster_size - 1);
+        let host_offset = entry & L2_OFFSET_MASK;
+        let reads_zero = self.version >= 3 && entry & CLUSTER_ZERO != 0;
+
+        if host_offset != 0 && entry & CLUSTER_COPIED == 0 {
+            return Err(Error::SharedCluster);
+        }
+        if host_offset != 0 && !reads_zero {
+            self.file.write_all_at(buf, host_offset + in_cluster)?;
+            return Ok(());
+        }
+
+        // Build the whole cluster, so parts not covered by `buf` keep reading
+        // what they did before the write.
+        let cluster_start = offset - in_cluster;
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if buf.len() as u64 != self.cluster_size && !reads_zero {
+            self.read_backing(&mut cluster, cluster_start)?;
+        }
+        cluster[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        // Preallocated zero clusters are reused, otherwise we need a new one.
+        let host_offset = match host_offset {
+            0 => self.allocate_cluster()?,
+            host_offset => host_offset,
+        };
+        self.file.write_all_at(&cluster, host_offset)?;
+        self.set_l2_entry(l2_offset, l2_index, host_offset | CLUSTER_COPIED)?;
+        Ok(())
+    }
+
+    /// Reads the guest visible contents of the image.
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let len = cmp::min(self.cluster_size - in_cluster, (buf.len() - done) as u64) as usize;
+            let chunk = &mut buf[done..done + len];
+            match self.cluster_mapping(pos)? {
+                ClusterMapping::Data(host_offset) => {
+                    self.file.read_exact_at(chunk, host_offset + in_cluster)?
+                }
+                ClusterMapping::Zero => chunk.fill(0),
+                ClusterMapping::Unallocated => self.read_backing(chunk, pos)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes to the image, allocating clusters as needed.
+    fn write(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let len = cmp::min(self.cluster_size - in_cluster, (buf.len() - done) as u64) as usize;
+            self.write_cluster(&buf[done..done + len], pos)?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// A QEMU Copy-On-Write (qcow2) disk image.
+///
+/// Clusters are allocated on first write, by appending them to the end of
+/// the image file. Metadata updates are written through immediately, so no
+/// state needs to be saved when the image is closed. Compressed clusters,
+/// encryption and writing to images with internal snapshots are not supported.
+pub struct Qcow2Disk {
+    size: u64,
+    inner: Mutex<QcowFile>,
+}
+
+impl Qcow2Disk {
+    /// Opens the qcow2 image in `file`. `path` is used to locate relative backing files.
+    pub fn new(file: File, path: &Path, read_only: bool) -> Result<Self> {
+        Ok(Self::from(QcowFile::open(file, path, read_only, 0)?))
+    }
+}
+
+impl From<QcowFile> for Qcow2Disk {
+    fn from(qcow: QcowFile) -> Self {
+        Qcow2Disk {
+            size: qcow.size,
+            inner: Mutex::new(qcow),
+        }
+    }
+}
+
+impl DiskBackend for Qcow2Disk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.inner.lock().unwrap().read(buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.lock().unwrap().write(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // Metadata is written through, so syncing the file is enough.
+        self.inner.lock().unwrap().file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempdir::TempDir;
+
+    fn read_be_u16(file: &File, offset: u64) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        file.read_exact_at(&mut buf, offset)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn read_be_u64(file: &File, offset: u64) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        file.read_exact_at(&mut buf, offset)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    const CLUSTER_BITS: u32 = 16;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+
+    /// Creates an empty version 3 image with the layout used by `qemu-img create`:
+    /// header, L1 table, refcount table and a refcount block in the first four clusters.
+    fn create_image(path: &Path, size: u64, backing_file: Option<&str>) -> File {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let l1_size = size.div_ceil(CLUSTER_SIZE * CLUSTER_SIZE / 8) as u32;
+
+        let mut header = vec![0u8; CLUSTER_SIZE as usize];
+        header[0..4].copy_from_slice(&QCOW_MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+        header[24..32].copy_from_slice(&size.to_be_bytes());
+        header[36..40].copy_from_slice(&l1_size.to_be_bytes());
+        header[40..48].copy_from_slice(&(CLUSTER_SIZE * 3).to_be_bytes());
+        header[48..56].copy_from_slice(&CLUSTER_SIZE.to_be_bytes());
+        header[56..60].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&DEFAULT_REFCOUNT_ORDER.to_be_bytes());
+        header[100..104].copy_from_slice(&(V3_HEADER_SIZE as u32).to_be_bytes());
+        if let Some(name) = backing_file {
+            let name_offset = 512u64;
+            header[8..16].copy_from_slice(&name_offset.to_be_bytes());
+            header[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+            header[name_offset as usize..name_offset as usize + name.len()]
+                .copy_from_slice(name.as_bytes());
+        }
+        file.write_all_at(&header, 0).unwrap();
+
+        // Refcount table pointing to the refcount block in the third cluster.
+        file.write_all_at(&(CLUSTER_SIZE * 2).to_be_bytes(), CLUSTER_SIZE)
+            .unwrap();
+        // Refcount block, with the first four clusters in use.
+        let mut refcounts = vec![0u8; CLUSTER_SIZE as usize];
+        for i in 0..4 {
+            refcounts[i * 2..i * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+        file.write_all_at(&refcounts, CLUSTER_SIZE * 2).unwrap();
+        // Empty L1 table.
+        file.set_len(CLUSTER_SIZE * 4).unwrap();
+        file
+    }
+
+    fn refcount(file: &File, cluster: u64) -> u16 {
+        let block = read_be_u64(file, CLUSTER_SIZE).unwrap();
+        read_be_u16(file, block + cluster * 2).unwrap()
+    }
+
+    #[test]
+    fn test_read_unallocated() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.qcow2");
+        let file = create_image(&path, 1 << 30, None);
+
+        let disk = Qcow2Disk::new(file, &path, false).unwrap();
+        assert_eq!(disk.size(), 1 << 30);
+        let mut buf = vec![0xffu8; 4096];
+        disk.read_exact_at(&mut buf, 12345).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_write_allocates_clusters() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.qcow2");
+        let file = create_image(&path, 1 << 30, None);
+
+        let disk = Qcow2Disk::new(file, &path, false).unwrap();
+        // Crosses a cluster boundary.
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let offset = 3 * CLUSTER_SIZE - 512;
+        disk.write_all_at(&data, offset).unwrap();
+        disk.write_all_at(&[0xaa; 16], offset).unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        disk.read_exact_at(&mut buf, offset - 512).unwrap();
+        assert!(buf[..512].iter().all(|b| *b == 0));
+        assert_eq!(&buf[512..528], &[0xaa; 16]);
+        assert_eq!(&buf[528..1536], &data[16..]);
+        assert!(buf[1536..].iter().all(|b| *b == 0));
+
+        // One L2 table and two data clusters were appended to the image.
+        let inner = disk.inner.lock().unwrap();
+        let file = &inner.file;
+        assert_eq!(file.metadata().unwrap().len(), CLUSTER_SIZE * 7);
+        for cluster in 0..7 {
+            assert_eq!(refcount(file, cluster), 1);
+        }
+        assert_eq!(refcount(file, 7), 0);
+        assert_eq!(
+            read_be_u64(file, CLUSTER_SIZE * 3).unwrap(),
+            (CLUSTER_SIZE * 4) | CLUSTER_COPIED
+        );
+
+        drop(inner);
+
+        // The data survives reopening the image.
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        let disk = Qcow2Disk::new(file, &path, true).unwrap();
+        let mut buf = vec![0u8; 1024];
+        disk.read_exact_at(&mut buf, offset).unwrap();
+        assert_eq!(&buf[..16], &[0xaa; 16]);
+        assert_eq!(&buf[16..], &data[16..]);
+        assert!(disk.write_all_at(&[0u8; 512], 0).is_err());
+    }
+
+    #[test]
+    fn test_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base.raw");
+        let base = File::create(&base_path).unwrap();
+        base.write_all_at(&[0x55; 8192], 0).unwrap();
+
+        let path = dir.as_path().join("overlay.qcow2");
+        let file = create_image(&path, 1 << 20, Some("base.raw"));
+        let disk = Qcow2Disk::new(file, &path, false).unwrap();
+
+        // Reads past the end of the backing file return zeroes.
+        let mut buf = vec![0u8; 1024];
+        disk.read_exact_at(&mut buf, 8192 - 512).unwrap();
+        assert!(buf[..512].iter().all(|b| *b == 0x55));
+        assert!(buf[512..].iter().all(|b| *b == 0));
+
+        // Partial writes keep the rest of the cluster from the backing file.
+        disk.write_all_at(&[0xaa; 512], 1024).unwrap();
+        let mut buf = vec![0u8; 4096];
+        disk.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..1024].iter().all(|b| *b == 0x55));
+        assert!(buf[1024..1536].iter().all(|b| *b == 0xaa));
+        assert!(buf[1536..].iter().all(|b| *b == 0x55));
+
+        // The backing file is never modified.
+        drop(disk);
+        let base_data = std::fs::read(&base_path).unwrap();
+        assert!(base_data.iter().all(|b| *b == 0x55));
+    }
+
+    #[test]
+    fn test_invalid_images() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.qcow2");
+        let file = create_image(&path, 1 << 20, None);
+
+        // Wrong magic.
+        file.write_all_at(&0u32.to_be_bytes(), 0).unwrap();
+        let f = file.try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Disk::new(f, &path, false),
+            Err(Error::InvalidMagic)
+        ));
+        file.write_all_at(&QCOW_MAGIC.to_be_bytes(), 0).unwrap();
+
+        // Corrupt images can only be opened read-only.
+        file.write_all_at(&INCOMPAT_CORRUPT.to_be_bytes(), 72)
+            .unwrap();
+        let f = file.try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Disk::new(f, &path, false),
+            Err(Error::UnsupportedFeatures(INCOMPAT_CORRUPT))
+        ));
+        let f = file.try_clone().unwrap();
+        assert!(Qcow2Disk::new(f, &path, true).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("overlay.qcow2");
+        let file = create_image(&path, 1 << 20, Some("base.raw"));
+
+        // Backing file names are limited to 1023 bytes.
+        file.write_all_at(&1024u32.to_be_bytes(), 16).unwrap();
+        let f = file.try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Disk::new(f, &path, false),
+            Err(Error::InvalidMetadata)
+        ));
+        file.write_all_at(&8u32.to_be_bytes(), 16).unwrap();
+
+        // And header extensions to the first cluster.
+        let mut ext = [0u8; 8];
+        ext[..4].copy_from_slice(&HEADER_EXT_BACKING_FORMAT.to_be_bytes());
+        ext[4..].copy_from_slice(&u32::MAX.to_be_bytes());
+        file.write_all_at(&ext, V3_HEADER_SIZE as u64).unwrap();
+        let f = file.try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Disk::new(f, &path, false),
+            Err(Error::InvalidMetadata)
+        ));
+    }
+}
@@ -0,0 +1,413 @@
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{fallocate, write_zeroes_slow, DiskBackend};
+
+// Alignment used for direct I/O on regular files. We can't tell the logical
+// block size of the underlying device, so use the largest common one.
+const DEFAULT_DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// A zeroed heap buffer with the alignment required by direct I/O.
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(1), align).unwrap();
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        match NonNull::new(ptr) {
+            Some(ptr) => AlignedBuf { ptr, layout },
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because the allocation is valid and initialized for its whole size.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because the allocation is valid and initialized for its whole size.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because the buffer was allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Returns the alignment direct I/O on `file` requires, or 1 if it wasn't
+/// opened with `O_DIRECT`.
+fn io_alignment(file: &File) -> io::Result<usize> {
+    // Safe because the file descriptor is valid and we check the return value.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & libc::O_DIRECT == 0 {
+        return Ok(1);
+    }
+
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(DEFAULT_DIRECT_IO_ALIGNMENT);
+    }
+    let mut block_size: libc::c_int = 0;
+    // Safe because the file descriptor is valid, the kernel only writes an int
+    // to `block_size`, and we check the return value.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut block_size) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(block_size as usize)
+}
+
+/// A disk image whose contents are exposed to the guest without any translation.
+///
+/// If the file was opened with `O_DIRECT`, requests that aren't aligned to
+/// the logical block size go through an aligned bounce buffer, reading the
+/// surrounding blocks first when writing part of them. The image size must
+/// then be a multiple of the block size, which is 4096 bytes for regular
+/// files, since the last block can't be read or written partially.
+pub struct RawDisk {
+    file: File,
+    size: AtomicU64,
+    align: usize,
+    // Serializes read-modify-write cycles, so partial writes to the same
+    // block don't undo each other.
+    rmw_lock: Mutex<()>,
+}
+
+impl RawDisk {
+    pub fn new(file: File) -> io::Result<Self> {
+        let align = io_alignment(&file)?;
+        let size = Self::file_size(&file, align)?;
+        Ok(Self {
+            file,
+            size: AtomicU64::new(size),
+            align,
+            rmw_lock: Mutex::new(()),
+        })
+    }
+
+    fn file_size(mut file: &File, align: usize) -> io::Result<u64> {
+        // Seek instead of relying on the metadata, so block devices report their real size.
+        let size = file.seek(SeekFrom::End(0))?;
+        if !size.is_multiple_of(align as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "disk size {size} is not a multiple of the {align} bytes direct I/O alignment"
+                ),
+            ));
+        }
+        Ok(size)
+    }
+
+    fn is_aligned(&self, buf: &[u8], offset: u64) -> bool {
+        let align = self.align as u64;
+        (buf.as_ptr() as u64 | buf.len() as u64 | offset).is_multiple_of(align)
+    }
+
+    /// Returns the block aligned range covering `len` bytes at `offset`.
+    fn aligned_range(&self, offset: u64, len: usize) -> (u64, u64) {
+        let align = self.align as u64;
+        let start = offset / align * align;
+        let end = (offset + len as u64).div_ceil(align) * align;
+        (start, end)
+    }
+
+    /// Zeroes `len` bytes at `offset`, punching a hole if `unmap` is set.
+    /// `fallocate` applies fallocate(2) with the given mode to the range.
+    fn zero_range<F>(&self, offset: u64, len: u64, unmap: bool, fallocate: F) -> io::Result<()>
+    where
+        F: Fn(libc::c_int) -> io::Result<()>,
+    {
+        if unmap && fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE).is_ok() {
+            return Ok(());
+        }
+
+        match fallocate(libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) {
+            // Fall back to writing the zeroes ourselves.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zeroes_slow(self, offset, len)
+            }
+            res => res,
+        }
+    }
+
+    /// Punches a hole with `fallocate`, unless the file system can't.
+    fn punch_hole<F>(fallocate: F) -> io::Result<()>
+    where
+        F: Fn(libc::c_int) -> io::Result<()>,
+    {
+        match fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) {
+            // Discarding is only a hint, so the data may as well stay.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            res => res,
+        }
+    }
+}
+
+impl DiskBackend for RawDisk {
+    fn size(&self) -> u64 {
+        self.size.load(Ordering::Acquire)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if self.is_aligned(buf, offset) {
+            return self.file.read_exact_at(buf, offset);
+        }
+
+        let (start, end) = self.aligned_range(offset, buf.len());
+        let mut bounce = AlignedBuf::new((end - start) as usize, self.align);
+        self.file.read_exact_at(&mut bounce, start)?;
+        let pos = (offset - start) as usize;
+        buf.copy_from_slice(&bounce[pos..pos + buf.len()]);
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.is_aligned(buf, offset) {
+            return self.file.write_all_at(buf, offset);
+        }
+
+        let (start, end) = self.aligned_range(offset, buf.len());
+        let mut bounce = AlignedBuf::new((end - start) as usize, self.align);
+        let partial = start != offset || end != offset + buf.len() as u64;
+        let _guard = if partial {
+            let guard = self.rmw_lock.lock().unwrap();
+            self.file.read_exact_at(&mut bounce, start)?;
+            Some(guard)
+        } else {
+            None
+        };
+        let pos = (offset - start) as usize;
+        bounce[pos..pos + buf.len()].copy_from_slice(buf);
+        self.file.write_all_at(&bounce, start)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Deallocates the given range of the backing file, leaving a hole that
+    /// reads back as zeroes.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        Self::punch_hole(|mode| fallocate(&self.file, mode, offset, len))
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.zero_range(offset, len, unmap, |mode| {
+            fallocate(&self.file, mode, offset, len)
+        })
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+
+    fn io_alignment(&self) -> usize {
+        self.align
+    }
+
+    fn refresh_size(&self) -> io::Result<u64> {
+        let size = Self::file_size(&self.file, self.align)?;
+        self.size.store(size, Ordering::Release);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use utils::tempfile::TempFile;
+
+    const PUNCH_HOLE: libc::c_int = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    const ZERO_RANGE: libc::c_int = libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE;
+
+    fn raw_disk(file: &TempFile, len: usize) -> RawDisk {
+        file.as_file().write_all_at(&vec![0xaa; len], 0).unwrap();
+        RawDisk::new(file.as_file().try_clone().unwrap()).unwrap()
+    }
+
+    fn contents(disk: &RawDisk, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Opens `file` with `O_DIRECT`, or returns `None` if its file system
+    /// doesn't support it.
+    fn open_direct(file: &TempFile) -> Option<io::Result<RawDisk>> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(file.as_path())
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return None,
+            file => file.unwrap(),
+        };
+        Some(RawDisk::new(file))
+    }
+
+    #[test]
+    fn test_direct_io_bounce() {
+        let file = TempFile::new().unwrap();
+        let mut expected = pattern(0x3000);
+        file.as_file().write_all_at(&expected, 0).unwrap();
+        let Some(disk) = open_direct(&file) else {
+            return;
+        };
+        let disk = disk.unwrap();
+        assert_eq!(disk.align, DEFAULT_DIRECT_IO_ALIGNMENT);
+
+        // Unaligned offset and length, across a block boundary.
+        let mut buf = [0u8; 100];
+        disk.read_exact_at(&mut buf, 0xfc0).unwrap();
+        assert_eq!(buf, expected[0xfc0..0x1024]);
+
+        // Aligned offset and length, but not the memory.
+        let mut buf = AlignedBuf::new(0x1001, DEFAULT_DIRECT_IO_ALIGNMENT);
+        disk.read_exact_at(&mut buf[1..], 0x1000).unwrap();
+        assert_eq!(buf[1..], expected[0x1000..0x2000]);
+
+        // Partial writes keep the rest of the blocks they touch.
+        disk.write_all_at(&[0xff; 100], 0xfc0).unwrap();
+        expected[0xfc0..0x1024].fill(0xff);
+        buf[1..].fill(0xee);
+        disk.write_all_at(&buf[1..], 0x2000).unwrap();
+        expected[0x2000..].fill(0xee);
+
+        let mut contents = AlignedBuf::new(0x3000, DEFAULT_DIRECT_IO_ALIGNMENT);
+        disk.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(*contents, expected[..]);
+        let mut contents = vec![0; 0x3000];
+        file.as_file().read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn test_direct_io_size() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x1001).unwrap();
+        let Some(disk) = open_direct(&file) else {
+            return;
+        };
+        let err = disk.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Buffered I/O has no such restriction.
+        assert_eq!(raw_disk(&file, 0x1001).size(), 0x1001);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let file = TempFile::new().unwrap();
+        let disk = raw_disk(&file, 0x4000);
+
+        disk.write_zeroes(0x1000, 0x1000, false).unwrap();
+        disk.write_zeroes(0x2000, 0x1000, true).unwrap();
+        assert_eq!(contents(&disk, 0, 0x1000), vec![0xaa; 0x1000]);
+        assert_eq!(contents(&disk, 0x1000, 0x2000), vec![0; 0x2000]);
+        assert_eq!(contents(&disk, 0x3000, 0x1000), vec![0xaa; 0x1000]);
+        // Zeroing never changes the size of the disk.
+        assert_eq!(file.as_file().metadata().unwrap().len(), 0x4000);
+    }
+
+    #[test]
+    fn test_write_zeroes_unsupported() {
+        let file = TempFile::new().unwrap();
+        let disk = raw_disk(&file, 0x4000);
+        let modes = RefCell::new(Vec::new());
+        let unsupported = |mode| {
+            modes.borrow_mut().push(mode);
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        };
+
+        // Without hole punching nor zero ranges, the zeroes are written.
+        disk.zero_range(0x1000, 0x1000, true, unsupported).unwrap();
+        assert_eq!(*modes.borrow(), [PUNCH_HOLE, ZERO_RANGE]);
+        assert_eq!(contents(&disk, 0x1000, 0x1000), vec![0; 0x1000]);
+
+        modes.borrow_mut().clear();
+        disk.zero_range(0x2000, 0x1000, false, unsupported).unwrap();
+        assert_eq!(*modes.borrow(), [ZERO_RANGE]);
+        assert_eq!(contents(&disk, 0x2000, 0x1000), vec![0; 0x1000]);
+        assert_eq!(contents(&disk, 0x3000, 0x1000), vec![0xaa; 0x1000]);
+    }
+
+    #[test]
+    fn test_write_zeroes_without_hole_punching() {
+        let file = TempFile::new().unwrap();
+        let disk = raw_disk(&file, 0x4000);
+        let modes = RefCell::new(Vec::new());
+
+        // Zero ranges are used when holes can't be punched.
+        disk.zero_range(0x1000, 0x1000, true, |mode| {
+            modes.borrow_mut().push(mode);
+            if mode == PUNCH_HOLE {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+            }
+            fallocate(&disk.file, mode, 0x1000, 0x1000)
+        })
+        .unwrap();
+        assert_eq!(*modes.borrow(), [PUNCH_HOLE, ZERO_RANGE]);
+        assert_eq!(contents(&disk, 0x1000, 0x1000), vec![0; 0x1000]);
+
+        // Other errors aren't papered over.
+        let err = disk
+            .zero_range(0x1000, 0x1000, false, |_| {
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    fn test_discard() {
+        let file = TempFile::new().unwrap();
+        let disk = raw_disk(&file, 0x4000);
+
+        disk.discard(0x1000, 0x1000).unwrap();
+
+        // Discarding succeeds on file systems that can't punch holes.
+        RawDisk::punch_hole(|mode| {
+            assert_eq!(mode, PUNCH_HOLE);
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        })
+        .unwrap();
+        let err =
+            RawDisk::punch_hole(|_| Err(io::Error::from_raw_os_error(libc::EIO))).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
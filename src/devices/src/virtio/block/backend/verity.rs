@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+
+use super::DiskBackend;
+
+// On-disk superblock written by `veritysetup format`, which precedes the
+// hash tree. All fields are little endian.
+const SUPERBLOCK_MAGIC: &[u8; 8] = b"verity\0\0";
+const SUPERBLOCK_LEN: usize = 512;
+const SUPERBLOCK_VERSION: u32 = 1;
+// Hash type 1 hashes the salt before the block, like every recent dm-verity
+// format. Type 0 is the original Chrome OS format.
+const HASH_TYPE: u32 = 1;
+const MAX_SALT_SIZE: usize = 256;
+
+const ALGORITHM: &str = "sha256";
+/// Size of the digests of the hash tree and of the root hash.
+pub const DIGEST_SIZE: usize = 32;
+
+// Block sizes we accept for both the data and the hash tree.
+const MIN_BLOCK_SIZE: u32 = 512;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+// Number of verified hash blocks kept in memory. Rereading them from the
+// file would allow them to be changed after being verified.
+const MAX_CACHED_HASH_BLOCKS: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data or hash block size isn't supported.
+    InvalidBlockSize(u32),
+    /// Invalid magic number.
+    InvalidMagic,
+    /// The root hash doesn't have the size of a digest.
+    InvalidRootHash(usize),
+    /// The salt is larger than the superblock allows.
+    InvalidSalt(usize),
+    /// I/O error accessing the data or the hash tree.
+    Io(io::Error),
+    /// A data or hash block doesn't match its digest.
+    Mismatch { hash_tree: bool, block: u64 },
+    /// The superblock describes more data than the disk image holds.
+    SizeMismatch { image: u64, data: u64 },
+    /// Only sha256 is supported.
+    UnsupportedAlgorithm(String),
+    /// Only hash type 1 is supported.
+    UnsupportedHashType(u32),
+    /// Only version 1 is supported.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidBlockSize(size) => write!(f, "invalid verity block size: {size}"),
+            InvalidMagic => write!(f, "no verity superblock found"),
+            InvalidRootHash(len) => write!(f, "root hash is {len} bytes long"),
+            InvalidSalt(len) => write!(f, "verity salt is {len} bytes long"),
+            Io(e) => write!(f, "verity I/O error: {e}"),
+            Mismatch {
+                hash_tree: false,
+                block,
+            } => write!(f, "verification of data block {block} failed"),
+            Mismatch {
+                hash_tree: true,
+                block,
+            } => write!(f, "verification of hash block {block} failed"),
+            SizeMismatch { image, data } => write!(
+                f,
+                "verity superblock describes {data} bytes of data, but the image has {image} bytes"
+            ),
+            UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported verity hash algorithm: {algorithm}")
+            }
+            UnsupportedHashType(hash_type) => {
+                write!(f, "unsupported verity hash type: {hash_type}")
+            }
+            UnsupportedVersion(version) => write!(f, "unsupported verity version: {version}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+struct Superblock {
+    data_block_size: u32,
+    hash_block_size: u32,
+    data_blocks: u64,
+    salt: Vec<u8>,
+}
+
+impl Superblock {
+    fn read_from(file: &File, offset: u64) -> Result<Self> {
+        let mut buf = [0u8; SUPERBLOCK_LEN];
+        file.read_exact_at(&mut buf, offset)?;
+        if &buf[0..8] != SUPERBLOCK_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != SUPERBLOCK_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let hash_type = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        if hash_type != HASH_TYPE {
+            return Err(Error::UnsupportedHashType(hash_type));
+        }
+        let algorithm = &buf[32..64];
+        let len = algorithm.iter().position(|&b| b == 0).unwrap_or(32);
+        let algorithm = String::from_utf8_lossy(&algorithm[..len]);
+        if algorithm != ALGORITHM {
+            return Err(Error::UnsupportedAlgorithm(algorithm.into_owned()));
+        }
+        let salt_size = u16::from_le_bytes(buf[80..82].try_into().unwrap()) as usize;
+        if salt_size > MAX_SALT_SIZE {
+            return Err(Error::InvalidSalt(salt_size));
+        }
+        Ok(Superblock {
+            data_block_size: u32::from_le_bytes(buf[64..68].try_into().unwrap()),
+            hash_block_size: u32::from_le_bytes(buf[68..72].try_into().unwrap()),
+            data_blocks: u64::from_le_bytes(buf[72..80].try_into().unwrap()),
+            salt: buf[88..88 + salt_size].to_vec(),
+        })
+    }
+}
+
+fn is_valid_block_size(block_size: u32) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+/// A read-only disk image whose blocks are checked against a Merkle tree of
+/// their hashes before being returned, in the dm-verity format.
+///
+/// Each data block is hashed into level 0 of the tree, the blocks of each
+/// level are hashed into the next one, and the single block of the top
+/// level is hashed into the root hash, which is trusted. Reads that include
+/// a block that doesn't match fail with `InvalidData`.
+pub struct VerityDisk {
+    base: Arc<dyn DiskBackend>,
+    hash_tree: File,
+    size: u64,
+    data_block_size: u64,
+    hash_block_size: u64,
+    salt: Vec<u8>,
+    root_hash: [u8; DIGEST_SIZE],
+    // Number of digests in a hash block, as a power of two.
+    hash_per_block_bits: u32,
+    // Offset in the file of the first hash block of each level, lowest
+    // level first. The levels are stored top level first.
+    level_offsets: Vec<u64>,
+    verified: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+}
+
+impl VerityDisk {
+    /// Verifies `base` with the hash tree in `hash_tree`, preceded by a
+    /// superblock at `hash_offset`, against `root_hash`.
+    pub fn new(
+        base: Arc<dyn DiskBackend>,
+        hash_tree: File,
+        hash_offset: u64,
+        root_hash: &[u8],
+    ) -> Result<Self> {
+        let root_hash: [u8; DIGEST_SIZE] = root_hash
+            .try_into()
+            .map_err(|_| Error::InvalidRootHash(root_hash.len()))?;
+        let sb = Superblock::read_from(&hash_tree, hash_offset)?;
+        for block_size in [sb.data_block_size, sb.hash_block_size] {
+            if !is_valid_block_size(block_size) {
+                return Err(Error::InvalidBlockSize(block_size));
+            }
+        }
+        let data_block_size = u64::from(sb.data_block_size);
+        let hash_block_size = u64::from(sb.hash_block_size);
+        let size = sb
+            .data_blocks
+            .checked_mul(data_block_size)
+            .filter(|&size| size <= base.size())
+            .ok_or(Error::SizeMismatch {
+                image: base.size(),
+                data: sb.data_blocks.saturating_mul(data_block_size),
+            })?;
+
+        let hash_per_block_bits = (hash_block_size / DIGEST_SIZE as u64).ilog2();
+        let mut levels = 0;
+        while hash_per_block_bits * levels < 64
+            && sb.data_blocks.saturating_sub(1) >> (hash_per_block_bits * levels) != 0
+        {
+            levels += 1;
+        }
+
+        // The tree starts right after the block holding the superblock.
+        let mut level_offsets = vec![0; levels as usize];
+        let mut offset = hash_offset + hash_block_size;
+        for level in (0..levels).rev() {
+            level_offsets[level as usize] = offset;
+            let shift = (level + 1) * hash_per_block_bits;
+            let blocks = if shift < 64 {
+                sb.data_blocks.div_ceil(1 << shift)
+            } else {
+                1
+            };
+            offset += blocks * hash_block_size;
+        }
+
+        Ok(VerityDisk {
+            base,
+            hash_tree,
+            size,
+            data_block_size,
+            hash_block_size,
+            salt: sb.salt,
+            root_hash,
+            hash_per_block_bits,
+            level_offsets,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn digest(&self, data: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+
+    /// Returns the trusted digest covering data block `block` at `level` of
+    /// the tree. Above the top level, that's the root hash.
+    fn expected_digest(&self, block: u64, level: usize) -> Result<[u8; DIGEST_SIZE]> {
+        if level == self.level_offsets.len() {
+            return Ok(self.root_hash);
+        }
+        let position = block >> (level as u32 * self.hash_per_block_bits);
+        let offset = self.level_offsets[level]
+            + (position >> self.hash_per_block_bits) * self.hash_block_size;
+        let hash_block = self.verified_hash_block(block, level, offset)?;
+        let index = (position & ((1 << self.hash_per_block_bits) - 1)) as usize;
+        // Digests are laid out on power of two boundaries.
+        let stride = (self.hash_block_size >> self.hash_per_block_bits) as usize;
+        let mut digest = [0u8; DIGEST_SIZE];
+        digest.copy_from_slice(&hash_block[index * stride..index * stride + DIGEST_SIZE]);
+        Ok(digest)
+    }
+
+    /// Reads the hash block at `offset`, on the path of data block `block`
+    /// at `level`, and verifies it against the levels above.
+    fn verified_hash_block(&self, block: u64, level: usize, offset: u64) -> Result<Arc<Vec<u8>>> {
+        if let Some(hash_block) = self.verified.lock().unwrap().get(&offset) {
+            return Ok(hash_block.clone());
+        }
+
+        let expected = self.expected_digest(block, level + 1)?;
+        let mut hash_block = vec![0u8; self.hash_block_size as usize];
+        self.hash_tree.read_exact_at(&mut hash_block, offset)?;
+        if self.digest(&hash_block) != expected {
+            return Err(Error::Mismatch {
+                hash_tree: true,
+                block: offset / self.hash_block_size,
+            });
+        }
+
+        let hash_block = Arc::new(hash_block);
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_CACHED_HASH_BLOCKS {
+            verified.clear();
+        }
+        verified.insert(offset, hash_block.clone());
+        Ok(hash_block)
+    }
+
+    fn verify_data_block(&self, block: u64, data: &[u8]) -> Result<()> {
+        if self.digest(data) != self.expected_digest(block, 0)? {
+            return Err(Error::Mismatch {
+                hash_tree: false,
+                block,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl DiskBackend for VerityDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let end = match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.size => end,
+            _ => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        };
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        // Only whole blocks can be verified.
+        let first = offset / self.data_block_size;
+        let last = end.div_ceil(self.data_block_size);
+        let start = first * self.data_block_size;
+        let mut data = vec![0u8; ((last - first) * self.data_block_size) as usize];
+        self.base.read_exact_at(&mut data, start)?;
+        for (block, chunk) in (first..last).zip(data.chunks(self.data_block_size as usize)) {
+            self.verify_data_block(block, chunk)?;
+        }
+        let pos = (offset - start) as usize;
+        buf.copy_from_slice(&data[pos..pos + buf.len()]);
+        Ok(())
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "verified disks are read-only",
+        ))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    use crate::virtio::block::backend::RawDisk;
+
+    const BLOCK_SIZE: usize = 4096;
+    const SALT: &[u8] = b"krun";
+
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(SALT);
+        hasher.update(data);
+        hasher.finalize().to_vec()
+    }
+
+    /// Builds the superblock and hash tree of `data` like `veritysetup
+    /// format` does, returning them along with the root hash.
+    fn format(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut levels: Vec<Vec<u8>> = Vec::new();
+        let mut digests: Vec<Vec<u8>> = data.chunks(BLOCK_SIZE).map(digest).collect();
+        while digests.len() > 1 {
+            let mut level = Vec::new();
+            for chunk in digests.chunks(BLOCK_SIZE / DIGEST_SIZE) {
+                let mut block = chunk.concat();
+                block.resize(BLOCK_SIZE, 0);
+                level.extend_from_slice(&block);
+            }
+            digests = level.chunks(BLOCK_SIZE).map(digest).collect();
+            levels.push(level);
+        }
+
+        let mut image = vec![0u8; BLOCK_SIZE];
+        image[0..8].copy_from_slice(SUPERBLOCK_MAGIC);
+        image[8..12].copy_from_slice(&SUPERBLOCK_VERSION.to_le_bytes());
+        image[12..16].copy_from_slice(&HASH_TYPE.to_le_bytes());
+        image[32..32 + ALGORITHM.len()].copy_from_slice(ALGORITHM.as_bytes());
+        image[64..68].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        image[68..72].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        image[72..80].copy_from_slice(&((data.len() / BLOCK_SIZE) as u64).to_le_bytes());
+        image[80..82].copy_from_slice(&(SALT.len() as u16).to_le_bytes());
+        image[88..88 + SALT.len()].copy_from_slice(SALT);
+        for level in levels.iter().rev() {
+            image.extend_from_slice(level);
+        }
+        (image, digests.remove(0))
+    }
+
+    fn create_file(contents: &[u8]) -> TempFile {
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all_at(contents, 0).unwrap();
+        f
+    }
+
+    fn open(data: &TempFile, hash_tree: &TempFile, root_hash: &[u8]) -> Result<VerityDisk> {
+        let base = RawDisk::new(File::open(data.as_path()).unwrap()).unwrap();
+        VerityDisk::new(
+            Arc::new(base),
+            File::open(hash_tree.as_path()).unwrap(),
+            0,
+            root_hash,
+        )
+    }
+
+    fn test_data(blocks: usize) -> Vec<u8> {
+        (0..blocks * BLOCK_SIZE).map(|i| (i / 512) as u8).collect()
+    }
+
+    #[test]
+    fn test_verified_read() {
+        // Enough blocks for a two level tree.
+        let data = test_data(300);
+        let (hash_tree, root_hash) = format(&data);
+        let data_file = create_file(&data);
+        let hash_file = create_file(&hash_tree);
+        let disk = open(&data_file, &hash_file, &root_hash).unwrap();
+        assert_eq!(disk.level_offsets.len(), 2);
+        assert_eq!(disk.size(), data.len() as u64);
+
+        // Unaligned reads crossing block boundaries.
+        let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+        disk.read_exact_at(&mut buf, 200 * BLOCK_SIZE as u64 - 512)
+            .unwrap();
+        assert_eq!(buf, data[200 * BLOCK_SIZE - 512..203 * BLOCK_SIZE - 512]);
+        assert!(disk.write_all_at(&buf, 0).is_err());
+
+        // A single block is verified against the root hash directly.
+        let data = test_data(1);
+        let (hash_tree, root_hash) = format(&data);
+        let data_file = create_file(&data);
+        let hash_file = create_file(&hash_tree);
+        let disk = open(&data_file, &hash_file, &root_hash).unwrap();
+        assert!(disk.level_offsets.is_empty());
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_tampered_data() {
+        let data = test_data(16);
+        let (hash_tree, root_hash) = format(&data);
+        let data_file = create_file(&data);
+        let hash_file = create_file(&hash_tree);
+        data_file
+            .as_file()
+            .write_all_at(&[0xff], 5 * BLOCK_SIZE as u64 + 17)
+            .unwrap();
+        let disk = open(&data_file, &hash_file, &root_hash).unwrap();
+
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read_exact_at(&mut buf, 4 * BLOCK_SIZE as u64).unwrap();
+        let err = disk
+            .read_exact_at(&mut buf, 5 * BLOCK_SIZE as u64 + 512)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_tampered_hash_tree() {
+        let data = test_data(16);
+        let (mut hash_tree, root_hash) = format(&data);
+        let data_file = create_file(&data);
+
+        // Wrong root hash.
+        let hash_file = create_file(&hash_tree);
+        let mut wrong_root = root_hash.clone();
+        wrong_root[0] ^= 1;
+        let disk = open(&data_file, &hash_file, &wrong_root).unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        assert!(disk.read_exact_at(&mut buf, 0).is_err());
+
+        // A digest rewritten to match tampered data.
+        let mut tampered = data[..BLOCK_SIZE].to_vec();
+        tampered[0] ^= 1;
+        hash_tree[BLOCK_SIZE..BLOCK_SIZE + DIGEST_SIZE].copy_from_slice(&digest(&tampered));
+        let hash_file = create_file(&hash_tree);
+        data_file.as_file().write_all_at(&tampered, 0).unwrap();
+        let disk = open(&data_file, &hash_file, &root_hash).unwrap();
+        assert!(disk.read_exact_at(&mut buf, 0).is_err());
+
+        assert!(matches!(
+            open(&data_file, &hash_file, &root_hash[1..]),
+            Err(Error::InvalidRootHash(31))
+        ));
+    }
+}
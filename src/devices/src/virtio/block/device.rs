@@ -8,85 +8,277 @@
 use std::cmp;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, Write};
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::result;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use log::{error, warn};
 use utils::eventfd::EventFd;
 use virtio_bindings::{virtio_blk::*, virtio_config::VIRTIO_F_VERSION_1};
-use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK, VIRTIO_MMIO_INT_VRING},
+    backend::{
+        open_disk_image, open_nbd, open_overlay, open_verity, DiskBackend, ImageType,
+        OverlayConfig, VerityConfig,
+    },
+    io_engine::{new_io_engine, Completion, IoEngine},
     request::*,
-    Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    Error, MAX_BLOCK_SIZE, MAX_DISCARD_SECTORS, MAX_DISCARD_SEG, MAX_NUM_QUEUES, MAX_SEGMENT_SIZE,
+    MAX_WRITE_ZEROES_SECTORS, MAX_WRITE_ZEROES_SEG, QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE,
 };
 
 use crate::legacy::Gic;
+use crate::rate_limiter::{RateLimiter, RateLimiterConfig};
 use crate::virtio::VIRTIO_MMIO_INT_CONFIG;
 use crate::Error as DeviceError;
 
 /// Configuration options for disk caching.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum CacheType {
-    /// Flushing mechanic will be advertised to the guest driver, but
-    /// the operation will be a noop.
+    /// Flushing mechanic will not be advertised to the guest driver, so
+    /// writes may be lost if the host crashes.
     #[default]
     Unsafe,
     /// Flushing mechanic will be advertised to the guest driver and
     /// flush requests coming from the guest will be performed using
     /// `fsync`.
     Writeback,
+    /// Like `Writeback`, but the disk image is opened with `O_DIRECT` to
+    /// bypass the host page cache. Only supported for raw images whose size
+    /// is a multiple of the direct I/O alignment, see `RawDisk`.
+    Direct,
 }
 
+/// Identity and topology of a disk, as reported to the guest. The default
+/// keeps the serial derived from the image file and advertises neither.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiskIdentity {
+    /// Serial returned by `VIRTIO_BLK_T_GET_ID`, up to 20 bytes long.
+    pub serial: Option<String>,
+    /// Logical block size in bytes, or zero for 512 bytes.
+    pub logical_block_size: u32,
+    /// Physical block size in bytes, or zero for the logical block size.
+    pub physical_block_size: u32,
+    /// Maximum number of data segments in a request, or zero to not advertise it.
+    pub seg_max: u32,
+}
+
+impl DiskIdentity {
+    /// Checks that the guest can make sense of the identity.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+        if let Some(serial) = &self.serial {
+            if serial.len() > VIRTIO_BLK_ID_BYTES as usize {
+                return invalid(format!(
+                    "serial is longer than {} bytes: {}",
+                    VIRTIO_BLK_ID_BYTES, serial
+                ));
+            }
+        }
+        let logical = self.logical_block_size();
+        if !logical.is_power_of_two()
+            || u64::from(logical) < SECTOR_SIZE
+            || logical > MAX_BLOCK_SIZE
+        {
+            return invalid(format!("invalid logical block size: {}", logical));
+        }
+        let physical = self.physical_block_size();
+        if !physical.is_power_of_two() || physical < logical || physical > MAX_BLOCK_SIZE {
+            return invalid(format!("invalid physical block size: {}", physical));
+        }
+        // The header and status descriptors take two entries of the queue.
+        if self.seg_max > u32::from(QUEUE_SIZE) - 2 {
+            return invalid(format!(
+                "invalid maximum number of segments: {}",
+                self.seg_max
+            ));
+        }
+        Ok(())
+    }
+
+    fn logical_block_size(&self) -> u32 {
+        match self.logical_block_size {
+            0 => SECTOR_SIZE as u32,
+            size => size,
+        }
+    }
+
+    fn physical_block_size(&self) -> u32 {
+        match self.physical_block_size {
+            0 => self.logical_block_size(),
+            size => size,
+        }
+    }
+}
+
+/// The virtio block configuration space, as described in section 5.2.4 of the
+/// virtio 1.1 specification. All fields are little endian. The layout is
+/// packed, so the guest doesn't see trailing padding past its 60 bytes.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub(crate) struct ConfigSpace {
+    capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+    geometry_cylinders: u16,
+    geometry_heads: u8,
+    geometry_sectors: u8,
+    blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+    max_write_zeroes_sectors: u32,
+    max_write_zeroes_seg: u32,
+    write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
+const _: () = assert!(std::mem::size_of::<ConfigSpace>() == 60);
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
-    file: File,
-    nsectors: u64,
+    image_type: ImageType,
+    overlay: Option<OverlayConfig>,
+    verity: Option<VerityConfig>,
+    identity: DiskIdentity,
+    disk: Arc<dyn DiskBackend>,
+    // Updated when the disk image is resized while requests may be executing.
+    nsectors: AtomicU64,
     image_id: Vec<u8>,
 }
 
 impl DiskProperties {
     pub fn new(
         disk_image_path: String,
+        image_type: ImageType,
+        overlay: Option<OverlayConfig>,
+        verity: Option<VerityConfig>,
+        identity: DiskIdentity,
         is_disk_read_only: bool,
         cache_type: CacheType,
     ) -> io::Result<Self> {
-        let mut disk_image = OpenOptions::new()
-            .read(true)
-            .write(!is_disk_read_only)
-            .open(PathBuf::from(&disk_image_path))?;
-        let disk_size = disk_image.seek(SeekFrom::End(0))?;
-
-        // We only support disk size, which uses the first two words of the configuration space.
-        // If the image is not a multiple of the sector size, the tail bits are not exposed.
-        if disk_size % SECTOR_SIZE != 0 {
+        // With an overlay, writes never reach the disk image, and verified
+        // images can't be written to at all.
+        let is_image_read_only = is_disk_read_only || overlay.is_some() || verity.is_some();
+        let mut options = OpenOptions::new();
+        options.read(true).write(!is_image_read_only);
+        if cache_type == CacheType::Direct {
+            if image_type != ImageType::Raw {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "direct I/O is only supported for raw images",
+                ));
+            }
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let (mut disk, default_id) = if image_type == ImageType::Nbd {
+            let disk = open_nbd(&disk_image_path, is_image_read_only)?;
+            // There's no file to derive an ID from.
+            (disk, vec![0; VIRTIO_BLK_ID_BYTES as usize])
+        } else {
+            let path = PathBuf::from(&disk_image_path);
+            let disk_image = options.open(&path)?;
+            let image_id = Self::build_disk_image_id(&disk_image);
+            let disk = open_disk_image(disk_image, &path, image_type, is_image_read_only)?;
+            (disk, image_id)
+        };
+        let image_id = match &identity.serial {
+            Some(serial) => {
+                let mut id = serial.as_bytes().to_vec();
+                id.resize(VIRTIO_BLK_ID_BYTES as usize, 0);
+                id
+            }
+            None => default_id,
+        };
+        if let Some(verity) = &verity {
+            disk = open_verity(disk, verity)?;
+        }
+        if let Some(overlay) = &overlay {
+            disk = open_overlay(disk, overlay)?;
+        }
+        let nsectors = Self::nsectors_for(disk.size(), &identity);
+
+        Ok(Self {
+            cache_type,
+            image_type,
+            overlay,
+            verity,
+            identity,
+            disk,
+            nsectors: AtomicU64::new(nsectors),
+            image_id,
+        })
+    }
+
+    /// Returns the number of sectors exposed to the guest for a disk of `disk_size` bytes.
+    fn nsectors_for(disk_size: u64, identity: &DiskIdentity) -> u64 {
+        // The capacity is always expressed in 512 bytes sectors, whatever the logical block
+        // size. If the image is not a multiple of the sector size, the tail bits are not exposed.
+        if !disk_size.is_multiple_of(SECTOR_SIZE) {
             warn!(
                 "Disk size {} is not a multiple of sector size {}; \
                  the remainder will not be visible to the guest.",
                 disk_size, SECTOR_SIZE
             );
         }
-
-        Ok(Self {
-            cache_type,
-            nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
-            file: disk_image,
-        })
+        let block_size = u64::from(identity.logical_block_size());
+        if !disk_size.is_multiple_of(block_size) {
+            warn!(
+                "Disk size {} is not a multiple of logical block size {}; \
+                 the guest may not be able to access the last block.",
+                disk_size, block_size
+            );
+        }
+        disk_size >> SECTOR_SHIFT
     }
 
-    pub fn file_mut(&mut self) -> &mut File {
-        &mut self.file
+    pub fn disk(&self) -> &dyn DiskBackend {
+        self.disk.as_ref()
     }
 
     pub fn nsectors(&self) -> u64 {
-        self.nsectors
+        self.nsectors.load(Ordering::Acquire)
+    }
+
+    /// Picks up a new size of the disk image, returning it in bytes.
+    pub fn refresh_size(&self) -> io::Result<u64> {
+        let disk_size = self.disk.refresh_size()?;
+        let nsectors = Self::nsectors_for(disk_size, &self.identity);
+        self.nsectors.store(nsectors, Ordering::Release);
+        Ok(disk_size)
+    }
+
+    /// Largest number of data segments a request may have.
+    pub fn seg_max(&self) -> u32 {
+        match self.identity.seg_max {
+            // The header and status descriptors take two entries of the queue.
+            0 => u32::from(QUEUE_SIZE) - 2,
+            seg_max => seg_max,
+        }
+    }
+
+    /// Largest data buffer a request may have, given the number and size of
+    /// the segments the guest is allowed to use.
+    pub fn max_data_len(&self) -> u64 {
+        u64::from(self.seg_max()) * u64::from(MAX_SEGMENT_SIZE)
     }
 
     pub fn image_id(&self) -> &[u8] {
@@ -124,31 +316,58 @@ impl DiskProperties {
 
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
-    pub fn virtio_block_config_space(&self) -> Vec<u8> {
-        // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
-        }
-        config
+    /// on the backing file size, the block sizes and segment limits of the
+    /// disk, the number of request queues, and with the discard and write
+    /// zeroes limits.
+    pub fn virtio_block_config_space(&self, num_queues: u16) -> Vec<u8> {
+        let logical_block_size = self.identity.logical_block_size();
+        let physical_block_exp =
+            (self.identity.physical_block_size() / logical_block_size).trailing_zeros();
+        let config = ConfigSpace {
+            capacity: self.nsectors().to_le(),
+            size_max: MAX_SEGMENT_SIZE.to_le(),
+            seg_max: self.identity.seg_max.to_le(),
+            blk_size: logical_block_size.to_le(),
+            physical_block_exp: physical_block_exp as u8,
+            num_queues: num_queues.to_le(),
+            max_discard_sectors: MAX_DISCARD_SECTORS.to_le(),
+            max_discard_seg: MAX_DISCARD_SEG.to_le(),
+            discard_sector_alignment: (logical_block_size >> SECTOR_SHIFT).to_le(),
+            max_write_zeroes_sectors: MAX_WRITE_ZEROES_SECTORS.to_le(),
+            max_write_zeroes_seg: MAX_WRITE_ZEROES_SEG.to_le(),
+            write_zeroes_may_unmap: 1,
+            ..Default::default()
+        };
+        config.as_slice().to_vec()
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn image_type(&self) -> ImageType {
+        self.image_type
+    }
+
+    pub fn overlay(&self) -> Option<&OverlayConfig> {
+        self.overlay.as_ref()
+    }
+
+    pub fn verity(&self) -> Option<&VerityConfig> {
+        self.verity.as_ref()
+    }
+
+    pub fn identity(&self) -> &DiskIdentity {
+        &self.identity
+    }
 }
 
 impl Drop for DiskProperties {
     fn drop(&mut self) {
         match self.cache_type {
-            CacheType::Writeback => {
-                // flush() first to force any cached data out.
-                if self.file.flush().is_err() {
-                    error!("Failed to flush block data on drop.");
-                }
+            CacheType::Writeback | CacheType::Direct => {
                 // Sync data out to physical media on host.
-                if self.file.sync_all().is_err() {
+                if self.disk.flush().is_err() {
                     error!("Failed to sync block data on drop.")
                 }
             }
@@ -162,7 +381,7 @@ impl Drop for DiskProperties {
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     // Host file and properties.
-    pub(crate) disk: DiskProperties,
+    pub(crate) disk: Arc<DiskProperties>,
 
     // Virtio fields.
     pub(crate) avail_features: u64,
@@ -174,9 +393,14 @@ pub struct Block {
     pub(crate) queues: Vec<Queue>,
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     pub(crate) interrupt_evt: EventFd,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
 
+    // Asynchronous request execution, unless it couldn't be started.
+    io_engine: Option<Box<dyn IoEngine>>,
+    pub(crate) completion_evt: EventFd,
+    pub(crate) rate_limiter: RateLimiter,
+
     // Implementation specific fields.
     pub(crate) id: String,
     pub(crate) partuuid: Option<String>,
@@ -190,33 +414,91 @@ pub struct Block {
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable, and contain an image of
+    /// type `disk_image_format`. If an `overlay` is given, the image is only
+    /// read and writes go to the overlay instead. With `verity`, every block
+    /// read from the image is checked against a hash tree, and the device is
+    /// read-only unless there's an overlay. Requests are executed
+    /// asynchronously, away from the thread handling the events of the
+    /// device, and may complete out of order. The `identity` sets the serial
+    /// and topology reported to the guest.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         partuuid: Option<String>,
         cache_type: CacheType,
         disk_image_path: String,
+        disk_image_format: ImageType,
+        overlay: Option<OverlayConfig>,
+        verity: Option<VerityConfig>,
         is_disk_read_only: bool,
         is_disk_root: bool,
+        num_queues: u16,
+        identity: DiskIdentity,
     ) -> io::Result<Block> {
-        let disk_properties = DiskProperties::new(disk_image_path, is_disk_read_only, cache_type)?;
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid number of queues: {}", num_queues),
+            ));
+        }
+
+        identity.validate()?;
+
+        let is_disk_read_only = is_disk_read_only || (verity.is_some() && overlay.is_none());
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            disk_image_format,
+            overlay,
+            verity,
+            identity.clone(),
+            is_disk_read_only,
+            cache_type,
+        )?;
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        // Flushing is a noop in unsafe mode, so don't make the guest bother.
+        if cache_type != CacheType::Unsafe {
+            avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
+        }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        if identity.logical_block_size != 0 || identity.physical_block_size != 0 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+        }
+
+        if identity.physical_block_size != 0 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_TOPOLOGY;
+        }
+
+        avail_features |= 1u64 << VIRTIO_BLK_F_SIZE_MAX;
+        if identity.seg_max != 0 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_SEG_MAX;
+        }
+
+        let mut queue_evts = Vec::new();
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK)?);
+        }
+
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
             id,
             root_device: is_disk_root,
             partuuid,
-            config_space: disk_properties.virtio_block_config_space(),
-            disk: disk_properties,
+            config_space: disk_properties.virtio_block_config_space(num_queues),
+            disk: Arc::new(disk_properties),
             avail_features,
             acked_features: 0u64,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
@@ -224,23 +506,50 @@ impl Block {
             queue_evts,
             queues,
             device_state: DeviceState::Inactive,
+            io_engine: None,
+            completion_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            rate_limiter: RateLimiter::new(RateLimiterConfig::default())?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK)?,
             intc: None,
             irq_line: None,
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
-        if let Err(e) = self.queue_evts[0].read() {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
+        if let Err(e) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", e);
         } else {
-            self.process_virtio_queues();
+            // Requests executed inline by the engine are already complete.
+            let used_any = self.process_queue(queue_index) | self.process_completions();
+            if used_any {
+                let _ = self.signal_used_queue();
+            }
+        }
+    }
+
+    pub(crate) fn process_completion_event(&mut self) {
+        if let Err(e) = self.completion_evt.read() {
+            error!("Failed to get completion event: {:?}", e);
+        } else if self.process_completions() {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    pub(crate) fn process_rate_limiter_event(&mut self) {
+        // Requests were deferred until the timer expired, so retry them now.
+        match self.rate_limiter.event_handler() {
+            Ok(_) => self.process_virtio_queues(),
+            Err(e) => error!("Failed to get rate limiter event: {:?}", e),
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        if self.process_queue(0) {
+        let mut used_any = false;
+        for queue_index in 0..self.queues.len() {
+            used_any |= self.process_queue(queue_index);
+        }
+        if used_any | self.process_completions() {
             let _ = self.signal_used_queue();
         }
     }
@@ -251,70 +560,94 @@ impl Block {
             // This should never happen, it's been already validated in the event handler.
             DeviceState::Inactive => unreachable!(),
         };
+        if self.rate_limiter.is_blocked() {
+            return false;
+        }
         let queue = &mut self.queues[queue_index];
         let mut used_any = false;
         while let Some(head) = queue.pop(mem) {
-            let len;
-            match Request::parse(&head, mem) {
+            let len = match Request::parse(&head, mem, &self.disk) {
                 Ok(request) => {
-                    let status = match request.execute(&mut self.disk, mem) {
-                        Ok(l) => {
-                            // Account for the status byte as well.
-                            // With a non-faulty driver, we shouldn't get to the point where we
-                            // overflow here (since data len must be a multiple of 512 bytes, so
-                            // it can't be u32::MAX). In the future, this should be fixed at the
-                            // request parsing level, so no data will actually be transferred in
-                            // scenarios like this one.
-                            if let Some(l) = l.checked_add(1) {
-                                len = l;
-                                VIRTIO_BLK_S_OK
-                            } else {
-                                len = l;
-                                VIRTIO_BLK_S_IOERR
-                            }
-                        }
-                        Err(e) => {
-                            match e {
-                                ExecuteError::Read(GuestMemoryError::PartialBuffer {
-                                    completed,
-                                    expected,
-                                }) => {
-                                    error!(
-                                        "Failed to execute virtio block read request: can only \
-                                        write {} of {} bytes.",
-                                        completed, expected
-                                    );
-                                    // This can not overflow since `completed` < data len which is
-                                    // an u32.
-                                    len = completed as u32 + 1;
-                                }
-                                _ => {
-                                    error!("Failed to execute virtio block request: {:?}", e);
-                                    // Status byte only.
-                                    len = 1;
-                                }
-                            };
-                            e.status()
-                        }
+                    let bytes = match request.request_type {
+                        RequestType::In | RequestType::Out => u64::from(request.data_len),
+                        _ => 0,
                     };
-
-                    if let Err(e) = mem.write_obj(status, request.status_addr) {
-                        error!("Failed to write virtio block status: {:?}", e)
+                    if !self.rate_limiter.consume(1, bytes) {
+                        // Leave the request in the queue until the timer expires.
+                        queue.undo_pop();
+                        break;
+                    }
+                    if let Some(engine) = self.io_engine.as_mut() {
+                        engine.submit(queue_index, head.index, request);
+                        continue;
                     }
+                    let result = request.execute(&self.disk, mem);
+                    request.write_status(mem, result)
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
-                    len = 0;
+                    0
                 }
-            }
+            };
 
             queue.add_used(mem, head.index, len);
             used_any = true;
         }
 
+        if let Some(engine) = self.io_engine.as_mut() {
+            engine.commit();
+        }
+
         used_any
     }
 
+    /// Returns the requests completed by the I/O engine to the driver.
+    fn process_completions(&mut self) -> bool {
+        match self.io_engine.as_mut() {
+            Some(engine) => {
+                let completions = engine.drain_completions();
+                self.add_completions(completions)
+            }
+            None => false,
+        }
+    }
+
+    fn add_completions(&mut self, completions: Vec<Completion>) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            DeviceState::Inactive => return false,
+        };
+        for completion in completions.iter() {
+            self.queues[completion.queue_index].add_used(
+                mem,
+                completion.head_index,
+                completion.len,
+            );
+        }
+        !completions.is_empty()
+    }
+
+    pub(crate) fn has_io_engine(&self) -> bool {
+        self.io_engine.is_some()
+    }
+
+    /// Starts the I/O engine, so a slow disk doesn't block the thread handling
+    /// the events of the device. Requests are executed synchronously if it
+    /// can't be started.
+    fn start_io_engine(&mut self, mem: &GuestMemoryMmap) {
+        match self
+            .completion_evt
+            .try_clone()
+            .and_then(|evt| new_io_engine(self.disk.clone(), mem.clone(), self.queues.len(), evt))
+        {
+            Ok(engine) => self.io_engine = Some(engine),
+            Err(e) => warn!(
+                "Failed to start asynchronous block I/O, requests will be executed synchronously: {}",
+                e
+            ),
+        }
+    }
+
     pub(crate) fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -331,20 +664,57 @@ impl Block {
 
     /// Update the backing file and the config space of the block device.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> io::Result<()> {
-        let disk_properties =
-            DiskProperties::new(disk_image_path, self.is_read_only(), self.cache_type())?;
-        self.disk = disk_properties;
-        self.config_space = self.disk.virtio_block_config_space();
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            self.disk.image_type(),
+            self.disk.overlay().cloned(),
+            self.disk.verity().cloned(),
+            self.disk.identity().clone(),
+            self.is_read_only(),
+            self.cache_type(),
+        )?;
+
+        // In-flight requests must complete on the disk they were submitted to.
+        if let Some(engine) = self.io_engine.take() {
+            let completions = engine.shutdown();
+            if self.add_completions(completions) {
+                let _ = self.signal_used_queue();
+            }
+        }
+
+        self.disk = Arc::new(disk_properties);
+        self.config_space = self
+            .disk
+            .virtio_block_config_space(self.queues.len() as u16);
+        if let DeviceState::Activated(ref mem) = self.device_state {
+            let mem = mem.clone();
+            self.start_io_engine(&mem);
+        }
 
         // Kick the driver to pick up the changes.
+        self.signal_config_change()
+    }
+
+    /// Picks up a new size of the backing file after it was resized on the
+    /// host, and tells the driver about the new capacity. Returns the new
+    /// size in bytes.
+    pub fn resize_disk(&mut self) -> io::Result<u64> {
+        let disk_size = self.disk.refresh_size()?;
+        self.config_space = self
+            .disk
+            .virtio_block_config_space(self.queues.len() as u16);
+        self.signal_config_change()?;
+        Ok(disk_size)
+    }
+
+    fn signal_config_change(&self) -> io::Result<()> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
         if let Some(intc) = &self.intc {
             intc.lock().unwrap().set_irq(self.irq_line.unwrap());
         } else {
-            self.interrupt_evt.write(1).unwrap();
+            self.interrupt_evt.write(1)?;
         }
-
         Ok(())
     }
 
@@ -362,6 +732,11 @@ impl Block {
         self.partuuid.as_ref()
     }
 
+    /// Provides the backend the requests of the guest are executed on.
+    pub fn disk(&self) -> &dyn DiskBackend {
+        self.disk.disk()
+    }
+
     /// Specifies if this block device is read only.
     pub fn is_read_only(&self) -> bool {
         self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
@@ -375,6 +750,11 @@ impl Block {
     pub fn cache_type(&self) -> CacheType {
         self.disk.cache_type()
     }
+
+    /// Replaces the bandwidth and operations limits of this block device.
+    pub fn update_rate_limiter(&mut self, config: RateLimiterConfig) {
+        self.rate_limiter.update(config);
+    }
 }
 
 impl VirtioDevice for Block {
@@ -455,7 +835,167 @@ impl VirtioDevice for Block {
             error!("Block: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
         }
+        self.start_io_engine(&mem);
         self.device_state = DeviceState::Activated(mem);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::block::request::tests::guest_memory;
+    use crate::virtio::block::test_utils::{default_block, default_block_with_path};
+    use utils::tempfile::TempFile;
+
+    fn block_with_identity(identity: DiskIdentity) -> io::Result<Block> {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x4000).unwrap();
+        Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            ImageType::Raw,
+            None,
+            None,
+            false,
+            false,
+            1,
+            identity,
+        )
+    }
+
+    fn read_u32(config: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(config[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_single_queue_io_engine() {
+        // Even a single queue doesn't execute requests on the event thread.
+        let mut block = default_block();
+        assert_eq!(block.queues.len(), 1);
+        assert!(!block.has_io_engine());
+        block.activate(guest_memory()).unwrap();
+        assert!(block.has_io_engine());
+    }
+
+    #[test]
+    fn test_config_space_layout() {
+        let block = default_block();
+        let config = &block.config_space;
+        assert_eq!(config.len(), 60);
+
+        assert_eq!(u16::from_le_bytes([config[34], config[35]]), 1);
+        assert_eq!(read_u32(config, 36), MAX_DISCARD_SECTORS);
+        assert_eq!(read_u32(config, 40), MAX_DISCARD_SEG);
+        assert_eq!(read_u32(config, 44), 1);
+        assert_eq!(read_u32(config, 48), MAX_WRITE_ZEROES_SECTORS);
+        assert_eq!(read_u32(config, 52), MAX_WRITE_ZEROES_SEG);
+        assert_eq!(config[56], 1);
+    }
+
+    #[test]
+    fn test_validate_identity() {
+        let valid = |identity: DiskIdentity| identity.validate().is_ok();
+
+        let serial = |len: usize| DiskIdentity {
+            serial: Some("s".repeat(len)),
+            ..Default::default()
+        };
+        assert!(valid(serial(VIRTIO_BLK_ID_BYTES as usize)));
+        assert!(!valid(serial(VIRTIO_BLK_ID_BYTES as usize + 1)));
+
+        let block_sizes = |logical_block_size, physical_block_size| DiskIdentity {
+            logical_block_size,
+            physical_block_size,
+            ..Default::default()
+        };
+        assert!(valid(block_sizes(0, 0)));
+        assert!(valid(block_sizes(512, 4096)));
+        assert!(valid(block_sizes(0, 4096)));
+        assert!(valid(block_sizes(MAX_BLOCK_SIZE, 0)));
+        assert!(!valid(block_sizes(256, 0)));
+        assert!(!valid(block_sizes(3072, 0)));
+        assert!(!valid(block_sizes(2 * MAX_BLOCK_SIZE, 0)));
+        // The physical block size is never smaller than the logical one.
+        assert!(!valid(block_sizes(4096, 512)));
+        assert!(!valid(block_sizes(512, 6144)));
+        assert!(!valid(block_sizes(512, 2 * MAX_BLOCK_SIZE)));
+
+        let seg_max = |seg_max| DiskIdentity {
+            seg_max,
+            ..Default::default()
+        };
+        assert!(valid(seg_max(u32::from(QUEUE_SIZE) - 2)));
+        assert!(!valid(seg_max(u32::from(QUEUE_SIZE) - 1)));
+
+        // Invalid identities are rejected when creating the device.
+        let res = block_with_identity(serial(VIRTIO_BLK_ID_BYTES as usize + 1));
+        assert_eq!(res.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_identity_config_space() {
+        // Without an identity, none of it is advertised.
+        let block = default_block();
+        let features = block.avail_features();
+        assert_eq!(features & (1 << VIRTIO_BLK_F_BLK_SIZE), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_TOPOLOGY), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_SEG_MAX), 0);
+        assert_eq!(read_u32(&block.config_space, 20), 512);
+        assert_eq!(
+            block.disk.max_data_len(),
+            u64::from(QUEUE_SIZE - 2) * u64::from(MAX_SEGMENT_SIZE)
+        );
+
+        let block = block_with_identity(DiskIdentity {
+            serial: Some("disk-serial".to_string()),
+            logical_block_size: 4096,
+            physical_block_size: 16384,
+            seg_max: 32,
+        })
+        .unwrap();
+        let features = block.avail_features();
+        assert_ne!(features & (1 << VIRTIO_BLK_F_BLK_SIZE), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_TOPOLOGY), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_SEG_MAX), 0);
+
+        let config = &block.config_space;
+        assert_eq!(read_u32(config, 12), 32);
+        assert_eq!(read_u32(config, 20), 4096);
+        // The physical block is 2^2 logical blocks.
+        assert_eq!(config[24], 2);
+        // Discards are aligned to logical blocks, in sectors.
+        assert_eq!(read_u32(config, 44), 8);
+        assert_eq!(block.disk.max_data_len(), 32 * u64::from(MAX_SEGMENT_SIZE));
+
+        let mut serial = b"disk-serial".to_vec();
+        serial.resize(VIRTIO_BLK_ID_BYTES as usize, 0);
+        assert_eq!(block.disk.image_id(), serial);
+    }
+
+    #[test]
+    fn test_resize_disk() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut block = default_block_with_path(f.as_path().to_str().unwrap().to_string());
+        let capacity = |block: &Block| {
+            let mut capacity = [0u8; 8];
+            block.read_config(0, &mut capacity);
+            u64::from_le_bytes(capacity)
+        };
+        assert_eq!(capacity(&block), 8);
+
+        f.as_file().set_len(0x3000).unwrap();
+        assert_eq!(block.resize_disk().unwrap(), 0x3000);
+        assert_eq!(capacity(&block), 24);
+        assert_eq!(block.disk.nsectors(), 24);
+        // The driver is told to read the config space again.
+        assert_eq!(
+            block.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG as usize
+        );
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+    }
+}
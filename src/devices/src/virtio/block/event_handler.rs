@@ -60,12 +60,19 @@ impl Subscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
+            let queue_index = self
+                .queue_evts
+                .iter()
+                .position(|evt| evt.as_raw_fd() == source);
+            let completion_fd = self.completion_evt.as_raw_fd();
+            let rate_limiter_fd = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
+                _ if queue_index.is_some() => self.process_queue_event(queue_index.unwrap()),
+                _ if completion_fd == source => self.process_completion_event(),
+                _ if rate_limiter_fd == source => self.process_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(evmgr),
                 _ => warn!("Block: Spurious event received: {:?}", source),
             }
@@ -83,10 +90,22 @@ impl Subscriber for Block {
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
         if self.is_activated() {
-            vec![EpollEvent::new(
+            let mut events: Vec<EpollEvent> = self
+                .queue_evts
+                .iter()
+                .map(|evt| EpollEvent::new(EventSet::IN, evt.as_raw_fd() as u64))
+                .collect();
+            events.push(EpollEvent::new(
                 EventSet::IN,
-                self.queue_evts[0].as_raw_fd() as u64,
-            )]
+                self.rate_limiter.as_raw_fd() as u64,
+            ));
+            if self.has_io_engine() {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    self.completion_evt.as_raw_fd() as u64,
+                ));
+            }
+            events
         } else {
             vec![EpollEvent::new(
                 EventSet::IN,
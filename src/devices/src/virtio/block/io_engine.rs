@@ -0,0 +1,617 @@
+//! Asynchronous execution of block requests, keeping slow disks from
+//! blocking the thread handling the events of the device.
+//!
+//! Requests are submitted to io_uring when the disk backend maps guest
+//! sectors directly to a host file, and to a pool of worker threads
+//! otherwise. The requests io_uring can't take as they are, such as discards
+//! or unaligned transfers, go to worker threads as well. Either way, requests may complete out of order, and the status
+//! byte is written to guest memory before the completion is reported.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{unbounded, Sender};
+use io_uring::{opcode, squeue, types, IoUring};
+use log::{error, warn};
+use utils::eventfd::EventFd;
+use vm_memory::{Address, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+use super::device::{CacheType, DiskProperties};
+use super::request::{ExecuteError, Request, RequestType};
+use super::QUEUE_SIZE;
+
+/// A request that finished executing.
+pub(crate) struct Completion {
+    pub queue_index: usize,
+    pub head_index: u16,
+    /// Number of bytes to report as used to the driver.
+    pub len: u32,
+}
+
+pub(crate) trait IoEngine: Send {
+    /// Starts executing `request`, popped from queue `queue_index` with
+    /// descriptor head `head_index`.
+    fn submit(&mut self, queue_index: usize, head_index: u16, request: Request);
+
+    /// Makes sure every request submitted so far is being executed.
+    fn commit(&mut self) {}
+
+    /// Returns the requests that completed since the last call.
+    fn drain_completions(&mut self) -> Vec<Completion>;
+
+    /// Waits for every in-flight request and returns their completions.
+    fn shutdown(self: Box<Self>) -> Vec<Completion>;
+}
+
+/// Creates the engine for `disk`, with room for `num_queues` full queues of
+/// requests in flight. `completion_evt` is signaled when requests complete.
+pub(crate) fn new_io_engine(
+    disk: Arc<DiskProperties>,
+    mem: GuestMemoryMmap,
+    num_queues: usize,
+    completion_evt: EventFd,
+) -> io::Result<Box<dyn IoEngine>> {
+    if let Some(fd) = disk.disk().raw_fd() {
+        match IoUringEngine::new(fd, disk.clone(), mem.clone(), num_queues, &completion_evt) {
+            Ok(engine) => return Ok(Box::new(engine)),
+            Err(e) => warn!(
+                "Failed to set up io_uring, using worker threads instead: {}",
+                e
+            ),
+        }
+    }
+    Ok(Box::new(ThreadPoolEngine::new(
+        disk,
+        mem,
+        num_queues,
+        completion_evt,
+    )?))
+}
+
+struct Inflight {
+    queue_index: usize,
+    head_index: u16,
+    request: Request,
+    /// Disk offset at which a read or write starts.
+    offset: u64,
+    /// Bytes transferred so far, as reads and writes may complete partially.
+    done: u32,
+    /// Host buffers of the remaining part of a read or write, which must stay
+    /// alive until the kernel completes it.
+    iovecs: Vec<libc::iovec>,
+}
+
+// Safe because the buffers `iovecs` point to are guest memory, which is
+// shared with the guest anyway and outlives the engine.
+unsafe impl Send for Inflight {}
+
+struct IoUringEngine {
+    ring: IoUring,
+    fd: RawFd,
+    align: u64,
+    disk: Arc<DiskProperties>,
+    // Keeps guest memory mapped while the kernel accesses it.
+    mem: GuestMemoryMmap,
+    inflight: HashMap<u64, Inflight>,
+    next_user_data: u64,
+    completed: Vec<Completion>,
+    /// Executes the requests that can't be handed to the kernel.
+    fallback: ThreadPoolEngine,
+}
+
+impl IoUringEngine {
+    fn new(
+        fd: RawFd,
+        disk: Arc<DiskProperties>,
+        mem: GuestMemoryMmap,
+        num_queues: usize,
+        completion_evt: &EventFd,
+    ) -> io::Result<Self> {
+        let entries = (QUEUE_SIZE as usize * num_queues) as u32;
+        let ring = IoUring::new(entries)?;
+        ring.submitter()
+            .register_eventfd(completion_evt.as_raw_fd())?;
+        let fallback = ThreadPoolEngine::new(
+            disk.clone(),
+            mem.clone(),
+            num_queues,
+            completion_evt.try_clone()?,
+        )?;
+
+        Ok(IoUringEngine {
+            ring,
+            fd,
+            align: disk.disk().io_alignment() as u64,
+            disk,
+            mem,
+            inflight: HashMap::new(),
+            next_user_data: 0,
+            completed: Vec::new(),
+            fallback,
+        })
+    }
+
+    fn complete(
+        &mut self,
+        queue_index: usize,
+        head_index: u16,
+        request: &Request,
+        result: Result<u32, ExecuteError>,
+    ) {
+        let len = request.write_status(&self.mem, result);
+        self.completed.push(Completion {
+            queue_index,
+            head_index,
+            len,
+        });
+    }
+
+    /// Collects the host buffers of the remaining part of `inflight`, or
+    /// returns `None` if a segment isn't contiguous in host memory or isn't
+    /// aligned as required by the disk.
+    fn iovecs(&self, inflight: &Inflight) -> Option<Vec<libc::iovec>> {
+        let mut iovecs = Vec::with_capacity(inflight.request.data_segments().len());
+        let mut skip = inflight.done;
+        for &(addr, len) in inflight.request.data_segments() {
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let addr = addr.checked_add(u64::from(skip))?;
+            let len = len - skip;
+            skip = 0;
+            let slice = self.mem.get_slice(addr, len as usize).ok()?;
+            let ptr = slice.ptr_guard_mut().as_ptr();
+            if !(ptr as u64 | u64::from(len)).is_multiple_of(self.align) {
+                return None;
+            }
+            iovecs.push(libc::iovec {
+                iov_base: ptr as *mut libc::c_void,
+                iov_len: len as usize,
+            });
+        }
+        Some(iovecs)
+    }
+
+    /// Builds the submission queue entry for the remaining part of `inflight`,
+    /// or returns `None` if it can't be handed to the kernel as is.
+    fn entry(&self, inflight: &mut Inflight) -> Option<squeue::Entry> {
+        let fd = types::Fd(self.fd);
+        let entry = match inflight.request.request_type {
+            RequestType::In | RequestType::Out => {
+                let offset = inflight.offset + u64::from(inflight.done);
+                if !offset.is_multiple_of(self.align) {
+                    return None;
+                }
+                inflight.iovecs = self.iovecs(inflight)?;
+                let iovecs = inflight.iovecs.as_ptr();
+                let count = inflight.iovecs.len() as u32;
+                if inflight.request.request_type == RequestType::In {
+                    opcode::Readv::new(fd, iovecs, count).offset(offset).build()
+                } else {
+                    opcode::Writev::new(fd, iovecs, count)
+                        .offset(offset)
+                        .build()
+                }
+            }
+            _ => opcode::Fsync::new(fd).build(),
+        };
+        Some(entry)
+    }
+
+    fn queue(&mut self, mut inflight: Inflight) {
+        let entry = match self.entry(&mut inflight) {
+            Some(entry) => entry,
+            None => {
+                self.fallback
+                    .submit(inflight.queue_index, inflight.head_index, inflight.request);
+                return;
+            }
+        };
+
+        let user_data = self.next_user_data;
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        let entry = entry.user_data(user_data);
+        // The ring has room for every descriptor of every queue, so it should
+        // never fill up, but make room by submitting just in case.
+        // Safe because the buffer is guest memory, which stays mapped for as
+        // long as `self.mem` is alive, and we wait for every in-flight request
+        // before dropping it.
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            if let Err(e) = self.ring.submit() {
+                error!("Failed to submit block requests to io_uring: {}", e);
+                self.fallback
+                    .submit(inflight.queue_index, inflight.head_index, inflight.request);
+                return;
+            }
+        }
+        self.inflight.insert(user_data, inflight);
+    }
+
+    fn handle_cqe(&mut self, user_data: u64, res: i32) {
+        let mut inflight = match self.inflight.remove(&user_data) {
+            Some(inflight) => inflight,
+            None => {
+                error!("Unexpected io_uring completion: {}", user_data);
+                return;
+            }
+        };
+
+        let request_type = inflight.request.request_type;
+        let result = if res < 0 {
+            Err(io::Error::from_raw_os_error(-res))
+        } else if request_type == RequestType::In || request_type == RequestType::Out {
+            inflight.done += res as u32;
+            if inflight.done < inflight.request.data_len {
+                if res == 0 {
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                } else {
+                    self.queue(inflight);
+                    return;
+                }
+            } else {
+                Ok(())
+            }
+        } else {
+            Ok(())
+        };
+
+        let result = match (request_type, result) {
+            (RequestType::In, Ok(())) => Ok(inflight.request.data_len),
+            (_, Ok(())) => Ok(0),
+            (RequestType::In, Err(e)) => Err(ExecuteError::Read(GuestMemoryError::IOError(e))),
+            (RequestType::Out, Err(e)) => Err(ExecuteError::Write(GuestMemoryError::IOError(e))),
+            (_, Err(e)) => Err(ExecuteError::SyncAll(e)),
+        };
+        self.complete(
+            inflight.queue_index,
+            inflight.head_index,
+            &inflight.request,
+            result,
+        );
+    }
+
+    fn reap(&mut self) {
+        let cqes: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, res) in cqes {
+            self.handle_cqe(user_data, res);
+        }
+    }
+
+    fn wait_idle(&mut self) {
+        while !self.inflight.is_empty() {
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                error!("Failed to wait for in-flight block requests: {}", e);
+                return;
+            }
+            self.reap();
+        }
+    }
+}
+
+impl IoEngine for IoUringEngine {
+    fn submit(&mut self, queue_index: usize, head_index: u16, request: Request) {
+        let offset = match request.request_type {
+            RequestType::In | RequestType::Out => match request.data_offset(&self.disk) {
+                Ok(offset) => offset,
+                Err(e) => {
+                    self.complete(queue_index, head_index, &request, Err(e));
+                    return;
+                }
+            },
+            RequestType::Flush if self.disk.cache_type() != CacheType::Unsafe => 0,
+            // Discards and zeroing may take a while on some filesystems, so
+            // nothing runs on the thread handling the events of the device.
+            _ => {
+                self.fallback.submit(queue_index, head_index, request);
+                return;
+            }
+        };
+
+        self.queue(Inflight {
+            queue_index,
+            head_index,
+            request,
+            offset,
+            done: 0,
+            iovecs: Vec::new(),
+        });
+    }
+
+    fn commit(&mut self) {
+        if let Err(e) = self.ring.submit() {
+            error!("Failed to submit block requests to io_uring: {}", e);
+        }
+    }
+
+    fn drain_completions(&mut self) -> Vec<Completion> {
+        self.reap();
+        // Partial transfers may have been queued again.
+        self.commit();
+        let mut completed = std::mem::take(&mut self.completed);
+        completed.extend(self.fallback.drain_completions());
+        completed
+    }
+
+    fn shutdown(mut self: Box<Self>) -> Vec<Completion> {
+        self.wait_idle();
+        // Requests requeued after a partial transfer may have gone to the
+        // workers, so they are waited for last.
+        self.fallback.join_workers();
+        let mut completed = std::mem::take(&mut self.completed);
+        completed.extend(self.fallback.drain_completions());
+        completed
+    }
+}
+
+impl Drop for IoUringEngine {
+    fn drop(&mut self) {
+        self.wait_idle();
+    }
+}
+
+struct Job {
+    queue_index: usize,
+    head_index: u16,
+    request: Request,
+}
+
+struct ThreadPoolEngine {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    completed: Arc<Mutex<Vec<Completion>>>,
+}
+
+impl ThreadPoolEngine {
+    fn new(
+        disk: Arc<DiskProperties>,
+        mem: GuestMemoryMmap,
+        num_workers: usize,
+        completion_evt: EventFd,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = unbounded::<Job>();
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = ThreadPoolEngine {
+            sender: Some(sender),
+            workers: Vec::with_capacity(num_workers),
+            completed,
+        };
+
+        for i in 0..num_workers {
+            let receiver = receiver.clone();
+            let disk = disk.clone();
+            let mem = mem.clone();
+            let completed = engine.completed.clone();
+            let completion_evt = completion_evt.try_clone()?;
+            let worker = thread::Builder::new()
+                .name(format!("block worker {}", i))
+                .spawn(move || {
+                    for job in receiver.iter() {
+                        let result = job.request.execute(&disk, &mem);
+                        let len = job.request.write_status(&mem, result);
+                        completed.lock().unwrap().push(Completion {
+                            queue_index: job.queue_index,
+                            head_index: job.head_index,
+                            len,
+                        });
+                        if let Err(e) = completion_evt.write(1) {
+                            error!("Failed to signal block request completion: {:?}", e);
+                        }
+                    }
+                })?;
+            engine.workers.push(worker);
+        }
+
+        Ok(engine)
+    }
+
+    fn join_workers(&mut self) {
+        // Workers exit once the channel is closed and drained.
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("Block worker thread panicked");
+            }
+        }
+    }
+}
+
+impl IoEngine for ThreadPoolEngine {
+    fn submit(&mut self, queue_index: usize, head_index: u16, request: Request) {
+        let job = Job {
+            queue_index,
+            head_index,
+            request,
+        };
+        if let Some(sender) = &self.sender {
+            if sender.send(job).is_err() {
+                error!("Block worker threads exited, dropping request");
+            }
+        }
+    }
+
+    fn drain_completions(&mut self) -> Vec<Completion> {
+        std::mem::take(&mut *self.completed.lock().unwrap())
+    }
+
+    fn shutdown(mut self: Box<Self>) -> Vec<Completion> {
+        self.join_workers();
+        self.drain_completions()
+    }
+}
+
+impl Drop for ThreadPoolEngine {
+    fn drop(&mut self) {
+        self.join_workers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+    use std::time::{Duration, Instant};
+
+    use utils::tempfile::TempFile;
+    use virtio_bindings::virtio_blk::{
+        VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+        VIRTIO_BLK_T_OUT,
+    };
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::*;
+    use crate::virtio::block::request::tests::{
+        disk_properties, guest_memory, read_guest, request, DATA_ADDR, STATUS_ADDR,
+    };
+    use crate::virtio::block::OverlayConfig;
+
+    /// Waits for the completion of the only request in flight.
+    fn wait_completion(engine: &mut dyn IoEngine) -> Completion {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut completions = engine.drain_completions();
+            if let Some(completion) = completions.pop() {
+                assert!(completions.is_empty());
+                return completion;
+            }
+            assert!(Instant::now() < deadline, "request didn't complete");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Writes through `engine`, then reads back what was written.
+    fn check_round_trip(engine: &mut dyn IoEngine, mem: &GuestMemoryMmap) {
+        mem.write_slice(&[0xcc; 0x400], GuestAddress(DATA_ADDR))
+            .unwrap();
+        engine.submit(
+            0,
+            3,
+            request(mem, VIRTIO_BLK_T_OUT, 1, &[(DATA_ADDR, 0x400)]),
+        );
+        engine.commit();
+        let completion = wait_completion(engine);
+        assert_eq!((completion.queue_index, completion.head_index), (0, 3));
+        assert_eq!(completion.len, 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        mem.write_slice(&[0; 0x400], GuestAddress(DATA_ADDR))
+            .unwrap();
+        mem.write_obj(0xffu8, GuestAddress(STATUS_ADDR)).unwrap();
+        engine.submit(
+            1,
+            5,
+            request(mem, VIRTIO_BLK_T_IN, 1, &[(DATA_ADDR, 0x400)]),
+        );
+        engine.commit();
+        let completion = wait_completion(engine);
+        assert_eq!((completion.queue_index, completion.head_index), (1, 5));
+        assert_eq!(completion.len, 0x401);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert_eq!(read_guest(mem, DATA_ADDR, 0x400), vec![0xcc; 0x400]);
+    }
+
+    #[test]
+    fn test_io_uring_round_trip() {
+        let file = TempFile::new().unwrap();
+        let disk = Arc::new(disk_properties(&file, None));
+        let mem = guest_memory();
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let fd = disk.disk().raw_fd().unwrap();
+        let mut engine = match IoUringEngine::new(fd, disk.clone(), mem.clone(), 2, &completion_evt)
+        {
+            Ok(engine) => engine,
+            // The host may not allow io_uring, which the fallback is tested for.
+            Err(e) => {
+                eprintln!("io_uring isn't available, skipping: {}", e);
+                return;
+            }
+        };
+        check_round_trip(&mut engine, &mem);
+
+        let mut buf = vec![0u8; 0x400];
+        file.as_file().read_exact_at(&mut buf, 0x200).unwrap();
+        assert_eq!(buf, vec![0xcc; 0x400]);
+
+        // Requests io_uring doesn't take go to worker threads.
+        mem.write_obj(0xffu8, GuestAddress(STATUS_ADDR)).unwrap();
+        engine.submit(
+            0,
+            9,
+            request(
+                &mem,
+                VIRTIO_BLK_T_GET_ID,
+                0,
+                &[(DATA_ADDR, VIRTIO_BLK_ID_BYTES)],
+            ),
+        );
+        let completion = wait_completion(&mut engine);
+        assert_eq!(completion.head_index, 9);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        engine.submit(
+            0,
+            11,
+            request(&mem, VIRTIO_BLK_T_IN, 1, &[(DATA_ADDR + 1, 0x200)]),
+        );
+        engine.commit();
+        let completion = wait_completion(&mut engine);
+        assert_eq!((completion.head_index, completion.len), (11, 0x201));
+        assert_eq!(read_guest(&mem, DATA_ADDR + 1, 0x200), vec![0xcc; 0x200]);
+        assert!(Box::new(engine).shutdown().is_empty());
+    }
+
+    #[test]
+    fn test_thread_pool_round_trip() {
+        let file = TempFile::new().unwrap();
+        let disk = Arc::new(disk_properties(&file, None));
+        let mem = guest_memory();
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut engine =
+            ThreadPoolEngine::new(disk.clone(), mem.clone(), 1, completion_evt).unwrap();
+        check_round_trip(&mut engine, &mem);
+
+        // Requests in flight complete before the engine is gone.
+        engine.submit(
+            0,
+            7,
+            request(&mem, VIRTIO_BLK_T_IN, 1, &[(DATA_ADDR, 0x400)]),
+        );
+        let completions = Box::new(engine).shutdown();
+        assert_eq!(completions.len(), 1);
+        assert_eq!(completions[0].head_index, 7);
+    }
+
+    #[test]
+    fn test_fallback_without_raw_fd() {
+        // An overlay doesn't map guest sectors to a host file, so requests
+        // can't go to io_uring and worker threads are used instead.
+        let file = TempFile::new().unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay = OverlayConfig {
+            path: overlay_file.as_path().to_str().unwrap().to_string(),
+            discard_on_exit: false,
+        };
+        let disk = Arc::new(disk_properties(&file, Some(overlay)));
+        assert!(disk.disk().raw_fd().is_none());
+        let mem = guest_memory();
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut engine = new_io_engine(disk.clone(), mem.clone(), 1, completion_evt).unwrap();
+        check_round_trip(engine.as_mut(), &mem);
+        assert!(engine.shutdown().is_empty());
+    }
+}
@@ -1,23 +1,35 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod backend;
 pub mod device;
 pub mod event_handler;
+mod io_engine;
 pub mod request;
 pub mod test_utils;
 
-pub use self::device::{Block, CacheType};
+pub use self::backend::{ImageType, OverlayConfig, VerityConfig};
+pub use self::device::{Block, CacheType, DiskIdentity};
 pub use self::event_handler::*;
 pub use self::request::*;
 
 use vm_memory::GuestMemoryError;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+// Number of request queues exposed by default, and the most we accept.
+pub const DEFAULT_NUM_QUEUES: u16 = 1;
+pub const MAX_NUM_QUEUES: u16 = 16;
+// Largest data segment a request may have, advertised as size_max.
+pub const MAX_SEGMENT_SIZE: u32 = 1 << 20;
+// Largest logical or physical block size we let disks report.
+pub const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+// Limits advertised to the guest for discard and write zeroes requests.
+pub const MAX_DISCARD_SECTORS: u32 = u32::MAX;
+pub const MAX_DISCARD_SEG: u32 = 32;
+pub const MAX_WRITE_ZEROES_SECTORS: u32 = u32::MAX;
+pub const MAX_WRITE_ZEROES_SEG: u32 = 32;
 
 #[derive(Debug)]
 pub enum Error {
@@ -29,6 +41,8 @@ pub enum Error {
     GetFileMetadata(std::io::Error),
     /// Guest gave us bad memory addresses.
     GuestMemory(GuestMemoryError),
+    /// Guest gave us a data buffer whose length doesn't match the request type.
+    InvalidDataLength,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -6,24 +6,33 @@
 // found in the THIRD-PARTY file.
 
 use std::convert::From;
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io;
+use std::os::unix::io::RawFd;
 use std::result;
 
+use log::error;
+
 use virtio_bindings::virtio_blk::*;
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::bitmap::BitmapSlice;
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, ReadVolatile,
+    VolatileMemoryError, VolatileSlice, WriteVolatile,
+};
 
 use super::super::DescriptorChain;
 use super::device::{CacheType, DiskProperties};
-use super::{Error, SECTOR_SHIFT, SECTOR_SIZE};
+use super::{
+    Error, MAX_DISCARD_SEG, MAX_SEGMENT_SIZE, MAX_WRITE_ZEROES_SEG, SECTOR_SHIFT, SECTOR_SIZE,
+};
 
 #[derive(Debug)]
 pub enum ExecuteError {
     BadRequest(Error),
-    Flush(io::Error),
+    Discard(io::Error),
     Read(GuestMemoryError),
-    Seek(io::Error),
     SyncAll(io::Error),
     Write(GuestMemoryError),
+    WriteZeroes(io::Error),
     Unsupported(u32),
 }
 
@@ -31,11 +40,11 @@ impl ExecuteError {
     pub fn status(&self) -> u32 {
         match *self {
             ExecuteError::BadRequest(_) => VIRTIO_BLK_S_IOERR,
-            ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
-            ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::SyncAll(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
@@ -47,6 +56,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -57,6 +68,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -67,7 +80,8 @@ pub struct Request {
     pub data_len: u32,
     pub status_addr: GuestAddress,
     sector: u64,
-    data_addr: GuestAddress,
+    // Guest buffers making up the data of the request, in order.
+    data_segments: Vec<(GuestAddress, u32)>,
 }
 
 /// The request header represents the mandatory fields of each block device request.
@@ -112,10 +126,86 @@ impl RequestHeader {
     }
 }
 
+/// A single range of a discard or write zeroes request.
+///
+/// The data buffer of those requests is an array of segments, each one containing:
+///   * sector: an u64 value representing the first sector of the range.
+///   * num_sectors: an u32 value representing the number of sectors in the range.
+///   * flags: an u32 value; only the unmap flag is defined, and only for write zeroes.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+/// Reads and writes a host file descriptor at an offset that advances with
+/// each transfer, so guest memory can be accessed directly by the kernel.
+struct FdCursor {
+    fd: RawFd,
+    offset: u64,
+}
+
+impl ReadVolatile for FdCursor {
+    fn read_volatile<B: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<B>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let guard = buf.ptr_guard_mut();
+        // Safe because the slice is valid for writes of its whole length and
+        // we check the return value.
+        let ret = unsafe {
+            libc::pread64(
+                self.fd,
+                guard.as_ptr() as *mut libc::c_void,
+                buf.len(),
+                self.offset as libc::off64_t,
+            )
+        };
+        if ret < 0 {
+            // We don't know whether part of the buffer was written to.
+            buf.bitmap().mark_dirty(0, buf.len());
+            return Err(VolatileMemoryError::IOError(io::Error::last_os_error()));
+        }
+        buf.bitmap().mark_dirty(0, ret as usize);
+        self.offset += ret as u64;
+        Ok(ret as usize)
+    }
+}
+
+impl WriteVolatile for FdCursor {
+    fn write_volatile<B: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<B>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let guard = buf.ptr_guard();
+        // Safe because the slice is valid for reads of its whole length and
+        // we check the return value.
+        let ret = unsafe {
+            libc::pwrite64(
+                self.fd,
+                guard.as_ptr() as *const libc::c_void,
+                buf.len(),
+                self.offset as libc::off64_t,
+            )
+        };
+        if ret < 0 {
+            return Err(VolatileMemoryError::IOError(io::Error::last_os_error()));
+        }
+        self.offset += ret as u64;
+        Ok(ret as usize)
+    }
+}
+
 impl Request {
-    pub fn parse(
+    pub(crate) fn parse(
         avail_desc: &DescriptorChain,
         mem: &GuestMemoryMmap,
+        disk: &DiskProperties,
     ) -> result::Result<Request, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -126,26 +216,24 @@ impl Request {
         let mut req = Request {
             request_type: RequestType::from(request_header.request_type),
             sector: request_header.sector,
-            data_addr: GuestAddress(0),
+            data_segments: Vec::new(),
             data_len: 0,
             status_addr: GuestAddress(0),
         };
 
-        let data_desc;
-        let status_desc;
-        let desc = avail_desc
+        let mut desc = avail_desc
             .next_descriptor()
             .ok_or(Error::DescriptorChainTooShort)?;
 
-        if !desc.has_next() {
-            status_desc = desc;
-            // Only flush requests are allowed to skip the data descriptor.
-            if req.request_type != RequestType::Flush {
-                return Err(Error::DescriptorChainTooShort);
-            }
-        } else {
-            data_desc = desc;
-            status_desc = data_desc
+        // Only flush requests are allowed to skip the data descriptor.
+        if !desc.has_next() && req.request_type != RequestType::Flush {
+            return Err(Error::DescriptorChainTooShort);
+        }
+
+        // Every descriptor but the last one holds data.
+        while desc.has_next() {
+            let data_desc = desc;
+            desc = data_desc
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
@@ -158,10 +246,27 @@ impl Request {
             if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
 
-            req.data_addr = data_desc.addr;
-            req.data_len = data_desc.len;
+            // The guest was told how many segments it may use and how large
+            // they may be.
+            if req.data_segments.len() as u32 >= disk.seg_max() || data_desc.len > MAX_SEGMENT_SIZE
+            {
+                return Err(Error::InvalidDataLength);
+            }
+            req.data_len = req
+                .data_len
+                .checked_add(data_desc.len)
+                .filter(|&len| u64::from(len) <= disk.max_data_len())
+                .ok_or(Error::InvalidDataLength)?;
+            req.data_segments.push((data_desc.addr, data_desc.len));
         }
+        let status_desc = desc;
 
         // The status MUST always be writable.
         if !status_desc.is_write_only() {
@@ -177,11 +282,161 @@ impl Request {
         Ok(req)
     }
 
-    pub(crate) fn execute(
+    /// Guest buffers making up the data of this request.
+    pub(crate) fn data_segments(&self) -> &[(GuestAddress, u32)] {
+        &self.data_segments
+    }
+
+    /// Returns the host file descriptor the data of this request can be
+    /// transferred on straight from and to guest memory, starting at disk
+    /// offset `offset`, if the backend has one and the buffers meet its
+    /// alignment requirements.
+    fn direct_fd(
         &self,
-        disk: &mut DiskProperties,
+        disk: &DiskProperties,
         mem: &GuestMemoryMmap,
-    ) -> result::Result<u32, ExecuteError> {
+        offset: u64,
+    ) -> Option<RawFd> {
+        let fd = disk.disk().raw_fd()?;
+        let align = disk.disk().io_alignment() as u64;
+        if align == 1 {
+            return Some(fd);
+        }
+        if !offset.is_multiple_of(align) {
+            return None;
+        }
+        for &(addr, len) in self.data_segments.iter() {
+            let slice = mem.get_slice(addr, len as usize).ok()?;
+            let ptr = slice.ptr_guard().as_ptr();
+            if !(ptr as u64 | u64::from(len)).is_multiple_of(align) {
+                return None;
+            }
+        }
+        Some(fd)
+    }
+
+    /// Allocates the buffer the data of this request goes through when it
+    /// can't be transferred directly, refusing requests larger than the guest
+    /// was allowed to make.
+    fn bounce_buf(&self, disk: &DiskProperties) -> result::Result<Vec<u8>, ExecuteError> {
+        if u64::from(self.data_len) > disk.max_data_len() {
+            return Err(ExecuteError::BadRequest(Error::InvalidDataLength));
+        }
+        Ok(vec![0u8; self.data_len as usize])
+    }
+
+    /// Reads the data of this request from `fd` straight into guest memory.
+    fn read_direct(
+        &self,
+        mem: &GuestMemoryMmap,
+        fd: RawFd,
+        offset: u64,
+    ) -> Result<(), GuestMemoryError> {
+        let mut cursor = FdCursor { fd, offset };
+        for &(addr, len) in self.data_segments.iter() {
+            mem.read_exact_volatile_from(addr, &mut cursor, len as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the data of this request to `fd` straight from guest memory.
+    fn write_direct(
+        &self,
+        mem: &GuestMemoryMmap,
+        fd: RawFd,
+        offset: u64,
+    ) -> Result<(), GuestMemoryError> {
+        let mut cursor = FdCursor { fd, offset };
+        for &(addr, len) in self.data_segments.iter() {
+            mem.write_all_volatile_to(addr, &mut cursor, len as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the data buffers of this request from guest memory into `buf`.
+    fn read_data(&self, mem: &GuestMemoryMmap, buf: &mut [u8]) -> Result<(), GuestMemoryError> {
+        let mut pos = 0;
+        for &(addr, len) in self.data_segments.iter() {
+            let len = std::cmp::min(len as usize, buf.len() - pos);
+            mem.read_slice(&mut buf[pos..pos + len], addr)?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Copies `buf` to the data buffers of this request in guest memory.
+    fn write_data(&self, mem: &GuestMemoryMmap, buf: &[u8]) -> Result<(), GuestMemoryError> {
+        let mut pos = 0;
+        for &(addr, len) in self.data_segments.iter() {
+            let len = std::cmp::min(len as usize, buf.len() - pos);
+            mem.write_slice(&buf[pos..pos + len], addr)
+                .map_err(|e| match e {
+                    // Report how much of the whole buffer was written.
+                    GuestMemoryError::PartialBuffer {
+                        completed,
+                        expected: _,
+                    } => GuestMemoryError::PartialBuffer {
+                        completed: pos + completed,
+                        expected: buf.len(),
+                    },
+                    e => e,
+                })?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Reads the segments of a discard or write zeroes request from guest memory.
+    fn read_segments(
+        &self,
+        mem: &GuestMemoryMmap,
+        max_segments: u32,
+    ) -> result::Result<Vec<DiscardWriteZeroesSegment>, ExecuteError> {
+        let segment_size = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+        let num_segments = self.data_len / segment_size;
+        if num_segments == 0
+            || num_segments * segment_size != self.data_len
+            || num_segments > max_segments
+        {
+            return Err(ExecuteError::BadRequest(Error::InvalidDataLength));
+        }
+
+        let mut buf = vec![0u8; self.data_len as usize];
+        self.read_data(mem, &mut buf)
+            .map_err(|e| ExecuteError::BadRequest(Error::GuestMemory(e)))?;
+        let segments = buf
+            .chunks_exact(segment_size as usize)
+            .map(|chunk| {
+                let mut segment = DiscardWriteZeroesSegment::default();
+                segment.as_mut_slice().copy_from_slice(chunk);
+                segment
+            })
+            .collect();
+        Ok(segments)
+    }
+
+    /// Checks that the range of a segment lies within the disk, returning
+    /// its offset and length in bytes.
+    fn segment_range(
+        segment: &DiscardWriteZeroesSegment,
+        disk: &DiskProperties,
+    ) -> result::Result<(u64, u64), ExecuteError> {
+        let top = segment
+            .sector
+            .checked_add(u64::from(segment.num_sectors))
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk.nsectors() {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+        Ok((
+            segment.sector << SECTOR_SHIFT,
+            u64::from(segment.num_sectors) << SECTOR_SHIFT,
+        ))
+    }
+
+    /// Checks that the data buffer of a read or write request lies within the
+    /// disk, returning the offset in bytes where the transfer starts.
+    pub(crate) fn data_offset(&self, disk: &DiskProperties) -> result::Result<u64, ExecuteError> {
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -192,29 +447,51 @@ impl Request {
         if top > disk.nsectors() {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
+        Ok(self.sector << SECTOR_SHIFT)
+    }
 
-        let cache_type = disk.cache_type();
-        let diskfile = disk.file_mut();
-        diskfile
-            .seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
-            .map_err(ExecuteError::Seek)?;
-
+    pub(crate) fn execute(
+        &self,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
         match self.request_type {
-            RequestType::In => mem
-                .read_exact_volatile_from(self.data_addr, diskfile, self.data_len as usize)
-                .map(|_| self.data_len)
-                .map_err(ExecuteError::Read),
-            RequestType::Out => mem
-                .write_all_volatile_to(self.data_addr, diskfile, self.data_len as usize)
-                .map(|_| 0)
-                .map_err(ExecuteError::Write),
+            RequestType::In => {
+                let offset = self.data_offset(disk)?;
+                if let Some(fd) = self.direct_fd(disk, mem, offset) {
+                    return self
+                        .read_direct(mem, fd, offset)
+                        .map(|_| self.data_len)
+                        .map_err(ExecuteError::Read);
+                }
+                let mut buf = self.bounce_buf(disk)?;
+                disk.disk()
+                    .read_exact_at(&mut buf, offset)
+                    .map_err(|e| ExecuteError::Read(GuestMemoryError::IOError(e)))?;
+                self.write_data(mem, &buf)
+                    .map(|_| self.data_len)
+                    .map_err(ExecuteError::Read)
+            }
+            RequestType::Out => {
+                let offset = self.data_offset(disk)?;
+                if let Some(fd) = self.direct_fd(disk, mem, offset) {
+                    return self
+                        .write_direct(mem, fd, offset)
+                        .map(|_| 0)
+                        .map_err(ExecuteError::Write);
+                }
+                let mut buf = self.bounce_buf(disk)?;
+                self.read_data(mem, &mut buf).map_err(ExecuteError::Write)?;
+                disk.disk()
+                    .write_all_at(&buf, offset)
+                    .map(|_| 0)
+                    .map_err(|e| ExecuteError::Write(GuestMemoryError::IOError(e)))
+            }
             RequestType::Flush => {
-                match cache_type {
-                    CacheType::Writeback => {
-                        // flush() first to force any cached data out.
-                        diskfile.flush().map_err(ExecuteError::Flush)?;
+                match disk.cache_type() {
+                    CacheType::Writeback | CacheType::Direct => {
                         // Sync data out to physical media on host.
-                        diskfile.sync_all().map_err(ExecuteError::SyncAll)?;
+                        disk.disk().flush().map_err(ExecuteError::SyncAll)?;
                     }
                     CacheType::Unsafe => {
                         // This is a noop.
@@ -227,11 +504,395 @@ impl Request {
                 if (self.data_len as usize) < disk_id.len() {
                     return Err(ExecuteError::BadRequest(Error::InvalidOffset));
                 }
-                mem.write_slice(disk_id, self.data_addr)
+                self.write_data(mem, disk_id)
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(ExecuteError::Write)
             }
+            RequestType::Discard => {
+                for segment in self.read_segments(mem, MAX_DISCARD_SEG)? {
+                    // No flags are defined for discard requests.
+                    if segment.flags != 0 {
+                        return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD));
+                    }
+                    let (offset, len) = Self::segment_range(&segment, disk)?;
+                    disk.disk()
+                        .discard(offset, len)
+                        .map_err(ExecuteError::Discard)?;
+                }
+                Ok(0)
+            }
+            RequestType::WriteZeroes => {
+                for segment in self.read_segments(mem, MAX_WRITE_ZEROES_SEG)? {
+                    if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                        return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+                    }
+                    let unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                    let (offset, len) = Self::segment_range(&segment, disk)?;
+                    disk.disk()
+                        .write_zeroes(offset, len, unmap)
+                        .map_err(ExecuteError::WriteZeroes)?;
+                }
+                Ok(0)
+            }
             RequestType::Unsupported(t) => Err(ExecuteError::Unsupported(t)),
         }
     }
+
+    /// Writes the status byte for the outcome of executing this request, and
+    /// returns the number of bytes to report as used to the driver.
+    pub(crate) fn write_status(
+        &self,
+        mem: &GuestMemoryMmap,
+        result: result::Result<u32, ExecuteError>,
+    ) -> u32 {
+        let len;
+        let status = match result {
+            Ok(l) => {
+                // Account for the status byte as well.
+                // With a non-faulty driver, we shouldn't get to the point where we
+                // overflow here (since data len must be a multiple of 512 bytes, so
+                // it can't be u32::MAX). In the future, this should be fixed at the
+                // request parsing level, so no data will actually be transferred in
+                // scenarios like this one.
+                if let Some(l) = l.checked_add(1) {
+                    len = l;
+                    VIRTIO_BLK_S_OK
+                } else {
+                    len = l;
+                    VIRTIO_BLK_S_IOERR
+                }
+            }
+            Err(e) => {
+                match e {
+                    ExecuteError::Read(GuestMemoryError::PartialBuffer {
+                        completed,
+                        expected,
+                    }) => {
+                        error!(
+                            "Failed to execute virtio block read request: can only \
+                            write {} of {} bytes.",
+                            completed, expected
+                        );
+                        // This can not overflow since `completed` < data len which is
+                        // an u32.
+                        len = completed as u32 + 1;
+                    }
+                    _ => {
+                        error!("Failed to execute virtio block request: {:?}", e);
+                        // Status byte only.
+                        len = 1;
+                    }
+                };
+                e.status()
+            }
+        };
+
+        if let Err(e) = mem.write_obj(status, self.status_addr) {
+            error!("Failed to write virtio block status: {:?}", e)
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::block::device::DiskIdentity;
+    use crate::virtio::block::{ImageType, OverlayConfig};
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    pub(crate) const DISK_SIZE: u64 = 0x10000;
+    const HEADER_ADDR: u64 = 0x1000;
+    pub(crate) const STATUS_ADDR: u64 = 0x1100;
+    pub(crate) const DATA_ADDR: u64 = 0x2000;
+
+    pub(crate) fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x40000)]).unwrap()
+    }
+
+    pub(crate) fn disk_properties(
+        file: &TempFile,
+        overlay: Option<OverlayConfig>,
+    ) -> DiskProperties {
+        file.as_file().set_len(DISK_SIZE).unwrap();
+        DiskProperties::new(
+            file.as_path().to_str().unwrap().to_string(),
+            ImageType::Raw,
+            overlay,
+            None,
+            DiskIdentity::default(),
+            false,
+            CacheType::Unsafe,
+        )
+        .unwrap()
+    }
+
+    /// Builds a request whose data is split in `segments`, given as guest
+    /// addresses and lengths, and parses it for `disk`.
+    fn parse_request(
+        mem: &GuestMemoryMmap,
+        disk: &DiskProperties,
+        request_type: u32,
+        sector: u64,
+        segments: &[(u64, u32)],
+    ) -> result::Result<Request, Error> {
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        mem.write_obj(
+            RequestHeader::new(request_type, sector),
+            GuestAddress(HEADER_ADDR),
+        )
+        .unwrap();
+        vq.dtable[0].set(HEADER_ADDR, 16, VIRTQ_DESC_F_NEXT, 1);
+        let data_flags = if request_type == VIRTIO_BLK_T_IN || request_type == VIRTIO_BLK_T_GET_ID {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        } else {
+            VIRTQ_DESC_F_NEXT
+        };
+        for (i, &(addr, len)) in segments.iter().enumerate() {
+            vq.dtable[i + 1].set(addr, len, data_flags, i as u16 + 2);
+        }
+        let status = segments.len() + 1;
+        vq.dtable[status].set(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        let mut queue = vq.create_queue();
+        let head = queue.pop(mem).unwrap();
+        Request::parse(&head, mem, disk)
+    }
+
+    /// Like `parse_request`, for a disk without limits of its own.
+    pub(crate) fn request(
+        mem: &GuestMemoryMmap,
+        request_type: u32,
+        sector: u64,
+        segments: &[(u64, u32)],
+    ) -> Request {
+        let file = TempFile::new().unwrap();
+        let disk = disk_properties(&file, None);
+        parse_request(mem, &disk, request_type, sector, segments).unwrap()
+    }
+
+    pub(crate) fn read_guest(mem: &GuestMemoryMmap, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        mem.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+        buf
+    }
+
+    fn check_read_write(disk: &DiskProperties, file: &TempFile) {
+        let mem = guest_memory();
+        let segments = [(DATA_ADDR, 0x200), (DATA_ADDR + 0x1000, 0x400)];
+        mem.write_slice(&[0xaa; 0x200], GuestAddress(DATA_ADDR))
+            .unwrap();
+        mem.write_slice(&[0xbb; 0x400], GuestAddress(DATA_ADDR + 0x1000))
+            .unwrap();
+
+        let req = request(&mem, VIRTIO_BLK_T_OUT, 2, &segments);
+        assert_eq!(req.execute(disk, &mem).unwrap(), 0);
+        // Without an overlay, the data reached the image.
+        if disk.overlay().is_none() {
+            let mut buf = vec![0u8; 0x600];
+            file.as_file().read_exact_at(&mut buf, 0x400).unwrap();
+            assert_eq!(&buf[..0x200], &[0xaa; 0x200]);
+            assert_eq!(&buf[0x200..], &[0xbb; 0x400]);
+        }
+
+        mem.write_slice(&[0; 0x2000], GuestAddress(DATA_ADDR))
+            .unwrap();
+        let segments = [(DATA_ADDR + 0x1000, 0x300), (DATA_ADDR, 0x300)];
+        let req = request(&mem, VIRTIO_BLK_T_IN, 2, &segments);
+        assert_eq!(req.execute(disk, &mem).unwrap(), 0x600);
+        let mut expected = vec![0xaa; 0x200];
+        expected.extend_from_slice(&[0xbb; 0x100]);
+        assert_eq!(read_guest(&mem, DATA_ADDR + 0x1000, 0x300), expected);
+        assert_eq!(read_guest(&mem, DATA_ADDR, 0x300), vec![0xbb; 0x300]);
+    }
+
+    #[test]
+    fn test_read_write_direct() {
+        let file = TempFile::new().unwrap();
+        let disk = disk_properties(&file, None);
+        let mem = guest_memory();
+        let req = request(&mem, VIRTIO_BLK_T_IN, 0, &[(DATA_ADDR, 0x200)]);
+        assert!(req.direct_fd(&disk, &mem, 0).is_some());
+
+        check_read_write(&disk, &file);
+    }
+
+    #[test]
+    fn test_read_write_bounce() {
+        let file = TempFile::new().unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay = OverlayConfig {
+            path: overlay_file.as_path().to_str().unwrap().to_string(),
+            discard_on_exit: false,
+        };
+        let disk = disk_properties(&file, Some(overlay));
+        let mem = guest_memory();
+        let req = request(&mem, VIRTIO_BLK_T_IN, 0, &[(DATA_ADDR, 0x200)]);
+        assert!(req.direct_fd(&disk, &mem, 0).is_none());
+
+        check_read_write(&disk, &file);
+    }
+
+    #[test]
+    fn test_parse_segment_limits() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(DISK_SIZE).unwrap();
+        let disk = DiskProperties::new(
+            file.as_path().to_str().unwrap().to_string(),
+            ImageType::Raw,
+            None,
+            None,
+            DiskIdentity {
+                seg_max: 2,
+                ..Default::default()
+            },
+            false,
+            CacheType::Unsafe,
+        )
+        .unwrap();
+        let mem = guest_memory();
+        let parse = |segments: &[(u64, u32)]| {
+            parse_request(&mem, &disk, VIRTIO_BLK_T_IN, 0, segments).map(|req| req.data_len)
+        };
+
+        let max = (DATA_ADDR, MAX_SEGMENT_SIZE);
+        assert_eq!(parse(&[max, max]).unwrap(), 2 * MAX_SEGMENT_SIZE);
+        assert!(matches!(
+            parse(&[max, max, (DATA_ADDR, 0x200)]),
+            Err(Error::InvalidDataLength)
+        ));
+        assert!(matches!(
+            parse(&[(DATA_ADDR, MAX_SEGMENT_SIZE + 1)]),
+            Err(Error::InvalidDataLength)
+        ));
+    }
+
+    /// Writes the segments of a discard or write zeroes request to guest
+    /// memory, and returns the data descriptor covering them.
+    fn write_segments(mem: &GuestMemoryMmap, segments: &[(u64, u32, u32)]) -> (u64, u32) {
+        for (i, &(sector, num_sectors, flags)) in segments.iter().enumerate() {
+            let segment = DiscardWriteZeroesSegment {
+                sector,
+                num_sectors,
+                flags,
+            };
+            mem.write_obj(segment, GuestAddress(DATA_ADDR + i as u64 * 16))
+                .unwrap();
+        }
+        (DATA_ADDR, segments.len() as u32 * 16)
+    }
+
+    /// Number of 512 bytes blocks allocated to `file`.
+    fn allocated_blocks(file: &TempFile) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        file.as_file().metadata().unwrap().blocks()
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_ranges() {
+        let file = TempFile::new().unwrap();
+        let disk = disk_properties(&file, None);
+        let mem = guest_memory();
+        let nsectors = DISK_SIZE >> SECTOR_SHIFT;
+
+        for request_type in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let execute = |segments: &[(u64, u32, u32)]| {
+                let data = write_segments(&mem, segments);
+                request(&mem, request_type, 0, &[data]).execute(&disk, &mem)
+            };
+
+            assert_eq!(execute(&[(0, 8, 0), (nsectors - 8, 8, 0)]).unwrap(), 0);
+            assert!(matches!(
+                execute(&[(nsectors - 8, 9, 0)]),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+            assert!(matches!(
+                execute(&[(u64::MAX, 1, 0)]),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+            // A bad segment fails the whole request.
+            assert!(matches!(
+                execute(&[(0, 8, 0), (nsectors, 1, 0)]),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+            assert!(matches!(
+                execute(&[(0, 1, 0); MAX_DISCARD_SEG as usize + 1]),
+                Err(ExecuteError::BadRequest(Error::InvalidDataLength))
+            ));
+            assert!(matches!(
+                execute(&[(0, 1, 1 << 1)]),
+                Err(ExecuteError::Unsupported(t)) if t == request_type
+            ));
+
+            // Segments are 16 bytes long.
+            let req = request(&mem, request_type, 0, &[(DATA_ADDR, 24)]);
+            assert!(matches!(
+                req.execute(&disk, &mem),
+                Err(ExecuteError::BadRequest(Error::InvalidDataLength))
+            ));
+        }
+
+        // The unmap flag is only defined for write zeroes.
+        let data = write_segments(&mem, &[(0, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)]);
+        let req = request(&mem, VIRTIO_BLK_T_DISCARD, 0, &[data]);
+        assert!(matches!(
+            req.execute(&disk, &mem),
+            Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD))
+        ));
+    }
+
+    #[test]
+    fn test_write_zeroes_unmap() {
+        let file = TempFile::new().unwrap();
+        let disk = disk_properties(&file, None);
+        let mem = guest_memory();
+        file.as_file().write_all_at(&[0xaa; 0x8000], 0).unwrap();
+        file.as_file().sync_all().unwrap();
+        let allocated = allocated_blocks(&file);
+
+        // Without unmap, the range stays allocated.
+        let data = write_segments(&mem, &[(0, 0x20, 0)]);
+        let req = request(&mem, VIRTIO_BLK_T_WRITE_ZEROES, 0, &[data]);
+        assert_eq!(req.execute(&disk, &mem).unwrap(), 0);
+        assert_eq!(allocated_blocks(&file), allocated);
+
+        let data = write_segments(&mem, &[(0x20, 0x20, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)]);
+        let req = request(&mem, VIRTIO_BLK_T_WRITE_ZEROES, 0, &[data]);
+        assert_eq!(req.execute(&disk, &mem).unwrap(), 0);
+        assert!(allocated_blocks(&file) < allocated);
+
+        let mut buf = vec![0xffu8; 0x8000];
+        file.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_bounce_buf_limit() {
+        let file = TempFile::new().unwrap();
+        let disk = disk_properties(&file, None);
+        let mem = guest_memory();
+
+        let mut req = request(&mem, VIRTIO_BLK_T_IN, 0, &[(DATA_ADDR, 0x200)]);
+        assert_eq!(req.bounce_buf(&disk).unwrap().len(), 0x200);
+
+        // The guest controls the length of the data, even past its memory.
+        req.data_len = (disk.max_data_len() + 1) as u32;
+        assert!(matches!(
+            req.bounce_buf(&disk),
+            Err(ExecuteError::BadRequest(Error::InvalidDataLength))
+        ));
+        assert_eq!(
+            disk.max_data_len(),
+            u64::from(u32::from(crate::virtio::block::QUEUE_SIZE) - 2)
+                * u64::from(MAX_SEGMENT_SIZE)
+        );
+    }
 }
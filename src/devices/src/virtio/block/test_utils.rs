@@ -3,7 +3,7 @@
 
 use std::os::unix::io::AsRawFd;
 
-use crate::virtio::{Block, CacheType, Queue};
+use crate::virtio::{Block, CacheType, DiskIdentity, ImageType, Queue};
 use polly::event_manager::{EventManager, Subscriber};
 use utils::epoll::{EpollEvent, EventSet};
 use utils::tempfile::TempFile;
@@ -21,7 +21,20 @@ pub fn default_block() -> Block {
 pub fn default_block_with_path(path: String) -> Block {
     let id = "test".to_string();
     // The default block device is read-write and non-root.
-    Block::new(id, None, CacheType::Unsafe, path, false, false).unwrap()
+    Block::new(
+        id,
+        None,
+        CacheType::Unsafe,
+        path,
+        ImageType::Raw,
+        None,
+        None,
+        false,
+        false,
+        1,
+        DiskIdentity::default(),
+    )
+    .unwrap()
 }
 
 pub fn invoke_handler_for_queue_event(b: &mut Block) {
@@ -0,0 +1,84 @@
+use std::io;
+use std::os::fd::RawFd;
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// Nothing was written
+    NothingRead,
+    /// Another internal error occurred
+    Internal(nix::Error),
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// Nothing was written, you can drop the frame or try to resend it later
+    NothingWritten,
+    /// Part of the buffer was written, the write has to be finished using try_finish_write
+    PartialWrite,
+    /// Passt doesnt seem to be running (received EPIPE)
+    ProcessNotRunning,
+    /// Another internal error occurred
+    Internal(nix::Error),
+}
+
+/// The host side of a virtio-net device, which exchanges ethernet frames
+/// with the guest.
+///
+/// Frames are passed around with room for a virtio-net header in front of
+/// them, so backends that understand the header can use it as-is and the
+/// others can overwrite it with their own framing.
+pub trait NetBackend: Send {
+    /// Try to read a frame into `buf[hdr_len..]`, returning its length. The
+    /// caller zeroes `buf[..hdr_len]`, which the backend may fill with the
+    /// virtio-net header of the frame instead.
+    /// If no bytes are available reports ReadError::NothingRead.
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError>;
+
+    /// Try to write the frame in `buf[hdr_len..]`, whose virtio-net header is
+    /// in `buf[..hdr_len]`. Backends may overwrite the header.
+    ///
+    /// If this function returns WriteError::PartialWrite, you have to finish the write using
+    /// try_finish_write.
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError>;
+
+    fn has_unfinished_write(&self) -> bool;
+
+    /// Try to finish a partial write
+    ///
+    /// * `hdr_len` - must be the same value as passed to write_frame, that caused the partial write
+    /// * `buf` - must be same buffer that was given to write_frame, that caused the partial write
+    fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError>;
+
+    /// Tells the backend which offloads the guest accepted, so it knows what
+    /// kind of frames it may hand to the guest.
+    fn set_offloads(&mut self, _acked_features: u64) -> nix::Result<()> {
+        Ok(())
+    }
+
+    /// Tells the backend whether the guest uses the queue pair it belongs
+    /// to, so it can steer frames to the queue pairs in use.
+    fn set_enabled(&mut self, _enabled: bool) -> nix::Result<()> {
+        Ok(())
+    }
+
+    /// Gets ready to start the process behind the backend again after it
+    /// exited, which `restart` does once `raw_restart_timer_fd` is readable.
+    fn schedule_restart(&mut self) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Starts the process behind the backend again after it exited. The
+    /// backend may have a new file descriptor afterwards.
+    fn restart(&mut self) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// File descriptor to poll for readiness to read and write frames.
+    fn raw_socket_fd(&self) -> RawFd;
+
+    /// File descriptor of the timer telling when to restart the process
+    /// behind the backend, if it can be restarted.
+    fn raw_restart_timer_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
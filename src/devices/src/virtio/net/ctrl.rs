@@ -0,0 +1,295 @@
+//! The control queue, through which the guest configures the device.
+
+use virtio_bindings::virtio_net::{
+    VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_MAC,
+    VIRTIO_NET_CTRL_MAC_ADDR_SET, VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI,
+    VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD,
+    VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX,
+    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_GUEST_ANNOUNCE, VIRTIO_NET_F_MQ, VIRTIO_NET_OK,
+    VIRTIO_NET_S_ANNOUNCE,
+};
+use vm_memory::{Bytes, GuestAddress};
+
+use super::device::Net;
+use crate::virtio::DeviceState;
+
+/// The largest command we accept, far larger than any the device handles.
+const MAX_COMMAND_SIZE: usize = 4096;
+
+impl Net {
+    pub(crate) fn process_ctrl_queue_event(&mut self) {
+        let index = self.ctrl_queue_index();
+        if let Err(e) = self.queue_evts[index].read() {
+            log::error!("Failed to get ctrl event from queue: {e:?}");
+        }
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut used_any = false;
+        while let Some(head) = self.queues[index].pop(&mem) {
+            let head_index = head.index;
+            let mut command = Vec::new();
+            let mut ack_addr: Option<GuestAddress> = None;
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    ack_addr = Some(desc.addr);
+                    break;
+                }
+                let start = command.len();
+                let len = (desc.len as usize).min(MAX_COMMAND_SIZE - start);
+                command.resize(start + len, 0);
+                if let Err(e) = mem.read_slice(&mut command[start..], desc.addr) {
+                    log::error!("Failed to read ctrl command: {e:?}");
+                    command.clear();
+                    break;
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            let ack = match command.as_slice() {
+                [class, cmd, data @ ..] => self.handle_ctrl_command(*class, *cmd, data),
+                _ => VIRTIO_NET_ERR as u8,
+            };
+            let used_len = match ack_addr {
+                Some(addr) => match mem.write_obj(ack, addr) {
+                    Ok(()) => 1,
+                    Err(e) => {
+                        log::error!("Failed to write ctrl ack: {e:?}");
+                        0
+                    }
+                },
+                None => 0,
+            };
+            self.queues[index].add_used(&mem, head_index, used_len);
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(e) = self.signal_used_queue() {
+                log::error!("Failed to signal ctrl queue: {e:?}");
+            }
+        }
+    }
+
+    /// Carries out a command of `class`, returning the ack for the guest.
+    fn handle_ctrl_command(&mut self, class: u8, cmd: u8, data: &[u8]) -> u8 {
+        let result = match (u32::from(class), u32::from(cmd)) {
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC | VIRTIO_NET_CTRL_RX_ALLMULTI)
+                if self.has_feature(VIRTIO_NET_F_CTRL_RX) =>
+            {
+                match data {
+                    [on] if u32::from(cmd) == VIRTIO_NET_CTRL_RX_PROMISC => {
+                        self.rx_filter.set_promisc(*on != 0);
+                        true
+                    }
+                    [on] => {
+                        self.rx_filter.set_allmulti(*on != 0);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+                if self.has_feature(VIRTIO_NET_F_CTRL_RX) =>
+            {
+                self.rx_filter.set_mac_tables(data)
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET)
+                if self.has_feature(VIRTIO_NET_F_CTRL_MAC_ADDR) =>
+            {
+                match data.try_into() {
+                    Ok(mac) => {
+                        self.set_guest_mac(mac);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD | VIRTIO_NET_CTRL_VLAN_DEL)
+                if self.has_feature(VIRTIO_NET_F_CTRL_VLAN) =>
+            {
+                match data {
+                    [lo, hi] => self.rx_filter.set_vlan(
+                        u16::from_le_bytes([*lo, *hi]),
+                        u32::from(cmd) == VIRTIO_NET_CTRL_VLAN_ADD,
+                    ),
+                    _ => false,
+                }
+            }
+            (VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK)
+                if self.has_feature(VIRTIO_NET_F_GUEST_ANNOUNCE) =>
+            {
+                self.set_status(VIRTIO_NET_S_ANNOUNCE, false);
+                true
+            }
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => self.set_queue_pairs(data),
+            _ => {
+                log::debug!("net: unsupported ctrl command {class}:{cmd}");
+                false
+            }
+        };
+        if result {
+            VIRTIO_NET_OK as u8
+        } else {
+            VIRTIO_NET_ERR as u8
+        }
+    }
+
+    /// Switches to the number of queue pairs in `data`, returning whether it
+    /// is valid.
+    fn set_queue_pairs(&mut self, data: &[u8]) -> bool {
+        let Some(&[lo, hi]) = data.get(..2) else {
+            return false;
+        };
+        let num_pairs = u16::from_le_bytes([lo, hi]) as usize;
+        if !self.has_feature(VIRTIO_NET_F_MQ)
+            || num_pairs == 0
+            || num_pairs > self.num_queue_pairs()
+        {
+            return false;
+        }
+
+        if let Err(e) = self.set_active_pairs(num_pairs) {
+            log::error!("Failed to switch to {num_pairs} queue pairs: {e}");
+            return false;
+        }
+        log::debug!("net: using {num_pairs} queue pairs");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use virtio_bindings::virtio_config::VIRTIO_F_VERSION_1;
+    use virtio_bindings::virtio_net::VIRTIO_NET_F_CTRL_VQ;
+
+    use super::*;
+    use crate::virtio::net::test_utils::{
+        activate, guest_memory, guest_status, mock_net, GuestQueues,
+    };
+    use crate::virtio::net::RX_INDEX;
+
+    fn features(bits: &[u32]) -> u64 {
+        bits.iter().fold(
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_CTRL_VQ,
+            |acc, bit| acc | 1 << bit,
+        )
+    }
+
+    /// Sends a command through the control queue, returning its ack.
+    fn command(net: &mut Net, queues: &GuestQueues, class: u32, cmd: u32, data: &[u8]) -> u32 {
+        let index = net.ctrl_queue_index();
+        queues.add_ctrl_command(index, class, cmd, data);
+        net.queue_evts[index].write(1).unwrap();
+        net.process_ctrl_queue_event();
+        u32::from(queues.ctrl_ack(index))
+    }
+
+    fn set_pairs(net: &mut Net, queues: &GuestQueues, data: &[u8]) -> u32 {
+        command(
+            net,
+            queues,
+            VIRTIO_NET_CTRL_MQ,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+            data,
+        )
+    }
+
+    #[test]
+    fn test_mq_vq_pairs_set() {
+        let (mut net, states) = mock_net(4);
+        let mem = guest_memory();
+        let queues = activate(&mut net, &mem, features(&[VIRTIO_NET_F_MQ]));
+        assert_eq!(net.ctrl_queue_index(), 8);
+        let enabled = || -> Vec<_> {
+            states
+                .iter()
+                .map(|state| state.lock().unwrap().enabled)
+                .collect()
+        };
+        // The guest starts with the first pair.
+        assert_eq!(enabled(), [None, Some(false), Some(false), Some(false)]);
+
+        // Frames waiting in the backend of a pair are delivered once the
+        // guest uses it.
+        let frame = vec![0xff; 64];
+        states[2].lock().unwrap().rx.push_back(frame.clone());
+        queues.add_rx_buffers(4 + RX_INDEX, 1);
+        assert_eq!(
+            set_pairs(&mut net, &queues, &3u16.to_le_bytes()),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(enabled(), [Some(true), Some(true), Some(true), Some(false)]);
+        assert_eq!(queues.received_frames(4 + RX_INDEX), [frame]);
+
+        // Out of bounds and malformed requests leave the pairs alone.
+        for data in [&0u16.to_le_bytes()[..], &5u16.to_le_bytes(), &[1]] {
+            assert_eq!(set_pairs(&mut net, &queues, data), VIRTIO_NET_ERR);
+        }
+        assert_eq!(enabled(), [Some(true), Some(true), Some(true), Some(false)]);
+
+        assert_eq!(
+            set_pairs(&mut net, &queues, &4u16.to_le_bytes()),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(enabled(), [Some(true); 4]);
+        assert_eq!(
+            set_pairs(&mut net, &queues, &1u16.to_le_bytes()),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(
+            enabled(),
+            [Some(true), Some(false), Some(false), Some(false)]
+        );
+    }
+
+    #[test]
+    fn test_mq_not_negotiated() {
+        let (mut net, states) = mock_net(2);
+        let mem = guest_memory();
+        let queues = activate(&mut net, &mem, features(&[]));
+        // The control queue comes right after the first pair.
+        assert_eq!(net.ctrl_queue_index(), 2);
+        assert_eq!(
+            set_pairs(&mut net, &queues, &2u16.to_le_bytes()),
+            VIRTIO_NET_ERR
+        );
+        assert_eq!(states[1].lock().unwrap().enabled, Some(false));
+    }
+
+    #[test]
+    fn test_announce_ack() {
+        let (mut net, _states) = mock_net(1);
+        let mem = guest_memory();
+        let queues = activate(&mut net, &mem, features(&[VIRTIO_NET_F_GUEST_ANNOUNCE]));
+        net.announce().unwrap();
+        assert_ne!(guest_status(&net) & VIRTIO_NET_S_ANNOUNCE, 0);
+
+        let ack = command(
+            &mut net,
+            &queues,
+            VIRTIO_NET_CTRL_ANNOUNCE,
+            VIRTIO_NET_CTRL_ANNOUNCE_ACK,
+            &[],
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        assert_eq!(guest_status(&net) & VIRTIO_NET_S_ANNOUNCE, 0);
+
+        // Guests that don't support announcements can't acknowledge them.
+        let (mut net, _states) = mock_net(1);
+        let queues = activate(&mut net, &mem, features(&[]));
+        let ack = command(
+            &mut net,
+            &queues,
+            VIRTIO_NET_CTRL_ANNOUNCE,
+            VIRTIO_NET_CTRL_ANNOUNCE_ACK,
+            &[],
+        );
+        assert_eq!(ack, VIRTIO_NET_ERR);
+    }
+}
@@ -5,24 +5,42 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 use crate::legacy::Gic;
+#[cfg(target_os = "linux")]
+use crate::rate_limiter::{RateLimiter, RateLimiterConfig};
+use crate::virtio::net::backend::{NetBackend, ReadError, WriteError};
+use crate::virtio::net::filter::RxFilter;
+use crate::virtio::net::nat;
 use crate::virtio::net::passt::Passt;
-use crate::virtio::net::{passt, MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX};
+#[cfg(target_os = "linux")]
+use crate::virtio::net::passt_process::{PasstCommand, PasstProcess};
+use crate::virtio::net::pcap::{Direction, PcapConfig, PcapWriter};
+#[cfg(target_os = "linux")]
+use crate::virtio::net::tap::Tap;
+use crate::virtio::net::unixgram::Unixgram;
 use crate::virtio::net::{Error, Result};
+use crate::virtio::net::{DEFAULT_MTU, MAX_BUFFER_SIZE, QUEUE_SIZE, RX_INDEX, TX_INDEX};
 use crate::virtio::{
-    ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
+    ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_CONFIG,
+    VIRTIO_MMIO_INT_VRING,
 };
 use crate::Error as DeviceError;
-use std::os::fd::RawFd;
+use std::io::{self, Write};
+use std::os::fd::{IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem, result};
 use utils::eventfd::EventFd;
 use virtio_bindings::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
-    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
+    virtio_net_hdr_v1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX,
+    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_ANNOUNCE, VIRTIO_NET_S_LINK_UP,
 };
-use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 const VIRTIO_F_VERSION_1: u32 = 32;
 
@@ -36,16 +54,56 @@ enum FrontendError {
 
 #[derive(Debug)]
 enum RxError {
-    Passt(passt::ReadError),
+    Backend(ReadError),
     DeviceError(DeviceError),
 }
 
 #[derive(Debug)]
 enum TxError {
-    Passt(passt::WriteError),
+    Backend(WriteError),
     DeviceError(DeviceError),
 }
 
+/// Where the frames of a virtio-net device go on the host.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VirtioNetBackend {
+    /// Sockets connected to a running passt instance, one per queue pair.
+    Passt(Vec<RawFd>),
+    /// passt instance started, and restarted if it exits, by the device.
+    #[cfg(target_os = "linux")]
+    PasstProcess(PasstCommand),
+    /// Path of a unix stream socket speaking QEMU's `-netdev stream` format.
+    UnixStream(PathBuf),
+    /// Path of a unix datagram socket carrying one frame per datagram.
+    Unixgram(PathBuf),
+    /// Unix datagram socket already connected to the server.
+    UnixgramFd(RawFd),
+    /// Name of a TAP interface, which is created if it doesn't exist.
+    #[cfg(target_os = "linux")]
+    Tap(String),
+    /// File descriptors already attached to a TAP interface, one per queue
+    /// pair.
+    #[cfg(target_os = "linux")]
+    TapFd(Vec<RawFd>),
+    /// Built-in userspace NAT, giving the guest access to the host's network
+    /// without any privileges.
+    UserNat,
+}
+
+/// The virtio-net configuration space, as described in section 5.1.4 of the
+/// virtio 1.1 specification.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct ConfigSpace {
+    mac: [u8; 6],
+    status: u16,
+    max_virtqueue_pairs: u16,
+    mtu: u16,
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
 pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -58,15 +116,98 @@ fn write_virtio_net_hdr(buf: &mut [u8]) -> usize {
     len
 }
 
-pub struct Net {
-    id: String,
-    passt: Passt,
+// Index of the queues of the pair `pair` in the Net device queues/queue_evts vectors.
+fn rx_queue_index(pair: usize) -> usize {
+    2 * pair + RX_INDEX
+}
 
-    avail_features: u64,
-    acked_features: u64,
+fn tx_queue_index(pair: usize) -> usize {
+    2 * pair + TX_INDEX
+}
 
-    pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: Vec<EventFd>,
+/// Opens the backend of each of `num_queue_pairs` for a guest using `mtu`.
+fn open_backends(
+    id: &str,
+    backend: VirtioNetBackend,
+    num_queue_pairs: usize,
+    mtu: Option<u16>,
+) -> Result<Vec<Box<dyn NetBackend>>> {
+    let check_fds = |fds: &[RawFd]| {
+        if fds.len() == num_queue_pairs {
+            Ok(())
+        } else {
+            Err(Error::Backend(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} file descriptors for {num_queue_pairs} queue pairs",
+                    fds.len()
+                ),
+            )))
+        }
+    };
+
+    let backend: Box<dyn NetBackend> = match backend {
+        VirtioNetBackend::Passt(fds) => {
+            check_fds(&fds)?;
+            return Ok(fds
+                .into_iter()
+                .map(|fd| Box::new(Passt::new(fd)) as Box<dyn NetBackend>)
+                .collect());
+        }
+        #[cfg(target_os = "linux")]
+        VirtioNetBackend::Tap(if_name) => {
+            return Ok(Tap::open_named(&if_name, num_queue_pairs)
+                .map_err(Error::Backend)?
+                .into_iter()
+                .map(|tap| Box::new(tap) as Box<dyn NetBackend>)
+                .collect());
+        }
+        #[cfg(target_os = "linux")]
+        VirtioNetBackend::TapFd(fds) => {
+            check_fds(&fds)?;
+            return fds
+                .into_iter()
+                .map(|fd| {
+                    // Safe because the caller handed the file descriptor over to us.
+                    unsafe { Tap::from_raw_fd(fd) }.map(|tap| Box::new(tap) as Box<dyn NetBackend>)
+                })
+                .collect::<io::Result<_>>()
+                .map_err(Error::Backend);
+        }
+        VirtioNetBackend::UnixgramFd(fd) => {
+            // Take the socket over first, so that it's closed on errors too.
+            let unixgram = Unixgram::new(fd);
+            check_fds(&[fd])?;
+            return Ok(vec![Box::new(unixgram)]);
+        }
+        _ if num_queue_pairs != 1 => {
+            return Err(Error::Backend(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the backend doesn't support multiple queue pairs",
+            )));
+        }
+        #[cfg(target_os = "linux")]
+        VirtioNetBackend::PasstProcess(command) => {
+            Box::new(PasstProcess::start(command).map_err(Error::Backend)?)
+        }
+        VirtioNetBackend::UnixStream(path) => {
+            let stream = UnixStream::connect(path).map_err(Error::Backend)?;
+            Box::new(Passt::new(stream.into_raw_fd()))
+        }
+        VirtioNetBackend::Unixgram(path) => {
+            Box::new(Unixgram::connect(&path).map_err(|e| Error::Backend(e.into()))?)
+        }
+        VirtioNetBackend::UserNat => Box::new(Unixgram::new(
+            nat::start(id, mtu.unwrap_or(DEFAULT_MTU)).map_err(Error::Backend)?,
+        )),
+    };
+    Ok(vec![backend])
+}
+
+/// A receive queue and a transmit queue, along with the backend their frames
+/// go to.
+struct QueuePair {
+    backend: Box<dyn NetBackend>,
 
     rx_frame_buf: [u8; MAX_BUFFER_SIZE],
     rx_frame_buf_len: usize,
@@ -75,6 +216,20 @@ pub struct Net {
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
     tx_frame_len: usize,
+}
+
+pub struct Net {
+    id: String,
+    pairs: Vec<QueuePair>,
+    /// Number of queue pairs the guest uses, the first ones.
+    active_pairs: usize,
+    config_space: ConfigSpace,
+
+    avail_features: u64,
+    acked_features: u64,
+
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: Vec<EventFd>,
 
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
@@ -84,30 +239,104 @@ pub struct Net {
 
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
+
+    pcap: Option<PcapWriter>,
+
+    /// Address the guest interface starts with, if it's not up to the guest.
+    mac: Option<[u8; 6]>,
+    /// Whether the cable is plugged in. Frames are dropped while it isn't.
+    link_up: bool,
+    pub(crate) rx_filter: RxFilter,
+
+    /// Limits the frames going to the guest, and those coming from it.
+    #[cfg(target_os = "linux")]
+    pub(crate) rx_rate_limiter: RateLimiter,
+    #[cfg(target_os = "linux")]
+    pub(crate) tx_rate_limiter: RateLimiter,
 }
 
 impl Net {
-    /// Create a new virtio network device using passt
-    pub fn new(id: String, passt_fd: RawFd) -> Result<Self> {
-        let passt = Passt::new(passt_fd);
-        let avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
+    /// Create a new virtio network device with `num_queue_pairs`, connected
+    /// to `backend`. If `mac` isn't set, the guest picks a random address,
+    /// and if `mtu` isn't, the guest uses the ethernet default.
+    pub fn new(
+        id: String,
+        backend: VirtioNetBackend,
+        num_queue_pairs: u16,
+        mac: Option<[u8; 6]>,
+        mtu: Option<u16>,
+    ) -> Result<Self> {
+        let backends = open_backends(&id, backend, num_queue_pairs as usize, mtu)?;
+        Self::with_backends(id, backends, mac, mtu)
+    }
+
+    /// Create a new virtio network device with a queue pair for each of
+    /// `backends`.
+    pub(crate) fn with_backends(
+        id: String,
+        backends: Vec<Box<dyn NetBackend>>,
+        mac: Option<[u8; 6]>,
+        mtu: Option<u16>,
+    ) -> Result<Self> {
+        let num_queue_pairs = backends.len() as u16;
+        let pairs = backends
+            .into_iter()
+            .map(|backend| QueuePair {
+                backend,
+                rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+                rx_frame_buf_len: 0,
+                rx_has_deferred_frame: false,
+                tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+                tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+                tx_frame_len: 0,
+            })
+            .collect();
+
+        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_CTRL_RX
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
+            | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
             | 1 << VIRTIO_F_VERSION_1;
+        let mut config_space = ConfigSpace {
+            status: (VIRTIO_NET_S_LINK_UP as u16).to_le(),
+            max_virtqueue_pairs: num_queue_pairs.to_le(),
+            ..Default::default()
+        };
+        if num_queue_pairs > 1 {
+            avail_features |= 1 << VIRTIO_NET_F_MQ;
+        }
+        if let Some(mac) = mac {
+            avail_features |= 1 << VIRTIO_NET_F_MAC;
+            config_space.mac = mac;
+        }
+        if let Some(mtu) = mtu {
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+            config_space.mtu = mtu.to_le();
+        }
 
+        // Each pair has a receive and a transmit queue, followed by the
+        // control queue.
+        let num_queues = 2 * num_queue_pairs as usize + 1;
         let mut queue_evts = Vec::new();
-        for _ in QUEUE_SIZES.iter() {
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
         }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Net {
             id,
-            passt,
+            pairs,
+            active_pairs: 1,
+            config_space,
 
             avail_features,
             acked_features: 0u64,
@@ -115,14 +344,6 @@ impl Net {
             queues,
             queue_evts,
 
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            rx_frame_buf_len: 0,
-            rx_has_deferred_frame: false,
-
-            tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            tx_frame_len: 0,
-            tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
-
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
 
@@ -131,27 +352,86 @@ impl Net {
 
             intc: None,
             irq_line: None,
+
+            pcap: None,
+
+            mac,
+            link_up: true,
+            rx_filter: RxFilter::new(mac, false),
+
+            #[cfg(target_os = "linux")]
+            rx_rate_limiter: RateLimiter::new(RateLimiterConfig::default())
+                .map_err(Error::RateLimiter)?,
+            #[cfg(target_os = "linux")]
+            tx_rate_limiter: RateLimiter::new(RateLimiterConfig::default())
+                .map_err(Error::RateLimiter)?,
         })
     }
 
+    pub(crate) fn has_feature(&self, feature: u32) -> bool {
+        self.acked_features & (1 << feature) != 0
+    }
+
+    /// Index of the control queue, which comes after the queue pairs the
+    /// guest can use.
+    pub(crate) fn ctrl_queue_index(&self) -> usize {
+        if self.has_feature(VIRTIO_NET_F_MQ) {
+            2 * self.pairs.len()
+        } else {
+            2
+        }
+    }
+
+    // The transport waits for every queue it's given to be set up, so it
+    // only gets the ones the negotiated features let the guest use.
+    fn num_queues_in_use(&self) -> usize {
+        if self.has_feature(VIRTIO_NET_F_CTRL_VQ) {
+            self.ctrl_queue_index() + 1
+        } else {
+            2
+        }
+    }
+
     /// Provides the ID of this net device.
     pub fn id(&self) -> &str {
         &self.id
     }
 
-    pub(crate) fn process_rx_queue_event(&mut self) {
-        if let Err(e) = self.queue_evts[RX_INDEX].read() {
+    /// Starts writing every frame exchanged with the backend to a pcapng file.
+    pub fn set_pcap(&mut self, config: PcapConfig) -> Result<()> {
+        self.pcap = Some(PcapWriter::new(config, &self.id).map_err(Error::Pcap)?);
+        Ok(())
+    }
+
+    /// Writes `frame` to the capture file, if any. Capturing stops on the
+    /// first error, rather than slowing down every frame after it.
+    fn capture(pcap: &mut Option<PcapWriter>, frame: &[u8], direction: Direction) {
+        if let Some(writer) = pcap {
+            if let Err(e) = writer.write_frame(frame, direction) {
+                log::error!("Failed to write frame to capture file, stopping capture: {e}");
+                *pcap = None;
+            }
+        }
+    }
+
+    /// Number of queue pairs of the device.
+    pub(crate) fn num_queue_pairs(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub(crate) fn process_rx_queue_event(&mut self, pair: usize) {
+        if let Err(e) = self.queue_evts[rx_queue_index(pair)].read() {
             log::error!("Failed to get rx event from queue: {:?}", e);
         }
-        if let Err(e) = self.process_rx() {
+        if let Err(e) = self.process_rx(pair) {
             log::error!("Failed to process rx: {e:?} (triggered by queue event)")
         };
     }
 
-    pub(crate) fn process_tx_queue_event(&mut self) {
-        match self.queue_evts[TX_INDEX].read() {
+    pub(crate) fn process_tx_queue_event(&mut self, pair: usize) {
+        match self.queue_evts[tx_queue_index(pair)].read() {
             Ok(_) => {
-                if let Err(e) = self.process_tx() {
+                if let Err(e) = self.process_tx(pair) {
                     log::error!("Failed to process tx event: {e:?}");
                 };
             }
@@ -161,42 +441,168 @@ impl Net {
         }
     }
 
-    pub(crate) fn process_passt_socket_readable(&mut self) {
-        if let Err(e) = self.process_rx() {
-            log::error!("Failed to process rx: {e:?} (triggered by passt socket readable)");
+    pub(crate) fn process_backend_socket_readable(&mut self, pair: usize) {
+        if let Err(e) = self.process_rx(pair) {
+            log::error!("Failed to process rx: {e:?} (triggered by backend socket readable)");
         };
     }
 
-    pub(crate) fn process_passt_socket_writeable(&mut self) {
-        match self
-            .passt
-            .try_finish_write(vnet_hdr_len(), &self.tx_frame_buf[..self.tx_frame_len])
+    pub(crate) fn process_backend_socket_writeable(&mut self, pair: usize) {
+        let qp = &mut self.pairs[pair];
+        match qp
+            .backend
+            .try_finish_write(vnet_hdr_len(), &qp.tx_frame_buf[..qp.tx_frame_len])
         {
             Ok(()) => {
-                if let Err(e) = self.process_tx() {
-                    log::error!("Failed to continue processing tx after passt socket was writable again: {e:?}");
+                if let Err(e) = self.process_tx(pair) {
+                    log::error!("Failed to continue processing tx after backend socket was writable again: {e:?}");
                 }
             }
-            Err(passt::WriteError::PartialWrite | passt::WriteError::NothingWritten) => {}
-            Err(e @ passt::WriteError::Internal(_)) => {
+            Err(WriteError::PartialWrite | WriteError::NothingWritten) => {}
+            Err(e @ WriteError::Internal(_)) => {
                 log::error!("Failed to finish write: {e:?}");
             }
-            Err(e @ passt::WriteError::ProcessNotRunning) => {
+            Err(e @ WriteError::ProcessNotRunning) => {
                 log::debug!("Failed to finish write: {e:?}");
             }
         }
     }
 
-    pub(crate) fn raw_passt_socket_fd(&self) -> RawFd {
-        self.passt.raw_socket_fd()
+    /// Asks the guest to announce its addresses, for instance with
+    /// gratuitous ARP, so the network learns where to find it again.
+    pub fn announce(&mut self) -> io::Result<()> {
+        if !self.is_activated() || !self.has_feature(VIRTIO_NET_F_GUEST_ANNOUNCE) {
+            return Ok(());
+        }
+        self.set_status(VIRTIO_NET_S_ANNOUNCE, true);
+        self.signal_config_change()
+    }
+
+    /// Plugs the cable of the interface in or out. The guest sees the carrier
+    /// change, and announces itself when it comes back.
+    pub fn set_link_state(&mut self, up: bool) -> io::Result<()> {
+        if up == self.link_up {
+            return Ok(());
+        }
+        self.link_up = up;
+        self.set_status(VIRTIO_NET_S_LINK_UP, up);
+        if !self.is_activated() {
+            return Ok(());
+        }
+        if up && self.has_feature(VIRTIO_NET_F_GUEST_ANNOUNCE) {
+            self.set_status(VIRTIO_NET_S_ANNOUNCE, true);
+        }
+        self.signal_config_change()
+    }
+
+    /// Replaces the limits of the bytes and frames going to the guest.
+    #[cfg(target_os = "linux")]
+    pub fn update_rx_rate_limiter(&mut self, config: RateLimiterConfig) {
+        self.rx_rate_limiter.update(config);
+    }
+
+    /// Replaces the limits of the bytes and frames coming from the guest.
+    #[cfg(target_os = "linux")]
+    pub fn update_tx_rate_limiter(&mut self, config: RateLimiterConfig) {
+        self.tx_rate_limiter.update(config);
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn process_rx_rate_limiter_event(&mut self) {
+        // Frames were deferred until the timer expired, so retry them now.
+        if let Err(e) = self.rx_rate_limiter.event_handler() {
+            log::error!("Failed to get rx rate limiter event: {e:?}");
+            return;
+        }
+        for pair in 0..self.active_pairs {
+            if let Err(e) = self.process_rx(pair) {
+                log::error!("Failed to process rx: {e:?} (triggered by rate limiter)");
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn process_tx_rate_limiter_event(&mut self) {
+        if let Err(e) = self.tx_rate_limiter.event_handler() {
+            log::error!("Failed to get tx rate limiter event: {e:?}");
+            return;
+        }
+        for pair in 0..self.active_pairs {
+            if let Err(e) = self.process_tx(pair) {
+                log::error!("Failed to process tx: {e:?} (triggered by rate limiter)");
+            }
+        }
     }
 
-    fn process_rx(&mut self) -> result::Result<(), RxError> {
+    /// Sets or clears the `VIRTIO_NET_S_*` bit `flag` of the status the guest sees.
+    pub(crate) fn set_status(&mut self, flag: u32, on: bool) {
+        let mut status = u16::from_le(self.config_space.status);
+        if on {
+            status |= flag as u16;
+        } else {
+            status &= !(flag as u16);
+        }
+        self.config_space.status = status.to_le();
+    }
+
+    /// Switches the guest interface to `mac`, at the request of the guest.
+    pub(crate) fn set_guest_mac(&mut self, mac: [u8; 6]) {
+        self.config_space.mac = mac;
+        self.rx_filter.set_mac(mac);
+    }
+
+    /// Has the guest use the first `num_pairs` queue pairs.
+    pub(crate) fn set_active_pairs(&mut self, num_pairs: usize) -> nix::Result<()> {
+        for (pair, qp) in self.pairs.iter_mut().enumerate() {
+            qp.backend.set_enabled(pair < num_pairs)?;
+        }
+        let old_pairs = self.active_pairs;
+        self.active_pairs = num_pairs;
+
+        // Frames may have piled up in the backends of the pairs just enabled.
+        for pair in old_pairs..num_pairs {
+            if let Err(e) = self.process_rx(pair) {
+                log::error!("Failed to process rx: {e:?} (triggered by enabling queue pair)");
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the backend of `pair`, whose process exited, ready to restart it.
+    pub(crate) fn schedule_backend_restart(&mut self, pair: usize) -> io::Result<()> {
+        self.pairs[pair].backend.schedule_restart()
+    }
+
+    /// Restarts the process behind the backend of `pair`, which exited, and
+    /// has the guest announce itself to the new one.
+    pub(crate) fn restart_backend(&mut self, pair: usize) -> io::Result<()> {
+        self.pairs[pair].backend.restart()?;
+        if let Err(e) = self.announce() {
+            log::warn!("Failed to request an announcement from the guest: {e}");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn raw_backend_socket_fd(&self, pair: usize) -> RawFd {
+        self.pairs[pair].backend.raw_socket_fd()
+    }
+
+    pub(crate) fn raw_backend_restart_timer_fd(&self, pair: usize) -> Option<RawFd> {
+        self.pairs[pair].backend.raw_restart_timer_fd()
+    }
+
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), RxError> {
+        // Frames for pairs the guest doesn't use wait in the backend until
+        // it enables them.
+        if pair >= self.active_pairs {
+            return Ok(());
+        }
+
         // if we have a deferred frame we try to process it first,
         // if that is not possible, we don't continue processing other frames
-        if self.rx_has_deferred_frame {
-            if self.write_frame_to_guest() {
-                self.rx_has_deferred_frame = false;
+        if self.pairs[pair].rx_has_deferred_frame {
+            if self.deliver_rx_frame(pair) {
+                self.pairs[pair].rx_has_deferred_frame = false;
             } else {
                 return Ok(());
             }
@@ -206,17 +612,17 @@ impl Net {
 
         // Read as many frames as possible.
         let result = loop {
-            match self.read_into_rx_frame_buf_from_passt() {
+            match self.read_into_rx_frame_buf_from_backend(pair) {
                 Ok(()) => {
-                    if self.write_frame_to_guest() {
+                    if self.deliver_rx_frame(pair) {
                         signal_queue = true;
                     } else {
-                        self.rx_has_deferred_frame = true;
+                        self.pairs[pair].rx_has_deferred_frame = true;
                         break Ok(());
                     }
                 }
-                Err(passt::ReadError::NothingRead) => break Ok(()),
-                Err(e @ passt::ReadError::Internal(_)) => break Err(RxError::Passt(e)),
+                Err(ReadError::NothingRead) => break Ok(()),
+                Err(e @ ReadError::Internal(_)) => break Err(RxError::Backend(e)),
             }
         };
 
@@ -229,19 +635,20 @@ impl Net {
         result
     }
 
-    fn process_tx(&mut self) -> result::Result<(), TxError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), TxError> {
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
             DeviceState::Inactive => unreachable!(),
         };
 
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(pair)];
+        let qp = &mut self.pairs[pair];
 
-        if self.passt.has_unfinished_write()
-            && self
-                .passt
-                .try_finish_write(vnet_hdr_len(), &self.tx_frame_buf[..self.tx_frame_len])
+        if qp.backend.has_unfinished_write()
+            && qp
+                .backend
+                .try_finish_write(vnet_hdr_len(), &qp.tx_frame_buf[..qp.tx_frame_len])
                 .is_err()
         {
             log::trace!("Cannot process tx because of unfinished partial write!");
@@ -255,24 +662,35 @@ impl Net {
             let mut read_count = 0;
             let mut next_desc = Some(head);
 
-            self.tx_iovec.clear();
+            qp.tx_iovec.clear();
             while let Some(desc) = next_desc {
                 if desc.is_write_only() {
-                    self.tx_iovec.clear();
+                    qp.tx_iovec.clear();
                     break;
                 }
-                self.tx_iovec.push((desc.addr, desc.len as usize));
+                qp.tx_iovec.push((desc.addr, desc.len as usize));
                 read_count += desc.len as usize;
                 next_desc = desc.next_descriptor();
             }
 
+            #[cfg(target_os = "linux")]
+            if self.link_up {
+                let bytes =
+                    cmp::min(read_count, qp.tx_frame_buf.len()).saturating_sub(vnet_hdr_len());
+                if !self.tx_rate_limiter.consume(1, bytes as u64) {
+                    // Leave the frame in the queue until the timer expires.
+                    tx_queue.undo_pop();
+                    break;
+                }
+            }
+
             // Copy buffer from across multiple descriptors.
             read_count = 0;
-            for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
-                let limit = cmp::min(read_count + desc_len, self.tx_frame_buf.len());
+            for (desc_addr, desc_len) in qp.tx_iovec.drain(..) {
+                let limit = cmp::min(read_count + desc_len, qp.tx_frame_buf.len());
 
                 let read_result =
-                    mem.read_slice(&mut self.tx_frame_buf[read_count..limit], desc_addr);
+                    mem.read_slice(&mut qp.tx_frame_buf[read_count..limit], desc_addr);
                 match read_result {
                     Ok(()) => {
                         read_count += limit - read_count;
@@ -285,21 +703,32 @@ impl Net {
                 }
             }
 
-            self.tx_frame_len = read_count;
-            match self
-                .passt
-                .write_frame(vnet_hdr_len(), &mut self.tx_frame_buf[..read_count])
-            {
+            if !self.link_up {
+                tx_queue.add_used(mem, head_index, 0);
+                raise_irq = true;
+                continue;
+            }
+
+            qp.tx_frame_len = read_count;
+            let result = qp
+                .backend
+                .write_frame(vnet_hdr_len(), &mut qp.tx_frame_buf[..read_count]);
+            if let Ok(()) | Err(WriteError::PartialWrite) = result {
+                if let Some(frame) = qp.tx_frame_buf[..read_count].get(vnet_hdr_len()..) {
+                    Self::capture(&mut self.pcap, frame, Direction::Outbound);
+                }
+            }
+            match result {
                 Ok(()) => {
-                    self.tx_frame_len = 0;
+                    qp.tx_frame_len = 0;
                     tx_queue.add_used(mem, head_index, 0);
                     raise_irq = true;
                 }
-                Err(passt::WriteError::NothingWritten) => {
+                Err(WriteError::NothingWritten) => {
                     tx_queue.undo_pop();
                     break;
                 }
-                Err(passt::WriteError::PartialWrite) => {
+                Err(WriteError::PartialWrite) => {
                     log::trace!("process_tx: partial write");
                     /*
                     This situation should be pretty rare, assuming reasonably sized socket buffers.
@@ -316,9 +745,9 @@ impl Net {
                     raise_irq = true;
                     break;
                 }
-                Err(
-                    e @ passt::WriteError::Internal(_) | e @ passt::WriteError::ProcessNotRunning,
-                ) => return Err(TxError::Passt(e)),
+                Err(e @ WriteError::Internal(_) | e @ WriteError::ProcessNotRunning) => {
+                    return Err(TxError::Backend(e))
+                }
             }
         }
 
@@ -329,7 +758,18 @@ impl Net {
         Ok(())
     }
 
-    fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
+    fn signal_config_change(&self) -> io::Result<()> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.lock().unwrap().set_irq(self.irq_line.unwrap());
+        } else {
+            self.interrupt_evt.write(1)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
         if let Some(intc) = &self.intc {
@@ -343,8 +783,8 @@ impl Net {
         }
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn write_frame_to_guest_impl(&mut self) -> result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of `pair` into the guest.
+    fn write_frame_to_guest_impl(&mut self, pair: usize) -> result::Result<(), FrontendError> {
         let mut result: std::result::Result<(), FrontendError> = Ok(());
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
@@ -352,11 +792,12 @@ impl Net {
             DeviceState::Inactive => unreachable!(),
         };
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue = &mut self.queues[rx_queue_index(pair)];
         let head_descriptor = queue.pop(mem).ok_or_else(|| FrontendError::EmptyQueue)?;
         let head_index = head_descriptor.index;
 
-        let mut frame_slice = &self.rx_frame_buf[..self.rx_frame_buf_len];
+        let qp = &self.pairs[pair];
+        let mut frame_slice = &qp.rx_frame_buf[..qp.rx_frame_buf_len];
 
         let frame_len = frame_slice.len();
         let mut maybe_next_descriptor = Some(head_descriptor);
@@ -396,12 +837,34 @@ impl Net {
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies the frame in the `rx_frame_buf` of `pair` into the guest, unless the guest has no
+    // room for it or the rate limiter holds it back, in which case it stays there until later.
+    fn deliver_rx_frame(&mut self, pair: usize) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            let mem = match self.device_state {
+                DeviceState::Activated(ref mem) => mem,
+                DeviceState::Inactive => unreachable!(),
+            };
+            // Only frames the guest has room for count towards the limits.
+            if self.queues[rx_queue_index(pair)].is_empty(mem) {
+                return false;
+            }
+            let qp = &self.pairs[pair];
+            let bytes = qp.rx_frame_buf_len.saturating_sub(vnet_hdr_len());
+            if !self.rx_rate_limiter.consume(1, bytes as u64) {
+                return false;
+            }
+        }
+        self.write_frame_to_guest(pair)
+    }
+
+    // Copies a single frame from the `rx_frame_buf` of `pair` into the guest. In case of an error
+    // retries the operation if possible. Returns true if the operation was successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.write_frame_to_guest_impl() {
+            match self.write_frame_to_guest_impl(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) => {
                     return false;
@@ -416,12 +879,32 @@ impl Net {
         false
     }
 
-    /// Fills self.rx_frame_buf with an ethernet frame from passt and prepends virtio_net_hdr to it
-    fn read_into_rx_frame_buf_from_passt(&mut self) -> result::Result<(), passt::ReadError> {
-        let mut len = 0;
-        len += write_virtio_net_hdr(&mut self.rx_frame_buf);
-        len += self.passt.read_frame(&mut self.rx_frame_buf[len..])?;
-        self.rx_frame_buf_len = len;
+    /// Fills the rx_frame_buf of `pair` with an ethernet frame from its backend and prepends
+    /// virtio_net_hdr to it. Frames the guest filters out, or that arrive while the link is
+    /// down, are dropped.
+    fn read_into_rx_frame_buf_from_backend(
+        &mut self,
+        pair: usize,
+    ) -> result::Result<(), ReadError> {
+        let qp = &mut self.pairs[pair];
+        let len = loop {
+            let mut len = 0;
+            len += write_virtio_net_hdr(&mut qp.rx_frame_buf);
+            let hdr_len = len;
+            len += qp.backend.read_frame(len, &mut qp.rx_frame_buf)?;
+            // Capture what arrived on the wire, including frames the guest
+            // filters out or can't see while the link is down.
+            Self::capture(
+                &mut self.pcap,
+                &qp.rx_frame_buf[hdr_len..len],
+                Direction::Inbound,
+            );
+            if self.link_up && self.rx_filter.accepts(&qp.rx_frame_buf[hdr_len..len]) {
+                break len;
+            }
+            log::trace!("Dropped eth frame filtered out by the guest");
+        };
+        qp.rx_frame_buf_len = len;
         Ok(())
     }
 }
@@ -444,11 +927,12 @@ impl VirtioDevice for Net {
     }
 
     fn queues(&self) -> &[Queue] {
-        &self.queues
+        &self.queues[..self.num_queues_in_use()]
     }
 
     fn queues_mut(&mut self) -> &mut [Queue] {
-        &mut self.queues
+        let num_queues = self.num_queues_in_use();
+        &mut self.queues[..num_queues]
     }
 
     fn queue_events(&self) -> &[EventFd] {
@@ -467,12 +951,18 @@ impl VirtioDevice for Net {
         self.irq_line = Some(irq);
     }
 
-    fn read_config(&self, offset: u64, data: &mut [u8]) {
-        log::warn!(
-            "Net: guest driver attempted to read device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space = self.config_space.as_slice();
+        let config_len = config_space.len() as u64;
+        if offset >= config_len {
+            log::error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
@@ -488,6 +978,26 @@ impl VirtioDevice for Net {
             log::error!("Net: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
         }
+        for qp in self.pairs.iter_mut() {
+            if let Err(e) = qp.backend.set_offloads(self.acked_features) {
+                log::error!("Net: Cannot set offloads of the backend: {e}");
+                return Err(super::super::ActivateError::BadActivate);
+            }
+        }
+        // The guest sets up the filter and its address again.
+        self.rx_filter = RxFilter::new(self.mac, self.has_feature(VIRTIO_NET_F_CTRL_VLAN));
+        self.config_space.mac = self.mac.unwrap_or_default();
+        self.set_status(VIRTIO_NET_S_ANNOUNCE, false);
+
+        // The guest starts with a single queue pair, and enables the others
+        // through the control queue.
+        self.active_pairs = 1;
+        for qp in self.pairs.iter_mut().skip(1) {
+            if let Err(e) = qp.backend.set_enabled(false) {
+                log::error!("Net: Cannot disable the backend queue: {e}");
+                return Err(super::super::ActivateError::BadActivate);
+            }
+        }
         self.device_state = DeviceState::Activated(mem);
         Ok(())
     }
@@ -499,3 +1009,209 @@ impl VirtioDevice for Net {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use polly::event_manager::{EventManager, Subscriber};
+    use utils::epoll::{EpollEvent, EventSet};
+
+    use super::*;
+    use crate::rate_limiter::TokenBucketConfig;
+    use crate::virtio::net::test_utils::{activate, guest_memory, guest_status, mock_net};
+
+    fn process_event(net: &mut Net, fd: RawFd) {
+        net.process(
+            &EpollEvent::new(EventSet::IN, fd as u64),
+            &mut EventManager::new().unwrap(),
+        );
+    }
+
+    // Lets a frame through every 50ms, without bursts.
+    fn frame_limit() -> RateLimiterConfig {
+        RateLimiterConfig {
+            ops: TokenBucketConfig { rate: 20, burst: 1 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rx_rate_limiter() {
+        let (mut net, states) = mock_net(1);
+        let mem = guest_memory();
+        let queues = activate(&mut net, &mem, 1 << VIRTIO_F_VERSION_1);
+        net.update_rx_rate_limiter(frame_limit());
+
+        let frames: Vec<_> = (0..3u8).map(|i| vec![i; 64]).collect();
+        states[0].lock().unwrap().rx.extend(frames.iter().cloned());
+        queues.add_rx_buffers(RX_INDEX, 4);
+        net.process_backend_socket_readable(0);
+        assert_eq!(queues.received_frames(RX_INDEX), frames[..1]);
+        assert!(net.rx_rate_limiter.is_blocked());
+
+        // The frames held back go through as the timer expires.
+        for received in 2..=3 {
+            let fd = net.rx_rate_limiter.as_raw_fd();
+            process_event(&mut net, fd);
+            assert_eq!(queues.received_frames(RX_INDEX), frames[..received]);
+        }
+        assert!(states[0].lock().unwrap().rx.is_empty());
+    }
+
+    #[test]
+    fn test_tx_rate_limiter() {
+        let (mut net, states) = mock_net(1);
+        let mem = guest_memory();
+        let queues = activate(&mut net, &mem, 1 << VIRTIO_F_VERSION_1);
+        net.update_tx_rate_limiter(frame_limit());
+
+        let frames: Vec<_> = (0..3u8).map(|i| vec![i; 64]).collect();
+        for frame in &frames {
+            queues.add_tx_frame(TX_INDEX, frame);
+        }
+        net.queue_evts[TX_INDEX].write(1).unwrap();
+        let fd = net.queue_evts[TX_INDEX].as_raw_fd();
+        process_event(&mut net, fd);
+        assert_eq!(states[0].lock().unwrap().tx, frames[..1]);
+        assert_eq!(queues.used(TX_INDEX), 1);
+        assert!(net.tx_rate_limiter.is_blocked());
+
+        // The frames left in the queue go through as the timer expires.
+        for sent in 2..=3 {
+            let fd = net.tx_rate_limiter.as_raw_fd();
+            process_event(&mut net, fd);
+            assert_eq!(states[0].lock().unwrap().tx, frames[..sent]);
+            assert_eq!(queues.used(TX_INDEX), sent as u16);
+        }
+    }
+
+    /// Whether the device told the driver its config space changed.
+    fn config_changed(net: &Net) -> bool {
+        let status = net.interrupt_status.swap(0, Ordering::SeqCst);
+        let _ = net.interrupt_evt.read();
+        status & VIRTIO_MMIO_INT_CONFIG as usize != 0
+    }
+
+    #[test]
+    fn test_link_state() {
+        let (mut net, states) = mock_net(1);
+        assert_eq!(guest_status(&net), VIRTIO_NET_S_LINK_UP);
+        // Before activation, the guest just finds the link down.
+        net.set_link_state(false).unwrap();
+        assert_eq!(guest_status(&net), 0);
+        assert!(!config_changed(&net));
+        net.set_link_state(true).unwrap();
+
+        let mem = guest_memory();
+        let queues = activate(
+            &mut net,
+            &mem,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_STATUS,
+        );
+        net.set_link_state(false).unwrap();
+        assert_eq!(guest_status(&net), 0);
+        assert!(config_changed(&net));
+        // Nothing changes if the link already is in that state.
+        net.set_link_state(false).unwrap();
+        assert!(!config_changed(&net));
+
+        // Frames are dropped both ways while the link is down.
+        states[0].lock().unwrap().rx.push_back(vec![0xff; 64]);
+        queues.add_rx_buffers(RX_INDEX, 1);
+        net.process_backend_socket_readable(0);
+        assert!(states[0].lock().unwrap().rx.is_empty());
+        assert!(queues.received_frames(RX_INDEX).is_empty());
+        queues.add_tx_frame(TX_INDEX, &[0xff; 64]);
+        net.queue_evts[TX_INDEX].write(1).unwrap();
+        net.process_tx_queue_event(0);
+        assert_eq!(queues.used(TX_INDEX), 1);
+        assert!(states[0].lock().unwrap().tx.is_empty());
+
+        // Without VIRTIO_NET_F_GUEST_ANNOUNCE, the guest isn't asked to
+        // announce itself when the link comes back.
+        net.set_link_state(true).unwrap();
+        assert_eq!(guest_status(&net), VIRTIO_NET_S_LINK_UP);
+        assert!(config_changed(&net));
+        let frame = vec![0xff; 64];
+        states[0].lock().unwrap().rx.push_back(frame.clone());
+        net.process_backend_socket_readable(0);
+        assert_eq!(queues.received_frames(RX_INDEX), [frame]);
+    }
+
+    #[test]
+    fn test_announce() {
+        let (mut net, _states) = mock_net(1);
+        // There is no guest to announce itself yet.
+        net.announce().unwrap();
+        assert_eq!(guest_status(&net), VIRTIO_NET_S_LINK_UP);
+
+        let mem = guest_memory();
+        activate(
+            &mut net,
+            &mem,
+            1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_NET_F_STATUS
+                | 1 << VIRTIO_NET_F_CTRL_VQ
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE,
+        );
+        net.announce().unwrap();
+        assert_eq!(
+            guest_status(&net),
+            VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE
+        );
+        assert!(config_changed(&net));
+
+        // The guest announces itself when the link comes back up.
+        net.set_status(VIRTIO_NET_S_ANNOUNCE, false);
+        net.set_link_state(false).unwrap();
+        assert_eq!(guest_status(&net), 0);
+        assert!(config_changed(&net));
+        net.set_link_state(true).unwrap();
+        assert_eq!(
+            guest_status(&net),
+            VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE
+        );
+        assert!(config_changed(&net));
+
+        // A reset forgets about pending announcements.
+        net.activate(mem.clone()).unwrap();
+        assert_eq!(guest_status(&net), VIRTIO_NET_S_LINK_UP);
+
+        // Announcing needs the guest to support it.
+        let (mut net, _states) = mock_net(1);
+        activate(
+            &mut net,
+            &mem,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_STATUS,
+        );
+        net.announce().unwrap();
+        assert_eq!(guest_status(&net), VIRTIO_NET_S_LINK_UP);
+        assert!(!config_changed(&net));
+    }
+
+    #[test]
+    fn test_restart_backend() {
+        let (mut net, states) = mock_net(2);
+        let mem = guest_memory();
+        activate(
+            &mut net,
+            &mem,
+            1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_NET_F_STATUS
+                | 1 << VIRTIO_NET_F_CTRL_VQ
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE,
+        );
+
+        // Only the backend that exited is restarted, and the guest announces
+        // itself to the new one.
+        net.restart_backend(1).unwrap();
+        assert_eq!(states[0].lock().unwrap().restarts, 0);
+        assert_eq!(states[1].lock().unwrap().restarts, 1);
+        assert_eq!(
+            guest_status(&net),
+            VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE
+        );
+        assert!(config_changed(&net));
+    }
+}
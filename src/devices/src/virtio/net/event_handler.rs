@@ -1,6 +1,7 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::io;
 use std::os::unix::io::AsRawFd;
 
 use polly::event_manager::{EventManager, Subscriber};
@@ -41,6 +42,68 @@ impl Net {
             log::error!("Failed to unregister net activate evt: {:?}", e);
         });
     }
+
+    /// Schedules a restart of the backend of `pair` after its process
+    /// exited, polling the timer of the restart instead of its socket.
+    fn process_backend_hang_up(
+        &mut self,
+        pair: usize,
+        event_manager: &mut EventManager,
+    ) -> io::Result<()> {
+        let old_fd = self.raw_backend_socket_fd(pair);
+        let timer_fd = self
+            .raw_backend_restart_timer_fd(pair)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        let self_subscriber = event_manager
+            .subscriber(old_fd)
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+        event_manager
+            .unregister(old_fd)
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+
+        self.schedule_backend_restart(pair)?;
+
+        event_manager
+            .register(
+                timer_fd,
+                EpollEvent::new(EventSet::IN, timer_fd as u64),
+                self_subscriber,
+            )
+            .map_err(|e| io::Error::other(format!("{e:?}")))
+    }
+
+    /// Restarts the backend of `pair` once the timer of the restart expired,
+    /// moving the registration of the timer over to the new socket. If the
+    /// process can't be started, it is tried again later.
+    fn process_backend_restart_timer(
+        &mut self,
+        pair: usize,
+        event_manager: &mut EventManager,
+    ) -> io::Result<()> {
+        if let Err(e) = self.restart_backend(pair) {
+            log::warn!("Failed to restart the backend of virtio-net: {e}");
+            return self.schedule_backend_restart(pair);
+        }
+
+        let timer_fd = self.raw_backend_restart_timer_fd(pair).unwrap();
+        let self_subscriber = event_manager
+            .subscriber(timer_fd)
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+        event_manager
+            .unregister(timer_fd)
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+        let new_fd = self.raw_backend_socket_fd(pair);
+        event_manager
+            .register(new_fd, backend_event(new_fd), self_subscriber)
+            .map_err(|e| io::Error::other(format!("{e:?}")))
+    }
+}
+
+fn backend_event(fd: i32) -> EpollEvent {
+    EpollEvent::new(
+        EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED | EventSet::READ_HANG_UP,
+        fd as u64,
+    )
 }
 
 impl Subscriber for Net {
@@ -49,34 +112,62 @@ impl Subscriber for Net {
         let event_set = event.event_set();
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let passt_socket = self.raw_passt_socket_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let queue_index = self
+                .queue_evts
+                .iter()
+                .position(|evt| evt.as_raw_fd() == source);
+            let backend_pair = (0..self.num_queue_pairs())
+                .find(|&pair| self.raw_backend_socket_fd(pair) == source);
+            let restart_pair = (0..self.num_queue_pairs())
+                .find(|&pair| self.raw_backend_restart_timer_fd(pair) == Some(source));
 
-            match event_set {
-                EventSet::IN if source == activate_fd => {
+            match (queue_index, backend_pair, restart_pair) {
+                _ if event_set == EventSet::IN && source == activate_fd => {
                     self.process_activate_event(evmgr);
                 }
-                EventSet::IN if source == virtq_rx_ev_fd => {
-                    self.process_rx_queue_event();
+                #[cfg(target_os = "linux")]
+                _ if source == self.rx_rate_limiter.as_raw_fd() => {
+                    self.process_rx_rate_limiter_event();
                 }
-                EventSet::IN if source == virtq_tx_ev_fd => {
-                    self.process_tx_queue_event();
+                #[cfg(target_os = "linux")]
+                _ if source == self.tx_rate_limiter.as_raw_fd() => {
+                    self.process_tx_rate_limiter_event();
+                }
+                (_, _, Some(pair)) => {
+                    if let Err(e) = self.process_backend_restart_timer(pair, evmgr) {
+                        log::error!(
+                            "Failed to restart the backend, virtio-net will stop working: {e}"
+                        );
+                        eprintln!("LIBKRUN VIRTIO-NET FATAL: Backend process couldn't be restarted! Networking is now disabled!");
+                    }
                 }
-                _ if source == passt_socket => {
+                (Some(index), _, _) if event_set == EventSet::IN => {
+                    if index == self.ctrl_queue_index() {
+                        self.process_ctrl_queue_event();
+                    } else if index % 2 == RX_INDEX {
+                        self.process_rx_queue_event(index / 2);
+                    } else if index % 2 == TX_INDEX {
+                        self.process_tx_queue_event(index / 2);
+                    }
+                }
+                (_, Some(pair), _) => {
                     if event_set.contains(EventSet::HANG_UP)
                         || event_set.contains(EventSet::READ_HANG_UP)
                     {
-                        log::error!("Got {event_set:?} on passt fd, virtio-net will stop working");
-                        eprintln!("LIBKRUN VIRTIO-NET FATAL: Passt process seems to have quit or crashed! Networking is now disabled!");
+                        if let Err(e) = self.process_backend_hang_up(pair, evmgr) {
+                            log::error!(
+                                "Got {event_set:?} on backend fd, virtio-net will stop working: {e}"
+                            );
+                            eprintln!("LIBKRUN VIRTIO-NET FATAL: Backend process seems to have quit or crashed! Networking is now disabled!");
+                        }
                     } else {
                         if event_set.contains(EventSet::IN) {
-                            self.process_passt_socket_readable()
+                            self.process_backend_socket_readable(pair)
                         }
 
                         if event_set.contains(EventSet::OUT) {
-                            self.process_passt_socket_writeable()
+                            self.process_backend_socket_writeable(pair)
                         }
                     }
                 }
@@ -98,17 +189,21 @@ impl Subscriber for Net {
 
     fn interest_list(&self) -> Vec<EpollEvent> {
         if self.is_activated() {
-            vec![
-                EpollEvent::new(EventSet::IN, self.queue_evts[RX_INDEX].as_raw_fd() as u64),
-                EpollEvent::new(EventSet::IN, self.queue_evts[TX_INDEX].as_raw_fd() as u64),
-                EpollEvent::new(
-                    EventSet::IN
-                        | EventSet::OUT
-                        | EventSet::EDGE_TRIGGERED
-                        | EventSet::READ_HANG_UP,
-                    self.raw_passt_socket_fd() as u64,
-                ),
-            ]
+            let queue_events = self
+                .queue_evts
+                .iter()
+                .map(|evt| EpollEvent::new(EventSet::IN, evt.as_raw_fd() as u64));
+            let backend_events = (0..self.num_queue_pairs())
+                .map(|pair| backend_event(self.raw_backend_socket_fd(pair)));
+            #[cfg(target_os = "linux")]
+            let rate_limiter_events = [&self.rx_rate_limiter, &self.tx_rate_limiter]
+                .map(|limiter| EpollEvent::new(EventSet::IN, limiter.as_raw_fd() as u64));
+            #[cfg(not(target_os = "linux"))]
+            let rate_limiter_events = [];
+            queue_events
+                .chain(backend_events)
+                .chain(rate_limiter_events)
+                .collect()
         } else {
             vec![EpollEvent::new(
                 EventSet::IN,
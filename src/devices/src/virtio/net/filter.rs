@@ -0,0 +1,242 @@
+//! The receive filter the guest sets up through the control queue, which
+//! decides which frames from the backend reach it.
+
+/// Entries of the MAC tables past which the device gives up on filtering
+/// that kind of address, like QEMU does.
+const MAC_TABLE_ENTRIES: usize = 64;
+/// VLAN IDs are 12 bits long.
+const MAX_VLAN_ID: u16 = 4095;
+
+const ETH_ALEN: usize = 6;
+const ETH_HLEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
+const BROADCAST: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+/// Reads a MAC table of a `VIRTIO_NET_CTRL_MAC_TABLE_SET` command: a 32-bit
+/// count of entries, followed by the entries. Returns the table and the rest
+/// of `data`.
+fn parse_mac_table(data: &[u8]) -> Option<(Vec<[u8; ETH_ALEN]>, &[u8])> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (entries, data) = data.split_at(4);
+    let entries = u32::from_le_bytes(entries.try_into().unwrap()) as usize;
+    let len = entries.checked_mul(ETH_ALEN)?;
+    if data.len() < len {
+        return None;
+    }
+    let (table, rest) = data.split_at(len);
+    let table = table
+        .chunks_exact(ETH_ALEN)
+        .map(|mac| mac.try_into().unwrap())
+        .collect();
+    Some((table, rest))
+}
+
+#[derive(Debug)]
+pub struct RxFilter {
+    /// Address of the guest interface, unless it's unknown because the
+    /// guest picked it and didn't tell.
+    mac: Option<[u8; ETH_ALEN]>,
+    promisc: bool,
+    allmulti: bool,
+    /// Extra addresses the guest accepts, or `None` if it accepts too many
+    /// of them to list.
+    unicast: Option<Vec<[u8; ETH_ALEN]>>,
+    multicast: Option<Vec<[u8; ETH_ALEN]>>,
+    /// Bitmap of the VLANs the guest accepts tagged frames from, if it
+    /// filters them.
+    vlans: Option<Box<[u64; 64]>>,
+}
+
+impl RxFilter {
+    /// A filter letting everything through, as the device does until the
+    /// guest sets it up. `vlan_filtering` says whether the guest is going to
+    /// tell which VLANs it accepts, in which case tagged frames are dropped
+    /// until it does.
+    pub fn new(mac: Option<[u8; ETH_ALEN]>, vlan_filtering: bool) -> Self {
+        RxFilter {
+            mac,
+            promisc: true,
+            allmulti: false,
+            unicast: Some(Vec::new()),
+            multicast: Some(Vec::new()),
+            vlans: vlan_filtering.then(|| Box::new([0; 64])),
+        }
+    }
+
+    pub fn set_mac(&mut self, mac: [u8; ETH_ALEN]) {
+        self.mac = Some(mac);
+    }
+
+    pub fn set_promisc(&mut self, on: bool) {
+        self.promisc = on;
+    }
+
+    pub fn set_allmulti(&mut self, on: bool) {
+        self.allmulti = on;
+    }
+
+    /// Replaces the unicast and multicast tables with the ones of a
+    /// `VIRTIO_NET_CTRL_MAC_TABLE_SET` command, returning whether it's valid.
+    pub fn set_mac_tables(&mut self, data: &[u8]) -> bool {
+        let Some((unicast, data)) = parse_mac_table(data) else {
+            return false;
+        };
+        let Some((multicast, data)) = parse_mac_table(data) else {
+            return false;
+        };
+        if !data.is_empty() {
+            return false;
+        }
+        self.unicast = Some(unicast).filter(|table| table.len() <= MAC_TABLE_ENTRIES);
+        self.multicast = Some(multicast).filter(|table| table.len() <= MAC_TABLE_ENTRIES);
+        true
+    }
+
+    /// Starts or stops accepting tagged frames from VLAN `id`, returning
+    /// whether it's valid.
+    pub fn set_vlan(&mut self, id: u16, on: bool) -> bool {
+        let Some(vlans) = self.vlans.as_mut() else {
+            return false;
+        };
+        if id > MAX_VLAN_ID {
+            return false;
+        }
+        let (word, bit) = (id as usize / 64, id % 64);
+        if on {
+            vlans[word] |= 1 << bit;
+        } else {
+            vlans[word] &= !(1 << bit);
+        }
+        true
+    }
+
+    /// Whether the guest wants `frame`.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if frame.len() < ETH_HLEN {
+            return false;
+        }
+        if let Some(vlans) = &self.vlans {
+            if u16::from_be_bytes([frame[12], frame[13]]) == ETH_P_8021Q {
+                let Some(tci) = frame.get(14..16) else {
+                    return false;
+                };
+                let id = u16::from_be_bytes([tci[0], tci[1]]) & MAX_VLAN_ID;
+                if vlans[id as usize / 64] & (1 << (id % 64)) == 0 {
+                    return false;
+                }
+            }
+        }
+        if self.promisc {
+            return true;
+        }
+
+        let dst: [u8; ETH_ALEN] = frame[..ETH_ALEN].try_into().unwrap();
+        if dst == BROADCAST {
+            true
+        } else if dst[0] & 1 != 0 {
+            self.allmulti
+                || self
+                    .multicast
+                    .as_ref()
+                    .is_none_or(|table| table.contains(&dst))
+        } else {
+            self.mac.is_none_or(|mac| mac == dst)
+                || self
+                    .unicast
+                    .as_ref()
+                    .is_none_or(|table| table.contains(&dst))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const OTHER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef];
+    const MULTICAST_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x12];
+
+    fn frame(dst: [u8; 6], vlan: Option<u16>) -> Vec<u8> {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&OTHER_MAC);
+        if let Some(id) = vlan {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        frame.extend_from_slice(&0x0800u16.to_be_bytes());
+        frame.extend_from_slice(&[0; 46]);
+        frame
+    }
+
+    fn mac_tables(unicast: &[[u8; 6]], multicast: &[[u8; 6]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for table in [unicast, multicast] {
+            data.extend_from_slice(&(table.len() as u32).to_le_bytes());
+            for mac in table {
+                data.extend_from_slice(mac);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_promisc() {
+        let mut filter = RxFilter::new(Some(GUEST_MAC), false);
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(GUEST_MAC, None)));
+        assert!(filter.accepts(&frame(BROADCAST, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(!filter.accepts(&frame(MULTICAST_MAC, None)));
+        assert!(!filter.accepts(&GUEST_MAC));
+
+        filter.set_allmulti(true);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+    }
+
+    #[test]
+    fn test_mac_tables() {
+        let mut filter = RxFilter::new(None, false);
+        filter.set_promisc(false);
+        // Without knowing the guest's address, unicast frames go through.
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        filter.set_mac(GUEST_MAC);
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+
+        assert!(filter.set_mac_tables(&mac_tables(&[OTHER_MAC], &[MULTICAST_MAC])));
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        let too_many = vec![OTHER_MAC; MAC_TABLE_ENTRIES + 1];
+        assert!(filter.set_mac_tables(&mac_tables(&[], &too_many)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame([0x01, 0, 0, 0, 0, 1], None)));
+
+        let mut truncated = mac_tables(&[OTHER_MAC], &[]);
+        truncated.pop();
+        assert!(!filter.set_mac_tables(&truncated));
+        assert!(!filter.set_mac_tables(&[0xff; 8]));
+    }
+
+    #[test]
+    fn test_vlans() {
+        let mut filter = RxFilter::new(Some(GUEST_MAC), false);
+        assert!(!filter.set_vlan(10, true));
+        assert!(filter.accepts(&frame(GUEST_MAC, Some(10))));
+
+        let mut filter = RxFilter::new(Some(GUEST_MAC), true);
+        assert!(filter.accepts(&frame(GUEST_MAC, None)));
+        assert!(!filter.accepts(&frame(GUEST_MAC, Some(10))));
+        assert!(filter.set_vlan(10, true));
+        assert!(filter.accepts(&frame(GUEST_MAC, Some(10))));
+        // The priority bits don't matter.
+        assert!(filter.accepts(&frame(GUEST_MAC, Some(0xe000 | 10))));
+        assert!(filter.set_vlan(10, false));
+        assert!(!filter.accepts(&frame(GUEST_MAC, Some(10))));
+        assert!(!filter.set_vlan(4096, true));
+    }
+}
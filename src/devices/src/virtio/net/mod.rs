@@ -3,25 +3,52 @@
 
 use std::{io, result};
 pub const MAX_BUFFER_SIZE: usize = 65562;
+// The smallest MTU an IPv4 host must accept.
+pub const MIN_MTU: u16 = 68;
+// The MTU the guest uses when the device doesn't advertise one.
+pub const DEFAULT_MTU: u16 = 1500;
 pub const QUEUE_SIZE: u16 = 128;
-pub const NUM_QUEUES: usize = 2;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+pub const DEFAULT_NUM_QUEUE_PAIRS: u16 = 1;
+pub const MAX_QUEUE_PAIRS: u16 = 16;
+// The index of the rx queue of a queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of a queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
 
+mod backend;
+mod ctrl;
 pub mod device;
 pub mod event_handler;
+mod filter;
+mod nat;
 mod passt;
+#[cfg(target_os = "linux")]
+mod passt_process;
+mod pcap;
+mod switch;
+#[cfg(target_os = "linux")]
+mod tap;
+#[cfg(test)]
+mod test_utils;
+mod unixgram;
 
-pub use self::device::Net;
+pub use self::device::{Net, VirtioNetBackend};
 pub use self::event_handler::*;
+#[cfg(target_os = "linux")]
+pub use self::passt_process::PasstCommand;
+pub use self::pcap::PcapConfig;
+pub use self::switch::Switch;
 
 #[derive(Debug)]
 pub enum Error {
     /// EventFd error.
     EventFd(io::Error),
+    /// Failed to open the backend.
+    Backend(io::Error),
+    /// Failed to create the capture file.
+    Pcap(io::Error),
+    /// Failed to create a rate limiter.
+    RateLimiter(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
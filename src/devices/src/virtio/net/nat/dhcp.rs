@@ -0,0 +1,147 @@
+//! A DHCP server that hands the guest the only address of its network.
+
+use std::net::Ipv4Addr;
+
+use smoltcp::wire::{DhcpMessageType, DhcpRepr};
+
+use super::{DNS_ADDR, GATEWAY_ADDR, GUEST_ADDR, NETMASK};
+
+const LEASE_DURATION: u32 = 24 * 60 * 60;
+
+/// Builds the reply to a DHCP message from the guest, if it needs one.
+pub fn reply(request: &DhcpRepr) -> Option<DhcpRepr<'static>> {
+    if request
+        .server_identifier
+        .is_some_and(|server| server != GATEWAY_ADDR)
+    {
+        // The guest picked another server.
+        return None;
+    }
+
+    let message_type = match request.message_type {
+        DhcpMessageType::Discover => DhcpMessageType::Offer,
+        DhcpMessageType::Request => {
+            // A client renewing its lease has its address in ciaddr instead.
+            let requested = request.requested_ip.unwrap_or(request.client_ip);
+            if requested == GUEST_ADDR {
+                DhcpMessageType::Ack
+            } else {
+                DhcpMessageType::Nak
+            }
+        }
+        _ => return None,
+    };
+
+    let mut reply = DhcpRepr {
+        message_type,
+        transaction_id: request.transaction_id,
+        secs: 0,
+        client_hardware_address: request.client_hardware_address,
+        client_ip: Ipv4Addr::UNSPECIFIED,
+        your_ip: Ipv4Addr::UNSPECIFIED,
+        server_ip: Ipv4Addr::UNSPECIFIED,
+        router: None,
+        subnet_mask: None,
+        relay_agent_ip: request.relay_agent_ip,
+        broadcast: false,
+        requested_ip: None,
+        client_identifier: None,
+        server_identifier: Some(GATEWAY_ADDR),
+        parameter_request_list: None,
+        dns_servers: None,
+        max_size: None,
+        lease_duration: None,
+        renew_duration: None,
+        rebind_duration: None,
+        additional_options: &[],
+    };
+    if message_type != DhcpMessageType::Nak {
+        reply.your_ip = GUEST_ADDR;
+        reply.server_ip = GATEWAY_ADDR;
+        reply.router = Some(GATEWAY_ADDR);
+        reply.subnet_mask = Some(NETMASK);
+        reply.dns_servers = Some([DNS_ADDR][..].try_into().unwrap());
+        reply.lease_duration = Some(LEASE_DURATION);
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smoltcp::wire::EthernetAddress;
+
+    fn request(message_type: DhcpMessageType) -> DhcpRepr<'static> {
+        DhcpRepr {
+            message_type,
+            transaction_id: 0x1234_5678,
+            secs: 3,
+            client_hardware_address: EthernetAddress([0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee]),
+            client_ip: Ipv4Addr::UNSPECIFIED,
+            your_ip: Ipv4Addr::UNSPECIFIED,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Addr::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: None,
+            parameter_request_list: Some(&[1, 3, 6]),
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        }
+    }
+
+    #[test]
+    fn test_lease() {
+        let discover = request(DhcpMessageType::Discover);
+        let offer = reply(&discover).unwrap();
+        assert_eq!(offer.message_type, DhcpMessageType::Offer);
+        assert_eq!(offer.transaction_id, discover.transaction_id);
+        assert_eq!(
+            offer.client_hardware_address,
+            discover.client_hardware_address
+        );
+        assert_eq!(offer.your_ip, GUEST_ADDR);
+        assert_eq!(offer.router, Some(GATEWAY_ADDR));
+        assert_eq!(offer.subnet_mask, Some(NETMASK));
+        assert_eq!(offer.dns_servers.as_deref(), Some(&[DNS_ADDR][..]));
+        assert_eq!(offer.server_identifier, Some(GATEWAY_ADDR));
+
+        let mut request_msg = request(DhcpMessageType::Request);
+        request_msg.requested_ip = Some(GUEST_ADDR);
+        request_msg.server_identifier = Some(GATEWAY_ADDR);
+        let ack = reply(&request_msg).unwrap();
+        assert_eq!(ack.message_type, DhcpMessageType::Ack);
+        assert_eq!(ack.your_ip, GUEST_ADDR);
+        assert_eq!(ack.lease_duration, Some(LEASE_DURATION));
+
+        // Renewal.
+        let mut renew = request(DhcpMessageType::Request);
+        renew.client_ip = GUEST_ADDR;
+        assert_eq!(reply(&renew).unwrap().message_type, DhcpMessageType::Ack);
+    }
+
+    #[test]
+    fn test_refusals() {
+        let mut other_addr = request(DhcpMessageType::Request);
+        other_addr.requested_ip = Some(Ipv4Addr::new(192, 168, 1, 10));
+        let nak = reply(&other_addr).unwrap();
+        assert_eq!(nak.message_type, DhcpMessageType::Nak);
+        assert_eq!(nak.your_ip, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(nak.router, None);
+
+        let mut other_server = request(DhcpMessageType::Request);
+        other_server.requested_ip = Some(GUEST_ADDR);
+        other_server.server_identifier = Some(Ipv4Addr::new(192, 168, 1, 1));
+        assert!(reply(&other_server).is_none());
+
+        assert!(reply(&request(DhcpMessageType::Release)).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! ICMP echo requests from the guest, which are sent from unprivileged ping
+//! sockets, or raw ones if those aren't allowed.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::{recvfrom, sendto, MsgFlags, SockaddrIn};
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr};
+
+use super::{checksum_caps, AddressMap, GuestLink, DNS_ADDR, GATEWAY_ADDR};
+
+/// How long an echo identifier stays open once the guest stops using it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Opens a nonblocking socket of `ty` for `protocol`.
+pub(super) fn open_socket(ty: libc::c_int, protocol: libc::c_int) -> io::Result<OwnedFd> {
+    // Safe because we check the result and take ownership of the descriptor.
+    let fd = unsafe { libc::socket(libc::AF_INET, ty, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created the socket and nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    fcntl(
+        fd.as_raw_fd(),
+        FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )?;
+    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    Ok(fd)
+}
+
+/// Sends an ICMP message built from `repr` from `src` to `dst` in the guest.
+fn send_to_guest(link: &GuestLink, src: Ipv4Addr, dst: Ipv4Addr, repr: &Icmpv4Repr) {
+    let ip_repr = Ipv4Repr {
+        src_addr: src,
+        dst_addr: dst,
+        next_header: IpProtocol::Icmp,
+        payload_len: repr.buffer_len(),
+        hop_limit: 64,
+    };
+    link.send_ipv4(&ip_repr, |buf| {
+        repr.emit(&mut Icmpv4Packet::new_unchecked(buf), &checksum_caps())
+    });
+}
+
+struct Flow {
+    socket: OwnedFd,
+    /// Whether the socket is a raw one, which doesn't pick identifiers and
+    /// hands out the IP header.
+    raw: bool,
+    guest_addr: Ipv4Addr,
+    last_used: Instant,
+}
+
+/// The ping sockets, by the identifier the guest uses. The host picks its
+/// own identifiers, which are swapped for the guest's in the replies.
+#[derive(Default)]
+pub struct IcmpNat {
+    flows: HashMap<u16, Flow>,
+    warned: bool,
+}
+
+impl IcmpNat {
+    /// Sends an ICMP message the guest sent from `src` to `dst`. Only echo
+    /// requests are passed on.
+    pub fn send(
+        &mut self,
+        link: &GuestLink,
+        addresses: &AddressMap,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        payload: &[u8],
+    ) {
+        let Ok(packet) = Icmpv4Packet::new_checked(payload) else {
+            return;
+        };
+        let Ok(Icmpv4Repr::EchoRequest {
+            ident,
+            seq_no,
+            data,
+        }) = Icmpv4Repr::parse(&packet, &checksum_caps())
+        else {
+            return;
+        };
+
+        if dst == GATEWAY_ADDR || dst == DNS_ADDR {
+            let reply = Icmpv4Repr::EchoReply {
+                ident,
+                seq_no,
+                data,
+            };
+            send_to_guest(link, dst, src, &reply);
+            return;
+        }
+        let Some(host_dst) = addresses.to_host(SocketAddrV4::new(dst, 0)) else {
+            return;
+        };
+
+        let flow = match self.flows.entry(ident) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Fall back to a raw socket if we aren't allowed to ping.
+                let (socket, raw) = match open_socket(libc::SOCK_DGRAM, libc::IPPROTO_ICMP)
+                    .map(|socket| (socket, false))
+                    .or_else(|_| {
+                        open_socket(libc::SOCK_RAW, libc::IPPROTO_ICMP).map(|socket| (socket, true))
+                    }) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        if !self.warned {
+                            self.warned = true;
+                            log::warn!(
+                                "nat: failed to open a ping socket, check net.ipv4.ping_group_range: {e}"
+                            );
+                        }
+                        return;
+                    }
+                };
+                entry.insert(Flow {
+                    socket,
+                    raw,
+                    guest_addr: src,
+                    last_used: Instant::now(),
+                })
+            }
+        };
+        flow.guest_addr = src;
+        flow.last_used = Instant::now();
+        // Unless the socket is raw, the kernel fills in the identifier and
+        // the checksum.
+        if let Err(e) = sendto(
+            flow.socket.as_raw_fd(),
+            payload,
+            &SockaddrIn::from(host_dst),
+            MsgFlags::MSG_DONTWAIT,
+        ) {
+            log::debug!("nat: failed to send an echo request to {host_dst}: {e}");
+        }
+    }
+
+    /// Passes the echo replies the host received on to the guest.
+    pub fn receive(&mut self, link: &GuestLink, addresses: &AddressMap) {
+        let mut buf = [0; u16::MAX as usize];
+        for (&ident, flow) in self.flows.iter_mut() {
+            while let Ok((len, Some(peer))) =
+                recvfrom::<SockaddrIn>(flow.socket.as_raw_fd(), &mut buf)
+            {
+                let mut data = &buf[..len];
+                // macOS hands out the IP header as well, like raw sockets.
+                if flow.raw || cfg!(target_os = "macos") {
+                    match Ipv4Packet::new_checked(data) {
+                        Ok(packet) => data = &data[packet.header_len() as usize..],
+                        Err(_) => continue,
+                    }
+                }
+                let Ok(packet) = Icmpv4Packet::new_checked(data) else {
+                    continue;
+                };
+                let Ok(Icmpv4Repr::EchoReply {
+                    ident: reply_ident,
+                    seq_no,
+                    data,
+                }) = Icmpv4Repr::parse(&packet, &checksum_caps())
+                else {
+                    continue;
+                };
+                // Raw sockets get every echo reply.
+                if flow.raw && reply_ident != ident {
+                    continue;
+                }
+                flow.last_used = Instant::now();
+                let peer =
+                    addresses.to_guest(SocketAddrV4::new(Ipv4Addr::from(peer.ip()), 0), false);
+                let reply = Icmpv4Repr::EchoReply {
+                    ident,
+                    seq_no,
+                    data,
+                };
+                send_to_guest(link, *peer.ip(), flow.guest_addr, &reply);
+            }
+        }
+    }
+
+    /// Closes the sockets that have been idle for too long.
+    pub fn expire(&mut self, now: Instant) {
+        self.flows
+            .retain(|_, flow| now.duration_since(flow.last_used) < IDLE_TIMEOUT);
+    }
+
+    pub fn poll_fds(&self, fds: &mut Vec<PollFd>) {
+        for flow in self.flows.values() {
+            fds.push(PollFd::new(flow.socket.as_raw_fd(), PollFlags::POLLIN));
+        }
+    }
+}
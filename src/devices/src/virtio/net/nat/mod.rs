@@ -0,0 +1,901 @@
+//! A userspace NAT, which lets the guest reach the network without any
+//! privileges or helper process on the host.
+//!
+//! The NAT runs on its own thread and exchanges frames with the device over a
+//! seqpacket socketpair, as if it were a vfkit-style server. It terminates the
+//! guest's TCP connections with smoltcp and opens matching connections from
+//! the host, relays UDP datagrams, ICMP echo requests and, when allowed to
+//! open raw sockets, any other IP protocol, and answers DHCP.
+//!
+//! The guest sees the same network as with slirp: it is `10.0.2.15/24`, the
+//! gateway `10.0.2.2` stands for the host's loopback interface and `10.0.2.3`
+//! forwards DNS to the host's first nameserver.
+
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::thread;
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{
+    recv, send, setsockopt, socketpair, sockopt, AddressFamily, MsgFlags, SockFlag, SockType,
+};
+use smoltcp::iface::{Config, Interface, SocketSet};
+use smoltcp::phy::{self, Checksum, ChecksumCapabilities, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    ArpPacket, ArpRepr, DhcpPacket, DhcpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, HardwareAddress, IpCidr, IpProtocol, Ipv4Packet, Ipv4Repr, TcpPacket, UdpPacket,
+    UdpRepr, DHCP_CLIENT_PORT, DHCP_SERVER_PORT, ETHERNET_HEADER_LEN, IPV4_HEADER_LEN,
+    UDP_HEADER_LEN,
+};
+
+use super::MAX_BUFFER_SIZE;
+
+mod dhcp;
+mod icmp;
+mod raw;
+mod tcp;
+mod udp;
+
+use self::icmp::IcmpNat;
+use self::raw::RawNat;
+use self::tcp::TcpNat;
+use self::udp::UdpNat;
+
+const PREFIX_LEN: u8 = 24;
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// Address of the host, as seen by the guest.
+const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// Address of the DNS forwarder.
+const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+/// Address the DHCP server hands out to the guest.
+const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Checksums the guest leaves to the host aren't filled in, so they are only
+/// computed, never verified.
+fn checksum_caps() -> ChecksumCapabilities {
+    let mut caps = ChecksumCapabilities::ignored();
+    caps.ipv4 = Checksum::Tx;
+    caps.udp = Checksum::Tx;
+    caps.tcp = Checksum::Tx;
+    caps.icmpv4 = Checksum::Tx;
+    caps
+}
+
+/// Returns the first IPv4 nameserver in the contents of a resolv.conf file.
+fn parse_nameserver(resolv_conf: &str) -> Option<Ipv4Addr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Maps the addresses the guest talks to onto the ones the host talks to,
+/// and back.
+#[derive(Clone, Copy, Debug)]
+struct AddressMap {
+    nameserver: Ipv4Addr,
+}
+
+impl AddressMap {
+    /// Returns where traffic the guest sends to `addr` goes, or None if it
+    /// doesn't leave the guest's network.
+    fn to_host(self, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *addr.ip();
+        let host_ip = if ip == GATEWAY_ADDR {
+            Ipv4Addr::LOCALHOST
+        } else if ip == DNS_ADDR {
+            self.nameserver
+        } else if (u32::from(ip) & u32::from(NETMASK))
+            == (u32::from(GUEST_ADDR) & u32::from(NETMASK))
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_unspecified()
+        {
+            return None;
+        } else {
+            ip
+        };
+        Some(SocketAddrV4::new(host_ip, addr.port()))
+    }
+
+    /// Returns the address the guest sees traffic from the host address
+    /// `addr` coming from. `dns` tells whether the guest sent the traffic
+    /// this answers to the DNS forwarder, rather than to `addr` itself.
+    fn to_guest(self, addr: SocketAddrV4, dns: bool) -> SocketAddrV4 {
+        if dns && *addr.ip() == self.nameserver && addr.port() == DNS_PORT {
+            SocketAddrV4::new(DNS_ADDR, DNS_PORT)
+        } else if addr.ip().is_loopback() {
+            SocketAddrV4::new(GATEWAY_ADDR, addr.port())
+        } else {
+            addr
+        }
+    }
+}
+
+/// Sends a frame to the guest. Frames that don't fit in the socket buffer are
+/// dropped, as on a real link.
+fn send_frame(fd: RawFd, frame: &[u8]) {
+    if let Err(e) = send(fd, frame, MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL) {
+        log::trace!("nat: dropped frame to the guest: {e}");
+    }
+}
+
+/// The NAT's end of the link with the guest.
+struct GuestLink {
+    fd: RawFd,
+    /// The guest's MTU, which no packet sent to it may exceed.
+    mtu: usize,
+    /// Learned from the frames the guest sends.
+    guest_mac: Option<EthernetAddress>,
+    /// Frame from the guest waiting to be handed to the TCP/IP stack.
+    rx_frame: Option<Vec<u8>>,
+}
+
+impl GuestLink {
+    /// Sends an IPv4 packet described by `repr`, whose payload is written by
+    /// `emit_payload`, to the guest.
+    fn send_ipv4(&self, repr: &Ipv4Repr, emit_payload: impl FnOnce(&mut [u8])) {
+        let Some(guest_mac) = self.guest_mac else {
+            return;
+        };
+        if IPV4_HEADER_LEN + repr.payload_len > self.mtu {
+            log::debug!("nat: dropping a packet larger than the guest's MTU");
+            return;
+        }
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + repr.payload_len];
+        EthernetRepr {
+            src_addr: GATEWAY_MAC,
+            dst_addr: guest_mac,
+            ethertype: EthernetProtocol::Ipv4,
+        }
+        .emit(&mut EthernetFrame::new_unchecked(&mut frame));
+        let mut packet = Ipv4Packet::new_unchecked(&mut frame[ETHERNET_HEADER_LEN..]);
+        repr.emit(&mut packet, &checksum_caps());
+        emit_payload(packet.payload_mut());
+        send_frame(self.fd, &frame);
+    }
+
+    /// Sends a UDP datagram to the guest.
+    fn send_udp(&self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        let repr = Ipv4Repr {
+            src_addr: *src.ip(),
+            dst_addr: *dst.ip(),
+            next_header: IpProtocol::Udp,
+            payload_len: UDP_HEADER_LEN + payload.len(),
+            hop_limit: 64,
+        };
+        self.send_ipv4(&repr, |buf| {
+            UdpRepr {
+                src_port: src.port(),
+                dst_port: dst.port(),
+            }
+            .emit(
+                &mut UdpPacket::new_unchecked(buf),
+                &(*src.ip()).into(),
+                &(*dst.ip()).into(),
+                payload.len(),
+                |buf| buf.copy_from_slice(payload),
+                &checksum_caps(),
+            )
+        });
+    }
+}
+
+struct GuestRxToken(Vec<u8>);
+
+impl phy::RxToken for GuestRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct GuestTxToken(RawFd);
+
+impl phy::TxToken for GuestTxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        send_frame(self.0, &frame);
+        result
+    }
+}
+
+impl phy::Device for GuestLink {
+    type RxToken<'a> = GuestRxToken;
+    type TxToken<'a> = GuestTxToken;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(GuestRxToken, GuestTxToken)> {
+        self.rx_frame
+            .take()
+            .map(|frame| (GuestRxToken(frame), GuestTxToken(self.fd)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<GuestTxToken> {
+        Some(GuestTxToken(self.fd))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        // This only limits the segments sent to the guest: the frames it sends
+        // may be larger when it uses TSO.
+        caps.max_transmission_unit = self.mtu + ETHERNET_HEADER_LEN;
+        caps.checksum = checksum_caps();
+        caps
+    }
+}
+
+struct Nat {
+    link: GuestLink,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    addresses: AddressMap,
+    tcp: TcpNat,
+    udp: UdpNat,
+    icmp: IcmpNat,
+    raw: RawNat,
+}
+
+impl Nat {
+    fn new(fd: RawFd, mtu: u16, nameserver: Ipv4Addr) -> Self {
+        let mut link = GuestLink {
+            fd,
+            mtu: mtu.into(),
+            guest_mac: None,
+            rx_frame: None,
+        };
+        let config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        let mut iface = Interface::new(config, &mut link, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for addr in [GATEWAY_ADDR, DNS_ADDR] {
+                addrs
+                    .push(IpCidr::new(addr.into(), PREFIX_LEN))
+                    .expect("too many addresses");
+            }
+        });
+        // Accept connections to any address, as long as they're routed
+        // through us.
+        iface.set_any_ip(true);
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(GATEWAY_ADDR)
+            .expect("too many routes");
+
+        Nat {
+            link,
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            addresses: AddressMap { nameserver },
+            tcp: TcpNat::default(),
+            udp: UdpNat::default(),
+            icmp: IcmpNat::default(),
+            raw: RawNat::default(),
+        }
+    }
+
+    /// Hands a frame to the TCP/IP stack.
+    fn ingress(&mut self, frame: &[u8]) {
+        self.link.rx_frame = Some(frame.to_vec());
+        self.iface
+            .poll_ingress_single(Instant::now(), &mut self.link, &mut self.sockets);
+    }
+
+    fn process_guest_frame(&mut self, frame: &[u8]) {
+        let Ok(eth) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        if eth.src_addr().is_unicast() {
+            self.link.guest_mac = Some(eth.src_addr());
+        }
+
+        match eth.ethertype() {
+            EthernetProtocol::Arp => {
+                // Only answer for our own addresses: with any_ip set, the
+                // stack would claim every address, including the guest's.
+                let Ok(arp) = ArpPacket::new_checked(eth.payload()) else {
+                    return;
+                };
+                if let Ok(ArpRepr::EthernetIpv4 {
+                    target_protocol_addr,
+                    ..
+                }) = ArpRepr::parse(&arp)
+                {
+                    if target_protocol_addr == GATEWAY_ADDR || target_protocol_addr == DNS_ADDR {
+                        self.ingress(frame);
+                    }
+                }
+            }
+            EthernetProtocol::Ipv4 => self.process_guest_ipv4(frame),
+            ethertype => log::trace!("nat: dropping {ethertype} frame from the guest"),
+        }
+    }
+
+    fn process_guest_ipv4(&mut self, frame: &[u8]) {
+        let Ok(packet) = Ipv4Packet::new_checked(&frame[ETHERNET_HEADER_LEN..]) else {
+            return;
+        };
+        let Ok(repr) = Ipv4Repr::parse(&packet, &checksum_caps()) else {
+            return;
+        };
+        if packet.more_frags() || packet.frag_offset() != 0 {
+            log::trace!("nat: dropping IPv4 fragment from the guest");
+            return;
+        }
+
+        match repr.next_header {
+            IpProtocol::Tcp => {
+                let Ok(segment) = TcpPacket::new_checked(packet.payload()) else {
+                    return;
+                };
+                let src = SocketAddrV4::new(repr.src_addr, segment.src_port());
+                let dst = SocketAddrV4::new(repr.dst_addr, segment.dst_port());
+                if segment.syn() && !segment.ack() {
+                    self.tcp
+                        .accept(&mut self.sockets, &self.addresses, src, dst);
+                }
+                self.ingress(frame);
+                self.tcp.drop_unaccepted(&mut self.sockets);
+            }
+            IpProtocol::Udp => {
+                let Ok(datagram) = UdpPacket::new_checked(packet.payload()) else {
+                    return;
+                };
+                let src = SocketAddrV4::new(repr.src_addr, datagram.src_port());
+                let dst = SocketAddrV4::new(repr.dst_addr, datagram.dst_port());
+                if dst.port() == DHCP_SERVER_PORT {
+                    self.process_dhcp(datagram.payload());
+                } else {
+                    self.udp.send(&self.addresses, src, dst, datagram.payload());
+                }
+            }
+            IpProtocol::Icmp => {
+                self.icmp.send(
+                    &self.link,
+                    &self.addresses,
+                    repr.src_addr,
+                    repr.dst_addr,
+                    packet.payload(),
+                );
+            }
+            protocol => {
+                self.raw.send(
+                    &self.addresses,
+                    protocol,
+                    repr.src_addr,
+                    repr.dst_addr,
+                    packet.payload(),
+                );
+            }
+        }
+    }
+
+    fn process_dhcp(&mut self, payload: &[u8]) {
+        let Ok(packet) = DhcpPacket::new_checked(payload) else {
+            return;
+        };
+        let Ok(request) = DhcpRepr::parse(&packet) else {
+            return;
+        };
+        let Some(reply) = dhcp::reply(&request) else {
+            return;
+        };
+        let mut buf = vec![0; reply.buffer_len()];
+        if reply
+            .emit(&mut DhcpPacket::new_unchecked(&mut buf))
+            .is_err()
+        {
+            return;
+        }
+        self.link.send_udp(
+            SocketAddrV4::new(GATEWAY_ADDR, DHCP_SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+            &buf,
+        );
+    }
+
+    /// Moves data between the guest's TCP connections and the host's until
+    /// neither side can take more.
+    fn pump_tcp(&mut self) {
+        loop {
+            let progress = self.tcp.pump(&mut self.sockets);
+            self.iface
+                .poll(Instant::now(), &mut self.link, &mut self.sockets);
+            self.tcp.drop_closed(&mut self.sockets);
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    /// Passes on to the guest whatever the host sockets received.
+    fn process_host(&mut self) {
+        self.udp.receive(&self.link, &self.addresses);
+        self.icmp.receive(&self.link, &self.addresses);
+        self.raw.receive(&self.link, &self.addresses);
+        self.pump_tcp();
+    }
+
+    fn run(mut self) {
+        let mut buf = vec![0; MAX_BUFFER_SIZE];
+        let mut fds = Vec::new();
+        loop {
+            fds.clear();
+            fds.push(PollFd::new(self.link.fd, PollFlags::POLLIN));
+            self.tcp.poll_fds(&self.sockets, &mut fds);
+            self.udp.poll_fds(&mut fds);
+            self.icmp.poll_fds(&mut fds);
+            self.raw.poll_fds(&mut fds);
+
+            let mut timeout = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map(|delay| delay.total_millis() as libc::c_int);
+            if fds.len() > 1 {
+                // Check for idle flows to expire every so often.
+                timeout = Some(timeout.map_or(1000, |t| t.min(1000)));
+            }
+            match poll(&mut fds, timeout.unwrap_or(-1)) {
+                Ok(_) | Err(nix::Error::EINTR) => (),
+                Err(e) => {
+                    log::error!("nat: poll failed: {e}");
+                    return;
+                }
+            }
+            if fds[0]
+                .revents()
+                .is_some_and(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR))
+            {
+                log::debug!("nat: the device is gone, stopping");
+                return;
+            }
+
+            loop {
+                match recv(self.link.fd, &mut buf, MsgFlags::MSG_DONTWAIT) {
+                    Ok(0) => break,
+                    Ok(len) => self.process_guest_frame(&buf[..len]),
+                    #[allow(unreachable_patterns)]
+                    Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => break,
+                    Err(e) => {
+                        log::error!("nat: failed to receive a frame from the guest: {e}");
+                        return;
+                    }
+                }
+            }
+
+            self.process_host();
+
+            let now = std::time::Instant::now();
+            self.udp.expire(now);
+            self.icmp.expire(now);
+            self.raw.expire(now);
+        }
+    }
+}
+
+/// Creates the socketpair the device and the NAT exchange frames over.
+/// Unlike a datagram pair, it tells the NAT when the device closes its end.
+fn link_pair() -> io::Result<(OwnedFd, OwnedFd)> {
+    let (guest_fd, nat_fd) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+    // Safe because we just created the sockets and nothing else owns them.
+    Ok(unsafe { (OwnedFd::from_raw_fd(guest_fd), OwnedFd::from_raw_fd(nat_fd)) })
+}
+
+/// Starts a NAT for the interface `id`, whose guest uses `mtu`, and returns
+/// the socket to exchange frames with it, one per message. The NAT stops when
+/// the socket is closed.
+pub fn start(id: &str, mtu: u16) -> io::Result<RawFd> {
+    let (guest_fd, nat_fd) = link_pair()?;
+    if let Err(e) = setsockopt(nat_fd.as_raw_fd(), sockopt::SndBuf, &(16 * 1024 * 1024)) {
+        log::warn!("Failed to increase SO_SNDBUF (performance may be decreased): {e}");
+    }
+
+    let nameserver = fs::read_to_string(RESOLV_CONF)
+        .ok()
+        .and_then(|conf| parse_nameserver(&conf))
+        .unwrap_or_else(|| {
+            log::warn!("nat: no IPv4 nameserver in {RESOLV_CONF}, using the host's loopback");
+            Ipv4Addr::LOCALHOST
+        });
+
+    thread::Builder::new()
+        .name(format!("{id} nat"))
+        .spawn(move || Nat::new(nat_fd.as_raw_fd(), mtu, nameserver).run())?;
+    Ok(guest_fd.into_raw_fd())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    use smoltcp::wire::{
+        ArpOperation, Icmpv4Packet, Icmpv4Repr, TcpControl, TcpRepr, TcpSeqNumber,
+    };
+
+    use super::super::unixgram::Unixgram;
+    use super::super::DEFAULT_MTU;
+    use super::*;
+
+    #[test]
+    fn test_parse_nameserver() {
+        let conf = "# Generated\nsearch example.com\nnameserver fe80::1\nnameserver  192.0.2.53 \nnameserver 192.0.2.54\n";
+        assert_eq!(parse_nameserver(conf), Some(Ipv4Addr::new(192, 0, 2, 53)));
+        assert_eq!(parse_nameserver("search example.com\n"), None);
+    }
+
+    #[test]
+    fn test_address_map() {
+        let map = AddressMap {
+            nameserver: Ipv4Addr::new(127, 0, 0, 53),
+        };
+        let addr = |a, b, c, d, port| SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port);
+
+        assert_eq!(
+            map.to_host(addr(10, 0, 2, 2, 8080)),
+            Some(addr(127, 0, 0, 1, 8080))
+        );
+        assert_eq!(
+            map.to_host(addr(10, 0, 2, 3, 53)),
+            Some(addr(127, 0, 0, 53, 53))
+        );
+        assert_eq!(
+            map.to_host(addr(192, 0, 2, 1, 443)),
+            Some(addr(192, 0, 2, 1, 443))
+        );
+        assert_eq!(map.to_host(addr(10, 0, 2, 4, 80)), None);
+        assert_eq!(map.to_host(addr(255, 255, 255, 255, 80)), None);
+        assert_eq!(map.to_host(addr(224, 0, 0, 251, 5353)), None);
+
+        assert_eq!(
+            map.to_guest(addr(127, 0, 0, 53, 53), true),
+            addr(10, 0, 2, 3, 53)
+        );
+        // Queries sent to the nameserver itself get their replies from it.
+        assert_eq!(
+            map.to_guest(addr(127, 0, 0, 53, 53), false),
+            addr(10, 0, 2, 2, 53)
+        );
+        assert_eq!(
+            map.to_guest(addr(127, 0, 0, 1, 8080), false),
+            addr(10, 0, 2, 2, 8080)
+        );
+        assert_eq!(
+            map.to_guest(addr(192, 0, 2, 1, 443), false),
+            addr(192, 0, 2, 1, 443)
+        );
+
+        let map = AddressMap {
+            nameserver: Ipv4Addr::new(192, 0, 2, 53),
+        };
+        assert_eq!(
+            map.to_guest(addr(192, 0, 2, 53, 53), true),
+            addr(10, 0, 2, 3, 53)
+        );
+        assert_eq!(
+            map.to_guest(addr(192, 0, 2, 53, 53), false),
+            addr(192, 0, 2, 53, 53)
+        );
+    }
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+    /// Returns a NAT and the guest's end of its link, which already knows
+    /// the guest's hardware address.
+    fn nat() -> (Nat, OwnedFd, OwnedFd) {
+        let (guest_fd, nat_fd) = link_pair().unwrap();
+        let mut nat = Nat::new(nat_fd.as_raw_fd(), DEFAULT_MTU, Ipv4Addr::LOCALHOST);
+
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + 28];
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: GUEST_MAC,
+            source_protocol_addr: GUEST_ADDR,
+            target_hardware_addr: EthernetAddress::default(),
+            target_protocol_addr: GATEWAY_ADDR,
+        };
+        let mut eth = EthernetFrame::new_unchecked(&mut frame);
+        EthernetRepr {
+            src_addr: GUEST_MAC,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        }
+        .emit(&mut eth);
+        arp.emit(&mut ArpPacket::new_unchecked(eth.payload_mut()));
+        nat.process_guest_frame(&frame);
+
+        let reply = recv_frame(&guest_fd).expect("no ARP reply");
+        let eth = EthernetFrame::new_checked(&reply).unwrap();
+        assert_eq!(eth.dst_addr(), GUEST_MAC);
+        assert_eq!(eth.ethertype(), EthernetProtocol::Arp);
+        (nat, guest_fd, nat_fd)
+    }
+
+    fn recv_frame(fd: &OwnedFd) -> Option<Vec<u8>> {
+        let mut buf = vec![0; MAX_BUFFER_SIZE];
+        let len = recv(fd.as_raw_fd(), &mut buf, MsgFlags::MSG_DONTWAIT).ok()?;
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    /// Waits for the NAT to send the guest an IPv4 packet.
+    fn wait_ipv4(nat: &mut Nat, fd: &OwnedFd) -> Vec<u8> {
+        for _ in 0..500 {
+            nat.process_host();
+            if let Some(frame) = recv_frame(fd) {
+                let eth = EthernetFrame::new_checked(&frame).unwrap();
+                assert_eq!(eth.dst_addr(), GUEST_MAC);
+                assert_eq!(eth.ethertype(), EthernetProtocol::Ipv4);
+                return frame[ETHERNET_HEADER_LEN..].to_vec();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no packet for the guest");
+    }
+
+    /// Hands the NAT an IPv4 packet the guest sent to `dst`.
+    fn send_ipv4(
+        nat: &mut Nat,
+        dst: Ipv4Addr,
+        next_header: IpProtocol,
+        payload_len: usize,
+        emit_payload: impl FnOnce(&mut [u8]),
+    ) {
+        let repr = Ipv4Repr {
+            src_addr: GUEST_ADDR,
+            dst_addr: dst,
+            next_header,
+            payload_len,
+            hop_limit: 64,
+        };
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + payload_len];
+        EthernetRepr {
+            src_addr: GUEST_MAC,
+            dst_addr: GATEWAY_MAC,
+            ethertype: EthernetProtocol::Ipv4,
+        }
+        .emit(&mut EthernetFrame::new_unchecked(&mut frame));
+        let mut packet = Ipv4Packet::new_unchecked(&mut frame[ETHERNET_HEADER_LEN..]);
+        repr.emit(&mut packet, &checksum_caps());
+        emit_payload(packet.payload_mut());
+        nat.process_guest_frame(&frame);
+    }
+
+    fn send_tcp(nat: &mut Nat, dst: SocketAddrV4, repr: &TcpRepr) {
+        send_ipv4(nat, *dst.ip(), IpProtocol::Tcp, repr.buffer_len(), |buf| {
+            repr.emit(
+                &mut TcpPacket::new_unchecked(buf),
+                &GUEST_ADDR.into(),
+                &(*dst.ip()).into(),
+                &checksum_caps(),
+            )
+        });
+    }
+
+    fn send_udp(nat: &mut Nat, src_port: u16, dst: SocketAddrV4, payload: &[u8]) {
+        let repr = UdpRepr {
+            src_port,
+            dst_port: dst.port(),
+        };
+        send_ipv4(
+            nat,
+            *dst.ip(),
+            IpProtocol::Udp,
+            UDP_HEADER_LEN + payload.len(),
+            |buf| {
+                repr.emit(
+                    &mut UdpPacket::new_unchecked(buf),
+                    &GUEST_ADDR.into(),
+                    &(*dst.ip()).into(),
+                    payload.len(),
+                    |buf| buf.copy_from_slice(payload),
+                    &checksum_caps(),
+                )
+            },
+        );
+    }
+
+    /// Waits for a TCP segment for the guest and returns its header fields
+    /// and payload.
+    fn wait_tcp(nat: &mut Nat, fd: &OwnedFd) -> (TcpRepr<'static>, Vec<u8>) {
+        let packet = wait_ipv4(nat, fd);
+        let packet = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert_eq!(packet.next_header(), IpProtocol::Tcp);
+        let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+        let repr = TcpRepr::parse(
+            &segment,
+            &packet.src_addr().into(),
+            &packet.dst_addr().into(),
+            &checksum_caps(),
+        )
+        .unwrap();
+        let payload = repr.payload.to_vec();
+        (
+            TcpRepr {
+                payload: &[],
+                ..repr
+            },
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_tcp_echo() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let (mut nat, guest_fd, _nat_fd) = nat();
+        let dst = SocketAddrV4::new(GATEWAY_ADDR, port);
+        let syn = TcpRepr {
+            src_port: 40000,
+            dst_port: port,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 8192,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            timestamp: None,
+            payload: &[],
+        };
+        send_tcp(&mut nat, dst, &syn);
+        let (syn_ack, _) = wait_tcp(&mut nat, &guest_fd);
+        assert_eq!(syn_ack.control, TcpControl::Syn);
+        assert_eq!(syn_ack.src_port, port);
+        assert_eq!(syn_ack.ack_number, Some(TcpSeqNumber(1001)));
+
+        send_tcp(
+            &mut nat,
+            dst,
+            &TcpRepr {
+                control: TcpControl::Psh,
+                seq_number: TcpSeqNumber(1001),
+                ack_number: Some(syn_ack.seq_number + 1),
+                max_seg_size: None,
+                payload: b"ping",
+                ..syn
+            },
+        );
+        // The data the host echoes may follow a bare acknowledgment.
+        let data = loop {
+            let (_, data) = wait_tcp(&mut nat, &guest_fd);
+            if !data.is_empty() {
+                break data;
+            }
+        };
+        assert_eq!(data, b"ping");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_udp_forward() {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let (mut nat, guest_fd, _nat_fd) = nat();
+
+        let payload = b"ping";
+        send_udp(
+            &mut nat,
+            5000,
+            SocketAddrV4::new(GATEWAY_ADDR, port),
+            payload,
+        );
+
+        let mut buf = [0; 16];
+        let (len, peer) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], payload);
+        host.send_to(b"pong", peer).unwrap();
+
+        let packet = wait_ipv4(&mut nat, &guest_fd);
+        let packet = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert_eq!(packet.next_header(), IpProtocol::Udp);
+        assert_eq!(packet.src_addr(), GATEWAY_ADDR);
+        assert_eq!(packet.dst_addr(), GUEST_ADDR);
+        let datagram = UdpPacket::new_checked(packet.payload()).unwrap();
+        assert_eq!(datagram.src_port(), port);
+        assert_eq!(datagram.dst_port(), 5000);
+        assert_eq!(datagram.payload(), b"pong");
+    }
+
+    #[test]
+    fn test_udp_larger_than_mtu() {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let (mut nat, guest_fd, _nat_fd) = nat();
+
+        send_udp(
+            &mut nat,
+            5000,
+            SocketAddrV4::new(GATEWAY_ADDR, port),
+            b"ping",
+        );
+        let mut buf = [0; 16];
+        let (_, peer) = host.recv_from(&mut buf).unwrap();
+        let max_len = DEFAULT_MTU as usize - IPV4_HEADER_LEN - UDP_HEADER_LEN;
+        host.send_to(&vec![1; max_len + 1], peer).unwrap();
+        host.send_to(&vec![2; max_len], peer).unwrap();
+
+        // Only the datagram that fits reaches the guest.
+        let packet = wait_ipv4(&mut nat, &guest_fd);
+        assert_eq!(packet.len(), DEFAULT_MTU as usize);
+        let packet = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        let datagram = UdpPacket::new_checked(packet.payload()).unwrap();
+        assert_eq!(datagram.payload(), &vec![2; max_len][..]);
+    }
+
+    #[test]
+    fn test_icmp_echo() {
+        let (mut nat, guest_fd, _nat_fd) = nat();
+        let request = Icmpv4Repr::EchoRequest {
+            ident: 7,
+            seq_no: 1,
+            data: b"ping",
+        };
+        send_ipv4(
+            &mut nat,
+            GATEWAY_ADDR,
+            IpProtocol::Icmp,
+            request.buffer_len(),
+            |buf| request.emit(&mut Icmpv4Packet::new_unchecked(buf), &checksum_caps()),
+        );
+
+        let packet = wait_ipv4(&mut nat, &guest_fd);
+        let packet = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert_eq!(packet.src_addr(), GATEWAY_ADDR);
+        assert_eq!(packet.dst_addr(), GUEST_ADDR);
+        let reply = Icmpv4Packet::new_checked(packet.payload()).unwrap();
+        assert_eq!(
+            Icmpv4Repr::parse(&reply, &checksum_caps()).unwrap(),
+            Icmpv4Repr::EchoReply {
+                ident: 7,
+                seq_no: 1,
+                data: b"ping",
+            }
+        );
+    }
+
+    #[test]
+    fn test_stops_when_backend_dropped() {
+        let (guest_fd, nat_fd) = link_pair().unwrap();
+        let nat = thread::spawn(move || {
+            Nat::new(nat_fd.as_raw_fd(), DEFAULT_MTU, Ipv4Addr::LOCALHOST).run()
+        });
+        drop(Unixgram::new(guest_fd.into_raw_fd()));
+
+        for _ in 0..500 {
+            if nat.is_finished() {
+                return nat.join().unwrap();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the NAT is still running");
+    }
+}
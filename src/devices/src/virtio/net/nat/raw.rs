@@ -0,0 +1,130 @@
+//! IP protocols other than TCP, UDP and ICMP, which are sent from raw
+//! sockets when the host lets us open them.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::{recv, sendto, MsgFlags, SockaddrIn};
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv4Repr};
+
+use super::icmp::open_socket;
+use super::{checksum_caps, AddressMap, GuestLink};
+
+/// How long replies from a peer are let through once the guest stops
+/// sending to it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Flow {
+    socket: OwnedFd,
+    guest_addr: Ipv4Addr,
+    /// The hosts the guest sent packets to, and when it last did. A raw
+    /// socket receives every packet of its protocol, so only those coming
+    /// from them are passed on.
+    peers: HashMap<Ipv4Addr, Instant>,
+}
+
+/// The raw sockets, by protocol.
+#[derive(Default)]
+pub struct RawNat {
+    flows: HashMap<u8, Flow>,
+    /// Protocols we weren't allowed to open a raw socket for.
+    denied: HashSet<u8>,
+}
+
+impl RawNat {
+    /// Sends the payload of a packet of `protocol` the guest sent from `src`
+    /// to `dst`. The host fills in the IP header.
+    pub fn send(
+        &mut self,
+        addresses: &AddressMap,
+        protocol: IpProtocol,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        payload: &[u8],
+    ) {
+        let number = u8::from(protocol);
+        if self.denied.contains(&number) {
+            return;
+        }
+        let Some(host_dst) = addresses.to_host(SocketAddrV4::new(dst, 0)) else {
+            return;
+        };
+
+        let flow = match self.flows.entry(number) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let socket = match open_socket(libc::SOCK_RAW, number.into()) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::warn!("nat: failed to open a raw socket for {protocol}, dropping its packets: {e}");
+                        self.denied.insert(number);
+                        return;
+                    }
+                };
+                entry.insert(Flow {
+                    socket,
+                    guest_addr: src,
+                    peers: HashMap::new(),
+                })
+            }
+        };
+        flow.guest_addr = src;
+        flow.peers.insert(*host_dst.ip(), Instant::now());
+        if let Err(e) = sendto(
+            flow.socket.as_raw_fd(),
+            payload,
+            &SockaddrIn::from(host_dst),
+            MsgFlags::MSG_DONTWAIT,
+        ) {
+            log::debug!("nat: failed to send a {protocol} packet to {host_dst}: {e}");
+        }
+    }
+
+    /// Passes the packets the host received from the guest's peers on to the
+    /// guest.
+    pub fn receive(&mut self, link: &GuestLink, addresses: &AddressMap) {
+        let mut buf = [0; u16::MAX as usize];
+        for flow in self.flows.values_mut() {
+            // Raw sockets hand out the IP header as well.
+            while let Ok(len) = recv(flow.socket.as_raw_fd(), &mut buf, MsgFlags::MSG_DONTWAIT) {
+                let Ok(packet) = Ipv4Packet::new_checked(&buf[..len]) else {
+                    continue;
+                };
+                let Ok(repr) = Ipv4Repr::parse(&packet, &checksum_caps()) else {
+                    continue;
+                };
+                let Some(last_used) = flow.peers.get_mut(&repr.src_addr) else {
+                    continue;
+                };
+                *last_used = Instant::now();
+                let src = addresses.to_guest(SocketAddrV4::new(repr.src_addr, 0), false);
+                let guest_repr = Ipv4Repr {
+                    src_addr: *src.ip(),
+                    dst_addr: flow.guest_addr,
+                    ..repr
+                };
+                link.send_ipv4(&guest_repr, |buf| buf.copy_from_slice(packet.payload()));
+            }
+        }
+    }
+
+    /// Forgets the peers the guest stopped talking to, and closes the
+    /// sockets that have none left.
+    pub fn expire(&mut self, now: Instant) {
+        self.flows.retain(|_, flow| {
+            flow.peers
+                .retain(|_, last_used| now.duration_since(*last_used) < IDLE_TIMEOUT);
+            !flow.peers.is_empty()
+        });
+    }
+
+    pub fn poll_fds(&self, fds: &mut Vec<PollFd>) {
+        for flow in self.flows.values() {
+            fds.push(PollFd::new(flow.socket.as_raw_fd(), PollFlags::POLLIN));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! TCP connections from the guest, which are terminated by smoltcp and
+//! continued by connections from the host.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd};
+
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, SockaddrIn};
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::tcp;
+use smoltcp::wire::IpListenEndpoint;
+
+use super::AddressMap;
+
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Starts connecting to `addr` without waiting for the connection to be
+/// established.
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    )?;
+    // Safe because we just created the socket and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    match connect(fd, &SockaddrIn::from(addr)) {
+        Ok(()) | Err(nix::Error::EINPROGRESS) => Ok(stream),
+        Err(e) => Err(e.into()),
+    }
+}
+
+struct Connection {
+    handle: SocketHandle,
+    host: TcpStream,
+    connecting: bool,
+    /// The host closed its side, so the guest gets a FIN once it has all
+    /// the data.
+    host_eof: bool,
+    /// The guest closed its side, which was passed on to the host.
+    guest_eof: bool,
+}
+
+impl Connection {
+    /// Moves as much data as possible between the host and the guest,
+    /// returning whether any was moved.
+    fn pump(&mut self, socket: &mut tcp::Socket) -> bool {
+        if self.connecting {
+            match self.host.take_error() {
+                Ok(None) if self.host.peer_addr().is_ok() => self.connecting = false,
+                Ok(None) => return false,
+                Ok(Some(e)) | Err(e) => {
+                    log::debug!("nat: failed to connect to the host: {e}");
+                    socket.abort();
+                    return false;
+                }
+            }
+        }
+
+        if matches!(socket.state(), tcp::State::Listen | tcp::State::SynReceived) {
+            return false;
+        }
+
+        let mut progress = false;
+        let mut result = Ok(());
+
+        while socket.can_recv() {
+            match socket.recv(|data| match self.host.write(data) {
+                Ok(len) => (len, Ok(len)),
+                Err(e) => (0, Err(e)),
+            }) {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(_)) => progress = true,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Ok(Err(e)) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if !self.guest_eof && !socket.may_recv() && socket.recv_queue() == 0 {
+            self.guest_eof = true;
+            let _ = self.host.shutdown(Shutdown::Write);
+        }
+
+        while result.is_ok() && !self.host_eof && socket.can_send() {
+            match socket.send(|buf| match self.host.read(buf) {
+                Ok(len) => (len, Ok(len)),
+                Err(e) => (0, Err(e)),
+            }) {
+                Ok(Ok(0)) => {
+                    self.host_eof = true;
+                    socket.close();
+                    progress = true;
+                }
+                Ok(Ok(_)) => progress = true,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Ok(Err(e)) => result = Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = result {
+            log::debug!("nat: connection reset by the host: {e}");
+            socket.abort();
+        }
+        progress
+    }
+}
+
+/// The guest's TCP connections, by guest address and destination address.
+#[derive(Default)]
+pub struct TcpNat {
+    connections: HashMap<(SocketAddrV4, SocketAddrV4), Connection>,
+}
+
+impl TcpNat {
+    /// Gets ready to accept a connection from `src` to `dst`, whose SYN is
+    /// about to be handed to the TCP/IP stack. Unless the host can connect
+    /// to the destination, the stack turns the guest down.
+    pub fn accept(
+        &mut self,
+        sockets: &mut SocketSet<'static>,
+        addresses: &AddressMap,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) {
+        if self.connections.contains_key(&(src, dst)) {
+            // A retransmission.
+            return;
+        }
+        let Some(host_addr) = addresses.to_host(dst) else {
+            return;
+        };
+        let host = match connect_nonblocking(host_addr) {
+            Ok(host) => host,
+            Err(e) => {
+                log::debug!("nat: failed to connect to {host_addr}: {e}");
+                return;
+            }
+        };
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+        );
+        socket.set_nagle_enabled(false);
+        if socket
+            .listen(IpListenEndpoint {
+                addr: Some((*dst.ip()).into()),
+                port: dst.port(),
+            })
+            .is_err()
+        {
+            return;
+        }
+        let handle = sockets.add(socket);
+        self.connections.insert(
+            (src, dst),
+            Connection {
+                handle,
+                host,
+                connecting: true,
+                host_eof: false,
+                guest_eof: false,
+            },
+        );
+    }
+
+    /// Forgets the sockets still listening, either because the stack turned
+    /// down the SYN they were meant for or because the guest reset the
+    /// connection before it was established.
+    ///
+    /// Called after each segment is handed to the stack, so each SYN ends
+    /// up in the socket it was meant for.
+    pub fn drop_unaccepted(&mut self, sockets: &mut SocketSet<'static>) {
+        self.connections.retain(|_, conn| {
+            let listening = sockets.get::<tcp::Socket>(conn.handle).is_listening();
+            if listening {
+                sockets.remove(conn.handle);
+            }
+            !listening
+        });
+    }
+
+    /// Forgets the connections that are over.
+    pub fn drop_closed(&mut self, sockets: &mut SocketSet<'static>) {
+        self.connections.retain(|_, conn| {
+            let closed = matches!(
+                sockets.get::<tcp::Socket>(conn.handle).state(),
+                tcp::State::Closed | tcp::State::TimeWait
+            );
+            if closed {
+                sockets.remove(conn.handle);
+            }
+            !closed
+        });
+    }
+
+    /// Moves data between all connections, returning whether any was moved.
+    pub fn pump(&mut self, sockets: &mut SocketSet<'static>) -> bool {
+        let mut progress = false;
+        for conn in self.connections.values_mut() {
+            progress |= conn.pump(sockets.get_mut::<tcp::Socket>(conn.handle));
+        }
+        progress
+    }
+
+    pub fn poll_fds(&self, sockets: &SocketSet<'static>, fds: &mut Vec<PollFd>) {
+        for conn in self.connections.values() {
+            let socket = sockets.get::<tcp::Socket>(conn.handle);
+            let mut events = PollFlags::empty();
+            if conn.connecting || socket.recv_queue() > 0 {
+                events |= PollFlags::POLLOUT;
+            }
+            if !conn.connecting && !conn.host_eof && socket.can_send() {
+                events |= PollFlags::POLLIN;
+            }
+            if !events.is_empty() {
+                fds.push(PollFd::new(conn.host.as_raw_fd(), events));
+            }
+        }
+    }
+}
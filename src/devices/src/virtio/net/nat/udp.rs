@@ -0,0 +1,107 @@
+//! UDP datagrams from the guest, which are sent from a host socket bound for
+//! each of the guest's ports.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use nix::poll::{PollFd, PollFlags};
+use smoltcp::wire::{IPV4_HEADER_LEN, UDP_HEADER_LEN};
+
+use super::{AddressMap, GuestLink, DNS_ADDR};
+
+/// How long a port stays open once the guest stops using it. Replies from
+/// the host keep it open as well.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Flow {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/// The host sockets, by guest address and whether they forward DNS
+/// queries. Only the replies to queries sent to the DNS forwarder look like
+/// they come from it.
+#[derive(Default)]
+pub struct UdpNat {
+    flows: HashMap<(SocketAddrV4, bool), Flow>,
+}
+
+impl UdpNat {
+    /// Sends a datagram the guest sent from `src` to `dst`.
+    pub fn send(
+        &mut self,
+        addresses: &AddressMap,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) {
+        let Some(host_dst) = addresses.to_host(dst) else {
+            return;
+        };
+        let flow = match self.flows.entry((src, *dst.ip() == DNS_ADDR)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::warn!("nat: failed to open a UDP socket: {e}");
+                        return;
+                    }
+                };
+                entry.insert(Flow {
+                    socket,
+                    last_used: Instant::now(),
+                })
+            }
+        };
+        flow.last_used = Instant::now();
+        if let Err(e) = flow.socket.send_to(payload, host_dst) {
+            log::debug!("nat: failed to send a UDP datagram to {host_dst}: {e}");
+        }
+    }
+
+    /// Passes the datagrams the host received on to the guest.
+    pub fn receive(&mut self, link: &GuestLink, addresses: &AddressMap) {
+        // One more byte than fits in a packet to the guest, to tell the
+        // datagrams that were truncated.
+        let max_len = link.mtu - IPV4_HEADER_LEN - UDP_HEADER_LEN;
+        let mut buf = vec![0; max_len + 1];
+        for (&(guest_addr, dns), flow) in self.flows.iter_mut() {
+            loop {
+                match flow.socket.recv_from(&mut buf) {
+                    Ok((len, SocketAddr::V4(_))) if len > max_len => {
+                        log::debug!("nat: dropping a UDP datagram larger than the guest's MTU");
+                    }
+                    Ok((len, SocketAddr::V4(peer))) => {
+                        flow.last_used = Instant::now();
+                        link.send_udp(addresses.to_guest(peer, dns), guest_addr, &buf[..len]);
+                    }
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        // Typically an ICMP error for a previous datagram.
+                        log::debug!("nat: UDP socket error: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Closes the sockets that have been idle for too long.
+    pub fn expire(&mut self, now: Instant) {
+        self.flows
+            .retain(|_, flow| now.duration_since(flow.last_used) < IDLE_TIMEOUT);
+    }
+
+    pub fn poll_fds(&self, fds: &mut Vec<PollFd>) {
+        for flow in self.flows.values() {
+            fds.push(PollFd::new(flow.socket.as_raw_fd(), PollFlags::POLLIN));
+        }
+    }
+}
@@ -1,30 +1,15 @@
-use nix::sys::socket::{getsockopt, recv, send, setsockopt, sockopt, MsgFlags};
+use nix::sys::socket::{getsockopt, recv, send, setsockopt, shutdown, sockopt, MsgFlags, Shutdown};
 use std::os::fd::{AsRawFd, RawFd};
 
+use super::backend::{NetBackend, ReadError, WriteError};
+
 /// Each frame from passt is prepended by a 4 byte "header".
 /// It is interpreted as a big-endian u32 integer and is the length of the following ethernet frame.
 const PASST_HEADER_LEN: usize = 4;
 
-#[derive(Debug)]
-pub enum ReadError {
-    /// Nothing was written
-    NothingRead,
-    /// Another internal error occurred
-    Internal(nix::Error),
-}
-
-#[derive(Debug)]
-pub enum WriteError {
-    /// Nothing was written, you can drop the frame or try to resend it later
-    NothingWritten,
-    /// Part of the buffer was written, the write has to be finished using try_finish_write
-    PartialWrite,
-    /// Passt doesnt seem to be running (received EPIPE)
-    ProcessNotRunning,
-    /// Another internal error occurred
-    Internal(nix::Error),
-}
-
+/// A stream socket carrying length-prefixed ethernet frames. Besides passt,
+/// this is the format of QEMU's `-netdev stream`, which other userspace
+/// network stacks speak too.
 pub struct Passt {
     fd: RawFd,
     // 0 when a frame length has not been read
@@ -53,8 +38,84 @@ impl Passt {
         }
     }
 
+    /// Try to read until filling the whole slice.
+    fn read_loop(&self, buf: &mut [u8], block_until_has_data: bool) -> Result<(), ReadError> {
+        let mut bytes_read = 0;
+
+        if !block_until_has_data {
+            match recv(
+                self.fd,
+                buf,
+                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+            ) {
+                Ok(size) => bytes_read += size,
+                #[allow(unreachable_patterns)]
+                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
+                    return Err(ReadError::NothingRead)
+                }
+                Err(e) => return Err(ReadError::Internal(e)),
+            }
+        }
+
+        while bytes_read < buf.len() {
+            match recv(
+                self.fd,
+                &mut buf[bytes_read..],
+                MsgFlags::MSG_WAITALL | MsgFlags::MSG_NOSIGNAL,
+            ) {
+                #[allow(unreachable_patterns)]
+                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
+                    log::warn!("read_loop: unexpected EAGAIN/EWOULDBLOCK on blocking socket");
+                    continue;
+                }
+                Err(e) => return Err(ReadError::Internal(e)),
+                Ok(size) => {
+                    bytes_read += size;
+                    //log::trace!("passt recv {}/{}", bytes_read, buf.len());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_loop(&mut self, buf: &[u8]) -> Result<(), WriteError> {
+        let mut bytes_send = 0;
+
+        while bytes_send < buf.len() {
+            match send(
+                self.fd,
+                &buf[bytes_send..],
+                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+            ) {
+                Ok(size) => bytes_send += size,
+                #[allow(unreachable_patterns)]
+                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
+                    if bytes_send == 0 {
+                        return Err(WriteError::NothingWritten);
+                    } else {
+                        log::trace!(
+                            "Wrote {} bytes, but socket blocked, will need try_finish_write() to finish",
+                            bytes_send
+                        );
+
+                        self.last_partial_write_length += bytes_send;
+                        return Err(WriteError::PartialWrite);
+                    }
+                }
+                Err(nix::Error::EPIPE) => return Err(WriteError::ProcessNotRunning),
+                Err(e) => return Err(WriteError::Internal(e)),
+            }
+        }
+        self.last_partial_write_length = 0;
+        Ok(())
+    }
+}
+
+impl NetBackend for Passt {
     /// Try to read a frame from passt. If no bytes are available reports ReadError::NothingRead
-    pub fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let buf = &mut buf[hdr_len..];
         if self.expecting_frame_length == 0 {
             self.expecting_frame_length = {
                 let mut frame_length_buf = [0u8; PASST_HEADER_LEN];
@@ -64,6 +125,14 @@ impl Passt {
         }
 
         let frame_length = self.expecting_frame_length as usize;
+        if frame_length > buf.len() {
+            // There's no telling where the next frame starts if we can't read
+            // this one, so give up on the connection.
+            log::error!("passt: {frame_length} byte frame doesn't fit in a buffer, disconnecting");
+            let _ = shutdown(self.fd, Shutdown::Both);
+            self.expecting_frame_length = 0;
+            return Err(ReadError::Internal(nix::Error::EMSGSIZE));
+        }
         self.read_loop(&mut buf[..frame_length], false)?;
         self.expecting_frame_length = 0;
         log::trace!("Read eth frame from passt: {} bytes", frame_length);
@@ -80,7 +149,7 @@ impl Passt {
     ///
     /// If this function returns WriteError::PartialWrite, you have to finish the write using
     /// try_finish_write.
-    pub fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
         if self.last_partial_write_length != 0 {
             panic!("Cannot write a frame to passt, while a partial write is not resolved.");
         }
@@ -98,7 +167,7 @@ impl Passt {
         Ok(())
     }
 
-    pub fn has_unfinished_write(&self) -> bool {
+    fn has_unfinished_write(&self) -> bool {
         self.last_partial_write_length != 0
     }
 
@@ -108,7 +177,7 @@ impl Passt {
     ///
     /// * `hdr_len` - must be the same value as passed to write_frame, that caused the partial write
     /// * `buf` - must be same buffer that was given to write_frame, that caused the partial write
-    pub fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError> {
+    fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError> {
         if self.last_partial_write_length != 0 {
             let already_written = self.last_partial_write_length;
             log::trace!("Requested to finish partial write");
@@ -122,80 +191,46 @@ impl Passt {
         Ok(())
     }
 
-    pub fn raw_socket_fd(&self) -> RawFd {
+    fn raw_socket_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
+}
 
-    /// Try to read until filling the whole slice.
-    fn read_loop(&self, buf: &mut [u8], block_until_has_data: bool) -> Result<(), ReadError> {
-        let mut bytes_read = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if !block_until_has_data {
-            match recv(
-                self.fd,
-                buf,
-                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
-            ) {
-                Ok(size) => bytes_read += size,
-                #[allow(unreachable_patterns)]
-                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
-                    return Err(ReadError::NothingRead)
-                }
-                Err(e) => return Err(ReadError::Internal(e)),
-            }
-        }
-
-        while bytes_read < buf.len() {
-            match recv(
-                self.fd,
-                &mut buf[bytes_read..],
-                MsgFlags::MSG_WAITALL | MsgFlags::MSG_NOSIGNAL,
-            ) {
-                #[allow(unreachable_patterns)]
-                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
-                    log::warn!("read_loop: unexpected EAGAIN/EWOULDBLOCK on blocking socket");
-                    continue;
-                }
-                Err(e) => return Err(ReadError::Internal(e)),
-                Ok(size) => {
-                    bytes_read += size;
-                    //log::trace!("passt recv {}/{}", bytes_read, buf.len());
-                }
-            }
-        }
+    use std::io::{Read, Write};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
 
-        Ok(())
-    }
+    const FRAME: &[u8] = &[0xff; 60];
+    const HDR_LEN: usize = 12;
 
-    fn write_loop(&mut self, buf: &[u8]) -> Result<(), WriteError> {
-        let mut bytes_send = 0;
+    #[test]
+    fn test_read_frame() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut backend = Passt::new(stream.into_raw_fd());
+        let mut buf = [0u8; HDR_LEN + 1500];
 
-        while bytes_send < buf.len() {
-            match send(
-                self.fd,
-                &buf[bytes_send..],
-                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
-            ) {
-                Ok(size) => bytes_send += size,
-                #[allow(unreachable_patterns)]
-                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => {
-                    if bytes_send == 0 {
-                        return Err(WriteError::NothingWritten);
-                    } else {
-                        log::trace!(
-                            "Wrote {} bytes, but socket blocked, will need try_finish_write() to finish",
-                            bytes_send
-                        );
+        peer.write_all(&(FRAME.len() as u32).to_be_bytes()).unwrap();
+        peer.write_all(FRAME).unwrap();
+        assert_eq!(backend.read_frame(HDR_LEN, &mut buf).unwrap(), FRAME.len());
+        assert_eq!(&buf[HDR_LEN..HDR_LEN + FRAME.len()], FRAME);
+    }
 
-                        self.last_partial_write_length += bytes_send;
-                        return Err(WriteError::PartialWrite);
-                    }
-                }
-                Err(nix::Error::EPIPE) => return Err(WriteError::ProcessNotRunning),
-                Err(e) => return Err(WriteError::Internal(e)),
-            }
-        }
-        self.last_partial_write_length = 0;
-        Ok(())
+    #[test]
+    fn test_frame_too_large() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut backend = Passt::new(stream.into_raw_fd());
+        let mut buf = [0u8; HDR_LEN + 1500];
+
+        peer.write_all(&1501u32.to_be_bytes()).unwrap();
+        assert!(matches!(
+            backend.read_frame(HDR_LEN, &mut buf),
+            Err(ReadError::Internal(nix::Error::EMSGSIZE))
+        ));
+        // The peer is disconnected.
+        assert_eq!(peer.read(&mut [0; 1]).unwrap(), 0);
     }
 }
@@ -0,0 +1,279 @@
+//! A passt instance started by libkrun itself, which is restarted whenever
+//! it exits so the guest keeps its network. Restarts are delayed by a timer
+//! the device polls, longer each time passt exits right after starting.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+use utils::timerfd::TimerFd;
+
+use super::backend::{NetBackend, ReadError, WriteError};
+use super::passt::Passt;
+
+/// How long passt has to stay up for its exit to be considered a one-off.
+const STABLE_TIME: Duration = Duration::from_secs(10);
+/// How long to wait before starting passt again after a one-off exit. The
+/// delay doubles each time passt exits early in a row.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+/// Longest delay between two attempts at starting passt.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// How to start passt.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PasstCommand {
+    /// Path of the passt executable.
+    pub path: PathBuf,
+    /// Arguments passed to passt, besides the ones telling it to stay in the
+    /// foreground and which socket to use.
+    pub args: Vec<String>,
+}
+
+impl PasstCommand {
+    /// Starts passt with its end of a new socket pair, returning the child
+    /// and our end.
+    fn spawn(&self) -> io::Result<(Child, OwnedFd)> {
+        let (ours, theirs) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        // Safe because we just created the sockets and nothing else owns them.
+        let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(ours), OwnedFd::from_raw_fd(theirs)) };
+        let theirs_fd = theirs.as_raw_fd();
+
+        let mut command = Command::new(&self.path);
+        command
+            .args(&self.args)
+            .arg("--foreground")
+            .arg("--fd")
+            .arg(theirs_fd.to_string());
+        // Safe because the closure only calls fcntl, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                // Let passt inherit its end of the socket pair, and only passt.
+                if libc::fcntl(theirs_fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        // passt has its own copy now.
+        drop(theirs);
+        Ok((child, ours))
+    }
+}
+
+pub struct PasstProcess {
+    command: PasstCommand,
+    child: Child,
+    socket: OwnedFd,
+    passt: Passt,
+    /// When passt was last started, or an attempt at it was made.
+    started: Instant,
+    quick_restarts: u32,
+    restart_timer: TimerFd,
+}
+
+impl PasstProcess {
+    pub fn start(command: PasstCommand) -> io::Result<Self> {
+        let (child, socket) = command.spawn()?;
+        log::debug!("Started passt (pid {})", child.id());
+        let passt = Passt::new(socket.as_raw_fd());
+        Ok(PasstProcess {
+            command,
+            child,
+            socket,
+            passt,
+            started: Instant::now(),
+            quick_restarts: 0,
+            restart_timer: TimerFd::new()?,
+        })
+    }
+
+    /// How long to wait before the next attempt at starting passt.
+    fn restart_delay(&self) -> Duration {
+        RESTART_DELAY
+            .saturating_mul(1 << self.quick_restarts.min(16))
+            .min(MAX_RESTART_DELAY)
+    }
+
+    fn stop(&mut self) {
+        // passt may already be gone, and there's nothing else to do about it.
+        let _ = self.child.kill();
+        match self.child.wait() {
+            Ok(status) => log::debug!("passt (pid {}) exited: {status}", self.child.id()),
+            Err(e) => log::warn!("Failed to wait for passt: {e}"),
+        }
+    }
+}
+
+impl NetBackend for PasstProcess {
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        self.passt.read_frame(hdr_len, buf)
+    }
+
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        self.passt.write_frame(hdr_len, buf)
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        self.passt.has_unfinished_write()
+    }
+
+    fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError> {
+        self.passt.try_finish_write(hdr_len, buf)
+    }
+
+    /// Waits for passt to exit and arms the timer for starting it again,
+    /// backing off if it keeps exiting, or failing to start, right away.
+    fn schedule_restart(&mut self) -> io::Result<()> {
+        self.stop();
+        if self.started.elapsed() < STABLE_TIME {
+            self.quick_restarts = self.quick_restarts.saturating_add(1);
+        } else {
+            self.quick_restarts = 0;
+        }
+        let delay = self.restart_delay();
+        log::warn!("passt exited, restarting it in {delay:?}");
+        self.restart_timer.reset(delay, None)?;
+        Ok(())
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        self.restart_timer.wait()?;
+        self.started = Instant::now();
+        let (child, socket) = self.command.spawn()?;
+        log::warn!("Restarted passt (pid {})", child.id());
+        self.passt = Passt::new(socket.as_raw_fd());
+        self.child = child;
+        self.socket = socket;
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn raw_restart_timer_fd(&self) -> Option<RawFd> {
+        Some(self.restart_timer.as_raw_fd())
+    }
+}
+
+impl Drop for PasstProcess {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A shell script standing in for passt. It gets `--foreground --fd <fd>`
+    /// as its arguments, so the socket is `$3`.
+    fn fake_passt(script: &str) -> PasstCommand {
+        PasstCommand {
+            path: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string(), "passt".to_string()],
+        }
+    }
+
+    /// A passt sending every frame back.
+    fn echo_passt() -> PasstCommand {
+        fake_passt("exec cat <&$3 >&$3")
+    }
+
+    /// Waits up to `timeout_ms` for any of `events` on `fd`, returning the
+    /// ones that happened.
+    fn wait_for(fd: RawFd, events: libc::c_short, timeout_ms: libc::c_int) -> libc::c_short {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        // Safe because we pass a single, valid pollfd.
+        assert!(unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } >= 0);
+        pollfd.revents
+    }
+
+    /// Whether the device would see passt hang up.
+    fn hung_up(process: &PasstProcess, timeout_ms: libc::c_int) -> bool {
+        let revents = wait_for(process.raw_socket_fd(), libc::POLLRDHUP, timeout_ms);
+        revents & (libc::POLLRDHUP | libc::POLLHUP) != 0
+    }
+
+    fn echo_frame(process: &mut PasstProcess, frame: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        buf.extend_from_slice(frame);
+        process.write_frame(4, &mut buf).unwrap();
+
+        wait_for(process.raw_socket_fd(), libc::POLLIN, 5000);
+        let mut buf = vec![0; 4 + 64];
+        let len = process.read_frame(4, &mut buf).unwrap();
+        buf[4..4 + len].to_vec()
+    }
+
+    #[test]
+    fn test_exit_detected() {
+        let process = PasstProcess::start(fake_passt("exit 0")).unwrap();
+
+        // This is what the device waits for to restart passt.
+        assert!(hung_up(&process, 5000));
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut process = PasstProcess::start(echo_passt()).unwrap();
+        assert_eq!(echo_frame(&mut process, b"first"), b"first");
+
+        let old_pid = process.child.id();
+        process.child.kill().unwrap();
+        assert!(hung_up(&process, 5000));
+
+        process.schedule_restart().unwrap();
+        process.restart().unwrap();
+        assert_ne!(process.child.id(), old_pid);
+        // Frames go through the socket of the new passt.
+        assert!(!hung_up(&process, 0));
+        assert_eq!(echo_frame(&mut process, b"second"), b"second");
+    }
+
+    #[test]
+    fn test_restart_backoff() {
+        let mut process = PasstProcess::start(fake_passt("exit 0")).unwrap();
+
+        // Exiting right after starting doubles the delay.
+        process.schedule_restart().unwrap();
+        assert_eq!(process.restart_delay(), RESTART_DELAY * 2);
+        assert!(process.restart_timer.is_armed().unwrap());
+        process.restart().unwrap();
+        assert!(!process.restart_timer.is_armed().unwrap());
+
+        // Failing to start is just as bad, and passt is tried again anyway.
+        process.command.path = PathBuf::from("/nonexistent/passt");
+        process.schedule_restart().unwrap();
+        assert!(process.restart().is_err());
+        process.schedule_restart().unwrap();
+        assert_eq!(process.quick_restarts, 3);
+
+        // The delay stops growing, but we never give up.
+        for _ in 0..16 {
+            process.schedule_restart().unwrap();
+        }
+        assert_eq!(process.restart_delay(), MAX_RESTART_DELAY);
+        assert!(process.restart_timer.is_armed().unwrap());
+
+        // Exiting once after running for a while is fine again.
+        process.started = Instant::now().checked_sub(STABLE_TIME).unwrap();
+        process.schedule_restart().unwrap();
+        assert_eq!(process.quick_restarts, 0);
+        assert_eq!(process.restart_delay(), RESTART_DELAY);
+    }
+}
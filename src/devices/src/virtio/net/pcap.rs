@@ -0,0 +1,240 @@
+//! Capture of the frames exchanged by a virtio-net device, in pcapng format.
+//!
+//! Each frame is written as an Enhanced Packet Block, timestamped in
+//! microseconds and flagged as inbound (host to guest) or outbound (guest to
+//! host), so captures can be opened with Wireshark or tcpdump as if they were
+//! taken on the guest's interface.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Direction of a captured frame, from the guest's point of view.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the backend to the guest.
+    Inbound = 1,
+    /// From the guest to the backend.
+    Outbound = 2,
+}
+
+/// Where and how much to capture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PcapConfig {
+    /// Path of the capture file, which is truncated when opened.
+    pub path: PathBuf,
+    /// Once the capture file grows past this size, it's moved to `path`
+    /// with a `.1` suffix, replacing the previous one, and a new file is
+    /// started. Zero means no limit.
+    pub max_size: u64,
+}
+
+/// Appends a block of `block_type` with `body` to `buf`, padding the body to
+/// 32 bits.
+fn push_block(buf: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let padded_len = body.len().next_multiple_of(4);
+    let total_len = (12 + padded_len) as u32;
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&total_len.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.resize(buf.len() + padded_len - body.len(), 0);
+    buf.extend_from_slice(&total_len.to_le_bytes());
+}
+
+/// Appends an option with `code` and `value` to `buf`, padding the value to
+/// 32 bits.
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len() + value.len().next_multiple_of(4) - value.len(), 0);
+}
+
+/// Writes frames to a pcapng file.
+pub struct PcapWriter {
+    config: PcapConfig,
+    if_name: String,
+    file: File,
+    size: u64,
+}
+
+impl PcapWriter {
+    /// Creates the capture file for the interface `if_name`.
+    pub fn new(config: PcapConfig, if_name: &str) -> io::Result<Self> {
+        let file = File::create(&config.path)?;
+        let mut writer = PcapWriter {
+            config,
+            if_name: if_name.to_string(),
+            file,
+            size: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // Version 1.0.
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // The length of the section is unknown.
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        push_block(&mut buf, SECTION_HEADER_BLOCK, &body);
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, self.if_name.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        push_block(&mut buf, INTERFACE_DESCRIPTION_BLOCK, &body);
+
+        self.write(&buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the current capture file out of the way and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut old_path = self.config.path.clone().into_os_string();
+        old_path.push(".1");
+        fs::rename(&self.config.path, Path::new(&old_path))?;
+        self.file = File::create(&self.config.path)?;
+        self.size = 0;
+        self.write_header()
+    }
+
+    /// Writes `frame`, which went in `direction`.
+    pub fn write_frame(&mut self, frame: &[u8], direction: Direction) -> io::Result<()> {
+        if self.config.max_size != 0 && self.size >= self.config.max_size {
+            self.rotate()?;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = Vec::with_capacity(frame.len() + 36);
+        // Interface ID.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        // Captured and original lengths.
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(frame);
+        body.resize(body.len().next_multiple_of(4), 0);
+        push_option(&mut body, OPT_EPB_FLAGS, &(direction as u32).to_le_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+
+        let mut buf = Vec::with_capacity(body.len() + 12);
+        push_block(&mut buf, ENHANCED_PACKET_BLOCK, &body);
+        self.write(&buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+
+    /// Splits a capture into its blocks, checking their framing.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let block_type = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            assert!(len.is_multiple_of(4));
+            let trailer = u32::from_le_bytes(data[pos + len - 4..pos + len].try_into().unwrap());
+            assert_eq!(trailer as usize, len);
+            blocks.push((block_type, &data[pos + 8..pos + len - 4]));
+            pos += len;
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_capture() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("eth0.pcapng");
+        let config = PcapConfig {
+            path: path.clone(),
+            max_size: 0,
+        };
+        let mut writer = PcapWriter::new(config, "eth0").unwrap();
+        writer.write_frame(&[0xaa; 61], Direction::Inbound).unwrap();
+        writer
+            .write_frame(&[0xbb; 64], Direction::Outbound)
+            .unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(&blocks[0].1[..4], &BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_ETHERNET.to_le_bytes());
+        assert_eq!(&blocks[1].1[12..16], b"eth0");
+
+        for ((block_type, body), (len, byte, direction)) in blocks[2..].iter().zip([
+            (61, 0xaa, Direction::Inbound),
+            (64, 0xbb, Direction::Outbound),
+        ]) {
+            assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+            assert_eq!(&body[12..16], &(len as u32).to_le_bytes());
+            assert_eq!(&body[16..20], &(len as u32).to_le_bytes());
+            assert!(body[20..20 + len].iter().all(|&b| b == byte));
+            let options = &body[20 + len.next_multiple_of(4)..];
+            assert_eq!(&options[..4], &[2, 0, 4, 0]);
+            assert_eq!(&options[4..8], &(direction as u32).to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("eth0.pcapng");
+        let old_path = dir.as_path().join("eth0.pcapng.1");
+        let config = PcapConfig {
+            path: path.clone(),
+            max_size: 512,
+        };
+        let mut writer = PcapWriter::new(config, "eth0").unwrap();
+        for i in 0..10u8 {
+            writer.write_frame(&[i; 100], Direction::Inbound).unwrap();
+        }
+
+        // Every file starts with its own headers, and the most recent frames
+        // are kept.
+        let old = fs::read(&old_path).unwrap();
+        let new = fs::read(&path).unwrap();
+        assert!(old.len() < 512 + 200);
+        let old = blocks(&old);
+        let new = blocks(&new);
+        assert_eq!(old[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(new[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(new.last().unwrap().1[20], 9);
+        assert_eq!(old.last().unwrap().1[20] + 1, new[2].1[20]);
+    }
+}
@@ -0,0 +1,398 @@
+//! A learning switch connecting virtio-net devices, possibly of different
+//! microVMs running in the same process, on a private ethernet segment.
+//!
+//! Each port is a unix datagram socketpair carrying one frame per datagram,
+//! whose other end a device uses like any unixgram backend. The switch runs
+//! in its own thread, learning which port each address is behind from the
+//! frames it forwards, and flooding the frames it doesn't know where to send.
+//!
+//! Datagram sockets don't report their peer going away, so a port is removed
+//! when sending or receiving on it fails because its device end was closed.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{
+    recv, send, setsockopt, socketpair, sockopt, AddressFamily, MsgFlags, SockFlag, SockType,
+};
+use utils::eventfd::EventFd;
+
+use super::MAX_BUFFER_SIZE;
+
+/// How long the switch remembers where an address is after last seeing it.
+const AGING_TIME: Duration = Duration::from_secs(300);
+/// How often the switch forgets the addresses it hasn't seen for too long.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+const ETH_ALEN: usize = 6;
+const ETH_HLEN: usize = 14;
+
+/// Where the switch last saw each address, by port ID.
+#[derive(Default)]
+struct MacTable {
+    entries: HashMap<[u8; ETH_ALEN], (u64, Instant)>,
+}
+
+impl MacTable {
+    fn learn(&mut self, mac: [u8; ETH_ALEN], port: u64, now: Instant) {
+        // Multicast addresses are never the source of a frame.
+        if mac[0] & 1 == 0 {
+            self.entries.insert(mac, (port, now));
+        }
+    }
+
+    fn lookup(&self, mac: &[u8; ETH_ALEN], now: Instant) -> Option<u64> {
+        self.entries
+            .get(mac)
+            .filter(|(_, seen)| now.duration_since(*seen) < AGING_TIME)
+            .map(|(port, _)| *port)
+    }
+
+    fn forget_port(&mut self, port: u64) {
+        self.entries.retain(|_, (p, _)| *p != port);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.entries
+            .retain(|_, (_, seen)| now.duration_since(*seen) < AGING_TIME);
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Ports added since the thread last looked.
+    new_ports: Vec<OwnedFd>,
+    stopped: bool,
+}
+
+/// A switch, whose thread stops, closing every port, when it's dropped.
+pub struct Switch {
+    shared: Arc<Mutex<Shared>>,
+    wake: Arc<EventFd>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Switch {
+    /// Starts a switch named `name`, without any ports.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let wake = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+        let mut thread = SwitchThread {
+            shared: shared.clone(),
+            wake: wake.clone(),
+            ports: Vec::new(),
+            next_port: 0,
+            table: MacTable::default(),
+        };
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || thread.run())?;
+        Ok(Switch {
+            shared,
+            wake,
+            thread: Some(thread),
+        })
+    }
+
+    /// Adds a port to the switch, returning the socket its device exchanges
+    /// frames on, one per datagram. The port goes away once the socket is
+    /// closed, the next time the switch tries to use it.
+    pub fn add_port(&self) -> io::Result<RawFd> {
+        let (device_fd, switch_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        // Safe because we just created the socket and nothing else owns it.
+        let switch_fd = unsafe { OwnedFd::from_raw_fd(switch_fd) };
+        if let Err(e) = setsockopt(switch_fd.as_raw_fd(), sockopt::SndBuf, &(16 * 1024 * 1024)) {
+            log::warn!("Failed to increase SO_SNDBUF (performance may be decreased): {e}");
+        }
+        self.shared.lock().unwrap().new_ports.push(switch_fd);
+        self.wake.write(1)?;
+        Ok(device_fd)
+    }
+}
+
+impl Drop for Switch {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().stopped = true;
+        let _ = self.wake.write(1);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("switch: thread panicked");
+            }
+        }
+    }
+}
+
+struct SwitchThread {
+    shared: Arc<Mutex<Shared>>,
+    wake: Arc<EventFd>,
+    /// The ports, by ID.
+    ports: Vec<(u64, OwnedFd)>,
+    next_port: u64,
+    table: MacTable,
+}
+
+impl SwitchThread {
+    fn run(&mut self) {
+        let mut buf = vec![0; MAX_BUFFER_SIZE];
+        let mut fds = Vec::new();
+        let mut last_expire = Instant::now();
+        loop {
+            {
+                let mut shared = self.shared.lock().unwrap();
+                if shared.stopped {
+                    return;
+                }
+                for fd in shared.new_ports.drain(..) {
+                    self.ports.push((self.next_port, fd));
+                    self.next_port += 1;
+                }
+            }
+
+            fds.clear();
+            fds.push(PollFd::new(self.wake.as_raw_fd(), PollFlags::POLLIN));
+            for (_, fd) in &self.ports {
+                fds.push(PollFd::new(fd.as_raw_fd(), PollFlags::POLLIN));
+            }
+            match poll(&mut fds, EXPIRE_INTERVAL.as_millis() as libc::c_int) {
+                Ok(_) | Err(nix::Error::EINTR) => (),
+                Err(e) => {
+                    log::error!("switch: poll failed: {e}");
+                    return;
+                }
+            }
+            if fds[0].revents().is_some_and(|r| !r.is_empty()) {
+                let _ = self.wake.read();
+            }
+
+            let now = Instant::now();
+            let mut closed = Vec::new();
+            for (index, pollfd) in fds[1..].iter().enumerate() {
+                let revents = pollfd.revents().unwrap_or(PollFlags::empty());
+                if revents.contains(PollFlags::POLLIN) {
+                    self.receive(index, &mut buf, now, &mut closed);
+                }
+                if revents.intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+                    closed.push(self.ports[index].0);
+                }
+            }
+            self.remove_ports(&closed);
+
+            if now.duration_since(last_expire) >= EXPIRE_INTERVAL {
+                self.table.expire(now);
+                last_expire = now;
+            }
+        }
+    }
+
+    fn remove_ports(&mut self, closed: &[u64]) {
+        self.ports.retain(|(port, _)| {
+            if closed.contains(port) {
+                log::debug!("switch: port {port} is gone");
+                false
+            } else {
+                true
+            }
+        });
+        for port in closed {
+            self.table.forget_port(*port);
+        }
+    }
+
+    /// Forwards the frames waiting on the port at `index`, adding the IDs of
+    /// the ports found to be closed to `closed`.
+    fn receive(&mut self, index: usize, buf: &mut [u8], now: Instant, closed: &mut Vec<u64>) {
+        loop {
+            match recv(self.ports[index].1.as_raw_fd(), buf, MsgFlags::MSG_DONTWAIT) {
+                Ok(len) => self.forward(index, &buf[..len], now, closed),
+                #[allow(unreachable_patterns)]
+                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => break,
+                Err(nix::Error::ECONNREFUSED | nix::Error::ENOTCONN) => {
+                    closed.push(self.ports[index].0);
+                    break;
+                }
+                Err(e) => {
+                    log::debug!("switch: failed to receive a frame: {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Sends `frame`, which came in on the port at `index`, where its
+    /// destination is, or everywhere else if that's unknown.
+    fn forward(&mut self, index: usize, frame: &[u8], now: Instant, closed: &mut Vec<u64>) {
+        if frame.len() < ETH_HLEN {
+            return;
+        }
+        let dst: [u8; ETH_ALEN] = frame[..ETH_ALEN].try_into().unwrap();
+        let src: [u8; ETH_ALEN] = frame[ETH_ALEN..2 * ETH_ALEN].try_into().unwrap();
+        let in_port = self.ports[index].0;
+        self.table.learn(src, in_port, now);
+
+        let out_port = self.table.lookup(&dst, now);
+        for (port, fd) in &self.ports {
+            if *port == in_port || out_port.is_some_and(|out| out != *port) || closed.contains(port)
+            {
+                continue;
+            }
+            match send(
+                fd.as_raw_fd(),
+                frame,
+                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+            ) {
+                Ok(_) => (),
+                Err(nix::Error::ECONNREFUSED | nix::Error::ENOTCONN | nix::Error::EPIPE) => {
+                    closed.push(*port)
+                }
+                // Like any switch, drop the frame if the port is congested.
+                Err(e) => log::trace!("switch: dropped a frame for port {port}: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_A: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0a];
+    const MAC_B: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0b];
+
+    #[test]
+    fn test_mac_table() {
+        let mut table = MacTable::default();
+        let now = Instant::now();
+        assert_eq!(table.lookup(&MAC_A, now), None);
+
+        table.learn(MAC_A, 1, now);
+        table.learn(MAC_B, 2, now);
+        table.learn([0x01, 0, 0x5e, 0, 0, 1], 2, now);
+        assert_eq!(table.lookup(&MAC_A, now), Some(1));
+        assert_eq!(table.lookup(&[0x01, 0, 0x5e, 0, 0, 1], now), None);
+
+        // Addresses move with the frames.
+        table.learn(MAC_A, 3, now);
+        assert_eq!(table.lookup(&MAC_A, now), Some(3));
+
+        table.forget_port(2);
+        assert_eq!(table.lookup(&MAC_B, now), None);
+
+        let later = now + AGING_TIME;
+        assert_eq!(table.lookup(&MAC_A, later), None);
+        table.expire(later);
+        assert!(table.entries.is_empty());
+    }
+
+    #[test]
+    fn test_switch() {
+        let switch = Switch::new("test switch").unwrap();
+        let a = switch.add_port().unwrap();
+        let b = switch.add_port().unwrap();
+        let c = switch.add_port().unwrap();
+        let mut buf = [0; 64];
+        let recv_frame = |fd, buf: &mut [u8]| {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            poll(&mut fds, 1000).unwrap();
+            recv(fd, buf, MsgFlags::MSG_DONTWAIT).ok()
+        };
+
+        // Unknown destinations are flooded.
+        let mut frame = [0; 60];
+        frame[..6].copy_from_slice(&MAC_B);
+        frame[6..12].copy_from_slice(&MAC_A);
+        send(a, &frame, MsgFlags::empty()).unwrap();
+        assert_eq!(recv_frame(b, &mut buf), Some(60));
+        assert_eq!(recv_frame(c, &mut buf), Some(60));
+
+        // Known ones aren't.
+        frame[..6].copy_from_slice(&MAC_A);
+        frame[6..12].copy_from_slice(&MAC_B);
+        send(b, &frame, MsgFlags::empty()).unwrap();
+        assert_eq!(recv_frame(a, &mut buf), Some(60));
+        assert_eq!(recv(c, &mut buf, MsgFlags::MSG_DONTWAIT).ok(), None);
+
+        for fd in [a, b, c] {
+            nix::unistd::close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_closed_port() {
+        let mut thread = SwitchThread {
+            shared: Arc::new(Mutex::new(Shared::default())),
+            wake: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            ports: Vec::new(),
+            next_port: 0,
+            table: MacTable::default(),
+        };
+        let mut device_fds = Vec::new();
+        for port in 0..3 {
+            let (device_fd, switch_fd) = socketpair(
+                AddressFamily::Unix,
+                SockType::Datagram,
+                None,
+                SockFlag::SOCK_CLOEXEC,
+            )
+            .unwrap();
+            // Safe because we just created the socket and nothing else owns it.
+            let switch_fd = unsafe { OwnedFd::from_raw_fd(switch_fd) };
+            thread.ports.push((port, switch_fd));
+            device_fds.push(device_fd);
+        }
+        let now = Instant::now();
+        thread.table.learn(MAC_B, 1, now);
+
+        // The device of port 1 goes away, which only shows when sending to it.
+        nix::unistd::close(device_fds[1]).unwrap();
+        let mut frame = [0xff; 60];
+        frame[6..12].copy_from_slice(&MAC_A);
+        let mut closed = Vec::new();
+        thread.forward(0, &frame, now, &mut closed);
+        assert_eq!(closed, vec![1]);
+
+        thread.remove_ports(&closed);
+        assert_eq!(
+            thread
+                .ports
+                .iter()
+                .map(|(port, _)| *port)
+                .collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(thread.table.lookup(&MAC_B, now), None);
+
+        // The frame still reached the others.
+        let mut buf = [0; 64];
+        assert_eq!(
+            recv(device_fds[2], &mut buf, MsgFlags::MSG_DONTWAIT).ok(),
+            Some(60)
+        );
+        for fd in [device_fds[0], device_fds[2]] {
+            nix::unistd::close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_drop_switch() {
+        let switch = Switch::new("test switch").unwrap();
+        let a = switch.add_port().unwrap();
+        drop(switch);
+
+        // The thread is gone, along with the switch end of every port.
+        assert_eq!(
+            send(a, &[0; 60], MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL),
+            Err(nix::Error::ECONNREFUSED)
+        );
+        nix::unistd::close(a).unwrap();
+    }
+}
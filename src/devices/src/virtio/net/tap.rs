@@ -0,0 +1,355 @@
+//! TAP interfaces, which let the guest join a host bridge like any other
+//! network interface.
+//!
+//! Frames are exchanged with the virtio-net header in front of them
+//! (`IFF_VNET_HDR`), so checksum and segmentation offloads work end to end.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd::{read, write};
+use nix::{ioctl_read_bad, ioctl_write_int_bad, ioctl_write_ptr, ioctl_write_ptr_bad};
+use nix::{request_code_read, request_code_write};
+use virtio_bindings::virtio_net::{
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
+    VIRTIO_NET_F_GUEST_UFO,
+};
+
+use super::backend::{NetBackend, ReadError, WriteError};
+use super::device::vnet_hdr_len;
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+ioctl_write_ptr_bad!(
+    tun_set_iff,
+    request_code_write!(b'T', 202, mem::size_of::<libc::c_int>()),
+    libc::ifreq
+);
+ioctl_read_bad!(
+    tun_get_iff,
+    request_code_read!(b'T', 210, mem::size_of::<libc::c_uint>()),
+    libc::ifreq
+);
+ioctl_write_int_bad!(
+    tun_set_offload,
+    request_code_write!(b'T', 208, mem::size_of::<libc::c_uint>())
+);
+ioctl_write_ptr!(tun_set_vnet_hdr_sz, b'T', 216, libc::c_int);
+ioctl_write_ptr_bad!(
+    tun_set_queue,
+    request_code_write!(b'T', 217, mem::size_of::<libc::c_int>()),
+    libc::ifreq
+);
+
+fn ifreq(if_name: &str) -> io::Result<libc::ifreq> {
+    let name = CString::new(if_name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let name = name.as_bytes_with_nul();
+    if name.len() > libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("interface name is too long: {if_name}"),
+        ));
+    }
+    // Safe because ifreq is plain data, for which all zeroes is a valid value.
+    let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, &src) in ifreq.ifr_name.iter_mut().zip(name) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifreq)
+}
+
+/// The `TUN_F_*` offloads matching the virtio-net features the guest
+/// accepted. Segmentation offloads all need checksum offload.
+fn tun_offloads(acked_features: u64) -> libc::c_uint {
+    let mut offloads = 0;
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_CSUM) != 0 {
+        offloads |= libc::TUN_F_CSUM;
+        if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+            offloads |= libc::TUN_F_TSO4;
+        }
+        if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO6) != 0 {
+            offloads |= libc::TUN_F_TSO6;
+        }
+        if acked_features & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+            offloads |= libc::TUN_F_UFO;
+        }
+    }
+    offloads
+}
+
+/// A queue of a TAP interface.
+pub struct Tap {
+    file: File,
+    /// Whether the queue is attached to the interface. The kernel refuses
+    /// to attach or detach a queue twice.
+    enabled: bool,
+}
+
+impl Tap {
+    /// Attaches to the TAP interface `if_name`, creating it if it doesn't
+    /// exist, and returns one queue for each of `num_queues`. More than one
+    /// queue requires the interface to support `IFF_MULTI_QUEUE`.
+    pub fn open_named(if_name: &str, num_queues: usize) -> io::Result<Vec<Tap>> {
+        let mut flags = libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR;
+        if num_queues > 1 {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+
+        let mut queues = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues.max(1) {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+                .open(TUN_DEVICE)?;
+            let mut ifreq = ifreq(if_name)?;
+            ifreq.ifr_ifru.ifru_flags = flags as libc::c_short;
+            // Safe because the file descriptor is valid and ifreq outlives the call.
+            unsafe { tun_set_iff(file.as_raw_fd(), &ifreq) }?;
+            queues.push(Tap::new(file)?);
+        }
+        Ok(queues)
+    }
+
+    /// Uses a file descriptor that is already attached to a TAP interface,
+    /// for instance by a CNI plugin. The interface must have been set up with
+    /// `IFF_VNET_HDR`.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a valid file descriptor, which the returned `Tap` takes
+    /// ownership of.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Tap> {
+        let file = File::from_raw_fd(fd);
+        // Safe because ifreq is plain data, for which all zeroes is a valid value.
+        let mut ifreq: libc::ifreq = mem::zeroed();
+        tun_get_iff(file.as_raw_fd(), &mut ifreq)?;
+        let flags = libc::c_int::from(ifreq.ifr_ifru.ifru_flags);
+        if flags & libc::IFF_TAP == 0 || flags & libc::IFF_VNET_HDR == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the file descriptor is not a TAP interface with IFF_VNET_HDR",
+            ));
+        }
+        let fl = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(fl | OFlag::O_NONBLOCK))?;
+        Tap::new(file)
+    }
+
+    fn new(file: File) -> io::Result<Tap> {
+        let hdr_len = vnet_hdr_len() as libc::c_int;
+        // Safe because the file descriptor is valid and hdr_len outlives the call.
+        unsafe { tun_set_vnet_hdr_sz(file.as_raw_fd(), &hdr_len) }?;
+        Ok(Tap {
+            file,
+            enabled: true,
+        })
+    }
+}
+
+impl NetBackend for Tap {
+    /// Reads a frame along with its virtio-net header, which the tap device
+    /// puts in `buf[..hdr_len]`.
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        match read(self.file.as_raw_fd(), buf) {
+            Ok(len) if len >= hdr_len => {
+                log::trace!("Read eth frame from tap: {} bytes", len - hdr_len);
+                Ok(len - hdr_len)
+            }
+            Ok(_) => Err(ReadError::Internal(Errno::EIO)),
+            #[allow(unreachable_patterns)]
+            Err(Errno::EAGAIN | Errno::EWOULDBLOCK) => Err(ReadError::NothingRead),
+            Err(e) => Err(ReadError::Internal(e)),
+        }
+    }
+
+    /// Writes a frame along with its virtio-net header. The tap device takes
+    /// whole frames, so writes are never partial.
+    fn write_frame(&mut self, _hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        match write(self.file.as_raw_fd(), buf) {
+            Ok(_) => Ok(()),
+            #[allow(unreachable_patterns)]
+            Err(Errno::EAGAIN | Errno::EWOULDBLOCK) => Err(WriteError::NothingWritten),
+            Err(e) => Err(WriteError::Internal(e)),
+        }
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    /// Lets the tap device pass frames with partial checksums or needing
+    /// segmentation to the guest, as long as it can handle them.
+    fn set_offloads(&mut self, acked_features: u64) -> nix::Result<()> {
+        let offloads = tun_offloads(acked_features);
+        // Safe because the file descriptor is valid and the ioctl doesn't
+        // access memory.
+        unsafe { tun_set_offload(self.file.as_raw_fd(), offloads as libc::c_int) }?;
+        Ok(())
+    }
+
+    /// Attaches the queue to the interface or detaches it, so the interface
+    /// only spreads frames across the queues the guest uses.
+    fn set_enabled(&mut self, enabled: bool) -> nix::Result<()> {
+        if enabled == self.enabled {
+            return Ok(());
+        }
+        // Safe because ifreq is plain data, for which all zeroes is a valid value.
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        let flags = if enabled {
+            libc::IFF_ATTACH_QUEUE
+        } else {
+            libc::IFF_DETACH_QUEUE
+        };
+        ifreq.ifr_ifru.ifru_flags = flags as libc::c_short;
+        // Safe because the file descriptor is valid and ifreq outlives the call.
+        unsafe { tun_set_queue(self.file.as_raw_fd(), &ifreq) }?;
+        self.enabled = enabled;
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::IntoRawFd;
+
+    use nix::ioctl_read;
+
+    use super::*;
+
+    ioctl_read!(tun_get_vnet_hdr_sz, b'T', 215, libc::c_int);
+
+    /// Creates a TAP interface with `num_queues`, unless the test isn't
+    /// allowed to.
+    fn open_tap(tag: &str, num_queues: usize) -> Option<Vec<Tap>> {
+        let if_name = format!("krun{}{tag}", std::process::id() % 100_000);
+        match Tap::open_named(&if_name, num_queues) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                None
+            }
+            res => Some(res.unwrap()),
+        }
+    }
+
+    fn iff_flags(tap: &Tap) -> libc::c_int {
+        // Safe because ifreq is plain data, for which all zeroes is a valid value.
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        // Safe because the file descriptor is valid and ifreq outlives the call.
+        unsafe { tun_get_iff(tap.file.as_raw_fd(), &mut ifreq) }.unwrap();
+        // Safe because the kernel filled in the flags.
+        libc::c_int::from(unsafe { ifreq.ifr_ifru.ifru_flags })
+    }
+
+    #[test]
+    fn test_offloads() {
+        let features = |bits: &[u32]| bits.iter().fold(0u64, |acc, bit| acc | 1 << bit);
+        assert_eq!(tun_offloads(0), 0);
+        assert_eq!(
+            tun_offloads(features(&[VIRTIO_NET_F_GUEST_CSUM])),
+            libc::TUN_F_CSUM
+        );
+        assert_eq!(
+            tun_offloads(features(&[
+                VIRTIO_NET_F_GUEST_CSUM,
+                VIRTIO_NET_F_GUEST_TSO4,
+                VIRTIO_NET_F_GUEST_TSO6,
+                VIRTIO_NET_F_GUEST_UFO,
+            ])),
+            libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6 | libc::TUN_F_UFO
+        );
+        // Segmentation offloads are useless without checksum offload.
+        assert_eq!(
+            tun_offloads(features(&[VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO])),
+            0
+        );
+    }
+
+    #[test]
+    fn test_open_named() {
+        let Some(mut queues) = open_tap("s", 1) else {
+            return;
+        };
+        assert_eq!(queues.len(), 1);
+        let tap = &mut queues[0];
+        let flags = iff_flags(tap);
+        assert_ne!(flags & libc::IFF_TAP, 0);
+        assert_ne!(flags & libc::IFF_NO_PI, 0);
+        assert_ne!(flags & libc::IFF_VNET_HDR, 0);
+        assert_eq!(flags & libc::IFF_MULTI_QUEUE, 0);
+
+        // Frames carry a header as large as the device's.
+        let mut hdr_len = 0;
+        // Safe because the file descriptor is valid and hdr_len outlives the call.
+        unsafe { tun_get_vnet_hdr_sz(tap.file.as_raw_fd(), &mut hdr_len) }.unwrap();
+        assert_eq!(hdr_len as usize, vnet_hdr_len());
+
+        tap.set_offloads(0).unwrap();
+        tap.set_offloads(
+            1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6,
+        )
+        .unwrap();
+        // Nothing was sent to the interface yet.
+        assert!(matches!(
+            tap.read_frame(vnet_hdr_len(), &mut [0; 1024]),
+            Err(ReadError::NothingRead)
+        ));
+    }
+
+    #[test]
+    fn test_open_multi_queue() {
+        let Some(mut queues) = open_tap("m", 3) else {
+            return;
+        };
+        assert_eq!(queues.len(), 3);
+        for tap in &queues {
+            assert_ne!(iff_flags(tap) & libc::IFF_MULTI_QUEUE, 0);
+        }
+
+        // Queues can be detached and attached again, but only once each.
+        let tap = &mut queues[2];
+        tap.set_enabled(false).unwrap();
+        tap.set_enabled(false).unwrap();
+        assert!(!tap.enabled);
+        tap.set_enabled(true).unwrap();
+        tap.set_enabled(true).unwrap();
+        assert!(tap.enabled);
+    }
+
+    #[test]
+    fn test_from_raw_fd() {
+        // Other file descriptors are refused, and closed.
+        let fd = File::open("/dev/null").unwrap().into_raw_fd();
+        // Safe because we own the file descriptor.
+        assert!(unsafe { Tap::from_raw_fd(fd) }.is_err());
+
+        let Some(mut queues) = open_tap("f", 1) else {
+            return;
+        };
+        let fd = queues.pop().unwrap().file.into_raw_fd();
+        // Safe because we own the file descriptor.
+        let tap = unsafe { Tap::from_raw_fd(fd) }.unwrap();
+        let fl = OFlag::from_bits_truncate(fcntl(tap.raw_socket_fd(), FcntlArg::F_GETFL).unwrap());
+        assert!(fl.contains(OFlag::O_NONBLOCK));
+    }
+}
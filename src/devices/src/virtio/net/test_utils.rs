@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::EventFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::virtio::net::backend::{NetBackend, ReadError, WriteError};
+use crate::virtio::net::device::{vnet_hdr_len, Net};
+use crate::virtio::queue::tests::VirtQueue;
+use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use crate::virtio::VirtioDevice;
+
+// Each queue takes a page, and its buffers a page each after the queues.
+const QUEUE_SIZE: u16 = 16;
+const BUFS_ADDR: u64 = 0x10_0000;
+const BUF_SIZE: u32 = 0x1000;
+
+/// What a `MockBackend` was asked to do, and the frames it holds for the
+/// guest.
+#[derive(Default)]
+pub struct MockState {
+    /// Frames the guest will receive.
+    pub rx: VecDeque<Vec<u8>>,
+    /// Frames the guest sent.
+    pub tx: Vec<Vec<u8>>,
+    pub enabled: Option<bool>,
+    pub restarts: usize,
+}
+
+/// Backend keeping frames in memory, whose state is shared with the test.
+pub struct MockBackend {
+    pub state: Arc<Mutex<MockState>>,
+    evt: EventFd,
+}
+
+impl MockBackend {
+    pub fn new() -> (Self, Arc<Mutex<MockState>>) {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let backend = MockBackend {
+            state: state.clone(),
+            evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        };
+        (backend, state)
+    }
+}
+
+impl NetBackend for MockBackend {
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let frame = self
+            .state
+            .lock()
+            .unwrap()
+            .rx
+            .pop_front()
+            .ok_or(ReadError::NothingRead)?;
+        buf[hdr_len..hdr_len + frame.len()].copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        self.state.lock().unwrap().tx.push(buf[hdr_len..].to_vec());
+        Ok(())
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> nix::Result<()> {
+        self.state.lock().unwrap().enabled = Some(enabled);
+        Ok(())
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().restarts += 1;
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.evt.as_raw_fd()
+    }
+}
+
+/// Creates a device with a `MockBackend` for each of `num_queue_pairs`.
+pub fn mock_net(num_queue_pairs: usize) -> (Net, Vec<Arc<Mutex<MockState>>>) {
+    let (backends, states): (Vec<Box<dyn NetBackend>>, Vec<_>) = (0..num_queue_pairs)
+        .map(|_| {
+            let (backend, state) = MockBackend::new();
+            (Box::new(backend) as Box<dyn NetBackend>, state)
+        })
+        .unzip();
+    let net = Net::with_backends("test".to_string(), backends, None, None).unwrap();
+    (net, states)
+}
+
+pub fn guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x40_0000)]).unwrap()
+}
+
+/// The `VIRTIO_NET_S_*` status the guest reads from the config space.
+pub fn guest_status(net: &Net) -> u32 {
+    let mut status = [0u8; 2];
+    net.read_config(6, &mut status);
+    u32::from(u16::from_le_bytes(status))
+}
+
+/// The guest side of the queues of a device.
+pub struct GuestQueues<'a> {
+    mem: &'a GuestMemoryMmap,
+    pub vqs: Vec<VirtQueue<'a>>,
+}
+
+/// Sets up every queue of `net` in `mem`, and activates it with the
+/// features in `acked_features`.
+pub fn activate<'a>(
+    net: &mut Net,
+    mem: &'a GuestMemoryMmap,
+    acked_features: u64,
+) -> GuestQueues<'a> {
+    net.set_acked_features(acked_features);
+    let vqs: Vec<_> = (0..net.queues.len())
+        .map(|index| VirtQueue::new(GuestAddress(index as u64 * 0x1000), mem, QUEUE_SIZE))
+        .collect();
+    for (queue, vq) in net.queues.iter_mut().zip(&vqs) {
+        *queue = vq.create_queue();
+    }
+    net.activate(mem.clone()).unwrap();
+    GuestQueues { mem, vqs }
+}
+
+fn buf_addr(queue: usize, desc: u16) -> GuestAddress {
+    GuestAddress(BUFS_ADDR + (queue as u64 * QUEUE_SIZE as u64 + desc as u64) * BUF_SIZE as u64)
+}
+
+impl GuestQueues<'_> {
+    /// Makes the next descriptor of the `queue`-th queue available, pointing
+    /// at a buffer that holds `data`, or room for a frame if `writable`.
+    fn add_buffer(&self, queue: usize, data: &[u8], writable: bool) {
+        let vq = &self.vqs[queue];
+        let idx = vq.avail.idx.get();
+        let desc = idx % QUEUE_SIZE;
+        let addr = buf_addr(queue, desc);
+        let (len, flags) = if writable {
+            (BUF_SIZE, VIRTQ_DESC_F_WRITE)
+        } else {
+            (data.len() as u32, 0)
+        };
+        self.mem.write_slice(data, addr).unwrap();
+        vq.dtable[desc as usize].set(addr.0, len, flags, 0);
+
+        vq.avail.ring[desc as usize].set(desc);
+        vq.avail.idx.set(idx.wrapping_add(1));
+    }
+
+    /// Gives `count` buffers to the `queue`-th queue to receive frames.
+    pub fn add_rx_buffers(&self, queue: usize, count: u16) {
+        for _ in 0..count {
+            self.add_buffer(queue, &[], true);
+        }
+    }
+
+    /// Has the guest send `frame` on the `queue`-th queue.
+    pub fn add_tx_frame(&self, queue: usize, frame: &[u8]) {
+        let mut buf = vec![0; vnet_hdr_len()];
+        buf.extend_from_slice(frame);
+        self.add_buffer(queue, &buf, false);
+    }
+
+    /// Number of descriptor chains the device put in the used ring of the
+    /// `queue`-th queue.
+    pub fn used(&self, queue: usize) -> u16 {
+        self.vqs[queue].used.idx.get()
+    }
+
+    /// Frames the guest received on the `queue`-th queue.
+    pub fn received_frames(&self, queue: usize) -> Vec<Vec<u8>> {
+        let vq = &self.vqs[queue];
+        (0..vq.used.idx.get())
+            .map(|idx| {
+                let elem = vq.used.ring[(idx % QUEUE_SIZE) as usize].get();
+                let mut buf = vec![0; elem.len as usize];
+                self.mem
+                    .read_slice(&mut buf, buf_addr(queue, elem.id as u16))
+                    .unwrap();
+                buf.split_off(vnet_hdr_len())
+            })
+            .collect()
+    }
+
+    /// Has the guest send the `class`:`cmd` command with `data` on the
+    /// `queue`-th queue, which must be the control queue.
+    pub fn add_ctrl_command(&self, queue: usize, class: u32, cmd: u32, data: &[u8]) {
+        let vq = &self.vqs[queue];
+        let idx = vq.avail.idx.get();
+        // The header, the data and the ack each take a descriptor.
+        let head = idx * 3 % QUEUE_SIZE;
+        let descs = [head, (head + 1) % QUEUE_SIZE, (head + 2) % QUEUE_SIZE];
+        let bufs: [&[u8]; 2] = [&[class as u8, cmd as u8], data];
+        for (i, buf) in bufs.into_iter().enumerate() {
+            let addr = buf_addr(queue, descs[i]);
+            self.mem.write_slice(buf, addr).unwrap();
+            vq.dtable[descs[i] as usize].set(
+                addr.0,
+                buf.len() as u32,
+                VIRTQ_DESC_F_NEXT,
+                descs[i + 1],
+            );
+        }
+        let ack_addr = buf_addr(queue, descs[2]);
+        self.mem.write_obj(0xffu8, ack_addr).unwrap();
+        vq.dtable[descs[2] as usize].set(ack_addr.0, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        vq.avail.ring[(idx % QUEUE_SIZE) as usize].set(head);
+        vq.avail.idx.set(idx.wrapping_add(1));
+    }
+
+    /// The ack of the last command the device handled on the `queue`-th
+    /// queue.
+    pub fn ctrl_ack(&self, queue: usize) -> u8 {
+        let vq = &self.vqs[queue];
+        let idx = vq.used.idx.get().wrapping_sub(1);
+        let head = vq.used.ring[(idx % QUEUE_SIZE) as usize].get().id as u16;
+        self.mem
+            .read_obj(buf_addr(queue, (head + 2) % QUEUE_SIZE))
+            .unwrap()
+    }
+}
@@ -0,0 +1,234 @@
+use nix::sys::socket::{
+    bind, connect, getsockopt, recv, send, setsockopt, socket, sockopt, AddressFamily, MsgFlags,
+    SockFlag, SockType, UnixAddr,
+};
+use nix::unistd::{close, unlink};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::backend::{NetBackend, ReadError, WriteError};
+
+/// First datagram sent to the server after connecting, which vfkit-style
+/// servers such as gvproxy expect. It's shorter than any ethernet frame, so
+/// other servers drop it.
+const VFKIT_MAGIC: &[u8] = b"VFKT";
+
+// Keeps the local addresses of several interfaces connected to the same
+// server apart.
+static NEXT_LOCAL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A unix datagram socket carrying one ethernet frame per datagram, as used
+/// by vfkit and gvproxy.
+pub struct Unixgram {
+    fd: RawFd,
+    // Address we bound to in order to receive frames, removed on drop.
+    local_path: Option<PathBuf>,
+}
+
+impl Unixgram {
+    /// Uses a datagram socket that is already connected to the server, for
+    /// instance one end of a socketpair.
+    pub fn new(fd: RawFd) -> Self {
+        if let Err(e) = setsockopt(fd, sockopt::SndBuf, &(16 * 1024 * 1024)) {
+            log::warn!("Failed to increase SO_SNDBUF (performance may be decreased): {e}");
+        }
+
+        log::debug!(
+            "unixgram socket (fd {fd}) buffer sizes: SndBuf={:?} RcvBuf={:?}",
+            getsockopt(fd, sockopt::SndBuf),
+            getsockopt(fd, sockopt::RcvBuf)
+        );
+
+        Self {
+            fd,
+            local_path: None,
+        }
+    }
+
+    /// Connects to the server listening on `path`. The socket is bound next
+    /// to it, so the server has an address to send frames to.
+    pub fn connect(path: &Path) -> nix::Result<Self> {
+        let mut local_path = path.as_os_str().to_owned();
+        local_path.push(format!(
+            "-krun-{}-{}.sock",
+            std::process::id(),
+            NEXT_LOCAL_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let local_path = PathBuf::from(local_path);
+        let local_addr = UnixAddr::new(&local_path)?;
+        let peer_addr = UnixAddr::new(path)?;
+
+        let fd = socket(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        // From now on, dropping the backend cleans up after us.
+        let mut backend = Self::new(fd);
+        backend.local_path = Some(local_path.clone());
+
+        // A previous process with the same pid may have left it behind.
+        let _ = unlink(&local_path);
+        bind(fd, &local_addr)?;
+        connect(fd, &peer_addr)?;
+        send(fd, VFKIT_MAGIC, MsgFlags::MSG_NOSIGNAL)?;
+        Ok(backend)
+    }
+}
+
+impl NetBackend for Unixgram {
+    fn read_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        match recv(self.fd, &mut buf[hdr_len..], MsgFlags::MSG_DONTWAIT) {
+            Ok(frame_length) => {
+                log::trace!("Read eth frame from unixgram: {} bytes", frame_length);
+                Ok(frame_length)
+            }
+            #[allow(unreachable_patterns)]
+            Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => Err(ReadError::NothingRead),
+            Err(e) => Err(ReadError::Internal(e)),
+        }
+    }
+
+    /// Sends the frame as a single datagram, so writes are never partial.
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        match send(
+            self.fd,
+            &buf[hdr_len..],
+            MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+        ) {
+            Ok(_) => Ok(()),
+            #[allow(unreachable_patterns)]
+            Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK | nix::Error::ENOBUFS) => {
+                Err(WriteError::NothingWritten)
+            }
+            Err(nix::Error::ECONNREFUSED | nix::Error::EPIPE) => Err(WriteError::ProcessNotRunning),
+            Err(e) => Err(WriteError::Internal(e)),
+        }
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Drop for Unixgram {
+    fn drop(&mut self) {
+        if let Some(local_path) = &self.local_path {
+            let _ = unlink(local_path);
+        }
+        let _ = close(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixDatagram;
+
+    use nix::sys::socket::socketpair;
+    use utils::tempdir::TempDir;
+
+    const FRAME: &[u8] = &[0xff; 60];
+    const HDR_LEN: usize = 12;
+
+    #[test]
+    fn test_socketpair() {
+        let (fd, peer) = socketpair(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let mut backend = Unixgram::new(fd);
+        let mut buf = [0u8; HDR_LEN + 1500];
+
+        assert!(matches!(
+            backend.read_frame(HDR_LEN, &mut buf),
+            Err(ReadError::NothingRead)
+        ));
+
+        // Frames are read after the header.
+        send(peer, FRAME, MsgFlags::empty()).unwrap();
+        assert_eq!(backend.read_frame(HDR_LEN, &mut buf).unwrap(), FRAME.len());
+        assert_eq!(&buf[HDR_LEN..HDR_LEN + FRAME.len()], FRAME);
+
+        // And written without it.
+        let mut frame = [0u8; HDR_LEN + 60];
+        frame[HDR_LEN..].copy_from_slice(FRAME);
+        backend.write_frame(HDR_LEN, &mut frame).unwrap();
+        let mut peer_buf = [0u8; 1500];
+        assert_eq!(
+            recv(peer, &mut peer_buf, MsgFlags::empty()).unwrap(),
+            FRAME.len()
+        );
+        assert_eq!(&peer_buf[..FRAME.len()], FRAME);
+
+        // Dropping the backend closes its end, even without a local path.
+        drop(backend);
+        assert!(send(peer, FRAME, MsgFlags::empty()).is_err());
+        close(peer).unwrap();
+    }
+
+    #[test]
+    fn test_connect() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("net.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let mut backend = Unixgram::connect(&path).unwrap();
+        let local_path = backend.local_path.clone().unwrap();
+        assert!(local_path.exists());
+
+        // The server learns the address of the client from the magic.
+        let mut buf = [0u8; 1500];
+        let (len, addr) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], VFKIT_MAGIC);
+        assert_eq!(addr.as_pathname(), Some(local_path.as_path()));
+
+        server.send_to(FRAME, &local_path).unwrap();
+        let mut frame = [0u8; HDR_LEN + 1500];
+        assert_eq!(
+            backend.read_frame(HDR_LEN, &mut frame).unwrap(),
+            FRAME.len()
+        );
+
+        drop(backend);
+        assert!(!local_path.exists());
+    }
+
+    #[test]
+    fn test_server_gone() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("net.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let mut backend = Unixgram::connect(&path).unwrap();
+        drop(server);
+
+        let mut frame = [0u8; HDR_LEN + 60];
+        assert!(matches!(
+            backend.write_frame(HDR_LEN, &mut frame),
+            Err(WriteError::ProcessNotRunning)
+        ));
+    }
+
+    #[test]
+    fn test_connect_without_server() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("net.sock");
+        assert!(Unixgram::connect(&path).is_err());
+        // The local socket is cleaned up on failure.
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+    }
+}
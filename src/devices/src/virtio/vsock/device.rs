@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -19,10 +20,14 @@ use super::super::{
     ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VsockError,
     VIRTIO_MMIO_INT_VRING,
 };
+use super::egress::EgressPolicy;
 use super::muxer::VsockMuxer;
 use super::packet::VsockPacket;
+use super::rate_limiter::TsiRateLimiter;
 use super::{defs, defs::uapi};
 use crate::legacy::Gic;
+#[cfg(target_os = "linux")]
+use crate::rate_limiter::RateLimiterConfig;
 
 pub(crate) const RXQ_INDEX: usize = 0;
 pub(crate) const TXQ_INDEX: usize = 1;
@@ -49,6 +54,8 @@ pub struct Vsock {
     pub(crate) interrupt_evt: EventFd,
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
+    /// Limits the data the guest sends through TSI.
+    pub(crate) tx_rate_limiter: TsiRateLimiter,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
 }
@@ -57,6 +64,9 @@ impl Vsock {
     pub(crate) fn with_queues(
         cid: u64,
         host_port_map: Option<HashMap<u16, u16>>,
+        host_udp_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: HashMap<PathBuf, PathBuf>,
+        egress_policy: EgressPolicy,
         queues: Vec<VirtQueue>,
     ) -> super::Result<Vsock> {
         let mut queue_events = Vec::new();
@@ -71,14 +81,19 @@ impl Vsock {
         let interrupt_evt =
             EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(VsockError::EventFd)?;
         let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let rx_rate_limiter = TsiRateLimiter::new().map_err(VsockError::RateLimiter)?;
 
         Ok(Vsock {
             cid,
             muxer: VsockMuxer::new(
                 cid,
                 host_port_map,
+                host_udp_port_map,
+                unix_socket_map,
+                egress_policy,
                 interrupt_evt.try_clone().unwrap(),
                 interrupt_status.clone(),
+                rx_rate_limiter,
             ),
             queue_rx,
             queue_tx,
@@ -91,18 +106,38 @@ impl Vsock {
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
                 .map_err(VsockError::EventFd)?,
             device_state: DeviceState::Inactive,
+            tx_rate_limiter: TsiRateLimiter::new().map_err(VsockError::RateLimiter)?,
             intc: None,
             irq_line: None,
         })
     }
 
-    /// Create a new virtio-vsock device with the given VM CID.
-    pub fn new(cid: u64, host_port_map: Option<HashMap<u16, u16>>) -> super::Result<Vsock> {
+    /// Create a new virtio-vsock device with the given VM CID. The TCP and UDP
+    /// ports the guest listens on are exposed on the host as mapped by
+    /// `host_port_map` and `host_udp_port_map`, or at the same port if the map
+    /// is `None`. The unix sockets of the guest can only connect to the paths
+    /// in `unix_socket_map`, which leads them to the unix sockets of the host
+    /// at the mapped paths. The destinations the guest may reach through TSI
+    /// are set by `egress_policy`.
+    pub fn new(
+        cid: u64,
+        host_port_map: Option<HashMap<u16, u16>>,
+        host_udp_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: HashMap<PathBuf, PathBuf>,
+        egress_policy: EgressPolicy,
+    ) -> super::Result<Vsock> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(cid, host_port_map, queues)
+        Self::with_queues(
+            cid,
+            host_port_map,
+            host_udp_port_map,
+            unix_socket_map,
+            egress_policy,
+            queues,
+        )
     }
 
     pub fn id(&self) -> &str {
@@ -117,6 +152,18 @@ impl Vsock {
         self.cid
     }
 
+    /// Replaces the limits of the data TSI passes to the guest.
+    #[cfg(target_os = "linux")]
+    pub fn update_rx_rate_limiter(&self, config: RateLimiterConfig) {
+        self.muxer.update_rx_rate_limiter(config);
+    }
+
+    /// Replaces the limits of the data the guest sends through TSI.
+    #[cfg(target_os = "linux")]
+    pub fn update_tx_rate_limiter(&self, config: RateLimiterConfig) {
+        self.tx_rate_limiter.update(config);
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -200,6 +247,12 @@ impl Vsock {
                 }
             };
 
+            if !self.tx_rate_limiter.consume(pkt.len() as usize) {
+                // Leave the packet in the queue until the timer expires.
+                queue_tx.undo_pop();
+                break;
+            }
+
             if pkt.type_() == uapi::VSOCK_TYPE_DGRAM {
                 debug!("vsock::process_stream_tx() is DGRAM");
                 if self.muxer.send_dgram_pkt(&pkt).is_err() {
@@ -0,0 +1,244 @@
+//! Egress policy of TSI, deciding which destinations the guest may reach
+//! through the sockets of the host.
+//!
+//! A policy is a list of rules, the first one matching a destination deciding
+//! whether the guest may connect or send datagrams to it. Destinations no rule
+//! matches are allowed, so a policy denying by default ends with deny rules
+//! matching everything.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EgressProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EgressAction {
+    Allow,
+    Deny,
+}
+
+/// A rule, written as "<action> <protocol> <network> <ports>", for instance
+/// "allow tcp 151.101.0.0/16 443" or "deny any any 1-1023". The protocol is
+/// "tcp", "udp" or "any", the network an address, with or without a prefix
+/// length, or "any", and the ports a port, a range of ports or "any".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EgressRule {
+    action: EgressAction,
+    protocol: Option<EgressProtocol>,
+    network: Option<(IpAddr, u8)>,
+    ports: (u16, u16),
+}
+
+impl EgressRule {
+    fn matches(&self, protocol: EgressProtocol, addr: IpAddr, port: u16) -> bool {
+        if self.protocol.is_some_and(|p| p != protocol) {
+            return false;
+        }
+        if port < self.ports.0 || port > self.ports.1 {
+            return false;
+        }
+        match self.network {
+            None => true,
+            Some((IpAddr::V4(net), len)) => match addr {
+                IpAddr::V4(addr) => {
+                    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+                    u32::from(addr) & mask == u32::from(net) & mask
+                }
+                IpAddr::V6(_) => false,
+            },
+            Some((IpAddr::V6(net), len)) => match addr {
+                IpAddr::V6(addr) => {
+                    let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+                    u128::from(addr) & mask == u128::from(net) & mask
+                }
+                IpAddr::V4(_) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err("an egress rule must have four fields");
+        }
+
+        let action = match fields[0] {
+            "allow" => EgressAction::Allow,
+            "deny" => EgressAction::Deny,
+            _ => return Err("invalid egress rule action"),
+        };
+
+        let protocol = match fields[1] {
+            "tcp" => Some(EgressProtocol::Tcp),
+            "udp" => Some(EgressProtocol::Udp),
+            "any" => None,
+            _ => return Err("invalid egress rule protocol"),
+        };
+
+        let network = if fields[2] == "any" {
+            None
+        } else {
+            let (addr, len) = match fields[2].split_once('/') {
+                Some((addr, len)) => (addr, Some(len)),
+                None => (fields[2], None),
+            };
+            let addr: IpAddr = addr.parse().map_err(|_| "invalid egress rule address")?;
+            let max_len = if addr.is_ipv4() { 32 } else { 128 };
+            let len = match len {
+                Some(len) => len
+                    .parse()
+                    .map_err(|_| "invalid egress rule prefix length")?,
+                None => max_len,
+            };
+            if len > max_len {
+                return Err("invalid egress rule prefix length");
+            }
+            Some((addr, len))
+        };
+
+        let ports = if fields[3] == "any" {
+            (0, u16::MAX)
+        } else {
+            let (first, last) = fields[3].split_once('-').unwrap_or((fields[3], fields[3]));
+            let first = first.parse().map_err(|_| "invalid egress rule port")?;
+            let last = last.parse().map_err(|_| "invalid egress rule port")?;
+            if first > last {
+                return Err("invalid egress rule port range");
+            }
+            (first, last)
+        };
+
+        Ok(EgressRule {
+            action,
+            protocol,
+            network,
+            ports,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EgressPolicy {
+    rules: Vec<EgressRule>,
+    /// The error number, as the guest defines it, of denied connections.
+    errno: i32,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        EgressPolicy {
+            rules: Vec::new(),
+            errno: libc::EPERM,
+        }
+    }
+}
+
+impl EgressPolicy {
+    pub fn new(rules: Vec<EgressRule>, errno: i32) -> Self {
+        EgressPolicy { rules, errno }
+    }
+
+    /// Checks whether the guest may reach `port` at `addr` with `protocol`,
+    /// returning the negated error number to give it if it may not.
+    pub fn check(&self, protocol: EgressProtocol, addr: IpAddr, port: u16) -> Result<(), i32> {
+        // An IPv6 socket reaches IPv4 destinations through mapped addresses.
+        let addr = addr.to_canonical();
+        match self.rules.iter().find(|r| r.matches(protocol, addr, port)) {
+            Some(rule) if rule.action == EgressAction::Deny => Err(-self.errno),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[&str]) -> EgressPolicy {
+        let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+        EgressPolicy::new(rules, libc::ECONNREFUSED)
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: EgressRule = "allow tcp 10.0.0.0/8 8000-8080".parse().unwrap();
+        assert_eq!(rule.action, EgressAction::Allow);
+        assert_eq!(rule.protocol, Some(EgressProtocol::Tcp));
+        assert_eq!(rule.network, Some(("10.0.0.0".parse().unwrap(), 8)));
+        assert_eq!(rule.ports, (8000, 8080));
+
+        let rule: EgressRule = "deny any 2001:db8::1 any".parse().unwrap();
+        assert_eq!(rule.protocol, None);
+        assert_eq!(rule.network, Some(("2001:db8::1".parse().unwrap(), 128)));
+        assert_eq!(rule.ports, (0, u16::MAX));
+
+        for rule in [
+            "allow tcp 10.0.0.0/8",
+            "permit tcp any any",
+            "allow sctp any any",
+            "allow tcp 10.0.0.0/33 any",
+            "allow tcp 10.0.0 any",
+            "allow tcp any 80-79",
+            "allow tcp any 65536",
+        ] {
+            assert!(rule.parse::<EgressRule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = policy(&[
+            "allow tcp 151.101.0.0/16 443",
+            "allow udp 10.0.0.1 53",
+            "deny any any any",
+        ]);
+        let mirror: IpAddr = "151.101.1.1".parse().unwrap();
+        assert_eq!(policy.check(EgressProtocol::Tcp, mirror, 443), Ok(()));
+        assert_eq!(
+            policy.check(EgressProtocol::Tcp, mirror, 80),
+            Err(-libc::ECONNREFUSED)
+        );
+        assert_eq!(
+            policy.check(EgressProtocol::Udp, mirror, 443),
+            Err(-libc::ECONNREFUSED)
+        );
+        assert_eq!(
+            policy.check(EgressProtocol::Udp, "10.0.0.1".parse().unwrap(), 53),
+            Ok(())
+        );
+
+        // Mapped addresses don't get around the IPv4 rules.
+        let mapped: IpAddr = "::ffff:151.101.1.1".parse().unwrap();
+        assert_eq!(policy.check(EgressProtocol::Tcp, mapped, 443), Ok(()));
+        let mapped: IpAddr = "::ffff:8.8.8.8".parse().unwrap();
+        assert_eq!(
+            policy.check(EgressProtocol::Tcp, mapped, 443),
+            Err(-libc::ECONNREFUSED)
+        );
+    }
+
+    #[test]
+    fn test_default_allow() {
+        let policy = policy(&["deny tcp ::/0 any", "deny udp 0.0.0.0/0 53"]);
+        assert_eq!(
+            policy.check(EgressProtocol::Tcp, "2001:db8::1".parse().unwrap(), 22),
+            Err(-libc::ECONNREFUSED)
+        );
+        assert_eq!(
+            policy.check(EgressProtocol::Tcp, "192.0.2.1".parse().unwrap(), 22),
+            Ok(())
+        );
+        assert_eq!(
+            EgressPolicy::default().check(EgressProtocol::Udp, "192.0.2.1".parse().unwrap(), 53),
+            Ok(())
+        );
+    }
+}
@@ -56,6 +56,21 @@ impl Vsock {
         raise_irq
     }
 
+    fn handle_tx_rate_limiter_event(&mut self) -> bool {
+        debug!("vsock: TX rate limiter event");
+
+        // Packets were deferred until the timer expired, so retry them now.
+        if let Err(e) = self.tx_rate_limiter.event_handler() {
+            error!("Failed to get vsock tx rate limiter event: {:?}", e);
+            return false;
+        }
+        let mut raise_irq = self.process_stream_tx();
+        if self.muxer.has_pending_rx() {
+            raise_irq |= self.process_stream_rx();
+        }
+        raise_irq
+    }
+
     fn handle_evq_event(&mut self, event: &EpollEvent) -> bool {
         debug!("vsock: event queue event");
 
@@ -109,6 +124,21 @@ impl Vsock {
                 error!("Failed to register vsock txq with event manager: {:?}", e);
             });
 
+        if let Some(fd) = self.tx_rate_limiter.timer_fd() {
+            event_manager
+                .register(
+                    fd,
+                    EpollEvent::new(EventSet::IN, fd as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to register vsock tx rate limiter with event manager: {:?}",
+                        e
+                    );
+                });
+        }
+
         event_manager
             .unregister(self.activate_evt.as_raw_fd())
             .unwrap_or_else(|e| {
@@ -123,6 +153,7 @@ impl Subscriber for Vsock {
         let rxq = self.queue_events[RXQ_INDEX].as_raw_fd();
         let txq = self.queue_events[TXQ_INDEX].as_raw_fd();
         let evq = self.queue_events[EVQ_INDEX].as_raw_fd();
+        let tx_rate_limiter = self.tx_rate_limiter.timer_fd();
         //let backend = self.backend.as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
 
@@ -132,6 +163,9 @@ impl Subscriber for Vsock {
                 _ if source == rxq => raise_irq = self.handle_rxq_event(event),
                 _ if source == txq => raise_irq = self.handle_txq_event(event),
                 _ if source == evq => raise_irq = self.handle_evq_event(event),
+                _ if Some(source) == tx_rate_limiter => {
+                    raise_irq = self.handle_tx_rate_limiter_event()
+                }
                 /*
                 _ if source == backend => {
                     raise_irq = self.notify_backend(event);
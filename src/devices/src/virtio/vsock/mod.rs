@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 mod device;
+mod egress;
 mod event_handler;
 mod muxer;
 mod muxer_rxq;
@@ -13,14 +14,18 @@ mod muxer_thread;
 #[allow(dead_code)]
 mod packet;
 mod proxy;
+mod rate_limiter;
 mod reaper;
+mod stream;
 mod tcp;
 #[cfg(target_os = "macos")]
 mod timesync;
 mod udp;
+mod unix;
 
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::device::Vsock;
+pub use self::egress::{EgressPolicy, EgressRule};
 
 use vm_memory::GuestMemoryError;
 
@@ -47,6 +52,11 @@ mod defs {
     pub const SOCK_STREAM: u16 = 1;
     pub const SOCK_DGRAM: u16 = 2;
 
+    /// Address families, as the guest (always Linux) defines them.
+    pub const AF_UNIX: u16 = 1;
+    pub const AF_INET: u16 = 2;
+    pub const AF_INET6: u16 = 10;
+
     /// Misc
     pub const TSI_PROXY_PORT: u32 = 620;
     pub const TSI_PROXY_CREATE: u32 = 1024;
@@ -58,6 +68,10 @@ mod defs {
     pub const TSI_ACCEPT: u32 = 1030;
     pub const TSI_PROXY_RELEASE: u32 = 1031;
 
+    /// Epoll data of the timer of the TSI receive rate limiter in the muxer
+    /// thread, which no proxy ID can take.
+    pub const RX_RATE_LIMITER_ID: u64 = u64::MAX;
+
     pub mod uapi {
 
         /// Virtio feature flags.
@@ -137,6 +151,8 @@ pub enum VsockError {
     UnwritableDescriptor,
     /// EventFd error
     EventFd(std::io::Error),
+    /// Failed to create a rate limiter.
+    RateLimiter(std::io::Error),
 }
 
 type Result<T> = std::result::Result<T, VsockError>;
@@ -1,23 +1,32 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::io::RawFd;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
+use nix::sys::socket::AddressFamily;
+
 use super::super::super::legacy::Gic;
 use super::super::Queue as VirtQueue;
 use super::super::VIRTIO_MMIO_INT_VRING;
 use super::defs;
 use super::defs::uapi;
+use super::egress::{EgressPolicy, EgressProtocol};
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiGetnameRsp, VsockPacket};
 use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
+use super::rate_limiter::TsiRateLimiter;
 use super::reaper::ReaperThread;
 use super::tcp::TcpProxy;
 #[cfg(target_os = "macos")]
 use super::timesync::TimesyncThread;
 use super::udp::UdpProxy;
+use super::unix::UnixProxy;
 use super::VsockError;
+#[cfg(target_os = "linux")]
+use crate::rate_limiter::RateLimiterConfig;
 use crossbeam_channel::{unbounded, Sender};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
@@ -95,6 +104,11 @@ pub fn push_packet(
 pub struct VsockMuxer {
     cid: u64,
     host_port_map: Option<HashMap<u16, u16>>,
+    host_udp_port_map: Option<HashMap<u16, u16>>,
+    unix_socket_map: HashMap<PathBuf, PathBuf>,
+    egress_policy: EgressPolicy,
+    /// Datagram proxies whose last destination the egress policy denied.
+    denied_sendto: Mutex<HashSet<u64>>,
     queue: Option<Arc<Mutex<VirtQueue>>>,
     mem: Option<GuestMemoryMmap>,
     rxq: Arc<Mutex<MuxerRxQ>>,
@@ -105,18 +119,28 @@ pub struct VsockMuxer {
     irq_line: Option<u32>,
     proxy_map: ProxyMap,
     reaper_sender: Option<Sender<u64>>,
+    rx_rate_limiter: TsiRateLimiter,
 }
 
 impl VsockMuxer {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         cid: u64,
         host_port_map: Option<HashMap<u16, u16>>,
+        host_udp_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: HashMap<PathBuf, PathBuf>,
+        egress_policy: EgressPolicy,
         interrupt_evt: EventFd,
         interrupt_status: Arc<AtomicUsize>,
+        rx_rate_limiter: TsiRateLimiter,
     ) -> Self {
         VsockMuxer {
             cid,
             host_port_map,
+            host_udp_port_map,
+            unix_socket_map,
+            egress_policy,
+            denied_sendto: Mutex::new(HashSet::new()),
             queue: None,
             mem: None,
             rxq: Arc::new(Mutex::new(MuxerRxQ::new())),
@@ -127,9 +151,15 @@ impl VsockMuxer {
             irq_line: None,
             proxy_map: Arc::new(RwLock::new(HashMap::new())),
             reaper_sender: None,
+            rx_rate_limiter,
         }
     }
 
+    #[cfg(target_os = "linux")]
+    pub(crate) fn update_rx_rate_limiter(&self, config: RateLimiterConfig) {
+        self.rx_rate_limiter.update(config);
+    }
+
     pub(crate) fn activate(
         &mut self,
         mem: GuestMemoryMmap,
@@ -156,6 +186,14 @@ impl VsockMuxer {
             timesync.run();
         }
 
+        if let Some(fd) = self.rx_rate_limiter.timer_fd() {
+            let _ = self.epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, defs::RX_RATE_LIMITER_ID),
+            );
+        }
+
         let (sender, receiver) = unbounded();
 
         let thread = MuxerThread::new(
@@ -170,6 +208,7 @@ impl VsockMuxer {
             intc,
             irq_line,
             sender.clone(),
+            self.rx_rate_limiter.clone(),
         );
         thread.run();
 
@@ -239,13 +278,42 @@ impl VsockMuxer {
         }
     }
 
+    /// Checks the egress policy for a destination of the guest, logging it if it's denied.
+    fn check_egress(&self, protocol: EgressProtocol, addr: &IpAddr, port: u16) -> Result<(), i32> {
+        let result = self.egress_policy.check(protocol, *addr, port);
+        if result.is_err() {
+            warn!(
+                "vsock: egress policy denied {:?} to {}",
+                protocol,
+                SocketAddr::new(*addr, port)
+            );
+        }
+        result
+    }
+
+    fn push_rx(&self, rx: MuxerRx) {
+        match (self.mem.as_ref(), self.queue.as_ref()) {
+            (Some(mem), Some(queue)) => push_packet(self.cid, rx, &self.rxq, queue, mem),
+            _ => warn!("vsock: response without mem or queue"),
+        }
+    }
+
     fn process_proxy_create(&self, pkt: &VsockPacket) {
         debug!("vsock: proxy create request");
         if let Some(req) = pkt.read_proxy_create() {
             debug!(
-                "vsock: proxy create request: peer_port={}, type={}",
-                req.peer_port, req._type
+                "vsock: proxy create request: peer_port={}, type={}, family={}",
+                req.peer_port, req._type, req.family
             );
+            let family = match req.family {
+                defs::AF_UNIX => AddressFamily::Unix,
+                defs::AF_INET => AddressFamily::Inet,
+                defs::AF_INET6 => AddressFamily::Inet6,
+                _ => {
+                    debug!("vsock: unknown family on proxy create request");
+                    return;
+                }
+            };
             let mem = match self.mem.as_ref() {
                 Some(m) => m,
                 None => {
@@ -261,18 +329,43 @@ impl VsockMuxer {
                 }
             };
             match req._type {
+                defs::SOCK_STREAM if family == AddressFamily::Unix => {
+                    debug!("vsock: proxy create unix stream");
+                    let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
+                    match UnixProxy::new(
+                        id,
+                        self.cid,
+                        defs::TSI_PROXY_PORT,
+                        req.peer_port,
+                        pkt.src_port(),
+                        mem.clone(),
+                        queue.clone(),
+                        self.rxq.clone(),
+                        self.rx_rate_limiter.clone(),
+                    ) {
+                        Ok(proxy) => {
+                            self.proxy_map
+                                .write()
+                                .unwrap()
+                                .insert(id, Mutex::new(Box::new(proxy)));
+                        }
+                        Err(e) => debug!("error creating unix proxy: {}", e),
+                    }
+                }
                 defs::SOCK_STREAM => {
                     debug!("vsock: proxy create stream");
                     let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
                     match TcpProxy::new(
                         id,
                         self.cid,
+                        family,
                         defs::TSI_PROXY_PORT,
                         req.peer_port,
                         pkt.src_port(),
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
+                        self.rx_rate_limiter.clone(),
                     ) {
                         Ok(proxy) => {
                             self.proxy_map
@@ -283,16 +376,21 @@ impl VsockMuxer {
                         Err(e) => debug!("error creating tcp proxy: {}", e),
                     }
                 }
+                defs::SOCK_DGRAM if family == AddressFamily::Unix => {
+                    debug!("vsock: unix dgram sockets aren't supported")
+                }
                 defs::SOCK_DGRAM => {
                     debug!("vsock: proxy create dgram");
                     let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
                     match UdpProxy::new(
                         id,
                         self.cid,
+                        family,
                         req.peer_port,
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
+                        self.rx_rate_limiter.clone(),
                     ) {
                         Ok(proxy) => {
                             self.proxy_map
@@ -310,15 +408,45 @@ impl VsockMuxer {
 
     fn process_connect(&self, pkt: &VsockPacket) {
         debug!("vsock: proxy connect request");
-        if let Some(req) = pkt.read_connect_req() {
+        if let Some(req) = pkt.read_unix_connect_req() {
+            let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
+            debug!("vsock: proxy unix connect request: id={}", id);
+            let update = self.proxy_map.read().unwrap().get(&id).map(|proxy| {
+                proxy
+                    .lock()
+                    .unwrap()
+                    .connect_unix(pkt, req, &self.unix_socket_map)
+            });
+
+            if let Some(update) = update {
+                self.process_proxy_update(id, update);
+            }
+        } else if let Some(req) = pkt.read_connect_req() {
             let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
             debug!("vsock: proxy connect request: id={}", id);
-            let update = self
-                .proxy_map
-                .read()
-                .unwrap()
-                .get(&id)
-                .map(|proxy| proxy.lock().unwrap().connect(pkt, req));
+            let update = self.proxy_map.read().unwrap().get(&id).map(|proxy| {
+                let mut proxy = proxy.lock().unwrap();
+                let allowed = match proxy.egress_protocol() {
+                    Some(protocol) => self.check_egress(protocol, &req.addr, req.port),
+                    None => Ok(()),
+                };
+                match allowed {
+                    Ok(()) => proxy.connect(pkt, req),
+                    Err(errno) => {
+                        // This response goes to the control port (DGRAM).
+                        let rx = MuxerRx::ConnResponse {
+                            local_port: pkt.dst_port(),
+                            peer_port: pkt.src_port(),
+                            result: errno,
+                        };
+                        self.push_rx(rx);
+                        ProxyUpdate {
+                            signal_queue: true,
+                            ..Default::default()
+                        }
+                    }
+                }
+            });
 
             if let Some(update) = update {
                 self.process_proxy_update(id, update);
@@ -346,6 +474,15 @@ impl VsockMuxer {
         if let Some(req) = pkt.read_sendto_addr() {
             let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
             debug!("vsock: new DGRAM sendto addr: id={}", id);
+            // There's no response to tell the guest, so the datagrams that follow are dropped.
+            if self
+                .check_egress(EgressProtocol::Udp, &req.addr, req.port)
+                .is_err()
+            {
+                self.denied_sendto.lock().unwrap().insert(id);
+                return;
+            }
+            self.denied_sendto.lock().unwrap().remove(&id);
             let update = self
                 .proxy_map
                 .read()
@@ -362,6 +499,9 @@ impl VsockMuxer {
     fn process_sendto_data(&self, pkt: &VsockPacket) {
         let id = (pkt.src_port() as u64) << 32 | defs::TSI_PROXY_PORT as u64;
         debug!("vsock: DGRAM sendto data: id={} src={}", id, pkt.src_port());
+        if self.denied_sendto.lock().unwrap().contains(&id) {
+            return;
+        }
         if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
             proxy.lock().unwrap().sendto_data(pkt);
         }
@@ -372,12 +512,15 @@ impl VsockMuxer {
         if let Some(req) = pkt.read_listen_req() {
             let id = (req.peer_port as u64) << 32 | defs::TSI_PROXY_PORT as u64;
             debug!("vsock: DGRAM listen request: id={}", id);
-            let update = self
-                .proxy_map
-                .read()
-                .unwrap()
-                .get(&id)
-                .map(|proxy| proxy.lock().unwrap().listen(pkt, req, &self.host_port_map));
+            let update = self.proxy_map.read().unwrap().get(&id).map(|proxy| {
+                let mut proxy = proxy.lock().unwrap();
+                let host_port_map = if proxy.is_dgram() {
+                    &self.host_udp_port_map
+                } else {
+                    &self.host_port_map
+                };
+                proxy.listen(pkt, req, host_port_map)
+            });
 
             if let Some(update) = update {
                 self.process_proxy_update(id, update);
@@ -411,6 +554,7 @@ impl VsockMuxer {
                 "vsock: DGRAM release request: id={} local_port={} peer_port={}",
                 id, req.local_port, req.peer_port
             );
+            self.denied_sendto.lock().unwrap().remove(&id);
             let update = if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
                 Some(proxy.lock().unwrap().release())
             } else {
@@ -439,6 +583,12 @@ impl VsockMuxer {
         if let Some(proxy_lock) = self.proxy_map.read().unwrap().get(&id) {
             debug!("vsock: DGRAM allowing OP_RW for {}", pkt.src_port());
             let mut proxy = proxy_lock.lock().unwrap();
+            if let Some((addr, port)) = proxy.reply_addr() {
+                // There's no response to tell the guest, so the datagram is dropped.
+                if self.check_egress(EgressProtocol::Udp, &addr, port).is_err() {
+                    return;
+                }
+            }
             let update = proxy.sendmsg(pkt);
             self.process_proxy_update(id, update);
         } else {
@@ -621,3 +771,276 @@ impl VsockMuxer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+    use std::os::unix::net::UnixListener;
+    use std::time::Duration;
+
+    use utils::tempdir::TempDir;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::queue::tests::VirtQueue as GuestQueue;
+    use crate::virtio::vsock::packet::tests::{add_rx_buffers, guest_memory, tx_packet};
+    use crate::virtio::vsock::proxy::ProxyStatus;
+
+    const CONTROL_PORT: u32 = 1234;
+    const PEER_PORT: u32 = 2048;
+    const PROXY_ID: u64 = (PEER_PORT as u64) << 32 | defs::TSI_PROXY_PORT as u64;
+
+    fn muxer(
+        host_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: HashMap<PathBuf, PathBuf>,
+    ) -> (VsockMuxer, GuestMemoryMmap) {
+        let mut muxer = VsockMuxer::new(
+            3,
+            host_port_map,
+            None,
+            unix_socket_map,
+            EgressPolicy::default(),
+            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            TsiRateLimiter::new().unwrap(),
+        );
+        // Without buffers in the queue, the responses for the guest stay in
+        // the RX queue of the muxer.
+        let mem = guest_memory();
+        muxer.mem = Some(mem.clone());
+        muxer.queue = Some(Arc::new(Mutex::new(VirtQueue::new(16))));
+        (muxer, mem)
+    }
+
+    /// Sends the guest's TSI request with `data` to `dst_port`.
+    fn send_control(muxer: &mut VsockMuxer, mem: &GuestMemoryMmap, dst_port: u32, data: &[u8]) {
+        let mut pkt = tx_packet(mem, data);
+        pkt.set_op(uapi::VSOCK_OP_RW)
+            .set_type(uapi::VSOCK_TYPE_DGRAM)
+            .set_dst_cid(uapi::VSOCK_HOST_CID)
+            .set_src_port(CONTROL_PORT)
+            .set_dst_port(dst_port);
+        muxer.send_dgram_pkt(&pkt).unwrap();
+    }
+
+    /// Sends a packet of the guest's connection to the proxy.
+    fn send_stream(
+        muxer: &mut VsockMuxer,
+        mem: &GuestMemoryMmap,
+        op: u16,
+        flags: u32,
+        data: &[u8],
+    ) {
+        let mut pkt = tx_packet(mem, data);
+        pkt.set_op(op)
+            .set_flags(flags)
+            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_dst_cid(uapi::VSOCK_HOST_CID)
+            .set_src_port(PEER_PORT)
+            .set_dst_port(defs::TSI_PROXY_PORT);
+        muxer.send_stream_pkt(&pkt).unwrap();
+    }
+
+    fn create_proxy(muxer: &mut VsockMuxer, mem: &GuestMemoryMmap, sock_type: u16, family: u16) {
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&sock_type.to_le_bytes());
+        req.extend_from_slice(&family.to_le_bytes());
+        send_control(muxer, mem, defs::TSI_PROXY_CREATE, &req);
+        assert!(muxer.proxy_map.read().unwrap().contains_key(&PROXY_ID));
+    }
+
+    fn proxy_status(muxer: &VsockMuxer) -> ProxyStatus {
+        muxer.proxy_map.read().unwrap()[&PROXY_ID]
+            .lock()
+            .unwrap()
+            .status()
+    }
+
+    fn pop_rx(muxer: &VsockMuxer) -> Option<MuxerRx> {
+        muxer.rxq.lock().unwrap().pop()
+    }
+
+    /// Checks that data from the guest reaches `stream`, and that it sees the
+    /// end of the stream once the guest shuts its side down.
+    fn check_rw_shutdown(muxer: &mut VsockMuxer, mem: &GuestMemoryMmap, mut stream: impl Read) {
+        send_stream(muxer, mem, uapi::VSOCK_OP_RW, 0, b"hello");
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        send_stream(
+            muxer,
+            mem,
+            uapi::VSOCK_OP_SHUTDOWN,
+            uapi::VSOCK_FLAGS_SHUTDOWN_SEND,
+            &[],
+        );
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tcp_connect_shutdown() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut muxer, mem) = muxer(None, HashMap::new());
+        create_proxy(&mut muxer, &mem, defs::SOCK_STREAM, defs::AF_INET);
+
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        req.extend_from_slice(&port.to_be_bytes());
+        send_control(&mut muxer, &mem, defs::TSI_CONNECT, &req);
+        if proxy_status(&muxer) == ProxyStatus::Connecting {
+            // What the muxer thread does once the socket is writable.
+            muxer.proxy_map.read().unwrap()[&PROXY_ID]
+                .lock()
+                .unwrap()
+                .process_event(EventSet::OUT);
+        }
+        assert_eq!(proxy_status(&muxer), ProxyStatus::Connected);
+        match pop_rx(&muxer) {
+            Some(MuxerRx::ConnResponse {
+                peer_port, result, ..
+            }) => {
+                assert_eq!(peer_port, CONTROL_PORT);
+                assert_eq!(result, 0);
+            }
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+
+        let (stream, _) = listener.accept().unwrap();
+        check_rw_shutdown(&mut muxer, &mem, stream);
+    }
+
+    #[test]
+    fn test_unix_connect_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.as_path().join("host.sock");
+        let listener = UnixListener::bind(&host_path).unwrap();
+        let guest_path = "/run/guest.sock";
+        let (mut muxer, mem) = muxer(
+            None,
+            HashMap::from([(PathBuf::from(guest_path), host_path)]),
+        );
+        create_proxy(&mut muxer, &mem, defs::SOCK_STREAM, defs::AF_UNIX);
+
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&defs::AF_UNIX.to_le_bytes());
+        let mut sun_path = [0u8; 108];
+        sun_path[..guest_path.len()].copy_from_slice(guest_path.as_bytes());
+        req.extend_from_slice(&sun_path);
+        send_control(&mut muxer, &mem, defs::TSI_CONNECT, &req);
+        assert_eq!(proxy_status(&muxer), ProxyStatus::Connected);
+        match pop_rx(&muxer) {
+            Some(MuxerRx::ConnResponse { result, .. }) => assert_eq!(result, 0),
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+
+        let (stream, _) = listener.accept().unwrap();
+        check_rw_shutdown(&mut muxer, &mem, stream);
+    }
+
+    #[test]
+    fn test_tcp_listen_accept() {
+        // Guest port 80 is bound to any free port of the host.
+        let (mut muxer, mem) = muxer(Some(HashMap::from([(80, 0)])), HashMap::new());
+        create_proxy(&mut muxer, &mem, defs::SOCK_STREAM, defs::AF_INET);
+        let listen_req = |port: u16| {
+            let mut req = PEER_PORT.to_le_bytes().to_vec();
+            req.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+            req.extend_from_slice(&port.to_be_bytes());
+            req.extend_from_slice(&5000u32.to_le_bytes());
+            req.extend_from_slice(&1u32.to_le_bytes());
+            req
+        };
+        let listen_result = |muxer: &VsockMuxer| match pop_rx(muxer) {
+            Some(MuxerRx::ListenResponse { result, .. }) => result,
+            rx => panic!("unexpected rx: {rx:?}"),
+        };
+
+        send_control(&mut muxer, &mem, defs::TSI_LISTEN, &listen_req(81));
+        assert_eq!(listen_result(&muxer), -libc::EPERM);
+        send_control(&mut muxer, &mem, defs::TSI_LISTEN, &listen_req(80));
+        assert_eq!(listen_result(&muxer), 0);
+        assert_eq!(proxy_status(&muxer), ProxyStatus::Listening);
+
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&(libc::O_NONBLOCK as u32).to_le_bytes());
+        send_control(&mut muxer, &mem, defs::TSI_ACCEPT, &req);
+        match pop_rx(&muxer) {
+            Some(MuxerRx::AcceptResponse { result, .. }) => {
+                assert_eq!(result, -libc::EWOULDBLOCK)
+            }
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+        // Releasing a listening proxy drops it right away.
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&defs::TSI_PROXY_PORT.to_le_bytes());
+        send_control(&mut muxer, &mem, defs::TSI_PROXY_RELEASE, &req);
+        assert!(muxer.proxy_map.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_udp_reply_egress() {
+        let (mut muxer, mem) = muxer(None, HashMap::new());
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        add_rx_buffers(&vq, 4);
+        muxer.queue = Some(Arc::new(Mutex::new(vq.create_queue())));
+        // The packets of the guest live elsewhere, to leave its queue alone.
+        let tx_mem = guest_memory();
+        create_proxy(&mut muxer, &tx_mem, defs::SOCK_DGRAM, defs::AF_INET);
+
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut req = PEER_PORT.to_le_bytes().to_vec();
+        req.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        req.extend_from_slice(&port.to_be_bytes());
+        req.extend_from_slice(&0u32.to_le_bytes());
+        req.extend_from_slice(&0u32.to_le_bytes());
+        send_control(&mut muxer, &tx_mem, defs::TSI_LISTEN, &req);
+
+        // A datagram from the host teaches the proxy where replies go.
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        client
+            .send_to(b"ping", (Ipv4Addr::LOCALHOST, port))
+            .unwrap();
+        for _ in 0..100 {
+            let proxy = &muxer.proxy_map.read().unwrap()[&PROXY_ID];
+            let mut proxy = proxy.lock().unwrap();
+            proxy.process_event(EventSet::IN);
+            if proxy.reply_addr().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let send_reply = |muxer: &mut VsockMuxer| {
+            let mut pkt = tx_packet(&tx_mem, b"pong");
+            pkt.set_op(uapi::VSOCK_OP_RW)
+                .set_type(uapi::VSOCK_TYPE_DGRAM)
+                .set_dst_cid(uapi::VSOCK_HOST_CID)
+                .set_src_port(PEER_PORT)
+                .set_dst_port(defs::TSI_PROXY_PORT);
+            muxer.send_dgram_pkt(&pkt).unwrap();
+        };
+        let mut buf = [0u8; 16];
+
+        // Replies are subject to the egress policy like any other datagram.
+        muxer.egress_policy =
+            EgressPolicy::new(vec!["deny udp 127.0.0.1 any".parse().unwrap()], libc::EPERM);
+        send_reply(&mut muxer);
+        assert!(client.recv_from(&mut buf).is_err());
+
+        muxer.egress_policy = EgressPolicy::default();
+        send_reply(&mut muxer);
+        let (cnt, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..cnt], b"pong");
+        assert_eq!(from.port(), port);
+    }
+}
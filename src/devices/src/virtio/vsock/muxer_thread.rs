@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -6,9 +7,11 @@ use std::thread;
 use super::super::super::legacy::Gic;
 use super::super::Queue as VirtQueue;
 use super::super::VIRTIO_MMIO_INT_VRING;
+use super::defs;
 use super::muxer::{push_packet, MuxerRx, ProxyMap};
 use super::muxer_rxq::MuxerRxQ;
 use super::proxy::{ProxyRemoval, ProxyUpdate};
+use super::rate_limiter::TsiRateLimiter;
 use super::tcp::TcpProxy;
 
 use crossbeam_channel::Sender;
@@ -29,6 +32,9 @@ pub struct MuxerThread {
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
     reaper_sender: Sender<u64>,
+    rx_rate_limiter: TsiRateLimiter,
+    /// Proxies that stopped receiving until the rate limiter lets them.
+    throttled: HashSet<u64>,
 }
 
 impl MuxerThread {
@@ -45,6 +51,7 @@ impl MuxerThread {
         intc: Option<Arc<Mutex<Gic>>>,
         irq_line: Option<u32>,
         reaper_sender: Sender<u64>,
+        rx_rate_limiter: TsiRateLimiter,
     ) -> Self {
         MuxerThread {
             cid,
@@ -58,6 +65,8 @@ impl MuxerThread {
             intc,
             irq_line,
             reaper_sender,
+            rx_rate_limiter,
+            throttled: HashSet::new(),
         }
     }
 
@@ -82,11 +91,15 @@ impl MuxerThread {
         }
     }
 
-    fn process_proxy_update(&self, id: u64, update: ProxyUpdate, thread_rng: &mut ThreadRng) {
+    fn process_proxy_update(&mut self, id: u64, update: ProxyUpdate, thread_rng: &mut ThreadRng) {
         if let Some(polling) = update.polling {
             self.update_polling(polling.0, polling.1, polling.2);
         }
 
+        if update.throttled {
+            self.throttled.insert(id);
+        }
+
         if let Some(credit_rx) = update.push_credit_req {
             debug!("send_credit_request");
             self.send_credit_request(credit_rx);
@@ -121,6 +134,7 @@ impl MuxerThread {
                 self.mem.clone(),
                 self.queue.clone(),
                 self.rxq.clone(),
+                self.rx_rate_limiter.clone(),
             );
             self.proxy_map
                 .write()
@@ -144,7 +158,27 @@ impl MuxerThread {
         }
     }
 
-    fn work(self) {
+    /// Lets the proxies that were throttled receive again, once the rate
+    /// limiter timer expired.
+    fn process_rx_rate_limiter_event(&mut self, thread_rng: &mut ThreadRng) {
+        if let Err(e) = self.rx_rate_limiter.event_handler() {
+            error!("vsock: failed to get rx rate limiter event: {:?}", e);
+            return;
+        }
+        for id in std::mem::take(&mut self.throttled) {
+            let update = self
+                .proxy_map
+                .read()
+                .unwrap()
+                .get(&id)
+                .map(|proxy| proxy.lock().unwrap().resume_rx());
+            if let Some(update) = update {
+                self.process_proxy_update(id, update, thread_rng);
+            }
+        }
+    }
+
+    fn work(mut self) {
         let mut thread_rng = thread_rng();
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
@@ -158,6 +192,11 @@ impl MuxerThread {
                         let evset = EventSet::from_bits(ev.events).unwrap();
                         let id = ev.data();
 
+                        if id == defs::RX_RATE_LIMITER_ID {
+                            self.process_rx_rate_limiter_event(&mut thread_rng);
+                            continue;
+                        }
+
                         let update = self.proxy_map.read().unwrap().get(&id).map(|proxy_lock| {
                             let mut proxy = proxy_lock.lock().unwrap();
                             proxy.process_event(evset)
@@ -175,3 +214,115 @@ impl MuxerThread {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+    use crate::rate_limiter::{RateLimiterConfig, TokenBucketConfig};
+    use crate::virtio::queue::tests::VirtQueue as GuestQueue;
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::virtio::vsock::packet::VSOCK_PKT_HDR_SIZE;
+    use crate::virtio::vsock::proxy::ProxyStatus;
+    use vm_memory::GuestAddress;
+
+    const PROXY_ID: u64 = 1;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap()
+    }
+
+    /// Whether the epoll of `thread` reports the proxy as readable.
+    fn polled(thread: &MuxerThread) -> bool {
+        let mut events = vec![EpollEvent::default(); 4];
+        let count = thread.epoll.wait(events.len(), 0, &mut events).unwrap();
+        events[..count].iter().any(|ev| ev.data() == PROXY_ID)
+    }
+
+    #[test]
+    fn test_resume_throttled_proxy() {
+        let mem = guest_memory();
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        vq.dtable[0].set(
+            0x1000,
+            VSOCK_PKT_HDR_SIZE as u32,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            1,
+        );
+        vq.dtable[1].set(0x2000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        let queue = Arc::new(Mutex::new(vq.create_queue()));
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+
+        // Let an operation through every 50ms, and use up the first one.
+        let rx_rate_limiter = TsiRateLimiter::new().unwrap();
+        rx_rate_limiter.update(RateLimiterConfig {
+            ops: TokenBucketConfig { rate: 20, burst: 1 },
+            ..Default::default()
+        });
+        rx_rate_limiter.charge(0);
+        assert!(rx_rate_limiter.is_blocked());
+
+        let (mut peer, sock) = UnixStream::pair().unwrap();
+        let fd = sock.into_raw_fd();
+        let mut proxy = TcpProxy::new_reverse(
+            PROXY_ID,
+            3,
+            0,
+            1024,
+            2048,
+            fd,
+            mem.clone(),
+            queue.clone(),
+            rxq.clone(),
+            rx_rate_limiter.clone(),
+        );
+        proxy.status = ProxyStatus::Connected;
+        let proxy_map: ProxyMap = Default::default();
+        proxy_map
+            .write()
+            .unwrap()
+            .insert(PROXY_ID, Mutex::new(Box::new(proxy)));
+
+        let mut thread = MuxerThread::new(
+            3,
+            Epoll::new().unwrap(),
+            rxq,
+            proxy_map.clone(),
+            mem.clone(),
+            queue,
+            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            None,
+            None,
+            unbounded().0,
+            rx_rate_limiter.clone(),
+        );
+        let mut thread_rng = thread_rng();
+        thread.update_polling(PROXY_ID, fd, EventSet::IN);
+
+        // The proxy stops polling its socket while the limiter is blocked.
+        peer.write_all(b"data").unwrap();
+        assert!(polled(&thread));
+        let update = proxy_map.read().unwrap()[&PROXY_ID]
+            .lock()
+            .unwrap()
+            .process_event(EventSet::IN);
+        thread.process_proxy_update(PROXY_ID, update, &mut thread_rng);
+        assert!(thread.throttled.contains(&PROXY_ID));
+        assert!(!polled(&thread));
+        assert_eq!(vq.used.idx.get(), 0);
+
+        // And polls it again once the timer expires.
+        thread.process_rx_rate_limiter_event(&mut thread_rng);
+        assert!(!rx_rate_limiter.is_blocked());
+        assert!(thread.throttled.is_empty());
+        assert!(polled(&thread));
+    }
+}
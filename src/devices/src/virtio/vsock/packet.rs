@@ -15,9 +15,11 @@
 /// in guest memory. This is done to avoid unnecessarily copying data from guest memory
 /// to temporary buffers, before passing it on to the vsock backend.
 use std::convert::TryInto;
-use std::ffi::CStr;
-use std::net::Ipv4Addr;
+use std::ffi::{CStr, OsStr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 use std::result;
 
 use utils::byte_order;
@@ -92,24 +94,46 @@ const HDROFF_BUF_ALLOC: usize = 36;
 // we have successfully written to a backing Unix socket.
 const HDROFF_FWD_CNT: usize = 40;
 
+// Sizes of the IPv6 variants of the TSI requests carrying an address, which only differ from the
+// IPv4 ones by the size of the address. They are told apart by the length of the packet.
+const TSI_CONNECT_REQ6_SIZE: usize = 22;
+const TSI_SENDTO_ADDR6_SIZE: usize = 22;
+const TSI_LISTEN_REQ6_SIZE: usize = 30;
+// Size of the variant of the TSI connect request for unix sockets, followed by a whole
+// `struct sockaddr_un` instead of an address and a port.
+const TSI_UNIX_CONNECT_REQ_SIZE: usize = 114;
+
+/// Wire layout: `peer_port: le32, type: le16[, family: le16]`. Older guests omit `family`.
 #[repr(C)]
 pub struct TsiProxyCreate {
     pub peer_port: u32,
     pub _type: u16,
+    /// Address family of the socket, `defs::AF_INET` if the guest didn't say.
+    pub family: u16,
 }
 
+/// Wire layout: `peer_port: le32, addr: [u8; 4], port: be16` (10 bytes), or with a 16 byte
+/// `addr` for IPv6 (22 bytes).
 #[repr(C)]
 pub struct TsiConnectReq {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
 }
 
+/// Wire layout: `peer_port: le32, struct sockaddr_un` (114 bytes).
+#[repr(C)]
+pub struct TsiUnixConnectReq {
+    pub peer_port: u32,
+    pub path: PathBuf,
+}
+
 #[repr(C)]
 pub struct TsiConnectRsp {
     pub result: i32,
 }
 
+/// Wire layout: `peer_port: le32, local_port: le32, peer: le32` (12 bytes).
 #[repr(C)]
 pub struct TsiGetnameReq {
     pub peer_port: u32,
@@ -117,10 +141,12 @@ pub struct TsiGetnameReq {
     pub peer: u32,
 }
 
+/// Wire layout: `addr: [u8; 4], port: be16, result: le32` (10 bytes), or with a 16 byte `addr`
+/// for IPv6 sockets (22 bytes).
 #[repr(C)]
 #[derive(Debug)]
 pub struct TsiGetnameRsp {
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
     pub result: i32,
 }
@@ -128,26 +154,29 @@ pub struct TsiGetnameRsp {
 impl Default for TsiGetnameRsp {
     fn default() -> Self {
         TsiGetnameRsp {
-            addr: Ipv4Addr::new(0, 0, 0, 0),
+            addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             port: 0,
             result: -1,
         }
     }
 }
 
+/// Wire layout: same as `TsiConnectReq`.
 #[repr(C)]
 #[derive(Debug)]
 pub struct TsiSendtoAddr {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
 }
 
+/// Wire layout: `peer_port: le32, addr: [u8; 4], port: be16, vm_port: le32, backlog: le32`
+/// (18 bytes), or with a 16 byte `addr` for IPv6 (30 bytes).
 #[repr(C)]
 #[derive(Debug)]
 pub struct TsiListenReq {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
     pub vm_port: u32,
     pub backlog: i32,
@@ -458,13 +487,29 @@ impl VsockPacket {
         }
     }
 
-    pub fn inet_addr(&self) -> Option<[u8; 4]> {
-        if self.buf_size >= 8 {
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            slice[0..4].try_into().ok()
+    pub fn inet_addr(&self) -> Option<IpAddr> {
+        match self.sa_family()? {
+            // struct sockaddr_in
+            defs::AF_INET if self.buf_size >= 8 => Some(self.read_ip_addr(4, false)),
+            // struct sockaddr_in6, with the flow information before the address
+            defs::AF_INET6 if self.buf_size >= 24 => Some(self.read_ip_addr(8, true)),
+            _ => None,
+        }
+    }
+
+    /// Whether the TSI request in the packet is the IPv6 variant, `size` bytes long.
+    fn is_inet6_req(&self, size: usize) -> bool {
+        self.len() as usize >= size && self.buf_size >= size
+    }
+
+    fn read_ip_addr(&self, offset: usize, inet6: bool) -> IpAddr {
+        let buf = self.buf().unwrap();
+        if inet6 {
+            let octets: [u8; 16] = buf[offset..offset + 16].try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(octets))
         } else {
-            None
+            let octets: [u8; 4] = buf[offset..offset + 4].try_into().unwrap();
+            IpAddr::V4(Ipv4Addr::from(octets))
         }
     }
 
@@ -482,8 +527,18 @@ impl VsockPacket {
         if self.buf_size >= 6 {
             let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
             let _type: u16 = byte_order::read_le_u16(&self.buf().unwrap()[4..]);
-
-            Some(TsiProxyCreate { peer_port, _type })
+            // Older guests only create IPv4 sockets and don't send the family.
+            let family = if self.len() >= 8 && self.buf_size >= 8 {
+                byte_order::read_le_u16(&self.buf().unwrap()[6..])
+            } else {
+                defs::AF_INET
+            };
+
+            Some(TsiProxyCreate {
+                peer_port,
+                _type,
+                family,
+            })
         } else {
             None
         }
@@ -491,12 +546,12 @@ impl VsockPacket {
 
     pub fn read_connect_req(&self) -> Option<TsiConnectReq> {
         if self.buf_size >= 10 {
-            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
-            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
+            let inet6 = self.is_inet6_req(TSI_CONNECT_REQ6_SIZE);
+            let port_offset = if inet6 { 20 } else { 8 };
 
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
+            let addr = self.read_ip_addr(4, inet6);
+            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[port_offset..]);
 
             Some(TsiConnectReq {
                 peer_port,
@@ -508,6 +563,30 @@ impl VsockPacket {
         }
     }
 
+    /// Reads a connect request for a unix socket, if the packet holds one.
+    pub fn read_unix_connect_req(&self) -> Option<TsiUnixConnectReq> {
+        if self.len() as usize >= TSI_UNIX_CONNECT_REQ_SIZE
+            && self.buf_size >= TSI_UNIX_CONNECT_REQ_SIZE
+        {
+            let buf = self.buf().unwrap();
+            if byte_order::read_le_u16(&buf[4..]) != defs::AF_UNIX {
+                return None;
+            }
+            let peer_port: u32 = byte_order::read_le_u32(&buf[0..]);
+            let sun_path = &buf[6..TSI_UNIX_CONNECT_REQ_SIZE];
+            // The path is only null-terminated if it's shorter than `sun_path`.
+            let len = sun_path
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(sun_path.len());
+            let path = PathBuf::from(OsStr::from_bytes(&sun_path[..len]));
+
+            Some(TsiUnixConnectReq { peer_port, path })
+        } else {
+            None
+        }
+    }
+
     pub fn write_connect_rsp(&mut self, rsp: TsiConnectRsp) {
         if self.buf_size >= 4 {
             if let Some(buf) = self.buf_mut() {
@@ -532,25 +611,28 @@ impl VsockPacket {
     }
 
     pub fn write_getname_rsp(&mut self, rsp: TsiGetnameRsp) {
-        if self.buf_size >= 10 {
+        let octets = match rsp.addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        let port_offset = octets.len();
+        if self.buf_size >= port_offset + 6 {
             if let Some(buf) = self.buf_mut() {
-                for (i, b) in rsp.addr.octets().iter().enumerate() {
-                    buf[i] = *b;
-                }
-                byte_order::write_be_u16(&mut buf[4..], rsp.port);
-                byte_order::write_le_u32(&mut buf[6..], rsp.result as u32);
+                buf[..port_offset].copy_from_slice(&octets);
+                byte_order::write_be_u16(&mut buf[port_offset..], rsp.port);
+                byte_order::write_le_u32(&mut buf[port_offset + 2..], rsp.result as u32);
             }
         }
     }
 
     pub fn read_sendto_addr(&self) -> Option<TsiSendtoAddr> {
         if self.buf_size >= 10 {
-            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
-            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
+            let inet6 = self.is_inet6_req(TSI_SENDTO_ADDR6_SIZE);
+            let port_offset = if inet6 { 20 } else { 8 };
 
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
+            let addr = self.read_ip_addr(4, inet6);
+            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[port_offset..]);
 
             Some(TsiSendtoAddr {
                 peer_port,
@@ -564,15 +646,14 @@ impl VsockPacket {
 
     pub fn read_listen_req(&self) -> Option<TsiListenReq> {
         if self.buf_size >= 18 {
-            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
-
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let inet6 = self.is_inet6_req(TSI_LISTEN_REQ6_SIZE);
+            let port_offset = if inet6 { 20 } else { 8 };
 
-            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
-            let vm_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[10..]);
-            let backlog: u32 = byte_order::read_le_u32(&self.buf().unwrap()[14..]);
+            let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
+            let addr = self.read_ip_addr(4, inet6);
+            let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[port_offset..]);
+            let vm_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[port_offset + 2..]);
+            let backlog: u32 = byte_order::read_le_u32(&self.buf().unwrap()[port_offset + 6..]);
 
             Some(TsiListenReq {
                 peer_port,
@@ -634,3 +715,215 @@ impl VsockPacket {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use vm_memory::{Bytes, GuestMemoryMmap};
+
+    use super::*;
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const HDR_ADDR: u64 = 0x1000;
+    const BUF_ADDR: u64 = 0x2000;
+
+    pub fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap()
+    }
+
+    /// Builds a TX packet in `mem` carrying `data`.
+    pub fn tx_packet(mem: &GuestMemoryMmap, data: &[u8]) -> VsockPacket {
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        vq.dtable[0].set(HDR_ADDR, VSOCK_PKT_HDR_SIZE as u32, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(BUF_ADDR, data.len() as u32, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        mem.write_slice(&[0u8; VSOCK_PKT_HDR_SIZE], GuestAddress(HDR_ADDR))
+            .unwrap();
+        mem.write_obj(
+            (data.len() as u32).to_le(),
+            GuestAddress(HDR_ADDR + HDROFF_LEN as u64),
+        )
+        .unwrap();
+        mem.write_slice(data, GuestAddress(BUF_ADDR)).unwrap();
+
+        let mut queue = vq.create_queue();
+        let head = queue.pop(mem).unwrap();
+        VsockPacket::from_tx_virtq_head(&head).unwrap()
+    }
+
+    /// Makes `count` buffers available to receive packets in `vq`, starting
+    /// at 0x1000.
+    pub fn add_rx_buffers(vq: &VirtQueue, count: u16) {
+        for i in 0..count {
+            let addr = 0x1000 + i as u64 * 0x2000;
+            vq.dtable[2 * i as usize].set(
+                addr,
+                VSOCK_PKT_HDR_SIZE as u32,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                2 * i + 1,
+            );
+            vq.dtable[2 * i as usize + 1].set(addr + 0x1000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i as usize].set(2 * i);
+        }
+        vq.avail.idx.set(count);
+    }
+
+    fn v4_req(peer_port: u32, port: u16, tail: &[u8]) -> Vec<u8> {
+        let mut req = peer_port.to_le_bytes().to_vec();
+        req.extend_from_slice(&[10, 0, 2, 15]);
+        req.extend_from_slice(&port.to_be_bytes());
+        req.extend_from_slice(tail);
+        req
+    }
+
+    fn v6_req(peer_port: u32, port: u16, tail: &[u8]) -> Vec<u8> {
+        let mut req = peer_port.to_le_bytes().to_vec();
+        req.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        req.extend_from_slice(&port.to_be_bytes());
+        req.extend_from_slice(tail);
+        req
+    }
+
+    const V4_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 2, 15));
+    const V6_ADDR: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+    #[test]
+    fn test_proxy_create() {
+        let mem = guest_memory();
+
+        let mut req = 1234u32.to_le_bytes().to_vec();
+        req.extend_from_slice(&defs::SOCK_STREAM.to_le_bytes());
+        let create = tx_packet(&mem, &req).read_proxy_create().unwrap();
+        assert_eq!(create.peer_port, 1234);
+        assert_eq!(create._type, defs::SOCK_STREAM);
+        assert_eq!(create.family, defs::AF_INET);
+
+        req.extend_from_slice(&defs::AF_INET6.to_le_bytes());
+        let create = tx_packet(&mem, &req).read_proxy_create().unwrap();
+        assert_eq!(create.family, defs::AF_INET6);
+
+        assert!(tx_packet(&mem, &req[..5]).read_proxy_create().is_none());
+    }
+
+    #[test]
+    fn test_connect_req() {
+        let mem = guest_memory();
+
+        let req = tx_packet(&mem, &v4_req(1234, 80, &[]))
+            .read_connect_req()
+            .unwrap();
+        assert_eq!(req.peer_port, 1234);
+        assert_eq!(req.addr, V4_ADDR);
+        assert_eq!(req.port, 80);
+
+        let req = tx_packet(&mem, &v6_req(1234, 80, &[]))
+            .read_connect_req()
+            .unwrap();
+        assert_eq!(req.peer_port, 1234);
+        assert_eq!(req.addr, V6_ADDR);
+        assert_eq!(req.port, 80);
+
+        // The length in the header, not the size of the buffer, tells the variants apart.
+        let mut pkt = tx_packet(&mem, &v4_req(1234, 80, &[0; 12]));
+        pkt.set_len(10);
+        assert_eq!(pkt.read_connect_req().unwrap().addr, V4_ADDR);
+
+        assert!(tx_packet(&mem, &v4_req(1234, 80, &[])[..9])
+            .read_connect_req()
+            .is_none());
+        assert!(tx_packet(&mem, &[]).read_connect_req().is_none());
+    }
+
+    #[test]
+    fn test_sendto_addr() {
+        let mem = guest_memory();
+
+        let addr = tx_packet(&mem, &v4_req(1234, 53, &[]))
+            .read_sendto_addr()
+            .unwrap();
+        assert_eq!(addr.peer_port, 1234);
+        assert_eq!(addr.addr, V4_ADDR);
+        assert_eq!(addr.port, 53);
+
+        let addr = tx_packet(&mem, &v6_req(1234, 53, &[]))
+            .read_sendto_addr()
+            .unwrap();
+        assert_eq!(addr.peer_port, 1234);
+        assert_eq!(addr.addr, V6_ADDR);
+        assert_eq!(addr.port, 53);
+
+        assert!(tx_packet(&mem, &v4_req(1234, 53, &[])[..9])
+            .read_sendto_addr()
+            .is_none());
+    }
+
+    #[test]
+    fn test_listen_req() {
+        let mem = guest_memory();
+        let mut tail = 5678u32.to_le_bytes().to_vec();
+        tail.extend_from_slice(&16i32.to_le_bytes());
+
+        let req = tx_packet(&mem, &v4_req(1234, 8080, &tail))
+            .read_listen_req()
+            .unwrap();
+        assert_eq!(req.peer_port, 1234);
+        assert_eq!(req.addr, V4_ADDR);
+        assert_eq!(req.port, 8080);
+        assert_eq!(req.vm_port, 5678);
+        assert_eq!(req.backlog, 16);
+
+        let req = tx_packet(&mem, &v6_req(1234, 8080, &tail))
+            .read_listen_req()
+            .unwrap();
+        assert_eq!(req.peer_port, 1234);
+        assert_eq!(req.addr, V6_ADDR);
+        assert_eq!(req.port, 8080);
+        assert_eq!(req.vm_port, 5678);
+        assert_eq!(req.backlog, 16);
+
+        assert!(tx_packet(&mem, &v4_req(1234, 8080, &tail)[..17])
+            .read_listen_req()
+            .is_none());
+    }
+
+    #[test]
+    fn test_getname() {
+        let mem = guest_memory();
+        let mut req = 1234u32.to_le_bytes().to_vec();
+        req.extend_from_slice(&5678u32.to_le_bytes());
+        req.extend_from_slice(&1u32.to_le_bytes());
+
+        let getname = tx_packet(&mem, &req).read_getname_req().unwrap();
+        assert_eq!(getname.peer_port, 1234);
+        assert_eq!(getname.local_port, 5678);
+        assert_eq!(getname.peer, 1);
+        assert!(tx_packet(&mem, &req[..11]).read_getname_req().is_none());
+
+        let mut pkt = tx_packet(&mem, &[0; 22]);
+        pkt.write_getname_rsp(TsiGetnameRsp {
+            addr: V4_ADDR,
+            port: 80,
+            result: 0,
+        });
+        assert_eq!(pkt.buf().unwrap()[..10], v4_req(0, 80, &[0; 4])[4..]);
+
+        pkt.write_getname_rsp(TsiGetnameRsp {
+            addr: V6_ADDR,
+            port: 80,
+            result: -libc::ENOTCONN,
+        });
+        let rsp = v6_req(0, 80, &(-libc::ENOTCONN).to_le_bytes())[4..].to_vec();
+        assert_eq!(pkt.buf().unwrap(), &rsp[..]);
+
+        // A response that doesn't fit is dropped.
+        let mut pkt = tx_packet(&mem, &[0; 21]);
+        pkt.write_getname_rsp(TsiGetnameRsp {
+            addr: V6_ADDR,
+            port: 80,
+            result: 0,
+        });
+        assert_eq!(pkt.buf().unwrap(), &[0; 21]);
+    }
+}
@@ -1,11 +1,28 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 
+use nix::sys::socket::SockaddrStorage;
+
+use super::egress::EgressProtocol;
 use super::muxer::MuxerRx;
-use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
+use super::packet::{
+    TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, TsiUnixConnectReq, VsockPacket,
+};
 use utils::epoll::EventSet;
 
+/// The IP address and port of `addr`, if it's an IPv4 or IPv6 address.
+pub fn ip_addr_port(addr: &SockaddrStorage) -> Option<(IpAddr, u16)> {
+    if let Some(addr) = addr.as_sockaddr_in() {
+        Some((IpAddr::V4(Ipv4Addr::from(addr.ip())), addr.port()))
+    } else {
+        addr.as_sockaddr_in6()
+            .map(|addr| (IpAddr::V6(addr.ip()), addr.port()))
+    }
+}
+
 #[derive(Debug)]
 pub enum RecvPkt {
     Close,
@@ -48,6 +65,8 @@ pub struct ProxyUpdate {
     pub new_proxy: Option<(u32, RawFd)>,
     pub push_accept: Option<(u64, u64)>,
     pub push_credit_req: Option<MuxerRx>,
+    /// The proxy stopped receiving until the rate limiter lets it.
+    pub throttled: bool,
 }
 
 impl fmt::Display for ProxyError {
@@ -59,7 +78,23 @@ impl fmt::Display for ProxyError {
 pub trait Proxy: Send + AsRawFd {
     fn id(&self) -> u64;
     fn status(&self) -> ProxyStatus;
+    /// The protocol the egress policy applies to the connections of the proxy as,
+    /// if it applies to them at all.
+    fn egress_protocol(&self) -> Option<EgressProtocol>;
+    /// Whether the socket of the proxy is a datagram one.
+    fn is_dgram(&self) -> bool;
+    /// Where the data of the guest goes when the proxy picks the destination
+    /// itself, which the egress policy must allow.
+    fn reply_addr(&self) -> Option<(IpAddr, u16)> {
+        None
+    }
     fn connect(&mut self, pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate;
+    fn connect_unix(
+        &mut self,
+        pkt: &VsockPacket,
+        req: TsiUnixConnectReq,
+        unix_socket_map: &HashMap<PathBuf, PathBuf>,
+    ) -> ProxyUpdate;
     fn confirm_connect(&mut self, _pkt: &VsockPacket) {}
     fn getpeername(&mut self, pkt: &VsockPacket);
     fn sendmsg(&mut self, pkt: &VsockPacket) -> ProxyUpdate;
@@ -80,4 +115,8 @@ pub trait Proxy: Send + AsRawFd {
     fn shutdown(&mut self, _pkt: &VsockPacket) {}
     fn release(&mut self) -> ProxyUpdate;
     fn process_event(&mut self, evset: EventSet) -> ProxyUpdate;
+    /// Starts receiving again after being throttled.
+    fn resume_rx(&mut self) -> ProxyUpdate {
+        ProxyUpdate::default()
+    }
 }
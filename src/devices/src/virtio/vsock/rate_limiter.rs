@@ -0,0 +1,78 @@
+//! Caps on the throughput of TSI, for all the connections of the microVM.
+//!
+//! A `TsiRateLimiter` is shared by everything passing data in one direction:
+//! the device thread for the data the guest sends, and the proxies, from the
+//! muxer thread, for the data they receive on its behalf. Rate limiting is
+//! only available on Linux, elsewhere the limiter lets everything through.
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "linux")]
+use crate::rate_limiter::{RateLimiter, RateLimiterConfig};
+
+#[derive(Clone)]
+pub struct TsiRateLimiter {
+    #[cfg(target_os = "linux")]
+    inner: Arc<Mutex<RateLimiter>>,
+}
+
+impl TsiRateLimiter {
+    /// Creates a limiter that doesn't limit anything until it's updated.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(TsiRateLimiter {
+            #[cfg(target_os = "linux")]
+            inner: Arc::new(Mutex::new(RateLimiter::new(RateLimiterConfig::default())?)),
+        })
+    }
+
+    /// Tries to take `bytes` for a packet, see `RateLimiter::consume`.
+    pub fn consume(&self, bytes: usize) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.inner.lock().unwrap().consume(1, bytes as u64);
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = bytes;
+            true
+        }
+    }
+
+    /// Takes `bytes` a proxy already received, see `RateLimiter::charge`.
+    pub fn charge(&self, bytes: usize) {
+        #[cfg(target_os = "linux")]
+        self.inner.lock().unwrap().charge(1, bytes as u64);
+        #[cfg(not(target_os = "linux"))]
+        let _ = bytes;
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.inner.lock().unwrap().is_blocked();
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    /// Handles the expiration of the timer, after which data can flow again.
+    pub fn event_handler(&self) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        return self.inner.lock().unwrap().event_handler();
+        #[cfg(not(target_os = "linux"))]
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn update(&self, config: RateLimiterConfig) {
+        self.inner.lock().unwrap().update(config);
+    }
+
+    /// File descriptor that becomes readable when the timer expires, if any.
+    pub fn timer_fd(&self) -> Option<RawFd> {
+        #[cfg(target_os = "linux")]
+        return Some(self.inner.lock().unwrap().as_raw_fd());
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+}
@@ -0,0 +1,944 @@
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::socket::{
+    accept, connect, recv, send, shutdown, socket, AddressFamily, MsgFlags, Shutdown, SockFlag,
+    SockType, SockaddrLike,
+};
+use nix::unistd::close;
+
+#[cfg(target_os = "macos")]
+use super::super::linux_errno::linux_errno_raw;
+use super::super::Queue as VirtQueue;
+use super::defs;
+use super::defs::uapi;
+use super::egress::EgressProtocol;
+use super::muxer::{push_packet, MuxerRx};
+use super::muxer_rxq::MuxerRxQ;
+use super::packet::{
+    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, TsiUnixConnectReq,
+    VsockPacket,
+};
+use super::proxy::{Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt};
+use super::rate_limiter::TsiRateLimiter;
+use utils::epoll::EventSet;
+
+use vm_memory::GuestMemoryMmap;
+
+/// Converts `err` to the negated errno the guest expects.
+pub fn guest_errno(err: nix::errno::Errno) -> i32 {
+    #[cfg(target_os = "macos")]
+    let errno = -linux_errno_raw(err as i32);
+    #[cfg(target_os = "linux")]
+    let errno = -(err as i32);
+    errno
+}
+
+/// Creates a non-blocking stream socket of `family`.
+pub fn stream_socket(id: u64, family: AddressFamily) -> Result<RawFd, ProxyError> {
+    let fd = socket(family, SockType::Stream, SockFlag::empty(), None)
+        .map_err(ProxyError::CreatingSocket)?;
+
+    // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
+    match fcntl(fd, FcntlArg::F_GETFL) {
+        Ok(flags) => match OFlag::from_bits(flags) {
+            Some(flags) => {
+                if let Err(e) = fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)) {
+                    warn!("error switching to non-blocking: id={}, err={}", id, e);
+                }
+            }
+            None => error!("invalid fd flags id={}", id),
+        },
+        Err(e) => error!("couldn't obtain fd flags id={}, err={}", id, e),
+    };
+
+    #[cfg(target_os = "macos")]
+    {
+        // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
+        let option_value: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_NOSIGPIPE,
+                &option_value as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&option_value) as libc::socklen_t,
+            )
+        };
+    }
+
+    Ok(fd)
+}
+
+/// The parts of a stream proxy that depend on the type of its socket: which
+/// addresses of the guest it may connect to or listen on, and how they map to
+/// the host.
+pub trait StreamSocket: Send {
+    /// The name of the socket type in log messages.
+    const NAME: &'static str;
+
+    /// The protocol the egress policy applies to the connections as, if any.
+    fn egress_protocol(&self) -> Option<EgressProtocol>;
+
+    /// The host address for a connect request to an IP address, or the
+    /// negated errno to answer the guest with.
+    fn connect_addr(&self, _req: &TsiConnectReq) -> Result<Box<dyn SockaddrLike>, i32> {
+        Err(-libc::EAFNOSUPPORT)
+    }
+
+    /// The host address for a connect request to a unix socket path, or the
+    /// negated errno to answer the guest with.
+    fn connect_unix_addr(
+        &self,
+        _req: &TsiUnixConnectReq,
+        _unix_socket_map: &HashMap<PathBuf, PathBuf>,
+    ) -> Result<Box<dyn SockaddrLike>, i32> {
+        Err(-libc::EAFNOSUPPORT)
+    }
+
+    /// The name of the peer `fd` is connected to, as told to the guest.
+    fn getpeername(&self, fd: RawFd) -> TsiGetnameRsp;
+
+    /// Binds `fd` to the host address for `req` and starts listening on it.
+    /// Returns 0 or a negated errno.
+    fn listen(
+        &self,
+        _fd: RawFd,
+        _req: &TsiListenReq,
+        _host_port_map: &Option<HashMap<u16, u16>>,
+    ) -> i32 {
+        -libc::EOPNOTSUPP
+    }
+}
+
+/// Proxies a stream socket of the guest to one of the host, keeping track
+/// of the vsock connection and its credit.
+pub struct StreamProxy<S: StreamSocket> {
+    id: u64,
+    cid: u64,
+    parent_id: u64,
+    local_port: u32,
+    peer_port: u32,
+    control_port: u32,
+    fd: RawFd,
+    pub status: ProxyStatus,
+    mem: GuestMemoryMmap,
+    queue: Arc<Mutex<VirtQueue>>,
+    rxq: Arc<Mutex<MuxerRxQ>>,
+    rx_cnt: Wrapping<u32>,
+    tx_cnt: Wrapping<u32>,
+    last_tx_cnt_sent: Wrapping<u32>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    push_cnt: Wrapping<u32>,
+    pending_accepts: u64,
+    rx_rate_limiter: TsiRateLimiter,
+    throttled: bool,
+    socket: S,
+}
+
+impl<S: StreamSocket> StreamProxy<S> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_fd(
+        socket: S,
+        fd: RawFd,
+        status: ProxyStatus,
+        id: u64,
+        cid: u64,
+        parent_id: u64,
+        local_port: u32,
+        peer_port: u32,
+        control_port: u32,
+        mem: GuestMemoryMmap,
+        queue: Arc<Mutex<VirtQueue>>,
+        rxq: Arc<Mutex<MuxerRxQ>>,
+        rx_rate_limiter: TsiRateLimiter,
+    ) -> Self {
+        StreamProxy {
+            id,
+            cid,
+            parent_id,
+            local_port,
+            peer_port,
+            control_port,
+            fd,
+            status,
+            mem,
+            queue,
+            rxq,
+            rx_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
+            last_tx_cnt_sent: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            push_cnt: Wrapping(0),
+            pending_accepts: 0,
+            rx_rate_limiter,
+            throttled: false,
+            socket,
+        }
+    }
+
+    fn init_data_pkt(&self, pkt: &mut VsockPacket) {
+        debug!(
+            "{}: init_data_pkt: id={}, local_port={}, peer_port={}",
+            S::NAME,
+            self.id,
+            self.local_port,
+            self.peer_port
+        );
+        pkt.set_op(uapi::VSOCK_OP_RW)
+            .set_src_cid(uapi::VSOCK_HOST_CID)
+            .set_dst_cid(self.cid)
+            .set_src_port(self.local_port)
+            .set_dst_port(self.peer_port)
+            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_buf_alloc(defs::CONN_TX_BUF_SIZE as u32)
+            .set_fwd_cnt(self.tx_cnt.0);
+    }
+
+    fn peer_avail_credit(&self) -> usize {
+        (Wrapping(self.peer_buf_alloc) - (self.rx_cnt - self.peer_fwd_cnt)).0 as usize
+    }
+
+    fn recv_to_pkt(&self, pkt: &mut VsockPacket) -> RecvPkt {
+        if let Some(buf) = pkt.buf_mut() {
+            let peer_credit = self.peer_avail_credit();
+            let max_len = std::cmp::min(buf.len(), peer_credit);
+
+            debug!(
+                "recv_to_pkt: peer_avail_credit={}, buf.len={}, max_len={}",
+                peer_credit,
+                buf.len(),
+                max_len,
+            );
+
+            if max_len == 0 {
+                return RecvPkt::WaitForCredit;
+            }
+
+            match recv(self.fd, &mut buf[..max_len], MsgFlags::MSG_DONTWAIT) {
+                Ok(cnt) => {
+                    debug!("vsock: {}: recv cnt={}", S::NAME, cnt);
+                    if cnt > 0 {
+                        debug!("vsock: {}: recv rx_cnt={}", S::NAME, self.rx_cnt);
+                        RecvPkt::Read(cnt)
+                    } else {
+                        RecvPkt::Close
+                    }
+                }
+                Err(e) => {
+                    debug!("vsock: {}: recv_pkt: recv error: {:?}", S::NAME, e);
+                    RecvPkt::Error
+                }
+            }
+        } else {
+            debug!("vsock: {}: recv_pkt: pkt without buf", S::NAME);
+            RecvPkt::Error
+        }
+    }
+
+    fn recv_pkt(&mut self) -> (bool, bool) {
+        let mut have_used = false;
+        let mut wait_credit = false;
+        let mut queue = self.queue.lock().unwrap();
+
+        while let Some(head) = queue.pop(&self.mem) {
+            if self.rx_rate_limiter.is_blocked() {
+                // Leave the data in the socket until the timer expires.
+                self.throttled = true;
+                queue.undo_pop();
+                break;
+            }
+
+            let len = match VsockPacket::from_rx_virtq_head(&head) {
+                Ok(mut pkt) => match self.recv_to_pkt(&mut pkt) {
+                    RecvPkt::WaitForCredit => {
+                        wait_credit = true;
+                        0
+                    }
+                    RecvPkt::Read(cnt) => {
+                        self.rx_rate_limiter.charge(cnt);
+                        self.rx_cnt += Wrapping(cnt as u32);
+                        self.init_data_pkt(&mut pkt);
+                        pkt.set_len(cnt as u32);
+                        pkt.hdr().len() + cnt
+                    }
+                    RecvPkt::Close => {
+                        self.status = ProxyStatus::Closed;
+                        0
+                    }
+                    RecvPkt::Error => 0,
+                },
+                Err(e) => {
+                    debug!("vsock: {}: recv_pkt: RX queue error: {:?}", S::NAME, e);
+                    0
+                }
+            };
+
+            if len == 0 {
+                queue.undo_pop();
+                break;
+            } else {
+                have_used = true;
+                self.push_cnt += Wrapping(len as u32);
+                debug!(
+                    "vsock: {}: recv_pkt: pushing packet with {} bytes, push_cnt={}",
+                    S::NAME,
+                    len,
+                    self.push_cnt
+                );
+                queue.add_used(&self.mem, head.index, len as u32);
+            }
+        }
+
+        debug!("vsock: {}: recv_pkt: have_used={}", S::NAME, have_used);
+        (have_used, wait_credit)
+    }
+
+    fn push_connect_rsp(&self, result: i32) {
+        debug!(
+            "{}: push_connect_rsp: id: {}, control_port: {}, result: {}",
+            S::NAME,
+            self.id,
+            self.control_port,
+            result
+        );
+
+        // This response goes to the control port (DGRAM).
+        let rx = MuxerRx::ConnResponse {
+            local_port: 1025,
+            peer_port: self.control_port,
+            result,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn push_reset(&self) {
+        debug!(
+            "{}: push_reset: id: {}, peer_port: {}, local_port: {}",
+            S::NAME,
+            self.id,
+            self.peer_port,
+            self.local_port
+        );
+
+        // This response goes to the connection.
+        let rx = MuxerRx::Reset {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn switch_to_connected(&mut self) {
+        self.status = ProxyStatus::Connected;
+        match fcntl(self.fd, FcntlArg::F_GETFL) {
+            Ok(flags) => match OFlag::from_bits(flags) {
+                Some(flags) => {
+                    if let Err(e) = fcntl(self.fd, FcntlArg::F_SETFL(flags & !OFlag::O_NONBLOCK)) {
+                        warn!("error switching to blocking: id={}, err={}", self.id, e);
+                    }
+                }
+                None => error!("invalid fd flags id={}", self.id),
+            },
+            Err(e) => error!("couldn't obtain fd flags id={}, err={}", self.id, e),
+        };
+    }
+
+    /// Connects to `addr`, or answers the guest with the errno of `addr`.
+    fn connect_to(&mut self, addr: Result<Box<dyn SockaddrLike>, i32>) -> ProxyUpdate {
+        let mut update = ProxyUpdate::default();
+
+        let result = match addr {
+            Ok(addr) => match connect(self.fd, &*addr) {
+                Ok(()) => {
+                    debug!("vsock: {}: connect: Connected", S::NAME);
+                    self.switch_to_connected();
+                    0
+                }
+                Err(nix::errno::Errno::EINPROGRESS) => {
+                    debug!("vsock: {}: connect: Connecting", S::NAME);
+                    self.status = ProxyStatus::Connecting;
+                    0
+                }
+                Err(e) => {
+                    debug!("vsock: {}: error connecting: {}", S::NAME, e);
+                    guest_errno(e)
+                }
+            },
+            Err(errno) => errno,
+        };
+
+        if self.status == ProxyStatus::Connecting {
+            update.polling = Some((self.id, self.fd, EventSet::IN | EventSet::OUT));
+        } else {
+            if self.status == ProxyStatus::Connected {
+                update.polling = Some((self.id, self.fd, EventSet::IN));
+            }
+            self.push_connect_rsp(result);
+        }
+
+        update
+    }
+}
+
+impl<S: StreamSocket> Proxy for StreamProxy<S> {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn status(&self) -> ProxyStatus {
+        self.status
+    }
+
+    fn egress_protocol(&self) -> Option<EgressProtocol> {
+        self.socket.egress_protocol()
+    }
+
+    fn is_dgram(&self) -> bool {
+        false
+    }
+
+    fn connect(&mut self, _pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate {
+        let addr = self.socket.connect_addr(&req);
+        self.connect_to(addr)
+    }
+
+    fn connect_unix(
+        &mut self,
+        _pkt: &VsockPacket,
+        req: TsiUnixConnectReq,
+        unix_socket_map: &HashMap<PathBuf, PathBuf>,
+    ) -> ProxyUpdate {
+        let addr = self.socket.connect_unix_addr(&req, unix_socket_map);
+        self.connect_to(addr)
+    }
+
+    fn confirm_connect(&mut self, pkt: &VsockPacket) {
+        debug!(
+            "{}: confirm_connect: local_port={} peer_port={}, src_port={}, dst_port={}",
+            S::NAME,
+            pkt.dst_port(),
+            pkt.src_port(),
+            self.local_port,
+            self.peer_port,
+        );
+
+        self.peer_buf_alloc = pkt.buf_alloc();
+        self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
+
+        self.local_port = pkt.dst_port();
+        self.peer_port = pkt.src_port();
+
+        // This response goes to the connection.
+        let rx = MuxerRx::OpResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn getpeername(&mut self, pkt: &VsockPacket) {
+        debug!("getpeername: id={}", self.id);
+
+        let data = self.socket.getpeername(self.fd);
+
+        debug!("getpeername: reply={:?}", data);
+
+        // This response goes to the control port (DGRAM).
+        let rx = MuxerRx::GetnameResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+            data,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn sendmsg(&mut self, pkt: &VsockPacket) -> ProxyUpdate {
+        debug!("vsock: {}: sendmsg", S::NAME);
+
+        let mut update = ProxyUpdate::default();
+
+        let ret = if let Some(buf) = pkt.buf() {
+            #[cfg(target_os = "macos")]
+            let flags = MsgFlags::empty();
+            #[cfg(target_os = "linux")]
+            let flags = MsgFlags::MSG_NOSIGNAL;
+
+            match send(self.fd, buf, flags) {
+                Ok(sent) => {
+                    if sent != buf.len() {
+                        error!("couldn't set everything: buf={}, sent={}", buf.len(), sent);
+                    }
+                    self.tx_cnt += Wrapping(sent as u32);
+                    sent as i32
+                }
+                Err(err) => guest_errno(err),
+            }
+        } else {
+            -libc::EINVAL
+        };
+
+        if ret > 0
+            && (self.tx_cnt - self.last_tx_cnt_sent).0 as usize >= (defs::CONN_TX_BUF_SIZE / 2)
+        {
+            debug!(
+                "sending credit update: id={}, tx_cnt={}, last_tx_cnt={}",
+                self.id, self.tx_cnt, self.last_tx_cnt_sent
+            );
+            self.last_tx_cnt_sent = self.tx_cnt;
+            // This packet goes to the connection.
+            let rx = MuxerRx::CreditUpdate {
+                local_port: pkt.dst_port(),
+                peer_port: pkt.src_port(),
+                fwd_cnt: self.tx_cnt.0,
+            };
+            push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+            update.signal_queue = true;
+        }
+
+        debug!("vsock: {}: sendmsg ret={}", S::NAME, ret);
+        update
+    }
+
+    fn sendto_addr(&mut self, _req: TsiSendtoAddr) -> ProxyUpdate {
+        ProxyUpdate::default()
+    }
+
+    fn listen(
+        &mut self,
+        pkt: &VsockPacket,
+        req: TsiListenReq,
+        host_port_map: &Option<HashMap<u16, u16>>,
+    ) -> ProxyUpdate {
+        debug!(
+            "listen: id={} addr={}, port={}, vm_port={} backlog={}",
+            self.id, req.addr, req.port, req.vm_port, req.backlog
+        );
+        let mut update = ProxyUpdate::default();
+
+        let result = if self.status == ProxyStatus::Listening
+            || self.status == ProxyStatus::WaitingOnAccept
+        {
+            0
+        } else {
+            self.socket.listen(self.fd, &req, host_port_map)
+        };
+
+        // This packet goes to the control port (DGRAM).
+        let rx = MuxerRx::ListenResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+            result,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+
+        if result == 0 {
+            self.peer_port = req.vm_port;
+            self.status = ProxyStatus::Listening;
+            update.polling = Some((self.id, self.fd, EventSet::IN));
+        }
+
+        update
+    }
+
+    fn accept(&mut self, req: TsiAcceptReq) -> ProxyUpdate {
+        debug!("accept: id={} flags={}", req.peer_port, req.flags);
+
+        let mut update = ProxyUpdate::default();
+
+        if self.pending_accepts > 0 {
+            self.pending_accepts -= 1;
+            self.push_accept_rsp(0);
+            update.signal_queue = true;
+        } else if (req.flags & libc::O_NONBLOCK as u32) != 0 {
+            self.push_accept_rsp(-libc::EWOULDBLOCK);
+            update.signal_queue = true;
+        } else {
+            self.status = ProxyStatus::WaitingOnAccept;
+        }
+
+        update
+    }
+
+    fn update_peer_credit(&mut self, pkt: &VsockPacket) -> ProxyUpdate {
+        debug!(
+            "update_credit: buf_alloc={} rx_cnt={} fwd_cnt={}",
+            pkt.buf_alloc(),
+            self.rx_cnt,
+            pkt.fwd_cnt()
+        );
+        self.peer_buf_alloc = pkt.buf_alloc();
+        self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
+
+        self.status = ProxyStatus::Connected;
+
+        ProxyUpdate {
+            polling: Some((self.id, self.fd, EventSet::IN)),
+            ..Default::default()
+        }
+    }
+
+    fn push_op_request(&self) {
+        debug!(
+            "push_op_request: id={}, local_port={} peer_port={}",
+            self.id, self.local_port, self.peer_port
+        );
+
+        // This packet goes to the connection.
+        let rx = MuxerRx::OpRequest {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn process_op_response(&mut self, pkt: &VsockPacket) -> ProxyUpdate {
+        debug!(
+            "process_op_response: id={} src_port={} dst_port={}",
+            self.id,
+            pkt.src_port(),
+            pkt.dst_port()
+        );
+
+        self.peer_buf_alloc = pkt.buf_alloc();
+        self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
+
+        self.switch_to_connected();
+
+        ProxyUpdate {
+            polling: Some((self.id, self.fd, EventSet::IN)),
+            push_accept: Some((self.id, self.parent_id)),
+            ..Default::default()
+        }
+    }
+
+    fn enqueue_accept(&mut self) {
+        debug!("enqueue_accept: control_port: {}", self.control_port);
+
+        if self.status == ProxyStatus::WaitingOnAccept {
+            self.status = ProxyStatus::Listening;
+            self.push_accept_rsp(0);
+        } else {
+            self.pending_accepts += 1;
+        }
+    }
+
+    fn push_accept_rsp(&self, result: i32) {
+        debug!(
+            "push_accept_rsp: control_port: {}, result: {}",
+            self.control_port, result
+        );
+
+        // This packet goes to the control port (DGRAM).
+        let rx = MuxerRx::AcceptResponse {
+            local_port: 1030,
+            peer_port: self.control_port,
+            result,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+    }
+
+    fn shutdown(&mut self, pkt: &VsockPacket) {
+        let recv_off = pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_RCV != 0;
+        let send_off = pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_SEND != 0;
+
+        let how = if recv_off && send_off {
+            Shutdown::Both
+        } else if recv_off {
+            Shutdown::Read
+        } else {
+            Shutdown::Write
+        };
+
+        if let Err(e) = shutdown(self.fd, how) {
+            warn!("error sending shutdown to socket: {}", e);
+        }
+    }
+
+    fn release(&mut self) -> ProxyUpdate {
+        debug!(
+            "release: id={}, tx_cnt={}, last_tx_cnt={}",
+            self.id, self.tx_cnt, self.last_tx_cnt_sent
+        );
+        let remove_proxy = if self.status == ProxyStatus::Listening {
+            ProxyRemoval::Immediate
+        } else {
+            ProxyRemoval::Deferred
+        };
+        ProxyUpdate {
+            remove_proxy,
+            ..Default::default()
+        }
+    }
+
+    fn resume_rx(&mut self) -> ProxyUpdate {
+        let mut update = ProxyUpdate::default();
+        if self.throttled {
+            self.throttled = false;
+            if self.status == ProxyStatus::Connected {
+                update.polling = Some((self.id, self.fd, EventSet::IN));
+            }
+        }
+        update
+    }
+
+    fn process_event(&mut self, evset: EventSet) -> ProxyUpdate {
+        let mut update = ProxyUpdate::default();
+
+        if evset.contains(EventSet::HANG_UP) {
+            debug!("process_event: HANG_UP");
+            if self.status == ProxyStatus::Connecting {
+                self.push_connect_rsp(-libc::ECONNREFUSED);
+            } else {
+                self.push_reset();
+            }
+
+            self.status = ProxyStatus::Closed;
+            update.polling = Some((self.id, self.fd, EventSet::empty()));
+            update.signal_queue = true;
+            update.remove_proxy = ProxyRemoval::Deferred;
+            return update;
+        }
+
+        if evset.contains(EventSet::IN) {
+            debug!("process_event: IN");
+            if self.status == ProxyStatus::Connected {
+                let (signal_queue, wait_credit) = self.recv_pkt();
+                update.signal_queue = signal_queue;
+
+                if wait_credit && self.status != ProxyStatus::WaitingCreditUpdate {
+                    self.status = ProxyStatus::WaitingCreditUpdate;
+                    let rx = MuxerRx::CreditRequest {
+                        local_port: self.local_port,
+                        peer_port: self.peer_port,
+                        fwd_cnt: self.tx_cnt.0,
+                    };
+                    update.push_credit_req = Some(rx);
+                }
+
+                if self.status == ProxyStatus::Closed {
+                    debug!(
+                        "process_event: endpoint closed, sending reset: id={}",
+                        self.id
+                    );
+                    self.push_reset();
+                    update.signal_queue = true;
+                    update.polling = Some((self.id(), self.fd, EventSet::empty()));
+                    return update;
+                } else if self.status == ProxyStatus::WaitingCreditUpdate {
+                    debug!("process_event: WaitingCreditUpdate");
+                    update.polling = Some((self.id(), self.fd, EventSet::empty()));
+                } else if self.throttled {
+                    debug!("process_event: throttled");
+                    update.polling = Some((self.id(), self.fd, EventSet::empty()));
+                    update.throttled = true;
+                }
+            } else if self.status == ProxyStatus::Listening
+                || self.status == ProxyStatus::WaitingOnAccept
+            {
+                match accept(self.fd) {
+                    Ok(accept_fd) => {
+                        update.new_proxy = Some((self.peer_port, accept_fd));
+                    }
+                    Err(e) => warn!("error accepting connection: id={}, err={}", self.id, e),
+                };
+                update.signal_queue = true;
+                return update;
+            } else {
+                debug!(
+                    "vsock::{}: EventSet::IN while not connected: {:?}",
+                    S::NAME,
+                    self.status
+                );
+            }
+        }
+
+        if evset.contains(EventSet::OUT) {
+            debug!("process_event: OUT");
+            if self.status == ProxyStatus::Connecting {
+                self.switch_to_connected();
+                self.push_connect_rsp(0);
+                update.signal_queue = true;
+                update.polling = Some((self.id(), self.fd, EventSet::IN));
+            } else {
+                error!("vsock::{}: EventSet::OUT while not connecting", S::NAME);
+            }
+        }
+
+        update
+    }
+}
+
+impl<S: StreamSocket> AsRawFd for StreamProxy<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<S: StreamSocket> Drop for StreamProxy<S> {
+    fn drop(&mut self) {
+        if let Err(e) = close(self.fd) {
+            warn!("error closing proxy fd: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::virtio::vsock::packet::tests::{guest_memory, tx_packet};
+    use crate::virtio::vsock::tcp::TcpSocket;
+    use crate::virtio::vsock::unix::UnixSocket;
+
+    const PROXY_ID: u64 = 1;
+
+    /// A proxy of `socket` type in `status`, with the peer of its socket.
+    fn stream_proxy<S: StreamSocket>(
+        socket: S,
+        status: ProxyStatus,
+        rxq: &Arc<Mutex<MuxerRxQ>>,
+    ) -> (StreamProxy<S>, UnixStream) {
+        let (sock, peer) = UnixStream::pair().unwrap();
+        let proxy = StreamProxy::from_fd(
+            socket,
+            sock.into_raw_fd(),
+            status,
+            PROXY_ID,
+            3,
+            0,
+            1024,
+            2048,
+            1025,
+            guest_memory(),
+            Arc::new(Mutex::new(VirtQueue::new(16))),
+            rxq.clone(),
+            TsiRateLimiter::new().unwrap(),
+        );
+        (proxy, peer)
+    }
+
+    fn pop_rx(rxq: &Arc<Mutex<MuxerRxQ>>) -> Option<MuxerRx> {
+        rxq.lock().unwrap().pop()
+    }
+
+    fn shutdown_pkt(mem: &GuestMemoryMmap, flags: u32) -> VsockPacket {
+        let mut pkt = tx_packet(mem, &[]);
+        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN).set_flags(flags);
+        pkt
+    }
+
+    fn check_shutdown<S: StreamSocket>(new_socket: impl Fn() -> S) {
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mem = guest_memory();
+
+        // The host peer sees the end of the stream once the guest stops sending...
+        let (mut proxy, mut peer) = stream_proxy(new_socket(), ProxyStatus::Connected, &rxq);
+        proxy.shutdown(&shutdown_pkt(&mem, uapi::VSOCK_FLAGS_SHUTDOWN_SEND));
+        assert_eq!(peer.read(&mut [0u8; 16]).unwrap(), 0);
+        // ...but can still send to it.
+        peer.write_all(b"data").unwrap();
+
+        // And can't send anymore once the guest stops receiving.
+        let (mut proxy, mut peer) = stream_proxy(new_socket(), ProxyStatus::Connected, &rxq);
+        proxy.shutdown(&shutdown_pkt(&mem, uapi::VSOCK_FLAGS_SHUTDOWN_RCV));
+        assert_eq!(
+            peer.write_all(b"data").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+
+        let (mut proxy, mut peer) = stream_proxy(new_socket(), ProxyStatus::Connected, &rxq);
+        proxy.shutdown(&shutdown_pkt(
+            &mem,
+            uapi::VSOCK_FLAGS_SHUTDOWN_RCV | uapi::VSOCK_FLAGS_SHUTDOWN_SEND,
+        ));
+        assert_eq!(peer.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(peer.write_all(b"data").is_err());
+        assert!(rxq.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        check_shutdown(|| TcpSocket);
+        check_shutdown(|| UnixSocket);
+    }
+
+    #[test]
+    fn test_hang_up() {
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+
+        // A connection that never completes is refused...
+        let (mut proxy, _peer) = stream_proxy(TcpSocket, ProxyStatus::Connecting, &rxq);
+        let update = proxy.process_event(EventSet::HANG_UP);
+        assert_eq!(proxy.status(), ProxyStatus::Closed);
+        assert_eq!(
+            update.polling,
+            Some((PROXY_ID, proxy.fd, EventSet::empty()))
+        );
+        assert!(matches!(update.remove_proxy, ProxyRemoval::Deferred));
+        match pop_rx(&rxq) {
+            Some(MuxerRx::ConnResponse { result, .. }) => assert_eq!(result, -libc::ECONNREFUSED),
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+
+        // ...and an established one is reset.
+        let (mut proxy, _peer) = stream_proxy(UnixSocket, ProxyStatus::Connected, &rxq);
+        proxy.process_event(EventSet::HANG_UP);
+        assert_eq!(proxy.status(), ProxyStatus::Closed);
+        match pop_rx(&rxq) {
+            Some(MuxerRx::Reset {
+                local_port,
+                peer_port,
+            }) => assert_eq!((local_port, peer_port), (1024, 2048)),
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+    }
+
+    #[test]
+    fn test_accept() {
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let accept_result = || match pop_rx(&rxq) {
+            Some(MuxerRx::AcceptResponse { result, .. }) => Some(result),
+            None => None,
+            rx => panic!("unexpected rx: {rx:?}"),
+        };
+        let (mut proxy, _peer) = stream_proxy(TcpSocket, ProxyStatus::Listening, &rxq);
+
+        // Non-blocking accepts fail right away without a pending connection.
+        let accept_req = |flags| TsiAcceptReq {
+            peer_port: 2048,
+            flags,
+        };
+        let nonblocking = libc::O_NONBLOCK as u32;
+        assert!(proxy.accept(accept_req(nonblocking)).signal_queue);
+        assert_eq!(accept_result(), Some(-libc::EWOULDBLOCK));
+
+        // Blocking ones are answered once a connection comes in.
+        proxy.accept(accept_req(0));
+        assert_eq!(proxy.status(), ProxyStatus::WaitingOnAccept);
+        assert_eq!(accept_result(), None);
+        proxy.enqueue_accept();
+        assert_eq!(proxy.status(), ProxyStatus::Listening);
+        assert_eq!(accept_result(), Some(0));
+
+        // Connections coming in first wait for the guest to accept them.
+        proxy.enqueue_accept();
+        proxy.enqueue_accept();
+        assert_eq!(accept_result(), None);
+        for _ in 0..2 {
+            assert!(proxy.accept(accept_req(0)).signal_queue);
+            assert_eq!(accept_result(), Some(0));
+        }
+        proxy.accept(accept_req(nonblocking));
+        assert_eq!(accept_result(), Some(-libc::EWOULDBLOCK));
+    }
+}
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
-    bind, connect, getpeername, recv, send, sendto, socket, AddressFamily, MsgFlags, SockFlag,
-    SockType, SockaddrIn,
+    bind, connect, getpeername, recvfrom, send, sendto, socket, AddressFamily, MsgFlags, SockFlag,
+    SockType, SockaddrStorage,
 };
 use nix::unistd::close;
 
@@ -16,24 +17,44 @@ use super::super::linux_errno::linux_errno_raw;
 use super::super::Queue as VirtQueue;
 use super::defs;
 use super::defs::uapi;
+use super::egress::EgressProtocol;
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
-    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
+    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, TsiUnixConnectReq,
+    VsockPacket,
 };
-use super::proxy::{Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt};
+use super::proxy::{
+    ip_addr_port, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+};
+use super::rate_limiter::TsiRateLimiter;
 use utils::epoll::EventSet;
 
 use vm_memory::GuestMemoryMmap;
 
+/// Where a bound socket that isn't connected sends the replies of the guest,
+/// which doesn't learn where the datagrams it receives come from.
+#[derive(Clone, Copy)]
+enum ReplyPeer {
+    /// No datagram was received yet.
+    Unknown,
+    /// Every datagram received so far came from this peer.
+    Single(SockaddrStorage),
+    /// Datagrams came from several peers, so there's no telling which one a
+    /// reply is meant for.
+    Several,
+}
+
 pub struct UdpProxy {
     pub id: u64,
     cid: u64,
     local_port: u32,
     peer_port: u32,
     fd: RawFd,
+    family: AddressFamily,
     pub status: ProxyStatus,
-    sendto_addr: Option<SockaddrIn>,
+    sendto_addr: Option<SockaddrStorage>,
+    reply_peer: ReplyPeer,
     listening: bool,
     mem: GuestMemoryMmap,
     queue: Arc<Mutex<VirtQueue>>,
@@ -42,24 +63,24 @@ pub struct UdpProxy {
     tx_cnt: Wrapping<u32>,
     peer_buf_alloc: u32,
     peer_fwd_cnt: Wrapping<u32>,
+    rx_rate_limiter: TsiRateLimiter,
+    throttled: bool,
 }
 
 impl UdpProxy {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: u64,
         cid: u64,
+        family: AddressFamily,
         peer_port: u32,
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        rx_rate_limiter: TsiRateLimiter,
     ) -> Result<Self, ProxyError> {
-        let fd = socket(
-            AddressFamily::Inet,
-            SockType::Datagram,
-            SockFlag::empty(),
-            None,
-        )
-        .map_err(ProxyError::CreatingSocket)?;
+        let fd = socket(family, SockType::Datagram, SockFlag::empty(), None)
+            .map_err(ProxyError::CreatingSocket)?;
 
         // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
         match fcntl(fd, FcntlArg::F_GETFL) {
@@ -95,8 +116,10 @@ impl UdpProxy {
             local_port: 0,
             peer_port,
             fd,
+            family,
             status: ProxyStatus::Idle,
             sendto_addr: None,
+            reply_peer: ReplyPeer::Unknown,
             listening: false,
             mem,
             queue,
@@ -105,6 +128,8 @@ impl UdpProxy {
             tx_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
+            rx_rate_limiter,
+            throttled: false,
         })
     }
 
@@ -139,7 +164,43 @@ impl UdpProxy {
     }
     */
 
-    fn recv_to_pkt(&self, pkt: &mut VsockPacket) -> RecvPkt {
+    /// Binds the socket to the host port the guest port `req.port` is mapped
+    /// to, so the datagrams sent there reach the guest. Binds to port 0 are
+    /// let through, as they don't forward anything.
+    fn try_bind(&self, req: &TsiListenReq, host_port_map: &Option<HashMap<u16, u16>>) -> i32 {
+        let port = if req.port == 0 {
+            0
+        } else if let Some(port_map) = host_port_map {
+            if let Some(port) = port_map.get(&req.port) {
+                *port
+            } else {
+                return -libc::EPERM;
+            }
+        } else {
+            req.port
+        };
+
+        match bind(
+            self.fd,
+            &SockaddrStorage::from(SocketAddr::new(req.addr, port)),
+        ) {
+            Ok(_) => {
+                debug!("vsock: udp: bind: id={} port={}", self.id, port);
+                0
+            }
+            Err(e) => {
+                warn!("vsock: udp: bind: id={} err={}", self.id, e);
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                errno
+            }
+        }
+    }
+
+    /// Receives a datagram into `pkt`, returning where it came from as well.
+    fn recv_to_pkt(&self, pkt: &mut VsockPacket) -> (RecvPkt, Option<SockaddrStorage>) {
         if let Some(buf) = pkt.buf_mut() {
             // Disable UDP credit accounting until is fixed in the kernel
             //let peer_credit = self.peer_avail_credit();
@@ -159,23 +220,23 @@ impl UdpProxy {
             }
             */
 
-            match recv(self.fd, &mut buf[..max_len], MsgFlags::empty()) {
-                Ok(cnt) => {
+            match recvfrom::<SockaddrStorage>(self.fd, &mut buf[..max_len]) {
+                Ok((cnt, peer)) => {
                     debug!("vsock: udp: recv cnt={}", cnt);
                     if cnt > 0 {
-                        RecvPkt::Read(cnt)
+                        (RecvPkt::Read(cnt), peer)
                     } else {
-                        RecvPkt::Close
+                        (RecvPkt::Close, None)
                     }
                 }
                 Err(e) => {
                     debug!("vsock: udp: recv_pkt: recv error: {:?}", e);
-                    RecvPkt::Error
+                    (RecvPkt::Error, None)
                 }
             }
         } else {
             debug!("vsock: udp: recv_pkt: pkt without buf");
-            RecvPkt::Error
+            (RecvPkt::Error, None)
         }
     }
 
@@ -185,23 +246,38 @@ impl UdpProxy {
         let mut queue = self.queue.lock().unwrap();
 
         while let Some(head) = queue.pop(&self.mem) {
+            if self.rx_rate_limiter.is_blocked() {
+                // Leave the datagrams in the socket until the timer expires.
+                self.throttled = true;
+                queue.undo_pop();
+                break;
+            }
+
             let len = match VsockPacket::from_rx_virtq_head(&head) {
                 Ok(mut pkt) => match self.recv_to_pkt(&mut pkt) {
-                    RecvPkt::WaitForCredit => {
+                    (RecvPkt::WaitForCredit, _) => {
                         wait_credit = true;
                         0
                     }
-                    RecvPkt::Read(cnt) => {
+                    (RecvPkt::Read(cnt), peer) => {
+                        if let Some(peer) = peer {
+                            self.reply_peer = match self.reply_peer {
+                                ReplyPeer::Unknown => ReplyPeer::Single(peer),
+                                ReplyPeer::Single(addr) if addr == peer => ReplyPeer::Single(addr),
+                                _ => ReplyPeer::Several,
+                            };
+                        }
+                        self.rx_rate_limiter.charge(cnt);
                         self.rx_cnt += Wrapping(cnt as u32);
                         self.init_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
                     }
-                    RecvPkt::Close => {
+                    (RecvPkt::Close, _) => {
                         self.status = ProxyStatus::Closed;
                         0
                     }
-                    RecvPkt::Error => 0,
+                    (RecvPkt::Error, _) => 0,
                 },
                 Err(e) => {
                     debug!("vsock: tcp: recv_pkt: RX queue error: {:?}", e);
@@ -233,11 +309,26 @@ impl Proxy for UdpProxy {
         self.status
     }
 
+    fn egress_protocol(&self) -> Option<EgressProtocol> {
+        Some(EgressProtocol::Udp)
+    }
+
+    fn is_dgram(&self) -> bool {
+        true
+    }
+
+    fn reply_addr(&self) -> Option<(IpAddr, u16)> {
+        match self.reply_peer {
+            ReplyPeer::Single(addr) if self.status != ProxyStatus::Connected => ip_addr_port(&addr),
+            _ => None,
+        }
+    }
+
     fn connect(&mut self, pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate {
         debug!("vsock: udp: connect: addr={}, port={}", req.addr, req.port);
         let res = match connect(
             self.fd,
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)),
+            &SockaddrStorage::from(SocketAddr::new(req.addr, req.port)),
         ) {
             Ok(()) => {
                 debug!("vsock: connect: Connected");
@@ -272,15 +363,47 @@ impl Proxy for UdpProxy {
         update
     }
 
+    fn connect_unix(
+        &mut self,
+        pkt: &VsockPacket,
+        _req: TsiUnixConnectReq,
+        _unix_socket_map: &HashMap<PathBuf, PathBuf>,
+    ) -> ProxyUpdate {
+        // This response goes to the connection.
+        let rx = MuxerRx::ConnResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+            result: -libc::EAFNOSUPPORT,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+        ProxyUpdate::default()
+    }
+
     fn getpeername(&mut self, pkt: &VsockPacket) {
         debug!("vsock: udp: process_getpeername");
 
-        let name = getpeername::<SockaddrIn>(self.fd).unwrap();
-        let addr = Ipv4Addr::from(name.ip());
-        let data = TsiGetnameRsp {
-            addr,
-            port: name.port(),
-            result: 0,
+        let data = match getpeername::<SockaddrStorage>(self.fd) {
+            Ok(name) => match ip_addr_port(&name) {
+                Some((addr, port)) => TsiGetnameRsp {
+                    addr,
+                    port,
+                    result: 0,
+                },
+                None => TsiGetnameRsp {
+                    result: -libc::EAFNOSUPPORT,
+                    ..Default::default()
+                },
+            },
+            Err(e) => {
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                TsiGetnameRsp {
+                    result: errno,
+                    ..Default::default()
+                }
+            }
         };
 
         // This response goes to the connection.
@@ -301,7 +424,15 @@ impl Proxy for UdpProxy {
             #[cfg(target_os = "linux")]
             let flags = MsgFlags::MSG_NOSIGNAL;
 
-            match send(self.fd, buf, flags) {
+            // A bound socket that isn't connected replies to the peer it
+            // receives from, as long as there's only one.
+            let res = match self.reply_peer {
+                _ if self.status == ProxyStatus::Connected => send(self.fd, buf, flags),
+                ReplyPeer::Single(addr) => sendto(self.fd, buf, &addr, flags),
+                ReplyPeer::Several => Err(nix::errno::Errno::EDESTADDRREQ),
+                ReplyPeer::Unknown => send(self.fd, buf, flags),
+            };
+            match res {
                 Ok(sent) => {
                     self.tx_cnt += Wrapping(sent as u32);
                     sent as i32
@@ -325,9 +456,17 @@ impl Proxy for UdpProxy {
 
         let mut update = ProxyUpdate::default();
 
-        self.sendto_addr = Some(SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)));
+        self.sendto_addr = Some(SockaddrStorage::from(SocketAddr::new(req.addr, req.port)));
         if !self.listening {
-            match bind(self.fd, &SockaddrIn::new(0, 0, 0, 0, 0)) {
+            let unspecified = if self.family == AddressFamily::Inet6 {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            };
+            match bind(
+                self.fd,
+                &SockaddrStorage::from(SocketAddr::new(unspecified, 0)),
+            ) {
                 Ok(_) => {
                     self.listening = true;
                     update.polling = Some((self.id, self.fd, EventSet::IN));
@@ -368,11 +507,32 @@ impl Proxy for UdpProxy {
 
     fn listen(
         &mut self,
-        _pkt: &VsockPacket,
-        _req: TsiListenReq,
-        _host_port_map: &Option<HashMap<u16, u16>>,
+        pkt: &VsockPacket,
+        req: TsiListenReq,
+        host_port_map: &Option<HashMap<u16, u16>>,
     ) -> ProxyUpdate {
-        ProxyUpdate::default()
+        debug!(
+            "vsock: udp: listen: id={} addr={}, port={}",
+            self.id, req.addr, req.port
+        );
+        let mut update = ProxyUpdate::default();
+
+        let result = self.try_bind(&req, host_port_map);
+
+        // This packet goes to the control port (DGRAM).
+        let rx = MuxerRx::ListenResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+            result,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+
+        if result == 0 {
+            self.listening = true;
+            update.polling = Some((self.id, self.fd, EventSet::IN));
+        }
+
+        update
     }
 
     fn accept(&mut self, _req: TsiAcceptReq) -> ProxyUpdate {
@@ -412,6 +572,15 @@ impl Proxy for UdpProxy {
         }
     }
 
+    fn resume_rx(&mut self) -> ProxyUpdate {
+        let mut update = ProxyUpdate::default();
+        if self.throttled {
+            self.throttled = false;
+            update.polling = Some((self.id, self.fd, EventSet::IN));
+        }
+        update
+    }
+
     fn process_event(&mut self, evset: EventSet) -> ProxyUpdate {
         let mut update = ProxyUpdate::default();
 
@@ -441,6 +610,10 @@ impl Proxy for UdpProxy {
             if self.status == ProxyStatus::WaitingCreditUpdate {
                 debug!("process_event: WaitingCreditUpdate");
                 update.polling = Some((self.id(), self.fd, EventSet::empty()));
+            } else if self.throttled {
+                debug!("process_event: throttled");
+                update.polling = Some((self.id(), self.fd, EventSet::empty()));
+                update.throttled = true;
             }
         }
 
@@ -465,3 +638,199 @@ impl Drop for UdpProxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use nix::sys::socket::recv;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::*;
+    use crate::virtio::queue::tests::VirtQueue as GuestQueue;
+    use crate::virtio::vsock::packet::tests::{add_rx_buffers, guest_memory, tx_packet};
+    use crate::virtio::vsock::packet::VSOCK_PKT_HDR_SIZE;
+
+    fn udp_proxy() -> UdpProxy {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        UdpProxy::new(
+            1,
+            3,
+            AddressFamily::Inet,
+            1024,
+            mem,
+            Arc::new(Mutex::new(VirtQueue::new(16))),
+            Arc::new(Mutex::new(MuxerRxQ::new())),
+            TsiRateLimiter::new().unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn listen_req(port: u16) -> TsiListenReq {
+        TsiListenReq {
+            peer_port: 1024,
+            addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            vm_port: 0,
+            backlog: 0,
+        }
+    }
+
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn bound_port(proxy: &UdpProxy) -> u16 {
+        let addr: SockaddrStorage = nix::sys::socket::getsockname(proxy.fd).unwrap();
+        addr.as_sockaddr_in().unwrap().port()
+    }
+
+    #[test]
+    fn test_bind_mapped_port() {
+        let host_port = free_port();
+        let port_map = Some(HashMap::from([(5353, host_port)]));
+
+        let proxy = udp_proxy();
+        assert_eq!(proxy.try_bind(&listen_req(5353), &port_map), 0);
+        assert_eq!(bound_port(&proxy), host_port);
+
+        // Datagrams sent to the host port reach the socket of the proxy.
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", ("127.0.0.1", host_port)).unwrap();
+        let mut buf = [0u8; 16];
+        let mut cnt = 0;
+        for _ in 0..100 {
+            match recv(proxy.fd, &mut buf, MsgFlags::MSG_DONTWAIT) {
+                Ok(n) => {
+                    cnt = n;
+                    break;
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        assert_eq!(&buf[..cnt], b"ping");
+    }
+
+    #[test]
+    fn test_bind_unmapped_port() {
+        let port_map = Some(HashMap::from([(5353, free_port())]));
+        assert_eq!(
+            udp_proxy().try_bind(&listen_req(5354), &port_map),
+            -libc::EPERM
+        );
+        // Binding to any port doesn't forward anything, so it's always allowed.
+        assert_eq!(udp_proxy().try_bind(&listen_req(0), &port_map), 0);
+    }
+
+    #[test]
+    fn test_bind_without_port_map() {
+        // A port map with only TCP mappings leaves UDP binds on the same port.
+        let port = free_port();
+        let proxy = udp_proxy();
+        assert_eq!(proxy.try_bind(&listen_req(port), &None), 0);
+        assert_eq!(bound_port(&proxy), port);
+
+        assert_eq!(udp_proxy().try_bind(&listen_req(0), &None), 0);
+    }
+
+    /// Creates a proxy bound to a free port of the host, which it returns.
+    fn bound_proxy(mem: &GuestMemoryMmap, vq: &GuestQueue) -> (UdpProxy, u16) {
+        let proxy = UdpProxy::new(
+            1,
+            3,
+            AddressFamily::Inet,
+            1024,
+            mem.clone(),
+            Arc::new(Mutex::new(vq.create_queue())),
+            Arc::new(Mutex::new(MuxerRxQ::new())),
+            TsiRateLimiter::new().unwrap(),
+        )
+        .unwrap();
+        let host_port = free_port();
+        let port_map = Some(HashMap::from([(5353, host_port)]));
+        assert_eq!(proxy.try_bind(&listen_req(5353), &port_map), 0);
+        (proxy, host_port)
+    }
+
+    /// Sends a datagram from `client` to `port`, and waits for the proxy to pass
+    /// it on to the guest.
+    fn send_to_guest(proxy: &mut UdpProxy, vq: &GuestQueue, client: &UdpSocket, port: u16) {
+        let used = vq.used.idx.get();
+        client.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+        for _ in 0..100 {
+            proxy.process_event(EventSet::IN);
+            if vq.used.idx.get() != used {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vq.used.idx.get(), used + 1);
+    }
+
+    fn client() -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn test_reply_to_sender() {
+        let mem = guest_memory();
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        add_rx_buffers(&vq, 1);
+        let (mut proxy, host_port) = bound_proxy(&mem, &vq);
+
+        // A datagram from the host reaches the guest...
+        let client = client();
+        send_to_guest(&mut proxy, &vq, &client, host_port);
+        assert_eq!(
+            vq.used.ring[0].get().len as usize,
+            VSOCK_PKT_HDR_SIZE + b"ping".len()
+        );
+        let mut buf = [0u8; 4];
+        mem.read_slice(&mut buf, GuestAddress(0x2000)).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // ...and the guest's reply goes back to the sender.
+        assert_eq!(
+            proxy.reply_addr(),
+            Some((
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                client.local_addr().unwrap().port()
+            ))
+        );
+        proxy.sendmsg(&tx_packet(&guest_memory(), b"pong"));
+        let mut buf = [0u8; 16];
+        let (cnt, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..cnt], b"pong");
+        assert_eq!(from.port(), host_port);
+    }
+
+    #[test]
+    fn test_reply_with_several_peers() {
+        let mem = guest_memory();
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        add_rx_buffers(&vq, 2);
+        let (mut proxy, host_port) = bound_proxy(&mem, &vq);
+
+        // Both datagrams reach the guest, but a reply could be for either
+        // sender, so it goes to neither.
+        let clients = [client(), client()];
+        for client in &clients {
+            send_to_guest(&mut proxy, &vq, client, host_port);
+        }
+        assert_eq!(proxy.reply_addr(), None);
+        proxy.sendmsg(&tx_packet(&guest_memory(), b"pong"));
+        let mut buf = [0u8; 16];
+        for client in &clients {
+            assert!(client.recv_from(&mut buf).is_err());
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use nix::sys::socket::{AddressFamily, SockaddrLike, UnixAddr};
+
+use super::super::Queue as VirtQueue;
+use super::egress::EgressProtocol;
+use super::muxer_rxq::MuxerRxQ;
+use super::packet::{TsiGetnameRsp, TsiUnixConnectReq};
+use super::proxy::{ProxyError, ProxyStatus};
+use super::rate_limiter::TsiRateLimiter;
+use super::stream::{guest_errno, stream_socket, StreamProxy, StreamSocket};
+
+use vm_memory::GuestMemoryMmap;
+
+/// The stream unix sockets of the guest may only connect to the paths of the
+/// guest mapped to paths of the host, and can't listen.
+pub struct UnixSocket;
+
+pub type UnixProxy = StreamProxy<UnixSocket>;
+
+impl StreamSocket for UnixSocket {
+    const NAME: &'static str = "unix";
+
+    fn egress_protocol(&self) -> Option<EgressProtocol> {
+        None
+    }
+
+    fn connect_unix_addr(
+        &self,
+        req: &TsiUnixConnectReq,
+        unix_socket_map: &HashMap<PathBuf, PathBuf>,
+    ) -> Result<Box<dyn SockaddrLike>, i32> {
+        // Only the mapped paths lead anywhere on the host.
+        let host_path = unix_socket_map.get(&req.path).ok_or_else(|| {
+            debug!(
+                "vsock: unix: connect: path={} isn't mapped",
+                req.path.display()
+            );
+            -libc::ENOENT
+        })?;
+        debug!(
+            "vsock: unix: connect: path={} host_path={}",
+            req.path.display(),
+            host_path.display()
+        );
+        let addr = UnixAddr::new(host_path.as_path()).map_err(guest_errno)?;
+        Ok(Box::new(addr))
+    }
+
+    fn getpeername(&self, _fd: RawFd) -> TsiGetnameRsp {
+        // The guest knows which path it connected to, there's no IP address to tell it.
+        TsiGetnameRsp {
+            result: -libc::EOPNOTSUPP,
+            ..Default::default()
+        }
+    }
+}
+
+impl UnixProxy {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u64,
+        cid: u64,
+        local_port: u32,
+        peer_port: u32,
+        control_port: u32,
+        mem: GuestMemoryMmap,
+        queue: Arc<Mutex<VirtQueue>>,
+        rxq: Arc<Mutex<MuxerRxQ>>,
+        rx_rate_limiter: TsiRateLimiter,
+    ) -> Result<Self, ProxyError> {
+        let fd = stream_socket(id, AddressFamily::Unix)?;
+
+        Ok(StreamProxy::from_fd(
+            UnixSocket,
+            fd,
+            ProxyStatus::Idle,
+            id,
+            cid,
+            0,
+            local_port,
+            peer_port,
+            control_port,
+            mem,
+            queue,
+            rxq,
+            rx_rate_limiter,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use utils::tempdir::TempDir;
+
+    use super::*;
+    use crate::virtio::vsock::defs::uapi;
+    use crate::virtio::vsock::muxer::MuxerRx;
+    use crate::virtio::vsock::packet::tests::{guest_memory, tx_packet};
+    use crate::virtio::vsock::packet::{TsiConnectReq, TsiListenReq};
+    use crate::virtio::vsock::proxy::Proxy;
+
+    const GUEST_PATH: &str = "/run/guest.sock";
+
+    fn unix_proxy(rxq: &Arc<Mutex<MuxerRxQ>>) -> UnixProxy {
+        UnixProxy::new(
+            1,
+            3,
+            1024,
+            2048,
+            1025,
+            guest_memory(),
+            Arc::new(Mutex::new(VirtQueue::new(16))),
+            rxq.clone(),
+            TsiRateLimiter::new().unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn connect_req(path: &str) -> TsiUnixConnectReq {
+        TsiUnixConnectReq {
+            peer_port: 2048,
+            path: PathBuf::from(path),
+        }
+    }
+
+    fn socket_map(host_path: &Path) -> HashMap<PathBuf, PathBuf> {
+        HashMap::from([(PathBuf::from(GUEST_PATH), host_path.to_path_buf())])
+    }
+
+    /// The result of the connect response the proxy queued.
+    fn conn_result(rxq: &Arc<Mutex<MuxerRxQ>>) -> i32 {
+        match rxq.lock().unwrap().pop() {
+            Some(MuxerRx::ConnResponse { result, .. }) => result,
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+    }
+
+    #[test]
+    fn test_connect_mapped_path() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.as_path().join("host.sock");
+        let listener = UnixListener::bind(&host_path).unwrap();
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = unix_proxy(&rxq);
+        let mem = guest_memory();
+        let pkt = tx_packet(&mem, &[]);
+
+        let update = proxy.connect_unix(&pkt, connect_req(GUEST_PATH), &socket_map(&host_path));
+        assert_eq!(proxy.status(), ProxyStatus::Connected);
+        assert_eq!(conn_result(&rxq), 0);
+        let (_, fd, evset) = update.polling.unwrap();
+        assert_eq!(fd, proxy.as_raw_fd());
+        assert_eq!(evset, utils::epoll::EventSet::IN);
+
+        // Data from the guest reaches the host socket.
+        let (mut stream, _) = listener.accept().unwrap();
+        proxy.sendmsg(&tx_packet(&mem, b"hello"));
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.as_path().join("host.sock");
+        let listener = UnixListener::bind(&host_path).unwrap();
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = unix_proxy(&rxq);
+        let mem = guest_memory();
+        let pkt = tx_packet(&mem, &[]);
+        proxy.connect_unix(&pkt, connect_req(GUEST_PATH), &socket_map(&host_path));
+        assert_eq!(conn_result(&rxq), 0);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        // The host side sees the end of the stream once the guest stops sending.
+        let mut pkt = tx_packet(&mem, &[]);
+        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
+            .set_flags(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
+        proxy.shutdown(&pkt);
+        let mut buf = Vec::new();
+        assert_eq!(stream.read_to_end(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_connect_unmapped_path() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.as_path().join("host.sock");
+        let _listener = UnixListener::bind(&host_path).unwrap();
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = unix_proxy(&rxq);
+        let mem = guest_memory();
+        let pkt = tx_packet(&mem, &[]);
+
+        // The host path itself isn't reachable unless it's mapped.
+        let path = host_path.to_str().unwrap();
+        let update = proxy.connect_unix(&pkt, connect_req(path), &socket_map(&host_path));
+        assert!(update.polling.is_none());
+        assert_eq!(proxy.status(), ProxyStatus::Idle);
+        assert_eq!(conn_result(&rxq), -libc::ENOENT);
+    }
+
+    #[test]
+    fn test_connect_missing_host_path() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.as_path().join("host.sock");
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = unix_proxy(&rxq);
+        let mem = guest_memory();
+        let pkt = tx_packet(&mem, &[]);
+
+        proxy.connect_unix(&pkt, connect_req(GUEST_PATH), &socket_map(&host_path));
+        assert_eq!(proxy.status(), ProxyStatus::Idle);
+        assert_eq!(conn_result(&rxq), -libc::ENOENT);
+    }
+
+    #[test]
+    fn test_inet_requests() {
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = unix_proxy(&rxq);
+        let mem = guest_memory();
+        let pkt = tx_packet(&mem, &[]);
+
+        let req = TsiConnectReq {
+            peer_port: 2048,
+            addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 80,
+        };
+        proxy.connect(&pkt, req);
+        assert_eq!(conn_result(&rxq), -libc::EAFNOSUPPORT);
+
+        let req = TsiListenReq {
+            peer_port: 2048,
+            addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 80,
+            vm_port: 0,
+            backlog: 1,
+        };
+        assert!(proxy.listen(&pkt, req, &None).polling.is_none());
+        match rxq.lock().unwrap().pop() {
+            Some(MuxerRx::ListenResponse { result, .. }) => assert_eq!(result, -libc::EOPNOTSUPP),
+            rx => panic!("unexpected rx: {rx:?}"),
+        }
+        assert_eq!(proxy.status(), ProxyStatus::Idle);
+    }
+}
@@ -8,24 +8,35 @@ use std::env;
 use std::ffi::CStr;
 #[cfg(target_os = "linux")]
 use std::ffi::CString;
-#[cfg(feature = "net")]
-use std::os::fd::RawFd;
 #[cfg(not(feature = "tee"))]
 use std::path::Path;
-#[cfg(feature = "tee")]
 use std::path::PathBuf;
 use std::slice;
 use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
 use std::sync::Mutex;
 
-#[cfg(feature = "tee")]
-use devices::virtio::CacheType;
+#[cfg(target_os = "linux")]
+use devices::rate_limiter::{RateLimiterConfig, TokenBucketConfig};
+#[cfg(target_os = "linux")]
+use devices::virtio::block::{backend::VERITY_DIGEST_SIZE, DEFAULT_NUM_QUEUES, MAX_NUM_QUEUES};
+#[cfg(all(feature = "net", target_os = "linux"))]
+use devices::virtio::net::PasstCommand;
+#[cfg(feature = "net")]
+use devices::virtio::net::{DEFAULT_NUM_QUEUE_PAIRS, MAX_QUEUE_PAIRS, MIN_MTU};
+#[cfg(target_os = "linux")]
+use devices::virtio::Vsock;
+#[cfg(target_os = "linux")]
+use devices::virtio::{Block, CacheType, DiskIdentity, ImageType, OverlayConfig, VerityConfig};
+use devices::virtio::{EgressPolicy, EgressRule};
+#[cfg(feature = "net")]
+use devices::virtio::{Net, PcapConfig, Switch, VirtioNetBackend};
 use env_logger::Env;
 use libc::{c_char, c_int, size_t};
 use once_cell::sync::Lazy;
 use polly::event_manager::EventManager;
 use vmm::resources::VmResources;
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
 use vmm::vmm_config::block::BlockDeviceConfig;
 use vmm::vmm_config::boot_source::{BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
 #[cfg(not(feature = "tee"))]
@@ -48,20 +59,48 @@ const MAX_ARGS: usize = 4096;
 // Path to the init binary to be executed inside the VM.
 const INIT_PATH: &str = "/init.krun";
 
+// Disk image formats accepted by krun_add_disk.
+#[cfg(target_os = "linux")]
+const KRUN_DISK_FORMAT_RAW: u32 = 0;
+#[cfg(target_os = "linux")]
+const KRUN_DISK_FORMAT_QCOW2: u32 = 1;
+#[cfg(target_os = "linux")]
+const KRUN_DISK_FORMAT_NBD: u32 = 2;
+
+// Cache modes accepted by krun_set_disk_cache_mode.
+#[cfg(target_os = "linux")]
+const KRUN_DISK_CACHE_UNSAFE: u32 = 0;
+#[cfg(target_os = "linux")]
+const KRUN_DISK_CACHE_WRITEBACK: u32 = 1;
+#[cfg(target_os = "linux")]
+const KRUN_DISK_CACHE_DIRECT: u32 = 2;
+
+// Flags accepted by krun_add_disk_overlay.
+#[cfg(target_os = "linux")]
+const KRUN_DISK_OVERLAY_DISCARD_ON_EXIT: u32 = 1 << 0;
+
+// Directions accepted by krun_set_net_rate_limit and krun_set_tsi_rate_limit.
+#[cfg(target_os = "linux")]
+const KRUN_RATE_LIMIT_RX: u32 = 0;
+#[cfg(target_os = "linux")]
+const KRUN_RATE_LIMIT_TX: u32 = 1;
+
 #[derive(Default)]
 struct TsiConfig {
     port_map: Option<HashMap<u16, u16>>,
-}
-
-#[cfg(feature = "net")]
-struct PasstConfig {
-    fd: RawFd,
+    udp_port_map: Option<HashMap<u16, u16>>,
+    unix_socket_map: HashMap<PathBuf, PathBuf>,
+    egress_policy: EgressPolicy,
+    #[cfg(target_os = "linux")]
+    rx_rate_limiter: RateLimiterConfig,
+    #[cfg(target_os = "linux")]
+    tx_rate_limiter: RateLimiterConfig,
 }
 
 enum NetworkConfig {
-    Tsi(TsiConfig),
+    Tsi(Box<TsiConfig>),
     #[cfg(feature = "net")]
-    Passt(PasstConfig),
+    VirtioNet(Vec<NetworkInterfaceConfig>),
 }
 
 impl Default for NetworkConfig {
@@ -71,6 +110,31 @@ impl Default for NetworkConfig {
     }
 }
 
+/// Sockets of switch ports, which are closed on drop. Closing them takes the
+/// ports out of their switches.
+#[cfg(feature = "net")]
+#[derive(Default)]
+struct SwitchPorts(Vec<c_int>);
+
+#[cfg(feature = "net")]
+impl SwitchPorts {
+    /// Hands the socket of the port over to its net device, if it is one.
+    fn hand_over(&mut self, backend: &VirtioNetBackend) {
+        if let VirtioNetBackend::UnixgramFd(fd) = backend {
+            self.0.retain(|port| port != fd);
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl Drop for SwitchPorts {
+    fn drop(&mut self) {
+        for fd in self.0.drain(..) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
 #[derive(Default)]
 struct ContextConfig {
     vmr: VmResources,
@@ -80,12 +144,18 @@ struct ContextConfig {
     args: Option<String>,
     rlimits: Option<String>,
     net_cfg: NetworkConfig,
+    // Sockets of the switch ports added to the context, until the net devices
+    // take them over.
+    #[cfg(feature = "net")]
+    switch_ports: SwitchPorts,
     #[cfg(not(feature = "tee"))]
     fs_cfg: Option<FsDeviceConfig>,
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     root_block_cfg: Option<BlockDeviceConfig>,
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     data_block_cfg: Option<BlockDeviceConfig>,
+    #[cfg(target_os = "linux")]
+    block_cfgs: Vec<BlockDeviceConfig>,
     #[cfg(feature = "tee")]
     tee_config_file: Option<PathBuf>,
 }
@@ -161,34 +231,153 @@ impl ContextConfig {
         self.root_block_cfg = Some(block_cfg);
     }
 
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     fn get_root_block_cfg(&self) -> Option<BlockDeviceConfig> {
         self.root_block_cfg.clone()
     }
 
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     fn set_data_block_cfg(&mut self, block_cfg: BlockDeviceConfig) {
         self.data_block_cfg = Some(block_cfg);
     }
 
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     fn get_data_block_cfg(&self) -> Option<BlockDeviceConfig> {
         self.data_block_cfg.clone()
     }
 
+    #[cfg(target_os = "linux")]
+    fn add_block_cfg(&mut self, block_cfg: BlockDeviceConfig) -> Result<(), ()> {
+        let id = &block_cfg.block_id;
+        if id == "root" || id == "data" || self.block_cfgs.iter().any(|cfg| &cfg.block_id == id) {
+            return Err(());
+        }
+        self.block_cfgs.push(block_cfg);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_block_cfgs(&self) -> Vec<BlockDeviceConfig> {
+        self.block_cfgs.clone()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_block_cfg_mut(&mut self, block_id: &str) -> Option<&mut BlockDeviceConfig> {
+        self.root_block_cfg
+            .iter_mut()
+            .chain(self.data_block_cfg.iter_mut())
+            .chain(self.block_cfgs.iter_mut())
+            .find(|cfg| cfg.block_id == block_id)
+    }
+
+    /// Adds a virtio-net interface, which disables TSI. Interfaces without
+    /// an ID get the first free one of "eth0", "eth1"... Fails if the ID is
+    /// already taken.
     #[cfg(feature = "net")]
-    fn set_net_cfg(&mut self, net_cfg: NetworkConfig) {
-        self.net_cfg = net_cfg;
+    fn add_net_iface(
+        &mut self,
+        iface_id: Option<String>,
+        backend: VirtioNetBackend,
+        mac: Option<[u8; 6]>,
+        mtu: Option<u16>,
+    ) -> Result<(), ()> {
+        if let NetworkConfig::Tsi(_) = self.net_cfg {
+            self.net_cfg = NetworkConfig::VirtioNet(Vec::new());
+        }
+        let NetworkConfig::VirtioNet(ifaces) = &mut self.net_cfg else {
+            unreachable!();
+        };
+        let is_taken = |id: &str| ifaces.iter().any(|iface| iface.iface_id == id);
+        let iface_id = match iface_id {
+            Some(id) if is_taken(&id) => return Err(()),
+            Some(id) => id,
+            None => (0..)
+                .map(|i| format!("eth{i}"))
+                .find(|id| !is_taken(id))
+                .unwrap(),
+        };
+        // Backends given file descriptors get one queue pair for each.
+        let num_queue_pairs = match &backend {
+            VirtioNetBackend::Passt(fds) => fds.len() as u16,
+            #[cfg(target_os = "linux")]
+            VirtioNetBackend::TapFd(fds) => fds.len() as u16,
+            _ => DEFAULT_NUM_QUEUE_PAIRS,
+        };
+        ifaces.push(NetworkInterfaceConfig {
+            iface_id,
+            backend,
+            num_queue_pairs,
+            mac,
+            mtu,
+            pcap: None,
+            link_up: true,
+            #[cfg(target_os = "linux")]
+            rx_rate_limiter: RateLimiterConfig::default(),
+            #[cfg(target_os = "linux")]
+            tx_rate_limiter: RateLimiterConfig::default(),
+        });
+        Ok(())
+    }
+
+    #[cfg(feature = "net")]
+    fn get_net_iface_mut(&mut self, iface_id: &str) -> Option<&mut NetworkInterfaceConfig> {
+        match &mut self.net_cfg {
+            NetworkConfig::VirtioNet(ifaces) => {
+                ifaces.iter_mut().find(|iface| iface.iface_id == iface_id)
+            }
+            NetworkConfig::Tsi(_) => None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_tsi_cfg_mut(&mut self) -> Option<&mut TsiConfig> {
+        match &mut self.net_cfg {
+            NetworkConfig::Tsi(tsi_config) => Some(tsi_config),
+            #[cfg(feature = "net")]
+            NetworkConfig::VirtioNet(_) => None,
+        }
     }
 
-    fn set_port_map(&mut self, new_port_map: HashMap<u16, u16>) -> Result<(), ()> {
+    fn set_port_map(
+        &mut self,
+        new_port_map: HashMap<u16, u16>,
+        new_udp_port_map: HashMap<u16, u16>,
+    ) -> Result<(), ()> {
         match &mut self.net_cfg {
             NetworkConfig::Tsi(tsi_config) => {
                 tsi_config.port_map.replace(new_port_map);
+                // Without UDP mappings, UDP binds keep reaching the same host port.
+                tsi_config.udp_port_map =
+                    Some(new_udp_port_map).filter(|port_map| !port_map.is_empty());
+                Ok(())
+            }
+            #[cfg(feature = "net")]
+            NetworkConfig::VirtioNet(_) => Err(()),
+        }
+    }
+
+    fn set_unix_socket_map(
+        &mut self,
+        new_unix_socket_map: HashMap<PathBuf, PathBuf>,
+    ) -> Result<(), ()> {
+        match &mut self.net_cfg {
+            NetworkConfig::Tsi(tsi_config) => {
+                tsi_config.unix_socket_map = new_unix_socket_map;
+                Ok(())
+            }
+            #[cfg(feature = "net")]
+            NetworkConfig::VirtioNet(_) => Err(()),
+        }
+    }
+
+    fn set_egress_policy(&mut self, new_egress_policy: EgressPolicy) -> Result<(), ()> {
+        match &mut self.net_cfg {
+            NetworkConfig::Tsi(tsi_config) => {
+                tsi_config.egress_policy = new_egress_policy;
                 Ok(())
             }
             #[cfg(feature = "net")]
-            NetworkConfig::Passt(_) => Err(()),
+            NetworkConfig::VirtioNet(_) => Err(()),
         }
     }
 
@@ -206,6 +395,29 @@ impl ContextConfig {
 static CTX_MAP: Lazy<Mutex<HashMap<u32, ContextConfig>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 static CTX_IDS: AtomicI32 = AtomicI32::new(0);
 
+// Devices of the running microVMs, by context ID.
+#[cfg(any(target_os = "linux", feature = "net"))]
+type RunningDevices<T> = Lazy<Mutex<HashMap<u32, Vec<Arc<Mutex<T>>>>>>;
+
+// Block devices of the running microVMs, for the settings that can be changed at runtime.
+#[cfg(target_os = "linux")]
+static RUNNING_BLOCKS: RunningDevices<Block> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Net devices of the running microVMs, for the settings that can be changed at runtime.
+#[cfg(feature = "net")]
+static RUNNING_NETS: RunningDevices<Net> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Switches connecting the net devices of microVMs, by switch ID. They outlive the contexts.
+#[cfg(feature = "net")]
+static NET_SWITCHES: Lazy<Mutex<HashMap<u32, Switch>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+#[cfg(feature = "net")]
+static NET_SWITCH_IDS: AtomicI32 = AtomicI32::new(0);
+
+// TSI devices of the running microVMs, for the settings that can be changed at runtime.
+#[cfg(target_os = "linux")]
+static RUNNING_VSOCKS: Lazy<Mutex<HashMap<u32, Arc<Mutex<Vsock>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 #[cfg(not(feature = "tee"))]
 #[link(name = "krunfw")]
 extern "C" {
@@ -452,8 +664,14 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
                 block_id: "root".to_string(),
                 cache_type: CacheType::Writeback,
                 disk_image_path: disk_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                overlay: None,
+                verity: None,
                 is_disk_read_only: false,
                 is_disk_root: true,
+                num_queues: DEFAULT_NUM_QUEUES,
+                identity: DiskIdentity::default(),
+                rate_limiter: RateLimiterConfig::default(),
             };
             cfg.set_root_block_cfg(block_device_config);
         }
@@ -465,7 +683,7 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
 pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32 {
     let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
         Ok(disk) => disk,
@@ -482,8 +700,14 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
                 block_id: "data".to_string(),
                 cache_type: CacheType::Writeback,
                 disk_image_path: disk_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                overlay: None,
+                verity: None,
                 is_disk_read_only: false,
                 is_disk_root: false,
+                num_queues: DEFAULT_NUM_QUEUES,
+                identity: DiskIdentity::default(),
+                rate_limiter: RateLimiterConfig::default(),
             };
             cfg.set_data_block_cfg(block_device_config);
         }
@@ -495,74 +719,115 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32 {
-    if fd < 0 {
-        return -libc::EINVAL;
-    }
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_add_disk(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_disk_path: *const c_char,
+    disk_format: u32,
+    read_only: bool,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
 
-    #[cfg(not(feature = "net"))]
-    {
-        let _ = ctx_id;
-        let _ = fd;
-        -libc::ENOTSUP
-    }
+    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
+        Ok(disk) => disk,
+        Err(_) => return -libc::EINVAL,
+    };
 
-    #[cfg(feature = "net")]
-    {
-        match CTX_MAP.lock().unwrap().entry(ctx_id) {
-            Entry::Occupied(mut ctx_cfg) => {
-                let cfg = ctx_cfg.get_mut();
-                cfg.set_net_cfg(NetworkConfig::Passt(PasstConfig { fd }));
+    let disk_image_format = match disk_format {
+        KRUN_DISK_FORMAT_RAW => ImageType::Raw,
+        KRUN_DISK_FORMAT_QCOW2 => ImageType::Qcow2,
+        KRUN_DISK_FORMAT_NBD => ImageType::Nbd,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::Writeback,
+                disk_image_path: disk_path.to_string(),
+                disk_image_format,
+                overlay: None,
+                verity: None,
+                is_disk_read_only: read_only,
+                is_disk_root: false,
+                num_queues: DEFAULT_NUM_QUEUES,
+                identity: DiskIdentity::default(),
+                rate_limiter: RateLimiterConfig::default(),
+            };
+            if cfg.add_block_cfg(block_device_config).is_err() {
+                return -libc::EEXIST;
             }
-            Entry::Vacant(_) => return -libc::ENOENT,
         }
-        KRUN_SUCCESS
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
+
+    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
-    let mut port_map = HashMap::new();
-    let port_map_array: &[*const c_char] = slice::from_raw_parts(c_port_map, MAX_ARGS);
-    for item in port_map_array.iter().take(MAX_ARGS) {
-        if item.is_null() {
-            break;
-        } else {
-            let s = match CStr::from_ptr(*item).to_str() {
-                Ok(s) => s,
-                Err(_) => return -libc::EINVAL,
-            };
-            let port_tuple: Vec<&str> = s.split(':').collect();
-            if port_tuple.len() != 2 {
-                return -libc::EINVAL;
-            }
-            let host_port: u16 = match port_tuple[0].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
-            };
-            let guest_port: u16 = match port_tuple[1].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
-            };
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_add_disk_overlay(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_base_path: *const c_char,
+    base_format: u32,
+    c_overlay_path: *const c_char,
+    flags: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
 
-            if port_map.contains_key(&guest_port) {
-                return -libc::EINVAL;
-            }
-            for hp in port_map.values() {
-                if *hp == host_port {
-                    return -libc::EINVAL;
-                }
-            }
-            port_map.insert(guest_port, host_port);
-        }
+    let base_path = match CStr::from_ptr(c_base_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let overlay_path = match CStr::from_ptr(c_overlay_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let disk_image_format = match base_format {
+        KRUN_DISK_FORMAT_RAW => ImageType::Raw,
+        KRUN_DISK_FORMAT_QCOW2 => ImageType::Qcow2,
+        KRUN_DISK_FORMAT_NBD => ImageType::Nbd,
+        _ => return -libc::EINVAL,
+    };
+
+    if flags & !KRUN_DISK_OVERLAY_DISCARD_ON_EXIT != 0 {
+        return -libc::EINVAL;
     }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            if cfg.set_port_map(port_map).is_err() {
-                return -libc::ENOTSUP;
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::Writeback,
+                disk_image_path: base_path.to_string(),
+                disk_image_format,
+                overlay: Some(OverlayConfig {
+                    path: overlay_path.to_string(),
+                    discard_on_exit: flags & KRUN_DISK_OVERLAY_DISCARD_ON_EXIT != 0,
+                }),
+                verity: None,
+                is_disk_read_only: false,
+                is_disk_root: false,
+                num_queues: DEFAULT_NUM_QUEUES,
+                identity: DiskIdentity::default(),
+                rate_limiter: RateLimiterConfig::default(),
+            };
+            if cfg.add_block_cfg(block_device_config).is_err() {
+                return -libc::EEXIST;
             }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -571,33 +836,60 @@ pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *cons
     KRUN_SUCCESS
 }
 
+/// Decodes a string of hexadecimal digits into bytes.
+#[cfg(target_os = "linux")]
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
-    let rlimits = if c_rlimits.is_null() {
-        return -libc::EINVAL;
-    } else {
-        let mut strvec = Vec::new();
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_verity(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_hash_path: *const c_char,
+    hash_offset: u64,
+    c_root_hash: *const c_char,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
 
-        let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
-        for item in array.iter().take(MAX_ARGS) {
-            if item.is_null() {
-                break;
-            } else {
-                let s = match CStr::from_ptr(*item).to_str() {
-                    Ok(s) => s,
-                    Err(_) => return -libc::EINVAL,
-                };
-                strvec.push(s);
-            }
-        }
+    let hash_path = match CStr::from_ptr(c_hash_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
 
-        format!("\"{}\"", strvec.join(","))
+    let root_hash = match CStr::from_ptr(c_root_hash)
+        .to_str()
+        .ok()
+        .and_then(parse_hex)
+    {
+        Some(hash) if hash.len() == VERITY_DIGEST_SIZE => hash,
+        _ => return -libc::EINVAL,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_rlimits(rlimits);
+            let cfg = ctx_cfg.get_mut();
+            match cfg.get_block_cfg_mut(block_id) {
+                Some(block_cfg) => {
+                    block_cfg.verity = Some(VerityConfig {
+                        hash_path: hash_path.to_string(),
+                        hash_offset,
+                        root_hash,
+                    })
+                }
+                None => return -libc::ENOENT,
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -607,15 +899,28 @@ pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
-    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
-        Ok(workdir) => workdir,
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_queues(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    num_queues: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
         Err(_) => return -libc::EINVAL,
     };
 
+    if num_queues == 0 || num_queues > MAX_NUM_QUEUES as u32 {
+        return -libc::EINVAL;
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+            let cfg = ctx_cfg.get_mut();
+            match cfg.get_block_cfg_mut(block_id) {
+                Some(block_cfg) => block_cfg.num_queues = num_queues as u16,
+                None => return -libc::ENOENT,
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -623,13 +928,1088 @@ pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_
     KRUN_SUCCESS
 }
 
-unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
-    let mut strvec = Vec::new();
-
-    for item in array.iter().take(MAX_ARGS) {
-        if item.is_null() {
-            break;
-        } else {
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_read_only(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    read_only: bool,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.get_block_cfg_mut(block_id) {
+                Some(block_cfg) => block_cfg.is_disk_read_only = read_only,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Applies `update` to the identity of the disk `block_id`, as long as the
+/// result is valid.
+#[cfg(target_os = "linux")]
+fn update_disk_identity<F>(ctx_id: u32, block_id: &str, update: F) -> i32
+where
+    F: FnOnce(&mut DiskIdentity),
+{
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let block_cfg = match cfg.get_block_cfg_mut(block_id) {
+                Some(block_cfg) => block_cfg,
+                None => return -libc::ENOENT,
+            };
+            let mut identity = block_cfg.identity.clone();
+            update(&mut identity);
+            if identity.validate().is_err() {
+                return -libc::EINVAL;
+            }
+            block_cfg.identity = identity;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_serial(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_serial: *const c_char,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let serial = match CStr::from_ptr(c_serial).to_str() {
+        Ok(serial) => serial,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    update_disk_identity(ctx_id, block_id, |identity| {
+        identity.serial = Some(serial.to_string())
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_block_size(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    logical_block_size: u32,
+    physical_block_size: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    update_disk_identity(ctx_id, block_id, |identity| {
+        identity.logical_block_size = logical_block_size;
+        identity.physical_block_size = physical_block_size;
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_seg_max(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    seg_max: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    update_disk_identity(ctx_id, block_id, |identity| identity.seg_max = seg_max)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_cache_mode(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    cache_mode: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let cache_type = match cache_mode {
+        KRUN_DISK_CACHE_UNSAFE => CacheType::Unsafe,
+        KRUN_DISK_CACHE_WRITEBACK => CacheType::Writeback,
+        KRUN_DISK_CACHE_DIRECT => CacheType::Direct,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.get_block_cfg_mut(block_id) {
+                Some(block_cfg) => block_cfg.cache_type = cache_type,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_disk_rate_limit(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    bytes_per_sec: u64,
+    bytes_burst: u64,
+    ops_per_sec: u64,
+    ops_burst: u64,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let rate_limiter = RateLimiterConfig {
+        bandwidth: TokenBucketConfig {
+            rate: bytes_per_sec,
+            burst: bytes_burst,
+        },
+        ops: TokenBucketConfig {
+            rate: ops_per_sec,
+            burst: ops_burst,
+        },
+    };
+
+    if let Some(cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+        return match cfg.get_block_cfg_mut(block_id) {
+            Some(block_cfg) => {
+                block_cfg.rate_limiter = rate_limiter;
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        };
+    }
+
+    // The microVM may already be running.
+    match RUNNING_BLOCKS.lock().unwrap().get(&ctx_id) {
+        Some(blocks) => match blocks
+            .iter()
+            .find(|block| block.lock().unwrap().id() == block_id)
+        {
+            Some(block) => {
+                block.lock().unwrap().update_rate_limiter(rate_limiter);
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        },
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_resize_disk(ctx_id: u32, c_block_id: *const c_char) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // The size is read when the microVM starts, so there's nothing to do yet.
+    if let Some(cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+        return match cfg.get_block_cfg_mut(block_id) {
+            Some(_) => KRUN_SUCCESS,
+            None => -libc::ENOENT,
+        };
+    }
+
+    match RUNNING_BLOCKS.lock().unwrap().get(&ctx_id) {
+        Some(blocks) => match blocks
+            .iter()
+            .find(|block| block.lock().unwrap().id() == block_id)
+        {
+            Some(block) => match block.lock().unwrap().resize_disk() {
+                Ok(size) => {
+                    debug!("Disk {} resized to {} bytes", block_id, size);
+                    KRUN_SUCCESS
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => -libc::ENOTSUP,
+                Err(e) => {
+                    error!("Failed to resize disk {}: {}", block_id, e);
+                    -e.raw_os_error().unwrap_or(libc::EINVAL)
+                }
+            },
+            None => -libc::ENOENT,
+        },
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = fd;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                let backend = VirtioNetBackend::Passt(vec![fd]);
+                if cfg
+                    .add_net_iface(Some("eth0".to_string()), backend, None, None)
+                    .is_err()
+                {
+                    return -libc::EEXIST;
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+/// Reads the optional MAC address passed to the net functions.
+#[cfg(feature = "net")]
+unsafe fn parse_mac(c_mac: *const u8) -> Option<[u8; 6]> {
+    if c_mac.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(c_mac, 6).try_into().unwrap())
+    }
+}
+
+/// Parses the backend of krun_add_net, in "type:argument" or "type" format.
+#[cfg(feature = "net")]
+fn parse_net_backend(spec: &str) -> Option<VirtioNetBackend> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    let fd = || arg.parse::<c_int>().ok().filter(|&fd| fd >= 0);
+    // One file descriptor for each queue pair.
+    let fds = || {
+        arg.split(',')
+            .map(|fd| fd.parse::<c_int>().ok().filter(|&fd| fd >= 0))
+            .collect::<Option<Vec<_>>>()
+            .filter(|fds| fds.len() <= MAX_QUEUE_PAIRS as usize)
+    };
+    match kind {
+        "passt" => fds().map(VirtioNetBackend::Passt),
+        "stream" if !arg.is_empty() => Some(VirtioNetBackend::UnixStream(PathBuf::from(arg))),
+        "unixgram" if !arg.is_empty() => Some(VirtioNetBackend::Unixgram(PathBuf::from(arg))),
+        "unixgram-fd" => fd().map(VirtioNetBackend::UnixgramFd),
+        #[cfg(target_os = "linux")]
+        "tap" if !arg.is_empty() && arg.len() < libc::IFNAMSIZ => {
+            Some(VirtioNetBackend::Tap(arg.to_string()))
+        }
+        #[cfg(target_os = "linux")]
+        "tap-fd" => fds().map(VirtioNetBackend::TapFd),
+        "nat" if arg.is_empty() => Some(VirtioNetBackend::UserNat),
+        _ => None,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_net(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    c_backend: *const c_char,
+    c_mac: *const u8,
+    mtu: u16,
+) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = c_backend;
+        let _ = c_mac;
+        let _ = mtu;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let iface_id = if c_iface_id.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(c_iface_id).to_str() {
+                Ok(id) if !id.is_empty() => Some(id.to_string()),
+                _ => return -libc::EINVAL,
+            }
+        };
+        let backend = match CStr::from_ptr(c_backend).to_str().map(parse_net_backend) {
+            Ok(Some(backend)) => backend,
+            _ => return -libc::EINVAL,
+        };
+        let mac = parse_mac(c_mac);
+        let mtu = match mtu {
+            0 => None,
+            mtu if mtu < MIN_MTU => return -libc::EINVAL,
+            mtu => Some(mtu),
+        };
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                if cfg.add_net_iface(iface_id, backend, mac, mtu).is_err() {
+                    return -libc::EEXIST;
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_net_pcap(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    c_path: *const c_char,
+    max_size: u64,
+) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = c_path;
+        let _ = max_size;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let iface_id = match CStr::from_ptr(c_iface_id).to_str() {
+            Ok(id) => id,
+            Err(_) => return -libc::EINVAL,
+        };
+        let pcap = if c_path.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(c_path).to_str() {
+                Ok(path) if !path.is_empty() => Some(PcapConfig {
+                    path: PathBuf::from(path),
+                    max_size,
+                }),
+                _ => return -libc::EINVAL,
+            }
+        };
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                match cfg.get_net_iface_mut(iface_id) {
+                    Some(iface) => iface.pcap = pcap,
+                    None => return -libc::ENOENT,
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_net_queues(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    num_queue_pairs: u32,
+) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = num_queue_pairs;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let iface_id = match CStr::from_ptr(c_iface_id).to_str() {
+            Ok(id) => id,
+            Err(_) => return -libc::EINVAL,
+        };
+
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_QUEUE_PAIRS as u32 {
+            return -libc::EINVAL;
+        }
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                match cfg.get_net_iface_mut(iface_id) {
+                    Some(iface) => iface.num_queue_pairs = num_queue_pairs as u16,
+                    None => return -libc::ENOENT,
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_link_state(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    up: bool,
+) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = up;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let iface_id = match CStr::from_ptr(c_iface_id).to_str() {
+            Ok(id) => id,
+            Err(_) => return -libc::EINVAL,
+        };
+
+        if let Some(cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+            return match cfg.get_net_iface_mut(iface_id) {
+                Some(iface) => {
+                    iface.link_up = up;
+                    KRUN_SUCCESS
+                }
+                None => -libc::ENOENT,
+            };
+        }
+
+        // The microVM may already be running.
+        match RUNNING_NETS.lock().unwrap().get(&ctx_id) {
+            Some(nets) => match nets.iter().find(|net| net.lock().unwrap().id() == iface_id) {
+                Some(net) => match net.lock().unwrap().set_link_state(up) {
+                    Ok(()) => KRUN_SUCCESS,
+                    Err(e) => {
+                        error!("Failed to change the link state of {}: {}", iface_id, e);
+                        -e.raw_os_error().unwrap_or(libc::EINVAL)
+                    }
+                },
+                None => -libc::ENOENT,
+            },
+            None => -libc::ENOENT,
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn krun_set_net_rate_limit(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    direction: u32,
+    bytes_per_sec: u64,
+    bytes_burst: u64,
+    packets_per_sec: u64,
+    packets_burst: u64,
+) -> i32 {
+    #[cfg(not(all(feature = "net", target_os = "linux")))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = direction;
+        let _ = bytes_per_sec;
+        let _ = bytes_burst;
+        let _ = packets_per_sec;
+        let _ = packets_burst;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    {
+        let iface_id = match CStr::from_ptr(c_iface_id).to_str() {
+            Ok(id) => id,
+            Err(_) => return -libc::EINVAL,
+        };
+        if direction != KRUN_RATE_LIMIT_RX && direction != KRUN_RATE_LIMIT_TX {
+            return -libc::EINVAL;
+        }
+
+        let rate_limiter = RateLimiterConfig {
+            bandwidth: TokenBucketConfig {
+                rate: bytes_per_sec,
+                burst: bytes_burst,
+            },
+            ops: TokenBucketConfig {
+                rate: packets_per_sec,
+                burst: packets_burst,
+            },
+        };
+
+        if let Some(cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+            return match cfg.get_net_iface_mut(iface_id) {
+                Some(iface) => {
+                    if direction == KRUN_RATE_LIMIT_RX {
+                        iface.rx_rate_limiter = rate_limiter;
+                    } else {
+                        iface.tx_rate_limiter = rate_limiter;
+                    }
+                    KRUN_SUCCESS
+                }
+                None => -libc::ENOENT,
+            };
+        }
+
+        // The microVM may already be running.
+        match RUNNING_NETS.lock().unwrap().get(&ctx_id) {
+            Some(nets) => match nets.iter().find(|net| net.lock().unwrap().id() == iface_id) {
+                Some(net) => {
+                    let mut net = net.lock().unwrap();
+                    if direction == KRUN_RATE_LIMIT_RX {
+                        net.update_rx_rate_limiter(rate_limiter);
+                    } else {
+                        net.update_tx_rate_limiter(rate_limiter);
+                    }
+                    KRUN_SUCCESS
+                }
+                None => -libc::ENOENT,
+            },
+            None => -libc::ENOENT,
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_net_passt(
+    ctx_id: u32,
+    c_iface_id: *const c_char,
+    c_passt_path: *const c_char,
+    c_argv: *const *const c_char,
+    c_mac: *const u8,
+) -> i32 {
+    #[cfg(not(all(feature = "net", target_os = "linux")))]
+    {
+        let _ = ctx_id;
+        let _ = c_iface_id;
+        let _ = c_passt_path;
+        let _ = c_argv;
+        let _ = c_mac;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    {
+        let iface_id = if c_iface_id.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(c_iface_id).to_str() {
+                Ok(id) if !id.is_empty() => Some(id.to_string()),
+                _ => return -libc::EINVAL,
+            }
+        };
+        let path = match CStr::from_ptr(c_passt_path).to_str() {
+            Ok(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return -libc::EINVAL,
+        };
+        let mut args = Vec::new();
+        if !c_argv.is_null() {
+            let argv_array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
+            for item in argv_array.iter().take(MAX_ARGS) {
+                if item.is_null() {
+                    break;
+                }
+                match CStr::from_ptr(*item).to_str() {
+                    Ok(arg) => args.push(arg.to_string()),
+                    Err(_) => return -libc::EINVAL,
+                }
+            }
+        }
+        let mac = parse_mac(c_mac);
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                let backend = VirtioNetBackend::PasstProcess(PasstCommand { path, args });
+                if cfg.add_net_iface(iface_id, backend, mac, None).is_err() {
+                    return -libc::EEXIST;
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_net_switch_create() -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let switch_id = NET_SWITCH_IDS.fetch_add(1, Ordering::SeqCst);
+        if switch_id == i32::MAX {
+            panic!("Switch ID space exhausted");
+        }
+        match Switch::new(&format!("net switch {switch_id}")) {
+            Ok(switch) => {
+                NET_SWITCHES
+                    .lock()
+                    .unwrap()
+                    .insert(switch_id as u32, switch);
+                switch_id
+            }
+            Err(e) => {
+                error!("Failed to create a switch: {}", e);
+                -e.raw_os_error().unwrap_or(libc::EINVAL)
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_net_switch_destroy(switch_id: u32) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = switch_id;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        // Take it out of the map first, as dropping it waits for its thread.
+        let switch = NET_SWITCHES.lock().unwrap().remove(&switch_id);
+        match switch {
+            Some(switch) => {
+                drop(switch);
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_net_switch_port(
+    ctx_id: u32,
+    switch_id: u32,
+    c_mac: *const u8,
+) -> i32 {
+    #[cfg(not(feature = "net"))]
+    {
+        let _ = ctx_id;
+        let _ = switch_id;
+        let _ = c_mac;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let mac = parse_mac(c_mac);
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                let fd = match NET_SWITCHES.lock().unwrap().get(&switch_id) {
+                    Some(switch) => match switch.add_port() {
+                        Ok(fd) => fd,
+                        Err(e) => {
+                            error!("Failed to add a port to switch {}: {}", switch_id, e);
+                            return -e.raw_os_error().unwrap_or(libc::EINVAL);
+                        }
+                    },
+                    None => return -libc::ENOENT,
+                };
+                let backend = VirtioNetBackend::UnixgramFd(fd);
+                if cfg.add_net_iface(None, backend, mac, None).is_err() {
+                    // Closing it takes the port out of the switch.
+                    libc::close(fd);
+                    return -libc::EEXIST;
+                }
+                cfg.switch_ports.0.push(fd);
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_net_tap(
+    ctx_id: u32,
+    c_tap_name: *const c_char,
+    c_mac: *const u8,
+) -> i32 {
+    #[cfg(not(all(feature = "net", target_os = "linux")))]
+    {
+        let _ = ctx_id;
+        let _ = c_tap_name;
+        let _ = c_mac;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    {
+        let tap_name = match CStr::from_ptr(c_tap_name).to_str() {
+            Ok(name) if !name.is_empty() && name.len() < libc::IFNAMSIZ => name,
+            _ => return -libc::EINVAL,
+        };
+        let mac = parse_mac(c_mac);
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                if cfg
+                    .add_net_iface(None, VirtioNetBackend::Tap(tap_name.to_string()), mac, None)
+                    .is_err()
+                {
+                    return -libc::EEXIST;
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_net_tap_fd(ctx_id: u32, fd: c_int, c_mac: *const u8) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    #[cfg(not(all(feature = "net", target_os = "linux")))]
+    {
+        let _ = ctx_id;
+        let _ = c_mac;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    {
+        let mac = parse_mac(c_mac);
+
+        match CTX_MAP.lock().unwrap().entry(ctx_id) {
+            Entry::Occupied(mut ctx_cfg) => {
+                let cfg = ctx_cfg.get_mut();
+                if cfg
+                    .add_net_iface(None, VirtioNetBackend::TapFd(vec![fd]), mac, None)
+                    .is_err()
+                {
+                    return -libc::EEXIST;
+                }
+            }
+            Entry::Vacant(_) => return -libc::ENOENT,
+        }
+        KRUN_SUCCESS
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_tsi_rate_limit(
+    ctx_id: u32,
+    direction: u32,
+    bytes_per_sec: u64,
+    bytes_burst: u64,
+) -> i32 {
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = ctx_id;
+        let _ = direction;
+        let _ = bytes_per_sec;
+        let _ = bytes_burst;
+        -libc::ENOTSUP
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if direction != KRUN_RATE_LIMIT_RX && direction != KRUN_RATE_LIMIT_TX {
+            return -libc::EINVAL;
+        }
+
+        let rate_limiter = RateLimiterConfig {
+            bandwidth: TokenBucketConfig {
+                rate: bytes_per_sec,
+                burst: bytes_burst,
+            },
+            ops: TokenBucketConfig::default(),
+        };
+
+        if let Some(cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+            return match cfg.get_tsi_cfg_mut() {
+                Some(tsi_cfg) => {
+                    if direction == KRUN_RATE_LIMIT_RX {
+                        tsi_cfg.rx_rate_limiter = rate_limiter;
+                    } else {
+                        tsi_cfg.tx_rate_limiter = rate_limiter;
+                    }
+                    KRUN_SUCCESS
+                }
+                None => -libc::ENOTSUP,
+            };
+        }
+
+        // The microVM may already be running.
+        match RUNNING_VSOCKS.lock().unwrap().get(&ctx_id) {
+            Some(vsock) => {
+                let vsock = vsock.lock().unwrap();
+                if direction == KRUN_RATE_LIMIT_RX {
+                    vsock.update_rx_rate_limiter(rate_limiter);
+                } else {
+                    vsock.update_tx_rate_limiter(rate_limiter);
+                }
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
+    let mut port_map = HashMap::new();
+    let mut udp_port_map = HashMap::new();
+    let port_map_array: &[*const c_char] = slice::from_raw_parts(c_port_map, MAX_ARGS);
+    for item in port_map_array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let s = match CStr::from_ptr(*item).to_str() {
+                Ok(s) => s,
+                Err(_) => return -libc::EINVAL,
+            };
+            // Mappings are for TCP unless they say otherwise.
+            let (s, port_map) = match s.split_once('/') {
+                None => (s, &mut port_map),
+                Some((s, "tcp")) => (s, &mut port_map),
+                Some((s, "udp")) => (s, &mut udp_port_map),
+                Some(_) => return -libc::EINVAL,
+            };
+            let port_tuple: Vec<&str> = s.split(':').collect();
+            if port_tuple.len() != 2 {
+                return -libc::EINVAL;
+            }
+            let host_port: u16 = match port_tuple[0].parse() {
+                Ok(p) => p,
+                Err(_) => return -libc::EINVAL,
+            };
+            let guest_port: u16 = match port_tuple[1].parse() {
+                Ok(p) => p,
+                Err(_) => return -libc::EINVAL,
+            };
+
+            if port_map.contains_key(&guest_port) {
+                return -libc::EINVAL;
+            }
+            for hp in port_map.values() {
+                if *hp == host_port {
+                    return -libc::EINVAL;
+                }
+            }
+            port_map.insert(guest_port, host_port);
+        }
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.set_port_map(port_map, udp_port_map).is_err() {
+                return -libc::ENOTSUP;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_unix_socket_map(
+    ctx_id: u32,
+    c_unix_socket_map: *const *const c_char,
+) -> i32 {
+    let mut unix_socket_map = HashMap::new();
+    let unix_socket_map_array: &[*const c_char] =
+        slice::from_raw_parts(c_unix_socket_map, MAX_ARGS);
+    for item in unix_socket_map_array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let s = match CStr::from_ptr(*item).to_str() {
+                Ok(s) => s,
+                Err(_) => return -libc::EINVAL,
+            };
+            // The host path ends at the first ':', the guest path may hold more.
+            let (host_path, guest_path) = match s.split_once(':') {
+                Some((host_path, guest_path)) => {
+                    (PathBuf::from(host_path), PathBuf::from(guest_path))
+                }
+                None => return -libc::EINVAL,
+            };
+            if !host_path.is_absolute() || !guest_path.is_absolute() {
+                return -libc::EINVAL;
+            }
+
+            if unix_socket_map.contains_key(&guest_path) {
+                return -libc::EINVAL;
+            }
+            unix_socket_map.insert(guest_path, host_path);
+        }
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.set_unix_socket_map(unix_socket_map).is_err() {
+                return -libc::ENOTSUP;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_egress_policy(
+    ctx_id: u32,
+    c_rules: *const *const c_char,
+    denied_errno: i32,
+) -> i32 {
+    if denied_errno <= 0 {
+        return -libc::EINVAL;
+    }
+
+    let mut rules = Vec::new();
+    let rules_array: &[*const c_char] = slice::from_raw_parts(c_rules, MAX_ARGS);
+    for item in rules_array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let rule: EgressRule = match CStr::from_ptr(*item).to_str().map(str::parse) {
+                Ok(Ok(rule)) => rule,
+                Ok(Err(e)) => {
+                    error!("Invalid egress rule: {}", e);
+                    return -libc::EINVAL;
+                }
+                Err(_) => return -libc::EINVAL,
+            };
+            rules.push(rule);
+        }
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg
+                .set_egress_policy(EgressPolicy::new(rules, denied_errno))
+                .is_err()
+            {
+                return -libc::ENOTSUP;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
+    let rlimits = if c_rlimits.is_null() {
+        return -libc::EINVAL;
+    } else {
+        let mut strvec = Vec::new();
+
+        let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
+        for item in array.iter().take(MAX_ARGS) {
+            if item.is_null() {
+                break;
+            } else {
+                let s = match CStr::from_ptr(*item).to_str() {
+                    Ok(s) => s,
+                    Err(_) => return -libc::EINVAL,
+                };
+                strvec.push(s);
+            }
+        }
+
+        format!("\"{}\"", strvec.join(","))
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_rlimits(rlimits);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
+    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
+        Ok(workdir) => workdir,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
+    let mut strvec = Vec::new();
+
+    for item in array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
             let s = CStr::from_ptr(*item).to_str()?;
             strvec.push(format!("\"{s}\""));
         }
@@ -746,6 +2126,35 @@ pub unsafe extern "C" fn krun_set_tee_config_file(ctx_id: u32, c_filepath: *cons
     KRUN_SUCCESS
 }
 
+/// Creates the block devices configured in the context, to be attached when
+/// the microVM is built.
+#[cfg(target_os = "linux")]
+fn add_block_devices(ctx_cfg: &mut ContextConfig) -> Result<(), ()> {
+    if let Some(block_cfg) = ctx_cfg.get_root_block_cfg() {
+        if ctx_cfg.vmr.add_block_device(block_cfg).is_err() {
+            error!("Error configuring virtio-blk for root block");
+            return Err(());
+        }
+    }
+
+    if let Some(block_cfg) = ctx_cfg.get_data_block_cfg() {
+        if ctx_cfg.vmr.add_block_device(block_cfg).is_err() {
+            error!("Error configuring virtio-blk for data block");
+            return Err(());
+        }
+    }
+
+    for block_cfg in ctx_cfg.get_block_cfgs() {
+        let block_id = block_cfg.block_id.clone();
+        if let Err(e) = ctx_cfg.vmr.add_block_device(block_cfg) {
+            error!("Error configuring virtio-blk for block {}: {}", block_id, e);
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
 #[no_mangle]
 pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     #[cfg(target_os = "linux")]
@@ -765,7 +2174,10 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     };
 
-    let mut ctx_cfg = match CTX_MAP.lock().unwrap().remove(&ctx_id) {
+    // Hold the map until the devices are registered as running, so updates
+    // racing with the start find the context in one place or the other.
+    let mut ctx_map = CTX_MAP.lock().unwrap();
+    let mut ctx_cfg = match ctx_map.remove(&ctx_id) {
         Some(ctx_cfg) => ctx_cfg,
         None => return -libc::ENOENT,
     };
@@ -778,20 +2190,9 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     }
 
-    #[cfg(feature = "tee")]
-    if let Some(block_cfg) = ctx_cfg.get_root_block_cfg() {
-        if ctx_cfg.vmr.add_block_device(block_cfg).is_err() {
-            error!("Error configuring virtio-blk for root block");
-            return -libc::EINVAL;
-        }
-    }
-
-    #[cfg(feature = "tee")]
-    if let Some(block_cfg) = ctx_cfg.get_data_block_cfg() {
-        if ctx_cfg.vmr.add_block_device(block_cfg).is_err() {
-            error!("Error configuring virtio-blk for data block");
-            return -libc::EINVAL;
-        }
+    #[cfg(target_os = "linux")]
+    if add_block_devices(&mut ctx_cfg).is_err() {
+        return -libc::EINVAL;
     }
 
     /*
@@ -834,26 +2235,52 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
                 vsock_id: "vsock0".to_string(),
                 guest_cid: 3,
                 host_port_map: tsi_cfg.port_map,
+                host_udp_port_map: tsi_cfg.udp_port_map,
+                unix_socket_map: tsi_cfg.unix_socket_map,
+                egress_policy: tsi_cfg.egress_policy,
+                #[cfg(target_os = "linux")]
+                rx_rate_limiter: tsi_cfg.rx_rate_limiter,
+                #[cfg(target_os = "linux")]
+                tx_rate_limiter: tsi_cfg.tx_rate_limiter,
             };
             ctx_cfg.vmr.set_vsock_device(vsock_device_config).unwrap();
         }
         #[cfg(feature = "net")]
-        NetworkConfig::Passt(passt_cfg) => {
-            let network_interface_config = NetworkInterfaceConfig {
-                iface_id: "eth0".to_string(),
-                passt_fd: passt_cfg.fd,
-            };
-            ctx_cfg
-                .vmr
-                .add_network_interface(network_interface_config)
-                .expect("Failed to create network interface");
+        NetworkConfig::VirtioNet(ifaces) => {
+            for network_interface_config in ifaces {
+                // The device closes the socket even if it fails.
+                ctx_cfg
+                    .switch_ports
+                    .hand_over(&network_interface_config.backend);
+                if let Err(e) = ctx_cfg.vmr.add_network_interface(network_interface_config) {
+                    error!("Failed to create network interface: {}", e);
+                    return -libc::EINVAL;
+                }
+            }
         }
     }
 
+    #[cfg(target_os = "linux")]
+    RUNNING_BLOCKS
+        .lock()
+        .unwrap()
+        .insert(ctx_id, ctx_cfg.vmr.block.list.iter().cloned().collect());
+    #[cfg(feature = "net")]
+    RUNNING_NETS
+        .lock()
+        .unwrap()
+        .insert(ctx_id, ctx_cfg.vmr.net_builder.iter().cloned().collect());
+    #[cfg(target_os = "linux")]
+    if let Some(vsock) = ctx_cfg.vmr.vsock.get() {
+        RUNNING_VSOCKS.lock().unwrap().insert(ctx_id, vsock.clone());
+    }
+    drop(ctx_map);
+
     let _vmm = match vmm::builder::build_microvm(&ctx_cfg.vmr, &mut event_manager) {
         Ok(vmm) => vmm,
         Err(e) => {
             error!("Building the microVM failed: {:?}", e);
+            unregister_running_devices(ctx_id);
             return -libc::EINVAL;
         }
     };
@@ -863,8 +2290,361 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
             Ok(_) => {}
             Err(e) => {
                 error!("Error in EventManager loop: {:?}", e);
+                unregister_running_devices(ctx_id);
                 return -libc::EINVAL;
             }
         }
     }
 }
+
+#[allow(unused_variables)]
+fn unregister_running_devices(ctx_id: u32) {
+    #[cfg(target_os = "linux")]
+    RUNNING_BLOCKS.lock().unwrap().remove(&ctx_id);
+    #[cfg(feature = "net")]
+    RUNNING_NETS.lock().unwrap().remove(&ctx_id);
+    #[cfg(target_os = "linux")]
+    RUNNING_VSOCKS.lock().unwrap().remove(&ctx_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Creates a context without the kernel bundle, which the configuration
+    /// calls don't need.
+    fn create_ctx() -> u32 {
+        static NEXT_CTX_ID: AtomicU32 = AtomicU32::new(1 << 16);
+        let ctx_id = NEXT_CTX_ID.fetch_add(1, Ordering::Relaxed);
+        CTX_MAP
+            .lock()
+            .unwrap()
+            .insert(ctx_id, ContextConfig::default());
+        ctx_id
+    }
+
+    fn c_strings(strings: &[&str]) -> (Vec<CString>, Vec<*const c_char>) {
+        let strings: Vec<CString> = strings.iter().map(|s| CString::new(*s).unwrap()).collect();
+        let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        ptrs.push(std::ptr::null());
+        (strings, ptrs)
+    }
+
+    type PortMap = Option<HashMap<u16, u16>>;
+
+    fn tsi_port_maps(ctx_id: u32) -> (PortMap, PortMap) {
+        match &CTX_MAP.lock().unwrap().get(&ctx_id).unwrap().net_cfg {
+            NetworkConfig::Tsi(tsi_config) => {
+                (tsi_config.port_map.clone(), tsi_config.udp_port_map.clone())
+            }
+            #[cfg(feature = "net")]
+            NetworkConfig::VirtioNet(_) => panic!("not using TSI"),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_net_switch_destroy() {
+        let switch_id = krun_net_switch_create();
+        assert!(switch_id >= 0);
+        let ctx_id = create_ctx();
+        assert_eq!(
+            unsafe { krun_add_net_switch_port(ctx_id, switch_id as u32, std::ptr::null()) },
+            KRUN_SUCCESS
+        );
+        assert_eq!(krun_net_switch_destroy(switch_id as u32), KRUN_SUCCESS);
+        assert_eq!(krun_net_switch_destroy(switch_id as u32), -libc::ENOENT);
+        assert_eq!(
+            unsafe { krun_add_net_switch_port(ctx_id, switch_id as u32, std::ptr::null()) },
+            -libc::ENOENT
+        );
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_free_ctx_closes_switch_ports() {
+        let ctx_id = create_ctx();
+        let (switch_id, fd) = add_switch_port(ctx_id);
+
+        assert_eq!(krun_free_ctx(ctx_id), KRUN_SUCCESS);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0);
+        assert_eq!(krun_net_switch_destroy(switch_id), KRUN_SUCCESS);
+    }
+
+    /// Adds a port of a new switch to the context, and returns the switch
+    /// and the socket of the port.
+    #[cfg(feature = "net")]
+    fn add_switch_port(ctx_id: u32) -> (u32, c_int) {
+        let switch_id = krun_net_switch_create();
+        assert!(switch_id >= 0);
+        assert_eq!(
+            unsafe { krun_add_net_switch_port(ctx_id, switch_id as u32, std::ptr::null()) },
+            KRUN_SUCCESS
+        );
+        let fd = CTX_MAP.lock().unwrap()[&ctx_id].switch_ports.0[0];
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        (switch_id as u32, fd)
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    #[test]
+    fn test_start_error_closes_switch_ports() {
+        let ctx_id = create_ctx();
+        let (switch_id, fd) = add_switch_port(ctx_id);
+        let (_strings, args) = c_strings(&["vda", "/nonexistent/disk.img"]);
+        assert_eq!(
+            unsafe { krun_add_disk(ctx_id, args[0], args[1], KRUN_DISK_FORMAT_RAW, false) },
+            KRUN_SUCCESS
+        );
+
+        // The disk fails to open before the net devices are created.
+        assert_eq!(krun_start_enter(ctx_id), -libc::EINVAL);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0);
+        assert_eq!(krun_net_switch_destroy(switch_id), KRUN_SUCCESS);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_net_device_error_closes_switch_port() {
+        let ctx_id = create_ctx();
+        let (switch_id, fd) = add_switch_port(ctx_id);
+        let (_strings, iface_id) = c_strings(&["eth0"]);
+        assert_eq!(
+            unsafe { krun_set_net_queues(ctx_id, iface_id[0], 2) },
+            KRUN_SUCCESS
+        );
+
+        // Switch ports only have one queue pair.
+        assert_eq!(krun_start_enter(ctx_id), -libc::EINVAL);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0);
+        assert_eq!(krun_net_switch_destroy(switch_id), KRUN_SUCCESS);
+    }
+
+    #[cfg(feature = "net")]
+    fn net_iface_ids(ctx_id: u32) -> Vec<String> {
+        match &CTX_MAP.lock().unwrap().get(&ctx_id).unwrap().net_cfg {
+            NetworkConfig::VirtioNet(ifaces) => {
+                ifaces.iter().map(|iface| iface.iface_id.clone()).collect()
+            }
+            NetworkConfig::Tsi(_) => Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_passt_fd_then_add_net() {
+        let ctx_id = create_ctx();
+        let (_strings, backend) = c_strings(&["unixgram:/nonexistent"]);
+        assert_eq!(unsafe { krun_set_passt_fd(ctx_id, 100) }, KRUN_SUCCESS);
+        // The interface set by krun_set_passt_fd stays in place.
+        let (_id, iface_id) = c_strings(&["eth1"]);
+        assert_eq!(
+            unsafe { krun_add_net(ctx_id, iface_id[0], backend[0], std::ptr::null(), 0) },
+            KRUN_SUCCESS
+        );
+        let (_id, iface_id) = c_strings(&["eth0"]);
+        assert_eq!(
+            unsafe { krun_add_net(ctx_id, iface_id[0], backend[0], std::ptr::null(), 0) },
+            -libc::EEXIST
+        );
+        assert_eq!(net_iface_ids(ctx_id), vec!["eth0", "eth1"]);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_add_net_then_passt_fd() {
+        let ctx_id = create_ctx();
+        let (_strings, backend) = c_strings(&["unixgram:/nonexistent"]);
+        let (_id, iface_id) = c_strings(&["wan"]);
+        assert_eq!(
+            unsafe { krun_add_net(ctx_id, iface_id[0], backend[0], std::ptr::null(), 0) },
+            KRUN_SUCCESS
+        );
+        assert_eq!(unsafe { krun_set_passt_fd(ctx_id, 100) }, KRUN_SUCCESS);
+        assert_eq!(net_iface_ids(ctx_id), vec!["wan", "eth0"]);
+
+        // Interfaces without an ID take "eth0" first.
+        let ctx_id = create_ctx();
+        assert_eq!(
+            unsafe { krun_add_net(ctx_id, std::ptr::null(), backend[0], std::ptr::null(), 0) },
+            KRUN_SUCCESS
+        );
+        assert_eq!(unsafe { krun_set_passt_fd(ctx_id, 100) }, -libc::EEXIST);
+        assert_eq!(net_iface_ids(ctx_id), vec!["eth0"]);
+    }
+
+    #[test]
+    fn test_unix_socket_map() {
+        let ctx_id = create_ctx();
+        let (_strings, socket_map) = c_strings(&[
+            "/var/run/docker.sock:/run/docker.sock",
+            "/tmp/host.sock:/run/with:colon.sock",
+        ]);
+        assert_eq!(
+            unsafe { krun_set_unix_socket_map(ctx_id, socket_map.as_ptr()) },
+            KRUN_SUCCESS
+        );
+        match &CTX_MAP.lock().unwrap().get(&ctx_id).unwrap().net_cfg {
+            NetworkConfig::Tsi(tsi_config) => assert_eq!(
+                tsi_config.unix_socket_map,
+                HashMap::from([
+                    ("/run/docker.sock".into(), "/var/run/docker.sock".into()),
+                    ("/run/with:colon.sock".into(), "/tmp/host.sock".into()),
+                ])
+            ),
+            #[cfg(feature = "net")]
+            NetworkConfig::VirtioNet(_) => panic!("not using TSI"),
+        }
+
+        for entry in [
+            "/tmp/host.sock",
+            "tmp/host.sock:/run/host.sock",
+            "/tmp/host.sock:run/host.sock",
+        ] {
+            let (_strings, socket_map) = c_strings(&[entry]);
+            assert_eq!(
+                unsafe { krun_set_unix_socket_map(ctx_id, socket_map.as_ptr()) },
+                -libc::EINVAL,
+                "{entry}"
+            );
+        }
+
+        let (_strings, socket_map) =
+            c_strings(&["/tmp/a.sock:/run/a.sock", "/tmp/b.sock:/run/a.sock"]);
+        assert_eq!(
+            unsafe { krun_set_unix_socket_map(ctx_id, socket_map.as_ptr()) },
+            -libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_tcp_port_map_leaves_udp_binds() {
+        let ctx_id = create_ctx();
+        let (_strings, port_map) = c_strings(&["18000:8000"]);
+        assert_eq!(
+            unsafe { krun_set_port_map(ctx_id, port_map.as_ptr()) },
+            KRUN_SUCCESS
+        );
+        let (tcp, udp) = tsi_port_maps(ctx_id);
+        assert_eq!(tcp, Some(HashMap::from([(8000, 18000)])));
+        // UDP binds still reach the same port of the host.
+        assert_eq!(udp, None);
+    }
+
+    #[test]
+    fn test_udp_port_map() {
+        let ctx_id = create_ctx();
+        let (_strings, port_map) = c_strings(&["18000:8000", "15353:5353/udp"]);
+        assert_eq!(
+            unsafe { krun_set_port_map(ctx_id, port_map.as_ptr()) },
+            KRUN_SUCCESS
+        );
+        let (tcp, udp) = tsi_port_maps(ctx_id);
+        assert_eq!(tcp, Some(HashMap::from([(8000, 18000)])));
+        assert_eq!(udp, Some(HashMap::from([(5353, 15353)])));
+
+        // Setting the map again replaces both of them.
+        let (_strings, port_map) = c_strings(&["18001:8001/tcp"]);
+        assert_eq!(
+            unsafe { krun_set_port_map(ctx_id, port_map.as_ptr()) },
+            KRUN_SUCCESS
+        );
+        let (tcp, udp) = tsi_port_maps(ctx_id);
+        assert_eq!(tcp, Some(HashMap::from([(8001, 18001)])));
+        assert_eq!(udp, None);
+
+        let (_strings, port_map) = c_strings(&["18000:8000/sctp"]);
+        assert_eq!(
+            unsafe { krun_set_port_map(ctx_id, port_map.as_ptr()) },
+            -libc::EINVAL
+        );
+    }
+
+    /// Builds the superblock and the single level hash tree of `data` like
+    /// `veritysetup format` does, returning them along with the root hash in
+    /// hexadecimal.
+    #[cfg(target_os = "linux")]
+    fn verity_format(data: &[u8]) -> (Vec<u8>, String) {
+        use sha2::{Digest, Sha256};
+
+        const BLOCK_SIZE: usize = 4096;
+        const SALT: &[u8] = b"krun";
+        let digest = |block: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(SALT);
+            hasher.update(block);
+            hasher.finalize().to_vec()
+        };
+
+        let mut hash_block: Vec<u8> = data.chunks(BLOCK_SIZE).flat_map(digest).collect();
+        hash_block.resize(BLOCK_SIZE, 0);
+        let mut hash_tree = vec![0u8; BLOCK_SIZE];
+        hash_tree[0..8].copy_from_slice(b"verity\0\0");
+        hash_tree[8..12].copy_from_slice(&1u32.to_le_bytes());
+        hash_tree[12..16].copy_from_slice(&1u32.to_le_bytes());
+        hash_tree[32..38].copy_from_slice(b"sha256");
+        hash_tree[64..68].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        hash_tree[68..72].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        hash_tree[72..80].copy_from_slice(&((data.len() / BLOCK_SIZE) as u64).to_le_bytes());
+        hash_tree[80..82].copy_from_slice(&(SALT.len() as u16).to_le_bytes());
+        hash_tree[88..88 + SALT.len()].copy_from_slice(SALT);
+        hash_tree.extend_from_slice(&hash_block);
+
+        let root_hash = digest(&hash_block)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        (hash_tree, root_hash)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_disk_verity() {
+        use std::os::unix::fs::FileExt;
+
+        use utils::tempfile::TempFile;
+
+        let data: Vec<u8> = (0..16 * 4096).map(|i| (i / 512) as u8).collect();
+        let (hash_tree, root_hash) = verity_format(&data);
+        let data_file = TempFile::new().unwrap();
+        data_file.as_file().write_all_at(&data, 0).unwrap();
+        let hash_file = TempFile::new().unwrap();
+        hash_file.as_file().write_all_at(&hash_tree, 0).unwrap();
+
+        let ctx_id = create_ctx();
+        let (_strings, args) = c_strings(&[
+            "verified",
+            data_file.as_path().to_str().unwrap(),
+            hash_file.as_path().to_str().unwrap(),
+            &root_hash,
+        ]);
+        assert_eq!(
+            unsafe { krun_add_disk(ctx_id, args[0], args[1], KRUN_DISK_FORMAT_RAW, false) },
+            KRUN_SUCCESS
+        );
+        assert_eq!(
+            unsafe { krun_set_disk_verity(ctx_id, args[0], args[2], 0, args[3]) },
+            KRUN_SUCCESS
+        );
+
+        // Create the devices like krun_start_enter does.
+        let mut ctx_cfg = CTX_MAP.lock().unwrap().remove(&ctx_id).unwrap();
+        add_block_devices(&mut ctx_cfg).unwrap();
+        let block = ctx_cfg.vmr.block.list[0].lock().unwrap();
+        assert!(block.is_read_only());
+
+        let mut buf = vec![0u8; 4096];
+        block.disk().read_exact_at(&mut buf, 4096).unwrap();
+        assert_eq!(buf, data[4096..8192]);
+
+        // Blocks changed on the host after the hash tree was built fail to read.
+        data_file
+            .as_file()
+            .write_all_at(&[0xff], 5 * 4096 + 17)
+            .unwrap();
+        assert!(block.disk().read_exact_at(&mut buf, 5 * 4096).is_err());
+        block.disk().read_exact_at(&mut buf, 4 * 4096).unwrap();
+    }
+}
@@ -30,7 +30,7 @@ use kbs_types::Tee;
 use crate::resources::TeeConfig;
 #[cfg(target_os = "linux")]
 use crate::signal_handler::register_sigwinch_handler;
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
 use crate::vmm_config::block::BlockBuilder;
 use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
 #[cfg(not(feature = "tee"))]
@@ -583,7 +583,7 @@ pub fn build_microvm(
         shm_region,
         intc.clone(),
     )?;
-    #[cfg(feature = "tee")]
+    #[cfg(target_os = "linux")]
     attach_block_devices(&mut vmm, &vm_resources.block, event_manager, intc.clone())?;
     if let Some(vsock) = vm_resources.vsock.get() {
         attach_unixsock_vsock_device(&mut vmm, vsock, event_manager, intc)?;
@@ -1202,7 +1202,7 @@ fn attach_balloon_device(
     Ok(())
 }
 
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
 fn attach_block_devices(
     vmm: &mut Vmm,
     block_devs: &BlockBuilder,
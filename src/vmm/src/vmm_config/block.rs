@@ -2,7 +2,8 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Block, CacheType};
+use devices::rate_limiter::RateLimiterConfig;
+use devices::virtio::{Block, CacheType, DiskIdentity, ImageType, OverlayConfig, VerityConfig};
 
 #[derive(Debug)]
 pub enum BlockConfigError {
@@ -26,8 +27,14 @@ pub struct BlockDeviceConfig {
     pub block_id: String,
     pub cache_type: CacheType,
     pub disk_image_path: String,
+    pub disk_image_format: ImageType,
+    pub overlay: Option<OverlayConfig>,
+    pub verity: Option<VerityConfig>,
     pub is_disk_read_only: bool,
     pub is_disk_root: bool,
+    pub num_queues: u16,
+    pub identity: DiskIdentity,
+    pub rate_limiter: RateLimiterConfig,
 }
 
 #[derive(Default)]
@@ -49,14 +56,21 @@ impl BlockBuilder {
     }
 
     pub fn create_block(config: BlockDeviceConfig) -> Result<Block> {
-        devices::virtio::Block::new(
+        let mut block = devices::virtio::Block::new(
             config.block_id,
             None,
             config.cache_type,
             config.disk_image_path,
+            config.disk_image_format,
+            config.overlay,
+            config.verity,
             config.is_disk_read_only,
             config.is_disk_root,
+            config.num_queues,
+            config.identity,
         )
-        .map_err(BlockConfigError::CreateBlockDevice)
+        .map_err(BlockConfigError::CreateBlockDevice)?;
+        block.update_rate_limiter(config.rate_limiter);
+        Ok(block)
     }
 }
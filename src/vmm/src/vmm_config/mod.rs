@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 /// Wrapper for configuring the Block devices attached to the microVM.
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
 pub mod block;
 
 /// Wrapper for configuring the microVM boot source.
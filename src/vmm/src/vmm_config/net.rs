@@ -2,19 +2,36 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt;
-use std::os::fd::RawFd;
 use std::result;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::Net;
+#[cfg(target_os = "linux")]
+use devices::rate_limiter::RateLimiterConfig;
+use devices::virtio::{Net, PcapConfig, VirtioNetBackend};
 
 #[derive(Debug, PartialEq)]
 //#[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
-    /// File descriptor of passt socket to connect this interface to
-    pub passt_fd: RawFd,
+    /// Where the frames of this interface go on the host.
+    pub backend: VirtioNetBackend,
+    /// Number of receive and transmit queue pairs.
+    pub num_queue_pairs: u16,
+    /// MAC address of the interface, or `None` to let the guest pick one.
+    pub mac: Option<[u8; 6]>,
+    /// MTU advertised to the guest, or `None` to not advertise one.
+    pub mtu: Option<u16>,
+    /// Capture file for the frames of this interface.
+    pub pcap: Option<PcapConfig>,
+    /// Whether the link is up when the microVM starts.
+    pub link_up: bool,
+    /// Limits of the frames going to the guest.
+    #[cfg(target_os = "linux")]
+    pub rx_rate_limiter: RateLimiterConfig,
+    /// Limits of the frames coming from the guest.
+    #[cfg(target_os = "linux")]
+    pub tx_rate_limiter: RateLimiterConfig,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -85,6 +102,27 @@ impl NetBuilder {
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
         // Create and return the Net device
-        Net::new(cfg.iface_id, cfg.passt_fd).map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = Net::new(
+            cfg.iface_id,
+            cfg.backend,
+            cfg.num_queue_pairs,
+            cfg.mac,
+            cfg.mtu,
+        )
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if let Some(pcap) = cfg.pcap {
+            net.set_pcap(pcap)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        if !cfg.link_up {
+            // The device isn't running yet, so this can't fail.
+            net.set_link_state(false).unwrap();
+        }
+        #[cfg(target_os = "linux")]
+        {
+            net.update_rx_rate_limiter(cfg.rx_rate_limiter);
+            net.update_tx_rate_limiter(cfg.tx_rate_limiter);
+        }
+        Ok(net)
     }
 }
@@ -3,9 +3,12 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Vsock, VsockError};
+#[cfg(target_os = "linux")]
+use devices::rate_limiter::RateLimiterConfig;
+use devices::virtio::{EgressPolicy, Vsock, VsockError};
 
 type MutexVsock = Arc<Mutex<Vsock>>;
 
@@ -37,6 +40,18 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// An optional map of host to guest port mappings.
     pub host_port_map: Option<HashMap<u16, u16>>,
+    /// An optional map of host to guest UDP port mappings.
+    pub host_udp_port_map: Option<HashMap<u16, u16>>,
+    /// Map of the paths of unix sockets in the guest to paths in the host.
+    pub unix_socket_map: HashMap<PathBuf, PathBuf>,
+    /// Destinations the guest may reach through TSI.
+    pub egress_policy: EgressPolicy,
+    /// Limits of the data TSI passes to the guest.
+    #[cfg(target_os = "linux")]
+    pub rx_rate_limiter: RateLimiterConfig,
+    /// Limits of the data the guest sends through TSI.
+    #[cfg(target_os = "linux")]
+    pub tx_rate_limiter: RateLimiterConfig,
 }
 
 struct VsockWrapper {
@@ -71,8 +86,20 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<Vsock> {
-        Vsock::new(u64::from(cfg.guest_cid), cfg.host_port_map)
-            .map_err(VsockConfigError::CreateVsockDevice)
+        let vsock = Vsock::new(
+            u64::from(cfg.guest_cid),
+            cfg.host_port_map,
+            cfg.host_udp_port_map,
+            cfg.unix_socket_map,
+            cfg.egress_policy,
+        )
+        .map_err(VsockConfigError::CreateVsockDevice)?;
+        #[cfg(target_os = "linux")]
+        {
+            vsock.update_rx_rate_limiter(cfg.rx_rate_limiter);
+            vsock.update_tx_rate_limiter(cfg.tx_rate_limiter);
+        }
+        Ok(vsock)
     }
 }
 
@@ -107,6 +134,13 @@ pub(crate) mod tests {
             vsock_id: vsock_dev_id.to_string(),
             guest_cid: 3,
             host_port_map: None,
+            host_udp_port_map: None,
+            unix_socket_map: HashMap::new(),
+            egress_policy: Default::default(),
+            #[cfg(target_os = "linux")]
+            rx_rate_limiter: Default::default(),
+            #[cfg(target_os = "linux")]
+            tx_rate_limiter: Default::default(),
         }
     }
 